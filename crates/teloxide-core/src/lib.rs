@@ -43,6 +43,8 @@
 //! - `erased` — enables [`ErasedRequester`] bot adaptor
 //! - `throttle` — enables [`Throttle`] bot adaptor
 //! - `cache_me` — enables [`CacheMe`] bot adaptor
+//! - `mock` — enables [`MockBot`], a bot for tests which doesn't talk to
+//!   Telegram
 //! - `full` — enables all features except `nightly` and tls-related
 //! - `nightly` — enables nightly-only features, currently:
 //!   - Removes some future boxing using `#![feature(type_alias_impl_trait)]`
@@ -53,6 +55,7 @@
 //! [`ErasedRequester`]: adaptors::ErasedRequester
 //! [`Throttle`]: adaptors::Throttle
 //! [`CacheMe`]: adaptors::CacheMe
+//! [`MockBot`]: mock::MockBot
 //! [`native-tls`]: https://docs.rs/native-tls
 //! [`rustls`]: https://docs.rs/rustls
 
@@ -118,6 +121,8 @@ pub use self::{
 
 pub mod adaptors;
 pub mod errors;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod net;
 pub mod payloads;
 pub mod prelude;
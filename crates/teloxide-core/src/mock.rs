@@ -0,0 +1,443 @@
+//! A bot which doesn't talk to Telegram, for tests.
+
+use std::{
+    collections::HashMap,
+    future::IntoFuture,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::{
+    payloads::*,
+    requests::{HasPayload, Output, Payload, Request, Requester},
+    types::*,
+    ApiError, Bot, RequestError,
+};
+
+/// A bot used to build payloads.
+///
+/// Its requests are never sent, they just give [`MockBot`] the same payloads
+/// as [`Bot`] would send.
+static PAYLOADS: Lazy<Bot> = Lazy::new(|| Bot::with_client("", reqwest::Client::new()));
+
+/// A [`Requester`] which records requests instead of sending them to
+/// Telegram.
+///
+/// Every request is recorded when it's sent and answered with a response set
+/// via [`MockBot::respond_with`] or [`MockBot::fail_with`]. Without one,
+/// `GetMe` returns [`MockBot::me`], requests returning `True` succeed and
+/// requests returning a [`Message`] (e.g. `SendMessage`) return a message in
+/// the chat of the request with the text of the request. Other requests fail
+/// with [`ApiError::Unknown`].
+///
+/// Clones of a `MockBot` share recorded requests and responses.
+///
+/// ## Examples
+///
+/// ```
+/// use teloxide_core::{mock::MockBot, payloads::GetChatMemberCount, prelude::*, types::ChatId};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let bot = MockBot::new();
+/// bot.respond_with::<GetChatMemberCount>(42);
+///
+/// assert_eq!(bot.get_chat_member_count(ChatId(-1)).await.unwrap(), 42);
+/// bot.send_message(ChatId(1), "Hi!").await.unwrap();
+///
+/// let sent = bot.take_sent();
+/// assert_eq!(sent[1].method, "SendMessage");
+/// assert_eq!(sent[1].text(), Some("Hi!"));
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockBot {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    me: Me,
+    responses: HashMap<&'static str, Result<Value, ApiError>>,
+    sent: Vec<SentRequest>,
+    last_message_id: i32,
+}
+
+/// A request recorded by [`MockBot`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SentRequest {
+    /// The name of the payload, e.g. `SendMessage`.
+    pub method: &'static str,
+
+    /// The payload, serialized as it would be sent to Telegram.
+    pub payload: Value,
+}
+
+impl SentRequest {
+    /// Returns the `text` of the request, if any.
+    #[must_use]
+    pub fn text(&self) -> Option<&str> {
+        self.payload.get("text").and_then(Value::as_str)
+    }
+
+    /// Returns the `chat_id` of the request, if it's a numeric ID.
+    #[must_use]
+    pub fn chat_id(&self) -> Option<ChatId> {
+        self.payload.get("chat_id").and_then(Value::as_i64).map(ChatId)
+    }
+}
+
+impl MockBot {
+    /// Creates a bot with a default [`Me`].
+    #[must_use]
+    pub fn new() -> Self {
+        let me = Me {
+            user: User {
+                id: UserId(1),
+                is_bot: true,
+                first_name: "Mock".to_owned(),
+                last_name: None,
+                username: Some("mock_bot".to_owned()),
+                language_code: None,
+                is_premium: false,
+                added_to_attachment_menu: false,
+            },
+            can_join_groups: true,
+            can_read_all_group_messages: false,
+            supports_inline_queries: false,
+            can_connect_to_business: false,
+        };
+        Self::with_me(me)
+    }
+
+    /// Creates a bot which returns `me` from `GetMe`.
+    #[must_use]
+    pub fn with_me(me: Me) -> Self {
+        let state = State { me, responses: HashMap::new(), sent: Vec::new(), last_message_id: 0 };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Returns the user of this bot.
+    #[must_use]
+    pub fn me(&self) -> Me {
+        self.state().me.clone()
+    }
+
+    /// Makes all the following requests with the payload `P` return
+    /// `response`.
+    pub fn respond_with<P>(&self, response: P::Output)
+    where
+        P: Payload,
+        P::Output: Serialize,
+    {
+        let response = serde_json::to_value(response).expect("Cannot serialize the response");
+        self.state().responses.insert(P::NAME, Ok(response));
+    }
+
+    /// Makes all the following requests with the payload `P` fail with
+    /// `error`.
+    pub fn fail_with<P>(&self, error: ApiError)
+    where
+        P: Payload,
+    {
+        self.state().responses.insert(P::NAME, Err(error));
+    }
+
+    /// Returns the requests sent so far.
+    #[must_use]
+    pub fn sent(&self) -> Vec<SentRequest> {
+        self.state().sent.clone()
+    }
+
+    /// Returns the requests sent so far and forgets them.
+    pub fn take_sent(&self) -> Vec<SentRequest> {
+        std::mem::take(&mut self.state().sent)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Records a request and returns a response to it.
+    fn respond<P>(&self, payload: &P) -> Result<P::Output, RequestError>
+    where
+        P: Payload + Serialize,
+        P::Output: DeserializeOwned,
+    {
+        let payload = serde_json::to_value(payload).expect("Cannot serialize the payload");
+        let mut state = self.state();
+        state.sent.push(SentRequest { method: P::NAME, payload: payload.clone() });
+
+        match state.responses.get(P::NAME) {
+            Some(Ok(response)) => return parse(response.clone()),
+            Some(Err(error)) => return Err(RequestError::Api(error.clone())),
+            None => {}
+        }
+
+        if P::NAME == GetMe::NAME {
+            return parse(serde_json::to_value(&state.me).expect("Cannot serialize `Me`"));
+        }
+        if let Ok(output) = serde_json::from_value(Value::Bool(true)) {
+            return Ok(output);
+        }
+        if let Ok(output) = serde_json::from_value(state.fake_message(&payload)) {
+            return Ok(output);
+        }
+
+        Err(RequestError::Api(ApiError::Unknown(format!(
+            "no response for `{}`, set one via `MockBot::respond_with`",
+            P::NAME
+        ))))
+    }
+}
+
+impl Default for MockBot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    /// Makes a message sent by this bot in reply to a request.
+    fn fake_message(&mut self, payload: &Value) -> Value {
+        self.last_message_id += 1;
+        let chat_id = payload.get("chat_id").and_then(Value::as_i64).unwrap_or(0);
+        let chat = if chat_id > 0 {
+            json!({ "id": chat_id, "type": "private", "first_name": "User" })
+        } else {
+            json!({ "id": chat_id, "type": "supergroup", "title": "Chat" })
+        };
+        let date = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let mut message = json!({
+            "message_id": payload.get("message_id").cloned().unwrap_or(self.last_message_id.into()),
+            "date": date,
+            "chat": chat,
+            "from": self.me.user,
+        });
+        for field in ["text", "message_thread_id", "reply_markup"] {
+            if let Some(value) = payload.get(field) {
+                message[field] = value.clone();
+            }
+        }
+        message
+    }
+}
+
+fn parse<T>(value: Value) -> Result<T, RequestError>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_value(value.clone())
+        .map_err(|source| RequestError::InvalidJson { source, raw: value.to_string().into() })
+}
+
+/// A request of [`MockBot`].
+#[must_use = "Requests are lazy and do nothing unless sent"]
+#[derive(Clone, Debug)]
+pub struct MockRequest<P> {
+    bot: MockBot,
+    payload: P,
+}
+
+impl<P> HasPayload for MockRequest<P>
+where
+    P: Payload,
+{
+    type Payload = P;
+
+    fn payload_mut(&mut self) -> &mut Self::Payload {
+        &mut self.payload
+    }
+
+    fn payload_ref(&self) -> &Self::Payload {
+        &self.payload
+    }
+}
+
+impl<P> Request for MockRequest<P>
+where
+    P: Payload + Serialize + Clone + Send + 'static,
+    P::Output: DeserializeOwned + Send,
+{
+    type Err = RequestError;
+    type Send = BoxFuture<'static, Result<Output<Self>, Self::Err>>;
+    type SendRef = BoxFuture<'static, Result<Output<Self>, Self::Err>>;
+
+    fn send(self) -> Self::Send {
+        Box::pin(async move { self.bot.respond(&self.payload) })
+    }
+
+    fn send_ref(&self) -> Self::SendRef {
+        self.clone().send()
+    }
+}
+
+impl<P> IntoFuture for MockRequest<P>
+where
+    P: Payload + Serialize + Clone + Send + 'static,
+    P::Output: DeserializeOwned + Send,
+{
+    type Output = Result<P::Output, RequestError>;
+    type IntoFuture = <Self as Request>::Send;
+
+    fn into_future(self) -> Self::IntoFuture {
+        self.send()
+    }
+}
+
+macro_rules! fty {
+    ($T:ident) => {
+        MockRequest<$T>
+    };
+}
+
+macro_rules! fwd_payload {
+    ($m:ident $this:ident ($($arg:ident : $T:ty),*)) => {
+        MockRequest { bot: $this.clone(), payload: PAYLOADS.$m($($arg),*).payload_ref().clone() }
+    };
+}
+
+impl Requester for MockBot {
+    type Err = RequestError;
+
+    requester_forward! {
+        get_me,
+        log_out,
+        close,
+        get_updates,
+        set_webhook,
+        delete_webhook,
+        get_webhook_info,
+        forward_message,
+        forward_messages,
+        copy_message,
+        copy_messages,
+        send_message,
+        send_photo,
+        send_audio,
+        send_document,
+        send_video,
+        send_animation,
+        send_voice,
+        send_video_note,
+        send_media_group,
+        send_location,
+        edit_message_live_location,
+        edit_message_live_location_inline,
+        stop_message_live_location,
+        stop_message_live_location_inline,
+        send_venue,
+        send_contact,
+        send_poll,
+        send_dice,
+        send_chat_action,
+        set_message_reaction,
+        get_user_profile_photos,
+        get_file,
+        kick_chat_member,
+        ban_chat_member,
+        unban_chat_member,
+        restrict_chat_member,
+        promote_chat_member,
+        set_chat_administrator_custom_title,
+        ban_chat_sender_chat,
+        unban_chat_sender_chat,
+        set_chat_permissions,
+        export_chat_invite_link,
+        create_chat_invite_link,
+        edit_chat_invite_link,
+        revoke_chat_invite_link,
+        set_chat_photo,
+        delete_chat_photo,
+        set_chat_title,
+        set_chat_description,
+        pin_chat_message,
+        unpin_chat_message,
+        unpin_all_chat_messages,
+        leave_chat,
+        get_chat,
+        get_chat_administrators,
+        get_chat_members_count,
+        get_chat_member_count,
+        get_chat_member,
+        set_chat_sticker_set,
+        delete_chat_sticker_set,
+        get_forum_topic_icon_stickers,
+        create_forum_topic,
+        edit_forum_topic,
+        close_forum_topic,
+        reopen_forum_topic,
+        delete_forum_topic,
+        unpin_all_forum_topic_messages,
+        edit_general_forum_topic,
+        close_general_forum_topic,
+        reopen_general_forum_topic,
+        hide_general_forum_topic,
+        unhide_general_forum_topic,
+        unpin_all_general_forum_topic_messages,
+        answer_callback_query,
+        get_user_chat_boosts,
+        set_my_commands,
+        get_business_connection,
+        get_my_commands,
+        set_my_name,
+        get_my_name,
+        set_my_description,
+        get_my_description,
+        set_my_short_description,
+        get_my_short_description,
+        set_chat_menu_button,
+        get_chat_menu_button,
+        set_my_default_administrator_rights,
+        get_my_default_administrator_rights,
+        delete_my_commands,
+        answer_inline_query,
+        answer_web_app_query,
+        edit_message_text,
+        edit_message_text_inline,
+        edit_message_caption,
+        edit_message_caption_inline,
+        edit_message_media,
+        edit_message_media_inline,
+        edit_message_reply_markup,
+        edit_message_reply_markup_inline,
+        stop_poll,
+        delete_message,
+        delete_messages,
+        send_sticker,
+        get_sticker_set,
+        get_custom_emoji_stickers,
+        upload_sticker_file,
+        create_new_sticker_set,
+        add_sticker_to_set,
+        set_sticker_position_in_set,
+        delete_sticker_from_set,
+        replace_sticker_in_set,
+        set_sticker_set_thumbnail,
+        set_custom_emoji_sticker_set_thumbnail,
+        set_sticker_set_title,
+        delete_sticker_set,
+        set_sticker_emoji_list,
+        set_sticker_keywords,
+        set_sticker_mask_position,
+        send_invoice,
+        create_invoice_link,
+        answer_shipping_query,
+        answer_pre_checkout_query,
+        set_passport_data_errors,
+        send_game,
+        set_game_score,
+        set_game_score_inline,
+        get_game_high_scores,
+        approve_chat_join_request,
+        decline_chat_join_request
+        => fwd_payload, fty
+    }
+}
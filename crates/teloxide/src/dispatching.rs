@@ -214,13 +214,23 @@
 //! [`examples/dispatching_features.rs`]: https://github.com/teloxide/teloxide/blob/master/crates/teloxide/examples/dispatching_features.rs
 //! [`Update`]: crate::types::Update
 
+pub mod data;
+pub mod dedup;
 pub mod dialogue;
+pub mod jobs;
+pub mod queue;
 
 mod dispatcher;
 mod distribution;
 mod filter_ext;
 mod handler_description;
 mod handler_ext;
+mod handler_tree;
+mod isolation;
+mod lanes;
+mod middleware;
+mod rate_limit;
+mod workers;
 
 #[cfg(feature = "tracing")]
 mod tracing;
@@ -231,6 +241,12 @@ pub use distribution::DefaultKey;
 pub use filter_ext::{MessageFilterExt, UpdateFilterExt};
 pub use handler_description::DpHandlerDescription;
 pub use handler_ext::{filter_command, filter_mention_command, HandlerExt};
+pub use handler_tree::{HandlerLeaf, HandlerNode, HandlerTree, LeafKind};
+pub use isolation::HandlerFailure;
+pub use lanes::Lane;
+pub use middleware::{Middleware, Next};
+pub use rate_limit::{Quota, RateLimiter};
+pub use workers::WorkerMetrics;
 
 #[cfg(feature = "tracing")]
 pub use self::tracing::UpdateHandlerTracingExt;
@@ -0,0 +1,384 @@
+//! Support for per-user and per-chat data.
+//!
+//! While [dialogues] store the state of a conversation, bots often need to
+//! persist arbitrary data such as a user's language, timezone or opt-ins. This
+//! module provides [`UserData`] and [`ChatData`], handles for reading and
+//! writing a value of type `T` associated with a user or a chat, which is kept
+//! in a [`DataStorage`] under a name, so that one storage can keep several
+//! kinds of data.
+//!
+//! [`DataStorage`] is implemented for [`InMemDataStorage`] and, depending on
+//! enabled features, for [`SqliteStorage`], [`PostgresStorage`] and
+//! [`RedisStorage`], so the same storage can hold both dialogues and data. The
+//! data is kept separately from dialogues and encoded with the same
+//! [`Serializer`].
+//!
+//! The handles are passed to handlers by [`enter_user_data`] and
+//! [`enter_chat_data`]:
+//!
+//! ```no_run
+//! use teloxide::{
+//!     dispatching::data::{self, InMemDataStorage, UserData},
+//!     prelude::*,
+//! };
+//!
+//! #[derive(Clone, Default)]
+//! struct Settings {
+//!     language: Option<String>,
+//! }
+//!
+//! type Storage = InMemDataStorage<Settings>;
+//!
+//! let handler = Update::filter_message()
+//!     .chain(data::enter_user_data::<Message, Storage, Settings, _>("settings"))
+//!     .endpoint(
+//!         |bot: Bot, msg: Message, data: UserData<Settings, Storage>, settings: Settings| async move {
+//!             if settings.language.is_none() {
+//!                 data.set(Settings { language: Some("en".to_owned()) }).await?;
+//!             }
+//!             bot.send_message(msg.chat.id, "Settings saved").await?;
+//!             Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+//!         },
+//!     );
+//! ```
+//!
+//! [dialogues]: crate::dispatching::dialogue
+//! [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+//! [`PostgresStorage`]: crate::dispatching::dialogue::PostgresStorage
+//! [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+//! [`Serializer`]: crate::dispatching::dialogue::serializer::Serializer
+
+pub use in_mem_data_storage::InMemDataStorage;
+
+use std::{fmt::Debug, marker::PhantomData, sync::Arc};
+
+use dptree::{prelude::DependencyMap, Handler};
+use futures::future::BoxFuture;
+use teloxide_core::types::{ChatId, UserId};
+
+use crate::dispatching::{
+    dialogue::{GetChatId, GetUserId},
+    DpHandlerDescription,
+};
+
+mod in_mem_data_storage;
+
+/// A key which identifies a value in a [`DataStorage`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DataKey {
+    /// The name of the data, which distinguishes different kinds of data of
+    /// the same user or chat, see [`DataHandle::new`].
+    pub name: &'static str,
+
+    /// The user or chat which the data is associated with.
+    pub owner: DataOwner,
+}
+
+/// A user or a chat which data is associated with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DataOwner {
+    /// Data associated with a user, see [`UserData`].
+    User(UserId),
+
+    /// Data associated with a chat, see [`ChatData`].
+    Chat(ChatId),
+}
+
+impl From<UserId> for DataOwner {
+    fn from(user_id: UserId) -> Self {
+        Self::User(user_id)
+    }
+}
+
+impl From<ChatId> for DataOwner {
+    fn from(chat_id: ChatId) -> Self {
+        Self::Chat(chat_id)
+    }
+}
+
+#[cfg(any(
+    feature = "sqlite-storage-nativetls",
+    feature = "sqlite-storage-rustls",
+    feature = "postgres-storage-nativetls",
+    feature = "mysql-storage-nativetls",
+    feature = "mysql-storage-rustls",
+    feature = "redis-storage",
+    feature = "file-storage"
+))]
+impl DataKey {
+    /// Returns the name of the owner kind, used by persistent storages.
+    pub(crate) fn scope(&self) -> &'static str {
+        match self.owner {
+            DataOwner::User(_) => "user",
+            DataOwner::Chat(_) => "chat",
+        }
+    }
+
+    /// Returns the ID of the user or chat, used by persistent storages.
+    pub(crate) fn id(&self) -> i64 {
+        match self.owner {
+            DataOwner::User(UserId(id)) => id as i64,
+            DataOwner::Chat(ChatId(id)) => id,
+        }
+    }
+}
+
+/// A storage of per-user and per-chat data.
+///
+/// Unlike [`Storage`], this trait is meant for arbitrary data that outlives
+/// dialogues, e.g. user settings.
+///
+/// [`Storage`]: crate::dispatching::dialogue::Storage
+pub trait DataStorage<T> {
+    type Error;
+
+    /// Returns the value indexed by `key` or `None` if there is no such value.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn get_data(
+        self: Arc<Self>,
+        key: DataKey,
+    ) -> BoxFuture<'static, Result<Option<T>, Self::Error>>;
+
+    /// Sets the value indexed by `key` to `value`.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn set_data(
+        self: Arc<Self>,
+        key: DataKey,
+        value: T,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        T: Send + 'static;
+
+    /// Removes the value indexed by `key`.
+    ///
+    /// Does nothing if there is no such value.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn remove_data(self: Arc<Self>, key: DataKey) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+/// A handle for controlling data of a particular user or chat.
+///
+/// Use it via [`UserData`] or [`ChatData`].
+#[derive(Debug)]
+pub struct DataHandle<T, S, Id>
+where
+    S: ?Sized,
+{
+    storage: Arc<S>,
+    name: &'static str,
+    id: Id,
+    _phantom: PhantomData<T>,
+}
+
+/// A handle for controlling data of a particular user.
+pub type UserData<T, S> = DataHandle<T, S, UserId>;
+
+/// A handle for controlling data of a particular chat.
+pub type ChatData<T, S> = DataHandle<T, S, ChatId>;
+
+// `#[derive]` requires generics to implement `Clone`, but `S` is wrapped around
+// `Arc`, and `T` is wrapped around PhantomData.
+impl<T, S, Id> Clone for DataHandle<T, S, Id>
+where
+    S: ?Sized,
+    Id: Copy,
+{
+    fn clone(&self) -> Self {
+        Self { storage: self.storage.clone(), name: self.name, id: self.id, _phantom: PhantomData }
+    }
+}
+
+impl<T, S, Id> DataHandle<T, S, Id>
+where
+    T: Send + 'static,
+    S: DataStorage<T> + ?Sized,
+    Id: Into<DataOwner> + Copy,
+{
+    /// Constructs a new handle with `storage` (where the data is stored),
+    /// `name` of the data and `id` of a user or chat whose data is controlled.
+    ///
+    /// Handles with different names don't share data, so one storage can keep
+    /// several kinds of data of the same user or chat, e.g. `"settings"` and
+    /// `"stats"`. The name is stored along with the data, so it shouldn't be
+    /// changed once the data is written.
+    #[must_use]
+    pub fn new(storage: Arc<S>, name: &'static str, id: Id) -> Self {
+        Self { storage, name, id, _phantom: PhantomData }
+    }
+
+    /// Returns the name of the data associated with this handle.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns a user or chat ID associated with this handle.
+    #[must_use]
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Retrieves the data or `None` if there is no data.
+    pub async fn get(&self) -> Result<Option<T>, S::Error> {
+        self.storage.clone().get_data(self.key()).await
+    }
+
+    /// Like [`DataHandle::get`] but returns a default value if there is no
+    /// data.
+    pub async fn get_or_default(&self) -> Result<T, S::Error>
+    where
+        T: Default,
+    {
+        Ok(self.get().await?.unwrap_or_default())
+    }
+
+    /// Sets the data to `value`.
+    pub async fn set(&self, value: T) -> Result<(), S::Error> {
+        self.storage.clone().set_data(self.key(), value).await
+    }
+
+    /// Replaces the data with the result of `f`, which receives the current
+    /// data or a default value if there is no data.
+    ///
+    /// Note that the data is read and written in two steps, so concurrent
+    /// updates of the same data may overwrite each other.
+    pub async fn update<F>(&self, f: F) -> Result<(), S::Error>
+    where
+        T: Default,
+        F: FnOnce(T) -> T,
+    {
+        let value = f(self.get_or_default().await?);
+        self.set(value).await
+    }
+
+    /// Removes the data from the storage provided to [`DataHandle::new`].
+    pub async fn remove(&self) -> Result<(), S::Error> {
+        self.storage.clone().remove_data(self.key()).await
+    }
+
+    fn key(&self) -> DataKey {
+        DataKey { name: self.name, owner: self.id.into() }
+    }
+}
+
+/// Passes [`UserData<T, S>`] for the data named `name` and `T` as handler
+/// dependencies.
+///
+/// If an incoming update has no user ID ([`GetUserId::user_id`] returns
+/// `None`), the rest of the chain will not be executed. If there is no data
+/// for the user, `T::default()` is passed. If reading the data fails, logs an
+/// error and the rest of the chain is not executed.
+///
+/// ## Dependency requirements
+///
+///  - `Arc<S>`
+///  - `Upd`
+#[must_use]
+pub fn enter_user_data<Upd, S, T, Output>(
+    name: &'static str,
+) -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
+where
+    S: DataStorage<T> + ?Sized + Send + Sync + 'static,
+    <S as DataStorage<T>>::Error: Debug + Send,
+    T: Default + Send + Sync + 'static,
+    Upd: GetUserId + Clone + Send + Sync + 'static,
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map(move |storage: Arc<S>, upd: Upd| {
+        let user_id = upd.user_id()?;
+        Some(UserData::new(storage, name, user_id))
+    })
+    .filter_map_async(|data: UserData<T, S>| async move {
+        match data.get_or_default().await {
+            Ok(data) => Some(data),
+            Err(err) => {
+                log::error!("user_data.get_or_default() failed: {:?}", err);
+                None
+            }
+        }
+    })
+}
+
+/// Passes [`ChatData<T, S>`] for the data named `name` and `T` as handler
+/// dependencies.
+///
+/// If an incoming update has no chat ID ([`GetChatId::chat_id`] returns
+/// `None`), the rest of the chain will not be executed. If there is no data
+/// for the chat, `T::default()` is passed. If reading the data fails, logs an
+/// error and the rest of the chain is not executed.
+///
+/// ## Dependency requirements
+///
+///  - `Arc<S>`
+///  - `Upd`
+#[must_use]
+pub fn enter_chat_data<Upd, S, T, Output>(
+    name: &'static str,
+) -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
+where
+    S: DataStorage<T> + ?Sized + Send + Sync + 'static,
+    <S as DataStorage<T>>::Error: Debug + Send,
+    T: Default + Send + Sync + 'static,
+    Upd: GetChatId + Clone + Send + Sync + 'static,
+    Output: Send + Sync + 'static,
+{
+    dptree::filter_map(move |storage: Arc<S>, upd: Upd| {
+        let chat_id = upd.chat_id()?;
+        Some(ChatData::new(storage, name, chat_id))
+    })
+    .filter_map_async(|data: ChatData<T, S>| async move {
+        match data.get_or_default().await {
+            Ok(data) => Some(data),
+            Err(err) => {
+                log::error!("chat_data.get_or_default() failed: {:?}", err);
+                None
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Settings {
+        language: Option<&'static str>,
+        notifications: bool,
+    }
+
+    #[tokio::test]
+    async fn test_user_and_chat_data() {
+        let storage = InMemDataStorage::<Settings>::new();
+        let user = UserData::new(Arc::clone(&storage), "settings", UserId(1));
+        let chat = ChatData::new(Arc::clone(&storage), "settings", ChatId(1));
+
+        assert_eq!(user.get().await.unwrap(), None);
+        assert_eq!(user.get_or_default().await.unwrap(), Settings::default());
+
+        user.set(Settings { language: Some("en"), notifications: false }).await.unwrap();
+        user.update(|s| Settings { notifications: true, ..s }).await.unwrap();
+        assert_eq!(
+            user.get().await.unwrap(),
+            Some(Settings { language: Some("en"), notifications: true })
+        );
+
+        // Users and chats with the same ID don't share data.
+        assert_eq!(chat.get().await.unwrap(), None);
+        chat.update(|s| Settings { language: Some("uk"), ..s }).await.unwrap();
+        assert_eq!(user.get().await.unwrap().unwrap().language, Some("en"));
+        assert_eq!(chat.get().await.unwrap().unwrap().language, Some("uk"));
+
+        // Data with different names doesn't collide.
+        let other = UserData::new(Arc::clone(&storage), "other", UserId(1));
+        assert_eq!(other.get().await.unwrap(), None);
+        other.set(Settings::default()).await.unwrap();
+        assert_eq!(user.get().await.unwrap().unwrap().language, Some("en"));
+
+        user.remove().await.unwrap();
+        user.remove().await.unwrap();
+        assert_eq!(user.get().await.unwrap(), None);
+        assert!(chat.get().await.unwrap().is_some());
+        assert!(other.get().await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,62 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+
+use super::{DataKey, DataStorage};
+
+/// A data storage based on [`std::collections::HashMap`].
+///
+/// ## Note
+/// All your data will be lost after you restart your bot. If you need to store
+/// it somewhere on a drive, you should use e.g. [`SqliteStorage`] or implement
+/// your own.
+///
+/// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+#[derive(Debug)]
+pub struct InMemDataStorage<T> {
+    map: Mutex<HashMap<DataKey, T>>,
+}
+
+impl<T> InMemDataStorage<T> {
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { map: Mutex::new(HashMap::new()) })
+    }
+}
+
+impl<T> DataStorage<T> for InMemDataStorage<T>
+where
+    T: Clone,
+    T: Send + 'static,
+{
+    type Error = Infallible;
+
+    fn get_data(
+        self: Arc<Self>,
+        key: DataKey,
+    ) -> BoxFuture<'static, Result<Option<T>, Self::Error>> {
+        Box::pin(async move { Ok(self.map.lock().await.get(&key).cloned()) })
+    }
+
+    fn set_data(
+        self: Arc<Self>,
+        key: DataKey,
+        value: T,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        T: Send + 'static,
+    {
+        Box::pin(async move {
+            self.map.lock().await.insert(key, value);
+            Ok(())
+        })
+    }
+
+    fn remove_data(self: Arc<Self>, key: DataKey) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.map.lock().await.remove(&key);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,89 @@
+//! Deduplication of updates.
+//!
+//! Telegram may deliver the same update more than once: it re-sends webhook
+//! updates that were not acknowledged in time, and a bot restarted after a
+//! crash may fetch a batch of updates it has already processed. If enabled
+//! via [`DispatcherBuilder::dedup`], [`Dispatcher`] records the [`UpdateId`]s
+//! of received updates in a [`DedupStorage`] and skips updates that were seen
+//! before.
+//!
+//! [`DedupStorage`] is implemented for [`InMemDedupStorage`], which remembers
+//! a bounded number of recent updates, and, depending on enabled features, for
+//! [`SqliteStorage`], [`PostgresStorage`], [`MySqlStorage`], [`RedisStorage`]
+//! and [`FileStorage`]. The persistent storages remember updates for 24 hours,
+//! the longest time Telegram keeps them, and can be shared by multiple
+//! instances of a bot. Since update IDs are only unique per bot, they are
+//! stored along with the ID of the bot that received them.
+//!
+//! ```no_run
+//! use teloxide::{
+//!     dispatching::{dedup::InMemDedupStorage, Dispatcher},
+//!     prelude::*,
+//! };
+//!
+//! let bot = Bot::new("TOKEN");
+//! let handler = dptree::entry() /* ... */;
+//! let dp = Dispatcher::builder(bot, handler).dedup(InMemDedupStorage::new(1024)).build();
+//! # let _: Dispatcher<_, (), _> = dp;
+//! ```
+//!
+//! [`DispatcherBuilder::dedup`]: crate::dispatching::DispatcherBuilder::dedup
+//! [`Dispatcher`]: crate::dispatching::Dispatcher
+//! [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+//! [`PostgresStorage`]: crate::dispatching::dialogue::PostgresStorage
+//! [`MySqlStorage`]: crate::dispatching::dialogue::MySqlStorage
+//! [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+//! [`FileStorage`]: crate::dispatching::dialogue::FileStorage
+
+pub use in_mem_dedup_storage::InMemDedupStorage;
+
+use std::{fmt::Debug, sync::Arc};
+
+use futures::future::BoxFuture;
+use teloxide_core::types::{UpdateId, UserId};
+
+mod in_mem_dedup_storage;
+
+/// How long persistent storages remember update IDs, in milliseconds.
+#[cfg(any(
+    feature = "sqlite-storage-nativetls",
+    feature = "sqlite-storage-rustls",
+    feature = "postgres-storage-nativetls",
+    feature = "mysql-storage-nativetls",
+    feature = "mysql-storage-rustls",
+    feature = "redis-storage",
+    feature = "file-storage"
+))]
+pub(crate) const PERSISTENT_WINDOW_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+/// Persistent SQL storages and `FileStorage` remove old update IDs every this
+/// many updates.
+#[cfg(any(
+    feature = "sqlite-storage-nativetls",
+    feature = "sqlite-storage-rustls",
+    feature = "postgres-storage-nativetls",
+    feature = "mysql-storage-nativetls",
+    feature = "mysql-storage-rustls",
+    feature = "file-storage"
+))]
+pub(crate) const PRUNE_INTERVAL: u32 = 1024;
+
+/// A storage of recently seen update IDs.
+///
+/// See the [module-level documentation](self) for more information.
+pub trait DedupStorage {
+    type Error: Debug;
+
+    /// Records `update_id` received by the bot with `bot_id` as seen.
+    ///
+    /// Returns `true` if it was not seen before, i.e. the update should be
+    /// processed, and `false` if it is a duplicate. Implementations must make
+    /// this check atomic, so that only one of concurrent calls with the same
+    /// `bot_id` and `update_id` returns `true`.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn mark_seen(
+        self: Arc<Self>,
+        bot_id: UserId,
+        update_id: UpdateId,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
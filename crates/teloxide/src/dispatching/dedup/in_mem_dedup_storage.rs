@@ -0,0 +1,87 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use teloxide_core::types::{UpdateId, UserId};
+
+use super::DedupStorage;
+
+/// A dedup storage that remembers a fixed number of the most recent update
+/// IDs.
+///
+/// ## Note
+/// The IDs are lost after you restart your bot and are not shared between
+/// multiple instances of it. If you need that, you should use e.g.
+/// [`RedisStorage`] or implement your own.
+///
+/// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+#[derive(Debug)]
+pub struct InMemDedupStorage {
+    capacity: usize,
+    window: Mutex<Window>,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    seen: HashSet<(UserId, UpdateId)>,
+    order: VecDeque<(UserId, UpdateId)>,
+}
+
+impl InMemDedupStorage {
+    /// Creates a storage that remembers up to `capacity` update IDs.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `capacity` is zero.
+    #[must_use]
+    pub fn new(capacity: usize) -> Arc<Self> {
+        assert_ne!(capacity, 0, "the capacity must be positive");
+        Arc::new(Self { capacity, window: Mutex::default() })
+    }
+}
+
+impl DedupStorage for InMemDedupStorage {
+    type Error = Infallible;
+
+    fn mark_seen(
+        self: Arc<Self>,
+        bot_id: UserId,
+        update_id: UpdateId,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        let mut window = self.window.lock().unwrap();
+        let new = window.seen.insert((bot_id, update_id));
+        if new {
+            window.order.push_back((bot_id, update_id));
+            if window.order.len() > self.capacity {
+                let oldest = window.order.pop_front().unwrap();
+                window.seen.remove(&oldest);
+            }
+        }
+
+        Box::pin(async move { Ok(new) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn window() {
+        let storage = InMemDedupStorage::new(2);
+        let mark_seen = |id| Arc::clone(&storage).mark_seen(UserId(1), UpdateId(id));
+
+        assert_eq!(mark_seen(1).await, Ok(true));
+        assert_eq!(mark_seen(1).await, Ok(false));
+        assert_eq!(mark_seen(2).await, Ok(true));
+        assert_eq!(mark_seen(3).await, Ok(true));
+        assert_eq!(mark_seen(2).await, Ok(false));
+        // Update IDs of other bots are independent.
+        assert_eq!(Arc::clone(&storage).mark_seen(UserId(2), UpdateId(2)).await, Ok(true));
+        // Forgotten since the window holds only two IDs.
+        assert_eq!(mark_seen(1).await, Ok(true));
+    }
+}
@@ -1,7 +1,8 @@
 //! Support for user dialogues.
 //!
 //! The main type is (surprise!) [`Dialogue`]. Under the hood, it is just a
-//! wrapper over [`Storage`] and a chat ID. All it does is provides convenient
+//! wrapper over [`Storage`] and a dialogue key, which is a chat ID by default.
+//! All it does is provides convenient
 //! method for manipulating the dialogue state. [`Storage`] is where all
 //! dialogue states are stored; it can be either [`InMemStorage`], which is a
 //! simple hash map from [`std::collections`], or an advanced database wrapper
@@ -91,49 +92,80 @@
 //! }
 //! ```
 //!
+//! By default, there is a single dialogue per chat. To keep a separate dialogue
+//! for each user in a group chat or for each forum topic, index dialogues by
+//! [`DialogueKey`] and enter them with [`enter_with`]:
+//!
+//! ```no_run
+//! # use teloxide::{dispatching::dialogue::{self, DialogueKey, InMemStorage}, prelude::*};
+//! # #[derive(Clone, Default)] enum State { #[default] Start }
+//! type MyDialogue = Dialogue<State, InMemStorage<State, DialogueKey>, DialogueKey>;
+//!
+//! let handler = Update::filter_message()
+//!     .chain(dialogue::enter_with::<Message, InMemStorage<State, DialogueKey>, State, _, _, _>(
+//!         DialogueKey::chat_user,
+//!     ))
+//!     .endpoint(|bot: Bot, dialogue: MyDialogue, msg: Message| async move {
+//!         bot.send_message(msg.chat.id, format!("Your dialogue: {}", dialogue.key())).await?;
+//!         respond(())
+//!     });
+//! ```
+//!
 //! [`examples/dialogue.rs`]: https://github.com/teloxide/teloxide/blob/master/crates/teloxide/examples/dialogue.rs
 
 #[cfg(feature = "redis-storage")]
 pub use self::{RedisStorage, RedisStorageError};
 
+#[cfg(feature = "file-storage")]
+pub use self::{FileStorage, FileStorageError};
+
 #[cfg(any(feature = "sqlite-storage-nativetls", feature = "sqlite-storage-rustls"))]
 pub use self::{SqliteStorage, SqliteStorageError};
 
 #[cfg(feature = "postgres-storage-nativetls")]
 pub use self::{PostgresStorage, PostgresStorageError};
 
-pub use get_chat_id::GetChatId;
+#[cfg(any(feature = "mysql-storage-nativetls", feature = "mysql-storage-rustls"))]
+pub use self::{MySqlStorage, MySqlStorageError};
+
+pub use dialogue_key::DialogueKey;
+pub use get_chat_id::{GetBusinessConnectionId, GetChatId, GetThreadId, GetUserId};
 pub use storage::*;
 
 use dptree::{prelude::DependencyMap, Handler};
 use teloxide_core::types::ChatId;
 
-use std::{fmt::Debug, marker::PhantomData, sync::Arc};
+use std::{fmt::Debug, marker::PhantomData, sync::Arc, time::Duration};
 
-use super::DpHandlerDescription;
+use super::{handler_tree::short_type_name, DpHandlerDescription, LeafKind};
 
+mod dialogue_key;
 mod get_chat_id;
 mod storage;
 
 /// A handle for controlling dialogue state.
+///
+/// A dialogue is indexed by a key of type `K`, which is [`ChatId`] by default.
+/// See [`DialogueKey`] for other options.
 #[derive(Debug)]
-pub struct Dialogue<D, S>
+pub struct Dialogue<D, S, K = ChatId>
 where
     S: ?Sized,
 {
     storage: Arc<S>,
-    chat_id: ChatId,
+    key: K,
     _phantom: PhantomData<D>,
 }
 
 // `#[derive]` requires generics to implement `Clone`, but `S` is wrapped around
 // `Arc`, and `D` is wrapped around PhantomData.
-impl<D, S> Clone for Dialogue<D, S>
+impl<D, S, K> Clone for Dialogue<D, S, K>
 where
     S: ?Sized,
+    K: Clone,
 {
     fn clone(&self) -> Self {
-        Dialogue { storage: self.storage.clone(), chat_id: self.chat_id, _phantom: PhantomData }
+        Dialogue { storage: self.storage.clone(), key: self.key.clone(), _phantom: PhantomData }
     }
 }
 
@@ -141,24 +173,37 @@ impl<D, S> Dialogue<D, S>
 where
     D: Send + 'static,
     S: Storage<D> + ?Sized,
+{
+    /// Returns a chat ID associated with this dialogue.
+    #[must_use]
+    pub fn chat_id(&self) -> ChatId {
+        self.key
+    }
+}
+
+impl<D, S, K> Dialogue<D, S, K>
+where
+    D: Send + 'static,
+    S: Storage<D, K> + ?Sized,
+    K: Clone,
 {
     /// Constructs a new dialogue with `storage` (where dialogues are stored)
-    /// and `chat_id` of a current dialogue.
+    /// and `key` of a current dialogue.
     #[must_use]
-    pub fn new(storage: Arc<S>, chat_id: ChatId) -> Self {
-        Self { storage, chat_id, _phantom: PhantomData }
+    pub fn new(storage: Arc<S>, key: K) -> Self {
+        Self { storage, key, _phantom: PhantomData }
     }
 
-    /// Returns a chat ID associated with this dialogue.
+    /// Returns a key associated with this dialogue.
     #[must_use]
-    pub fn chat_id(&self) -> ChatId {
-        self.chat_id
+    pub fn key(&self) -> &K {
+        &self.key
     }
 
     /// Retrieves the current state of the dialogue or `None` if there is no
-    /// dialogue.
+    /// dialogue or it has expired.
     pub async fn get(&self) -> Result<Option<D>, S::Error> {
-        self.storage.clone().get_dialogue(self.chat_id).await
+        self.storage.clone().get_dialogue(self.key.clone()).await
     }
 
     /// Like [`Dialogue::get`] but returns a default value if there is no
@@ -170,7 +215,7 @@ where
         match self.get().await? {
             Some(d) => Ok(d),
             None => {
-                self.storage.clone().update_dialogue(self.chat_id, D::default()).await?;
+                self.storage.clone().update_dialogue(self.key.clone(), D::default()).await?;
                 Ok(D::default())
             }
         }
@@ -185,10 +230,85 @@ where
         D: From<State>,
     {
         let new_dialogue = state.into();
-        self.storage.clone().update_dialogue(self.chat_id, new_dialogue).await?;
+        self.storage.clone().update_dialogue(self.key.clone(), new_dialogue).await?;
         Ok(())
     }
 
+    /// Updates the dialogue state, which expires after `ttl`.
+    ///
+    /// Once `ttl` passes, the dialogue is treated as absent, so [`enter`]
+    /// starts it over from `D::default()`. See
+    /// [`Storage::update_dialogue_with_ttl`] for details.
+    pub async fn update_with_ttl<State>(&self, state: State, ttl: Duration) -> Result<(), S::Error>
+    where
+        D: From<State>,
+    {
+        let new_dialogue = state.into();
+        self.storage.clone().update_dialogue_with_ttl(self.key.clone(), new_dialogue, ttl).await?;
+        Ok(())
+    }
+
+    /// Retrieves the current state of the dialogue along with its version or
+    /// `None` if there is no dialogue or it has expired.
+    pub async fn get_versioned(&self) -> Result<Option<VersionedDialogue<D>>, S::Error>
+    where
+        S: VersionedStorage<D, K>,
+    {
+        self.storage.clone().get_dialogue_versioned(self.key.clone()).await
+    }
+
+    /// Updates the dialogue state if its version still equals `expected`.
+    ///
+    /// See [`VersionedStorage::update_dialogue_versioned`].
+    pub async fn update_versioned<State>(
+        &self,
+        state: State,
+        expected: Option<DialogueVersion>,
+    ) -> Result<DialogueVersion, S::Error>
+    where
+        S: VersionedStorage<D, K>,
+        D: From<State>,
+    {
+        self.storage
+            .clone()
+            .update_dialogue_versioned(self.key.clone(), state.into(), expected)
+            .await
+    }
+
+    /// Replaces the dialogue state with the result of `f`, retrying on
+    /// concurrent modifications.
+    ///
+    /// `f` receives the current state (or `None` if there is no dialogue) and
+    /// returns a new one. If the dialogue was modified between reading and
+    /// writing, `f` is called again with the fresh state, up to
+    /// `max_attempts` times in total. After that, the version conflict error is
+    /// returned.
+    pub async fn update_with_retry<F>(
+        &self,
+        max_attempts: usize,
+        mut f: F,
+    ) -> Result<DialogueVersion, S::Error>
+    where
+        S: VersionedStorage<D, K>,
+        F: FnMut(Option<D>) -> D,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let (dialogue, version) = match self.get_versioned().await? {
+                Some(VersionedDialogue { dialogue, version }) => (Some(dialogue), Some(version)),
+                None => (None, None),
+            };
+
+            match self.update_versioned(f(dialogue), version).await {
+                Err(err) if S::is_version_conflict(&err) && attempt < max_attempts => {
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
     /// Updates the dialogue with a default value.
     pub async fn reset(&self) -> Result<(), S::Error>
     where
@@ -199,7 +319,7 @@ where
 
     /// Removes the dialogue from the storage provided to [`Dialogue::new`].
     pub async fn exit(&self) -> Result<(), S::Error> {
-        self.storage.clone().remove_dialogue(self.chat_id).await
+        self.storage.clone().remove_dialogue(self.key.clone()).await
     }
 }
 
@@ -216,6 +336,7 @@ where
 ///
 /// [`HandlerExt::enter_dialogue`]: super::HandlerExt::enter_dialogue
 #[must_use]
+#[track_caller]
 pub fn enter<Upd, S, D, Output>() -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
 where
     S: Storage<D> + ?Sized + Send + Sync + 'static,
@@ -224,17 +345,130 @@ where
     Upd: GetChatId + Clone + Send + Sync + 'static,
     Output: Send + Sync + 'static,
 {
-    dptree::filter_map(|storage: Arc<S>, upd: Upd| {
-        let chat_id = upd.chat_id()?;
-        Some(Dialogue::new(storage, chat_id))
+    enter_with::<Upd, S, D, ChatId, Output, _>(Upd::chat_id)
+}
+
+/// Enters a dialogue context, indexing the dialogue by a key extracted from
+/// the update via `key`.
+///
+/// This is a generalization of [`enter`]: if `key` returns `None`, the handler
+/// is not executed. [`DialogueKey`] provides extractors such as
+/// [`DialogueKey::chat_user`] and [`DialogueKey::thread`].
+///
+/// A call to this function is the same as
+/// `dptree::entry().enter_dialogue_with(key)`.
+///
+/// See [`HandlerExt::enter_dialogue_with`].
+///
+/// ## Dependency requirements
+///
+///  - `Arc<S>`
+///  - `Upd`
+///
+/// [`HandlerExt::enter_dialogue_with`]: super::HandlerExt::enter_dialogue_with
+#[must_use]
+#[track_caller]
+pub fn enter_with<Upd, S, D, K, Output, F>(
+    key: F,
+) -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
+where
+    S: Storage<D, K> + ?Sized + Send + Sync + 'static,
+    <S as Storage<D, K>>::Error: Debug + Send,
+    D: Default + Send + Sync + 'static,
+    K: Clone + Send + Sync + 'static,
+    Upd: Clone + Send + Sync + 'static,
+    Output: Send + Sync + 'static,
+    F: Fn(&Upd) -> Option<K> + Send + Sync + 'static,
+{
+    let enter = DpHandlerDescription::labeled(
+        LeafKind::FilterMap,
+        format!("enter_dialogue::<{}>", short_type_name::<D>()),
+    );
+    let load =
+        DpHandlerDescription::labeled(LeafKind::FilterMapAsync, "load dialogue state".to_owned());
+    dptree::filter_map_with_description(enter, move |storage: Arc<S>, upd: Upd| {
+        let key = key(&upd)?;
+        Some(Dialogue::new(storage, key))
     })
-    .filter_map_async(|dialogue: Dialogue<D, S>| async move {
-        match dialogue.get_or_default().await {
-            Ok(dialogue) => Some(dialogue),
-            Err(err) => {
-                log::error!("dialogue.get_or_default() failed: {:?}", err);
-                None
+    .chain(dptree::filter_map_async_with_description(
+        load,
+        |dialogue: Dialogue<D, S, K>| async move {
+            match dialogue.get_or_default().await {
+                Ok(dialogue) => Some(dialogue),
+                Err(err) => {
+                    log::error!("dialogue.get_or_default() failed: {:?}", err);
+                    None
+                }
             }
-        }
-    })
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dialogue_key() {
+        let storage = InMemStorage::<i32, DialogueKey>::new();
+        let alice = Dialogue::new(
+            Arc::clone(&storage),
+            DialogueKey::ChatUser(ChatId(-100), teloxide_core::types::UserId(1)),
+        );
+        let bob = Dialogue::new(
+            Arc::clone(&storage),
+            DialogueKey::ChatUser(ChatId(-100), teloxide_core::types::UserId(2)),
+        );
+
+        alice.update(1).await.unwrap();
+        bob.update(2).await.unwrap();
+        assert_eq!(alice.get().await.unwrap(), Some(1));
+        assert_eq!(bob.get().await.unwrap(), Some(2));
+
+        alice.exit().await.unwrap();
+        assert_eq!(alice.get().await.unwrap(), None);
+        assert_eq!(bob.get().await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_update_with_retry() {
+        let storage = InMemStorage::<i32>::new();
+        let dialogue = Dialogue::new(Arc::clone(&storage), ChatId(1));
+
+        let mut attempts = 0;
+        dialogue
+            .update_with_retry(3, |state| {
+                attempts += 1;
+                if attempts == 1 {
+                    // Simulate a concurrent writer.
+                    futures::executor::block_on(
+                        Arc::clone(&storage).update_dialogue(ChatId(1), 100),
+                    )
+                    .unwrap();
+                }
+                state.unwrap_or_default() + 1
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(attempts, 2);
+        assert_eq!(dialogue.get().await.unwrap(), Some(101));
+    }
+
+    #[tokio::test]
+    async fn test_update_with_retry_gives_up() {
+        let storage = InMemStorage::<i32>::new();
+        let dialogue = Dialogue::new(Arc::clone(&storage), ChatId(1));
+
+        let err = dialogue
+            .update_with_retry(2, |state| {
+                futures::executor::block_on(Arc::clone(&storage).update_dialogue(ChatId(1), 100))
+                    .unwrap();
+                state.unwrap_or_default() + 1
+            })
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, InMemStorageError::VersionConflict));
+    }
 }
@@ -0,0 +1,169 @@
+use std::fmt;
+
+use teloxide_core::types::{BusinessConnectionId, ChatId, MessageId, ThreadId, UserId};
+
+use super::{GetBusinessConnectionId, GetChatId, GetThreadId, GetUserId, StorageKey};
+
+/// A dialogue key which distinguishes dialogues in a more fine-grained way than
+/// [`ChatId`].
+///
+/// The associated functions ([`DialogueKey::chat`],
+/// [`DialogueKey::chat_user`], [`DialogueKey::thread`] and
+/// [`DialogueKey::business_connection`]) extract a key from an update and are
+/// intended to be passed to [`enter_with`].
+///
+/// [`enter_with`]: crate::dispatching::dialogue::enter_with
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DialogueKey {
+    /// A dialogue shared by everyone in a chat.
+    Chat(ChatId),
+
+    /// A dialogue of a particular user in a chat, e.g. of a group member.
+    ChatUser(ChatId, UserId),
+
+    /// A dialogue in a particular thread of a chat, e.g. in a forum topic.
+    Thread(ChatId, ThreadId),
+
+    /// A dialogue in a particular business connection.
+    BusinessConnection(BusinessConnectionId),
+}
+
+impl DialogueKey {
+    /// Extracts [`DialogueKey::Chat`] from `upd`.
+    #[must_use]
+    pub fn chat<Upd>(upd: &Upd) -> Option<Self>
+    where
+        Upd: GetChatId,
+    {
+        upd.chat_id().map(Self::Chat)
+    }
+
+    /// Extracts [`DialogueKey::ChatUser`] from `upd`.
+    #[must_use]
+    pub fn chat_user<Upd>(upd: &Upd) -> Option<Self>
+    where
+        Upd: GetChatId + GetUserId,
+    {
+        Some(Self::ChatUser(upd.chat_id()?, upd.user_id()?))
+    }
+
+    /// Extracts [`DialogueKey::Thread`] from `upd`, falling back to
+    /// [`DialogueKey::Chat`] if `upd` does not belong to a thread.
+    #[must_use]
+    pub fn thread<Upd>(upd: &Upd) -> Option<Self>
+    where
+        Upd: GetChatId + GetThreadId,
+    {
+        let chat_id = upd.chat_id()?;
+
+        Some(match upd.thread_id() {
+            Some(thread_id) => Self::Thread(chat_id, thread_id),
+            None => Self::Chat(chat_id),
+        })
+    }
+
+    /// Extracts [`DialogueKey::BusinessConnection`] from `upd`.
+    #[must_use]
+    pub fn business_connection<Upd>(upd: &Upd) -> Option<Self>
+    where
+        Upd: GetBusinessConnectionId,
+    {
+        upd.business_connection_id().map(Self::BusinessConnection)
+    }
+}
+
+impl From<ChatId> for DialogueKey {
+    fn from(chat_id: ChatId) -> Self {
+        Self::Chat(chat_id)
+    }
+}
+
+impl fmt::Display for DialogueKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+/// Keys are encoded as `<chat_id>`, `<chat_id>:user:<user_id>`,
+/// `<chat_id>:thread:<thread_id>` and `business:<business_connection_id>`, so
+/// [`DialogueKey::Chat`] is encoded the same way as [`ChatId`].
+impl StorageKey for DialogueKey {
+    fn encode(&self) -> String {
+        match self {
+            Self::Chat(chat_id) => chat_id.encode(),
+            Self::ChatUser(chat_id, UserId(user_id)) => format!("{chat_id}:user:{user_id}"),
+            Self::Thread(chat_id, ThreadId(MessageId(thread_id))) => {
+                format!("{chat_id}:thread:{thread_id}")
+            }
+            Self::BusinessConnection(BusinessConnectionId(id)) => format!("business:{id}"),
+        }
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        if let Some(id) = s.strip_prefix("business:") {
+            return Some(Self::BusinessConnection(BusinessConnectionId(id.to_owned())));
+        }
+
+        let Some((chat_id, rest)) = s.split_once(':') else {
+            return ChatId::decode(s).map(Self::Chat);
+        };
+        let chat_id = ChatId::decode(chat_id)?;
+
+        match rest.split_once(':')? {
+            ("user", user_id) => Some(Self::ChatUser(chat_id, UserId(user_id.parse().ok()?))),
+            ("thread", thread_id) => {
+                Some(Self::Thread(chat_id, ThreadId(MessageId(thread_id.parse().ok()?))))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide_core::types::Message;
+
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let keys = [
+            DialogueKey::Chat(ChatId(-100)),
+            DialogueKey::ChatUser(ChatId(-100), UserId(42)),
+            DialogueKey::Thread(ChatId(-100), ThreadId(MessageId(7))),
+            DialogueKey::BusinessConnection(BusinessConnectionId("a:b".to_owned())),
+        ];
+
+        for key in keys {
+            assert_eq!(DialogueKey::decode(&key.encode()), Some(key));
+        }
+
+        assert_eq!(DialogueKey::Chat(ChatId(-100)).encode(), ChatId(-100).encode());
+        assert_eq!(DialogueKey::decode("teloxide_dialogue_version:1"), None);
+        assert_eq!(DialogueKey::decode("1:user:x"), None);
+        assert_eq!(DialogueKey::decode("1:topic:2"), None);
+    }
+
+    #[test]
+    fn thread() {
+        let message = |is_topic_message: bool| {
+            let message = serde_json::json!({
+                "message_id": 5,
+                "message_thread_id": 4,
+                "is_topic_message": is_topic_message,
+                "date": 1675229140,
+                "chat": {"id": -100, "title": "t", "type": "supergroup", "is_forum": is_topic_message},
+                "from": {"id": 1, "is_bot": false, "first_name": "A"},
+                "text": "hi",
+            });
+            serde_json::from_value::<Message>(message).unwrap()
+        };
+
+        assert_eq!(
+            DialogueKey::thread(&message(true)),
+            Some(DialogueKey::Thread(ChatId(-100), ThreadId(MessageId(4))))
+        );
+        // A reply in a regular supergroup has a thread ID, but isn't in a topic.
+        assert_eq!(DialogueKey::thread(&message(false)), Some(DialogueKey::Chat(ChatId(-100))));
+    }
+}
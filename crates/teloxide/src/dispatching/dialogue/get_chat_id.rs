@@ -1,5 +1,6 @@
 use crate::types::{
-    CallbackQuery, Chat, ChatId, ChatJoinRequest, ChatMemberUpdated, Message, Update,
+    BusinessConnectionId, CallbackQuery, Chat, ChatId, ChatJoinRequest, ChatMemberUpdated, Message,
+    MessageKind, ThreadId, Update, UpdateKind, UserId,
 };
 
 /// Something that may have a chat ID.
@@ -43,3 +44,113 @@ impl GetChatId for ChatJoinRequest {
         Some(self.chat.id)
     }
 }
+
+/// Something that may have a user ID.
+pub trait GetUserId {
+    #[must_use]
+    fn user_id(&self) -> Option<UserId>;
+}
+
+impl GetUserId for Message {
+    fn user_id(&self) -> Option<UserId> {
+        self.from.as_ref().map(|user| user.id)
+    }
+}
+
+impl GetUserId for CallbackQuery {
+    fn user_id(&self) -> Option<UserId> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for Update {
+    fn user_id(&self) -> Option<UserId> {
+        self.from().map(|user| user.id)
+    }
+}
+
+impl GetUserId for ChatMemberUpdated {
+    fn user_id(&self) -> Option<UserId> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for ChatJoinRequest {
+    fn user_id(&self) -> Option<UserId> {
+        Some(self.from.id)
+    }
+}
+
+/// Something that may have a message thread ID, e.g. a message in a forum
+/// topic.
+///
+/// Only forum topics are considered threads, so replies in a regular
+/// supergroup, which also have a message thread ID, don't have one here.
+pub trait GetThreadId {
+    #[must_use]
+    fn thread_id(&self) -> Option<ThreadId>;
+}
+
+impl GetThreadId for Message {
+    fn thread_id(&self) -> Option<ThreadId> {
+        self.thread_id.filter(|_| self.is_topic_message)
+    }
+}
+
+impl GetThreadId for CallbackQuery {
+    fn thread_id(&self) -> Option<ThreadId> {
+        self.message.as_ref()?.regular_message()?.thread_id()
+    }
+}
+
+impl GetThreadId for Update {
+    fn thread_id(&self) -> Option<ThreadId> {
+        match &self.kind {
+            UpdateKind::Message(m)
+            | UpdateKind::EditedMessage(m)
+            | UpdateKind::ChannelPost(m)
+            | UpdateKind::EditedChannelPost(m)
+            | UpdateKind::BusinessMessage(m)
+            | UpdateKind::EditedBusinessMessage(m) => m.thread_id(),
+            UpdateKind::CallbackQuery(q) => q.thread_id(),
+            _ => None,
+        }
+    }
+}
+
+/// Something that may have a business connection ID.
+pub trait GetBusinessConnectionId {
+    #[must_use]
+    fn business_connection_id(&self) -> Option<BusinessConnectionId>;
+}
+
+impl GetBusinessConnectionId for Message {
+    fn business_connection_id(&self) -> Option<BusinessConnectionId> {
+        match &self.kind {
+            MessageKind::Common(common) => common.business_connection_id.clone(),
+            _ => None,
+        }
+    }
+}
+
+impl GetBusinessConnectionId for CallbackQuery {
+    fn business_connection_id(&self) -> Option<BusinessConnectionId> {
+        self.message.as_ref()?.regular_message()?.business_connection_id()
+    }
+}
+
+impl GetBusinessConnectionId for Update {
+    fn business_connection_id(&self) -> Option<BusinessConnectionId> {
+        match &self.kind {
+            UpdateKind::BusinessMessage(m) | UpdateKind::EditedBusinessMessage(m) => {
+                m.business_connection_id()
+            }
+            UpdateKind::BusinessConnection(conn) => Some(conn.id.clone()),
+            UpdateKind::DeletedBusinessMessages(deleted) => {
+                Some(deleted.business_connection_id.clone())
+            }
+            UpdateKind::CallbackQuery(q) => q.business_connection_id(),
+            _ => None,
+        }
+    }
+}
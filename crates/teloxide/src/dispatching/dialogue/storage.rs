@@ -1,29 +1,40 @@
 pub mod serializer;
 
+mod cached_storage;
 mod in_mem_storage;
 mod trace_storage;
 
 #[cfg(feature = "redis-storage")]
 mod redis_storage;
 
+#[cfg(feature = "file-storage")]
+mod file_storage;
+
 #[cfg(any(feature = "sqlite-storage-nativetls", feature = "sqlite-storage-rustls"))]
 mod sqlite_storage;
 
 #[cfg(feature = "postgres-storage-nativetls")]
 mod postgres_storage;
 
-use futures::future::BoxFuture;
+#[cfg(any(feature = "mysql-storage-nativetls", feature = "mysql-storage-rustls"))]
+mod mysql_storage;
+
+use futures::{future::BoxFuture, stream::BoxStream};
 use teloxide_core::types::ChatId;
 
 pub use self::{
+    cached_storage::CachedStorage,
     in_mem_storage::{InMemStorage, InMemStorageError},
     trace_storage::TraceStorage,
 };
 
 #[cfg(feature = "redis-storage")]
 pub use redis_storage::{RedisStorage, RedisStorageError};
+
+#[cfg(feature = "file-storage")]
+pub use file_storage::{FileStorage, FileStorageError};
 pub use serializer::Serializer;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[cfg(any(feature = "sqlite-storage-nativetls", feature = "sqlite-storage-rustls"))]
 pub use sqlite_storage::{SqliteStorage, SqliteStorageError};
@@ -31,9 +42,12 @@ pub use sqlite_storage::{SqliteStorage, SqliteStorageError};
 #[cfg(feature = "postgres-storage-nativetls")]
 pub use postgres_storage::{PostgresStorage, PostgresStorageError};
 
+#[cfg(any(feature = "mysql-storage-nativetls", feature = "mysql-storage-rustls"))]
+pub use mysql_storage::{MySqlStorage, MySqlStorageError};
+
 /// A storage with an erased error type.
-pub type ErasedStorage<D> =
-    dyn Storage<D, Error = Box<dyn std::error::Error + Send + Sync>> + Send + Sync;
+pub type ErasedStorage<D, K = ChatId> =
+    dyn Storage<D, K, Error = Box<dyn std::error::Error + Send + Sync>> + Send + Sync;
 
 /// A storage of dialogues.
 ///
@@ -41,7 +55,14 @@ pub type ErasedStorage<D> =
 /// be sure that after you restart your bot, all the dialogues won't be lost.
 ///
 /// `Storage` is used only to store dialogue states, i.e. it can't be used as a
-/// generic database.
+/// generic database. To store per-user or per-chat data, see
+/// [`crate::dispatching::data`].
+///
+/// Dialogues are indexed by keys of type `K`, which is [`ChatId`] by default,
+/// so that every chat has a single dialogue. Use [`DialogueKey`] (or your own
+/// type) to keep separate dialogues for each user in a group chat, each forum
+/// topic, or each business connection. Storages that serialize keys require
+/// them to implement [`StorageKey`].
 ///
 /// Currently we support the following storages out of the box:
 ///
@@ -52,90 +73,253 @@ pub type ErasedStorage<D> =
 /// [`InMemStorage`]: crate::dispatching::dialogue::InMemStorage
 /// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
 /// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
-pub trait Storage<D> {
+/// [`DialogueKey`]: crate::dispatching::dialogue::DialogueKey
+pub trait Storage<D, K = ChatId> {
     type Error;
 
-    /// Removes a dialogue indexed by `chat_id`.
+    /// Removes a dialogue indexed by `key`.
     ///
-    /// If the dialogue indexed by `chat_id` does not exist, this function
+    /// If the dialogue indexed by `key` does not exist, this function
     /// results in an error.
     #[must_use = "Futures are lazy and do nothing unless polled with .await"]
-    fn remove_dialogue(
-        self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static;
 
-    /// Updates a dialogue indexed by `chat_id` with `dialogue`.
+    /// Updates a dialogue indexed by `key` with `dialogue`.
     #[must_use = "Futures are lazy and do nothing unless polled with .await"]
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: ChatId,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static;
 
-    /// Returns the dialogue indexed by `chat_id`.
+    /// Updates a dialogue indexed by `key` with `dialogue`, which expires
+    /// after `ttl`.
+    ///
+    /// An expired dialogue is treated as absent: [`Storage::get_dialogue`]
+    /// returns `None` for it and [`Storage::remove_dialogue`] results in an
+    /// error. A subsequent call to [`Storage::update_dialogue`] makes the
+    /// dialogue non-expiring again.
+    ///
+    /// The default implementation ignores `ttl` and calls
+    /// [`Storage::update_dialogue`]. All storages provided by teloxide override
+    /// it.
     #[must_use = "Futures are lazy and do nothing unless polled with .await"]
-    fn get_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>>;
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let _ = ttl;
+        self.update_dialogue(key, dialogue)
+    }
+
+    /// Returns the dialogue indexed by `key`.
+    ///
+    /// Returns `None` if the dialogue does not exist or has expired.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>>;
 
     /// Erases [`Self::Error`] to [`std::error::Error`].
     #[must_use]
-    fn erase(self: Arc<Self>) -> Arc<ErasedStorage<D>>
+    fn erase(self: Arc<Self>) -> Arc<ErasedStorage<D, K>>
     where
         Self: Sized + Send + Sync + 'static,
+        K: Send + 'static,
         Self::Error: std::error::Error + Send + Sync + 'static,
     {
         Arc::new(Eraser(self))
     }
 }
 
+/// A dialogue key which can be converted to and from a string.
+///
+/// Storages which keep dialogues in an external database use this trait to
+/// store keys other than [`ChatId`].
+pub trait StorageKey: Sized {
+    /// Encodes the key as a string.
+    ///
+    /// Distinct keys must be encoded as distinct strings.
+    fn encode(&self) -> String;
+
+    /// Decodes a key previously encoded by [`StorageKey::encode`].
+    ///
+    /// Returns `None` if `s` is not a valid encoding of a key.
+    fn decode(s: &str) -> Option<Self>;
+}
+
+impl StorageKey for ChatId {
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        s.parse().ok().map(ChatId)
+    }
+}
+
+/// Returns the number of milliseconds elapsed since the UNIX epoch.
+///
+/// Used by the persistent storages to store dialogue expiration timestamps.
+#[cfg(any(
+    feature = "sqlite-storage-nativetls",
+    feature = "sqlite-storage-rustls",
+    feature = "postgres-storage-nativetls",
+    feature = "mysql-storage-nativetls",
+    feature = "mysql-storage-rustls",
+    feature = "file-storage"
+))]
+fn unix_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// A version of a stored dialogue.
+///
+/// The version changes every time the dialogue is updated, so it can be used
+/// to detect concurrent modifications. See [`VersionedStorage`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DialogueVersion(pub i64);
+
+/// A dialogue along with its [`DialogueVersion`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VersionedDialogue<D> {
+    pub dialogue: D,
+    pub version: DialogueVersion,
+}
+
+/// A storage of dialogues which supports optimistic concurrency control.
+///
+/// Every stored dialogue has a [`DialogueVersion`], which changes on each
+/// update, including updates made through plain [`Storage::update_dialogue`].
+/// [`VersionedStorage::update_dialogue_versioned`] writes a dialogue only if
+/// its version still equals the version that was read, so two racing updates
+/// of the same chat cannot silently overwrite each other.
+///
+/// [`InMemStorage`] and [`RedisStorage`] never reuse a version of a key, so a
+/// stale update fails even if the dialogue was removed (or has expired) and
+/// then created again in the meantime. Other storages track versions per
+/// stored dialogue, so a dialogue created again after it was removed may reuse
+/// an old version.
+///
+/// See also [`Dialogue::update_with_retry`].
+///
+/// [`InMemStorage`]: crate::dispatching::dialogue::InMemStorage
+/// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+/// [`Dialogue::update_with_retry`]: crate::dispatching::dialogue::Dialogue::update_with_retry
+pub trait VersionedStorage<D, K = ChatId>: Storage<D, K> {
+    /// Returns the dialogue indexed by `key` along with its version.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>>;
+
+    /// Updates a dialogue indexed by `key` with `dialogue` if its current
+    /// version equals `expected`, returning the new version.
+    ///
+    /// `expected` being `None` means that the dialogue must not exist. If the
+    /// stored version differs, this function results in an error for which
+    /// [`VersionedStorage::is_version_conflict`] returns `true`.
+    ///
+    /// Like [`Storage::update_dialogue`], this makes the dialogue
+    /// non-expiring.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn update_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>>
+    where
+        D: Send + 'static;
+
+    /// Returns `true` if `error` was caused by a version mismatch in
+    /// [`VersionedStorage::update_dialogue_versioned`].
+    fn is_version_conflict(error: &Self::Error) -> bool;
+}
+
+/// A storage of dialogues which can enumerate all of them.
+///
+/// This is useful for admin dashboards, broadcasting reminders to users with
+/// unfinished dialogues, and data migrations. Expired dialogues are never
+/// enumerated or counted.
+pub trait EnumerableStorage<D, K = ChatId>: Storage<D, K> {
+    /// Returns a stream of all stored dialogues along with their keys.
+    ///
+    /// Dialogues are fetched lazily in batches, so the stream does not reflect
+    /// a consistent snapshot of the storage if it is modified concurrently.
+    #[must_use = "Streams are lazy and do nothing unless polled"]
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>>;
+
+    /// Returns the number of stored dialogues.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>>;
+
+    /// Removes all stored dialogues.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
 struct Eraser<S>(Arc<S>);
 
-impl<D, S> Storage<D> for Eraser<S>
+impl<D, K, S> Storage<D, K> for Eraser<S>
 where
-    S: Storage<D> + Send + Sync + 'static,
+    S: Storage<D, K> + Send + Sync + 'static,
+    K: Send + 'static,
     S::Error: std::error::Error + Send + Sync + 'static,
 {
     type Error = Box<dyn std::error::Error + Send + Sync>;
 
-    fn remove_dialogue(
-        self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
         Box::pin(
-            async move { Arc::clone(&self.0).remove_dialogue(chat_id).await.map_err(|e| e.into()) },
+            async move { Arc::clone(&self.0).remove_dialogue(key).await.map_err(|e| e.into()) },
         )
     }
 
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: ChatId,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
         Box::pin(async move {
-            Arc::clone(&self.0).update_dialogue(chat_id, dialogue).await.map_err(|e| e.into())
+            Arc::clone(&self.0).update_dialogue(key, dialogue).await.map_err(|e| e.into())
         })
     }
 
-    fn get_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
-        Box::pin(
-            async move { Arc::clone(&self.0).get_dialogue(chat_id).await.map_err(|e| e.into()) },
-        )
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            Arc::clone(&self.0)
+                .update_dialogue_with_ttl(key, dialogue, ttl)
+                .await
+                .map_err(|e| e.into())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move { Arc::clone(&self.0).get_dialogue(key).await.map_err(|e| e.into()) })
     }
 }
 
@@ -153,4 +337,115 @@ mod tests {
         Arc::clone(&erased).remove_dialogue(chat_id).await.unwrap();
         assert_eq!(Arc::clone(&erased).get_dialogue(chat_id).await.unwrap(), None);
     }
+
+    #[tokio::test]
+    async fn test_in_mem_versioned() {
+        let chat_id = ChatId(123);
+        let storage = InMemStorage::new();
+
+        assert_eq!(Arc::clone(&storage).get_dialogue_versioned(chat_id).await.unwrap(), None);
+
+        let v1 = Arc::clone(&storage).update_dialogue_versioned(chat_id, 1, None).await.unwrap();
+        assert_eq!(
+            Arc::clone(&storage).get_dialogue_versioned(chat_id).await.unwrap(),
+            Some(VersionedDialogue { dialogue: 1, version: v1 })
+        );
+
+        // The dialogue already exists.
+        let err =
+            Arc::clone(&storage).update_dialogue_versioned(chat_id, 2, None).await.unwrap_err();
+        assert!(InMemStorage::<i32>::is_version_conflict(&err));
+
+        // A plain update changes the version.
+        Arc::clone(&storage).update_dialogue(chat_id, 3).await.unwrap();
+        let err =
+            Arc::clone(&storage).update_dialogue_versioned(chat_id, 4, Some(v1)).await.unwrap_err();
+        assert!(matches!(err, InMemStorageError::VersionConflict));
+
+        let VersionedDialogue { dialogue, version: v2 } =
+            Arc::clone(&storage).get_dialogue_versioned(chat_id).await.unwrap().unwrap();
+        assert_eq!(dialogue, 3);
+        assert_ne!(v1, v2);
+        Arc::clone(&storage).update_dialogue_versioned(chat_id, 5, Some(v2)).await.unwrap();
+        assert_eq!(Arc::clone(&storage).get_dialogue(chat_id).await.unwrap(), Some(5));
+
+        // A dialogue created again after removal gets a new version.
+        let chat_id = ChatId(456);
+        let v1 = Arc::clone(&storage).update_dialogue_versioned(chat_id, 1, None).await.unwrap();
+        Arc::clone(&storage).remove_dialogue(chat_id).await.unwrap();
+        Arc::clone(&storage).update_dialogue_versioned(chat_id, 2, None).await.unwrap();
+        let err =
+            Arc::clone(&storage).update_dialogue_versioned(chat_id, 3, Some(v1)).await.unwrap_err();
+        assert!(matches!(err, InMemStorageError::VersionConflict));
+
+        // The same goes for a dialogue created again after expiration.
+        let chat_id = ChatId(789);
+        Arc::clone(&storage)
+            .update_dialogue_with_ttl(chat_id, 1, Duration::from_millis(50))
+            .await
+            .unwrap();
+        let VersionedDialogue { version: v1, .. } =
+            Arc::clone(&storage).get_dialogue_versioned(chat_id).await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(Arc::clone(&storage).get_dialogue(chat_id).await.unwrap(), None);
+        Arc::clone(&storage).update_dialogue_versioned(chat_id, 2, None).await.unwrap();
+        let err =
+            Arc::clone(&storage).update_dialogue_versioned(chat_id, 3, Some(v1)).await.unwrap_err();
+        assert!(matches!(err, InMemStorageError::VersionConflict));
+    }
+
+    #[tokio::test]
+    async fn test_in_mem_enumerable() {
+        use futures::TryStreamExt;
+
+        let storage = InMemStorage::new();
+
+        Arc::clone(&storage).update_dialogue(ChatId(1), 1).await.unwrap();
+        Arc::clone(&storage).update_dialogue(ChatId(2), 2).await.unwrap();
+        Arc::clone(&storage)
+            .update_dialogue_with_ttl(ChatId(3), 3, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(Arc::clone(&storage).count_dialogues().await.unwrap(), 3);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(Arc::clone(&storage).count_dialogues().await.unwrap(), 2);
+
+        let mut dialogues: Vec<_> =
+            Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+        dialogues.sort();
+        assert_eq!(dialogues, [(ChatId(1), 1), (ChatId(2), 2)]);
+
+        Arc::clone(&storage).clear_all().await.unwrap();
+        assert_eq!(Arc::clone(&storage).count_dialogues().await.unwrap(), 0);
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_in_mem_ttl() {
+        let chat_id = ChatId(123);
+        let storage = InMemStorage::new();
+
+        Arc::clone(&storage)
+            .update_dialogue_with_ttl(chat_id, 1, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(Arc::clone(&storage).get_dialogue(chat_id).await.unwrap(), Some(1));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(Arc::clone(&storage).get_dialogue(chat_id).await.unwrap(), None);
+        assert!(matches!(
+            Arc::clone(&storage).remove_dialogue(chat_id).await.unwrap_err(),
+            InMemStorageError::DialogueNotFound
+        ));
+
+        // A regular update makes the dialogue non-expiring again.
+        Arc::clone(&storage)
+            .update_dialogue_with_ttl(chat_id, 2, Duration::from_millis(50))
+            .await
+            .unwrap();
+        Arc::clone(&storage).update_dialogue(chat_id, 3).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(Arc::clone(&storage).get_dialogue(chat_id).await.unwrap(), Some(3));
+    }
 }
@@ -0,0 +1,557 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, stream::BoxStream};
+use teloxide_core::types::ChatId;
+
+use crate::dispatching::dialogue::{
+    DialogueVersion, EnumerableStorage, Storage, VersionedDialogue, VersionedStorage,
+};
+
+/// A dialogue storage wrapper which caches dialogues of an underlying storage
+/// in memory.
+///
+/// Up to `capacity` dialogues are cached; when the cache is full, the least
+/// recently used one is evicted. Writes go to the underlying storage first and
+/// are cached only if they succeed (write-through), so the underlying storage
+/// always has the latest state. The absence of a dialogue is cached too.
+/// Dialogues written concurrently are not cached, since it's unknown in which
+/// order the writes reached the underlying storage.
+///
+/// If the underlying storage is shared with other processes (e.g. several bot
+/// instances use the same Redis), the cache may return stale dialogues. To
+/// bound the staleness, set a time-to-live via [`CachedStorage::with_ttl`] or
+/// call [`CachedStorage::invalidate`] when you know that a dialogue has been
+/// changed elsewhere.
+///
+/// [`VersionedStorage::get_dialogue_versioned`] always bypasses the cache.
+pub struct CachedStorage<S, D, K = ChatId> {
+    inner: Arc<S>,
+    cache: Mutex<Cache<K, D>>,
+    capacity: usize,
+    ttl: Option<Duration>,
+}
+
+impl<S, D, K> CachedStorage<S, D, K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Wraps `inner` into a cache holding up to `capacity` dialogues.
+    #[must_use = "This function is pure, that is does nothing unless its output is used"]
+    pub fn new(inner: Arc<S>, capacity: usize) -> Arc<Self> {
+        Arc::new(Self { inner, cache: Mutex::new(Cache::new()), capacity, ttl: None })
+    }
+
+    /// Like [`CachedStorage::new`], but cached dialogues are re-read from
+    /// `inner` once `ttl` has passed since they were cached.
+    #[must_use = "This function is pure, that is does nothing unless its output is used"]
+    pub fn with_ttl(inner: Arc<S>, capacity: usize, ttl: Duration) -> Arc<Self> {
+        Arc::new(Self { inner, cache: Mutex::new(Cache::new()), capacity, ttl: Some(ttl) })
+    }
+
+    #[must_use = "This function is pure, that is does nothing unless its output is used"]
+    pub fn into_inner(self) -> Arc<S> {
+        self.inner
+    }
+
+    /// Removes a dialogue indexed by `key` from the cache, so that it is read
+    /// from the underlying storage next time.
+    pub fn invalidate(&self, key: &K) {
+        self.cache.lock().unwrap().invalidate(key);
+    }
+
+    /// Removes all dialogues from the cache.
+    pub fn invalidate_all(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Starts a write of a dialogue indexed by `key` to the underlying storage,
+    /// removing it from the cache until the write is finished.
+    fn begin_write(&self, key: K) -> Operation<'_, S, D, K> {
+        let ticket = self.cache.lock().unwrap().begin(&key, true);
+        Operation { storage: self, key, ticket, write: true, finished: false }
+    }
+}
+
+/// A read or write of a dialogue in the underlying storage, started by
+/// [`CachedStorage::begin_write`] or [`CachedStorage::get_dialogue`].
+///
+/// If it's dropped without being finished, e.g. because the write has failed,
+/// a written dialogue is removed from the cache.
+struct Operation<'a, S, D, K>
+where
+    K: Eq + Hash + Clone,
+{
+    storage: &'a CachedStorage<S, D, K>,
+    key: K,
+    ticket: Ticket,
+    write: bool,
+    finished: bool,
+}
+
+impl<S, D, K> Operation<'_, S, D, K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Caches `dialogue` as the current state of the dialogue, which expires
+    /// after `ttl`, if any.
+    ///
+    /// If the dialogue was written by someone else meanwhile, it's unknown
+    /// which write reached the underlying storage last, so it's not cached.
+    /// A written dialogue is also removed from the cache in this case.
+    fn finish(mut self, dialogue: Option<D>, ttl: Option<Duration>) {
+        self.finished = true;
+
+        let now = Instant::now();
+        let expires_at =
+            [self.storage.ttl, ttl].into_iter().flatten().min().and_then(|t| now.checked_add(t));
+
+        let mut cache = self.storage.cache.lock().unwrap();
+        if cache.finish(&self.key, self.ticket) {
+            let entry = Cached { dialogue, expires_at, tick: 0 };
+            cache.insert(self.key.clone(), entry, self.storage.capacity);
+        } else if self.write {
+            cache.remove(&self.key);
+        }
+    }
+}
+
+impl<S, D, K> Drop for Operation<'_, S, D, K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let mut cache = self.storage.cache.lock().unwrap();
+        cache.finish(&self.key, self.ticket);
+        if self.write {
+            cache.remove(&self.key);
+        }
+    }
+}
+
+/// A least-recently-used cache of dialogues.
+struct Cache<K, D> {
+    entries: HashMap<K, Cached<D>>,
+    /// Keys ordered by the time of their last use.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    /// Dialogues which are being read from or written to the underlying
+    /// storage.
+    pending: HashMap<K, Pending>,
+    /// The number of times the whole cache was cleared.
+    epoch: u64,
+}
+
+struct Cached<D> {
+    dialogue: Option<D>,
+    expires_at: Option<Instant>,
+    tick: u64,
+}
+
+/// Operations on a dialogue which are in progress.
+struct Pending {
+    /// The number of writes started, used to detect writes that happen while
+    /// the dialogue is read from or written to the underlying storage.
+    writes: u64,
+    operations: usize,
+}
+
+/// The state of a dialogue at the start of an [`Operation`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Ticket {
+    writes: u64,
+    epoch: u64,
+}
+
+impl<K, D> Cache<K, D>
+where
+    K: Eq + Hash + Clone,
+{
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            pending: HashMap::new(),
+            epoch: 0,
+        }
+    }
+
+    /// Returns a cached dialogue, or `None` if it is not cached.
+    fn get(&mut self, key: &K) -> Option<Option<D>>
+    where
+        D: Clone,
+    {
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires_at.is_some_and(|expires_at| expires_at <= Instant::now()) {
+            self.remove(key);
+            return None;
+        }
+
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+
+        Some(entry.dialogue.clone())
+    }
+
+    fn insert(&mut self, key: K, mut entry: Cached<D>, capacity: usize) {
+        self.remove(&key);
+
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+
+        while self.entries.len() > capacity {
+            let Some((_, key)) = self.order.pop_first() else { break };
+            self.entries.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    /// Removes a dialogue, so that operations on it which are in progress
+    /// don't cache it.
+    fn invalidate(&mut self, key: &K) {
+        self.remove(key);
+        if let Some(pending) = self.pending.get_mut(key) {
+            pending.writes += 1;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.epoch += 1;
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Starts an operation on a dialogue indexed by `key`.
+    fn begin(&mut self, key: &K, write: bool) -> Ticket {
+        if write {
+            self.remove(key);
+        }
+
+        let pending =
+            self.pending.entry(key.clone()).or_insert(Pending { writes: 0, operations: 0 });
+        pending.operations += 1;
+        if write {
+            pending.writes += 1;
+        }
+        Ticket { writes: pending.writes, epoch: self.epoch }
+    }
+
+    /// Finishes an operation started by [`Cache::begin`], returning whether
+    /// the dialogue can be cached, that is no other write of it was started
+    /// and the cache wasn't cleared meanwhile.
+    fn finish(&mut self, key: &K, ticket: Ticket) -> bool {
+        let Some(pending) = self.pending.get_mut(key) else { return false };
+        let current = Ticket { writes: pending.writes, epoch: self.epoch };
+
+        pending.operations -= 1;
+        if pending.operations == 0 {
+            self.pending.remove(key);
+        }
+        current == ticket
+    }
+}
+
+impl<S, D, K> Storage<D, K> for CachedStorage<S, D, K>
+where
+    D: Clone + Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
+    S: Storage<D, K> + Send + Sync + 'static,
+{
+    type Error = <S as Storage<D, K>>::Error;
+
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let write = self.begin_write(key.clone());
+            <S as Storage<D, K>>::remove_dialogue(self.inner.clone(), key).await?;
+            write.finish(None, None);
+            Ok(())
+        })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let write = self.begin_write(key.clone());
+            <S as Storage<D, K>>::update_dialogue(self.inner.clone(), key, dialogue.clone())
+                .await?;
+            write.finish(Some(dialogue), None);
+            Ok(())
+        })
+    }
+
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let write = self.begin_write(key.clone());
+            <S as Storage<D, K>>::update_dialogue_with_ttl(
+                self.inner.clone(),
+                key,
+                dialogue.clone(),
+                ttl,
+            )
+            .await?;
+            write.finish(Some(dialogue), Some(ttl));
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            let ticket = {
+                let mut cache = self.cache.lock().unwrap();
+                if let Some(dialogue) = cache.get(&key) {
+                    return Ok(dialogue);
+                }
+                cache.begin(&key, false)
+            };
+            let read = Operation {
+                storage: &*self,
+                key: key.clone(),
+                ticket,
+                write: false,
+                finished: false,
+            };
+
+            let dialogue = <S as Storage<D, K>>::get_dialogue(self.inner.clone(), key).await?;
+
+            // The dialogue isn't cached if it might have been changed meanwhile.
+            read.finish(dialogue.clone(), None);
+            Ok(dialogue)
+        })
+    }
+}
+
+impl<S, D, K> VersionedStorage<D, K> for CachedStorage<S, D, K>
+where
+    D: Clone + Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
+    S: VersionedStorage<D, K> + Send + Sync + 'static,
+{
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        <S as VersionedStorage<D, K>>::get_dialogue_versioned(self.inner.clone(), key)
+    }
+
+    fn update_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let write = self.begin_write(key.clone());
+            let version = <S as VersionedStorage<D, K>>::update_dialogue_versioned(
+                self.inner.clone(),
+                key,
+                dialogue.clone(),
+                expected,
+            )
+            .await?;
+            write.finish(Some(dialogue), None);
+            Ok(version)
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        S::is_version_conflict(error)
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for CachedStorage<S, D, K>
+where
+    D: Clone + Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
+    S: EnumerableStorage<D, K> + Send + Sync + 'static,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        <S as EnumerableStorage<D, K>>::list_dialogues(self.inner.clone())
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        <S as EnumerableStorage<D, K>>::count_dialogues(self.inner.clone())
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.invalidate_all();
+            let res = <S as EnumerableStorage<D, K>>::clear_all(self.inner.clone()).await;
+            self.invalidate_all();
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatching::dialogue::InMemStorage;
+
+    #[tokio::test]
+    async fn test_cached_storage() {
+        let inner = InMemStorage::<i32>::new();
+        let storage = CachedStorage::new(Arc::clone(&inner), 2);
+
+        Arc::clone(&storage).update_dialogue(ChatId(1), 1).await.unwrap();
+        Arc::clone(&storage).update_dialogue(ChatId(2), 2).await.unwrap();
+
+        // Cached dialogues are not re-read from the underlying storage.
+        Arc::clone(&inner).update_dialogue(ChatId(1), 10).await.unwrap();
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(1));
+
+        storage.invalidate(&ChatId(1));
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(10));
+
+        // `ChatId(2)` is the least recently used one, so it's evicted.
+        Arc::clone(&storage).update_dialogue(ChatId(3), 3).await.unwrap();
+        Arc::clone(&inner).update_dialogue(ChatId(1), 100).await.unwrap();
+        Arc::clone(&inner).update_dialogue(ChatId(2), 20).await.unwrap();
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(10));
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(2)).await.unwrap(), Some(20));
+
+        // Writes go through to the underlying storage.
+        Arc::clone(&storage).remove_dialogue(ChatId(3)).await.unwrap();
+        assert_eq!(Arc::clone(&inner).get_dialogue(ChatId(3)).await.unwrap(), None);
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(3)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_cached_storage_ttl() {
+        let inner = InMemStorage::<i32>::new();
+        let storage = CachedStorage::with_ttl(Arc::clone(&inner), 10, Duration::from_millis(50));
+
+        Arc::clone(&storage).update_dialogue(ChatId(1), 1).await.unwrap();
+        Arc::clone(&inner).update_dialogue(ChatId(1), 2).await.unwrap();
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(1));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(2));
+
+        // Dialogues expiring in the underlying storage expire in the cache too.
+        Arc::clone(&storage)
+            .update_dialogue_with_ttl(ChatId(2), 1, Duration::from_millis(10))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(2)).await.unwrap(), None);
+    }
+
+    /// A storage that acknowledges a write of `n` only `n` milliseconds after
+    /// it has been applied, and returns a dialogue 5 milliseconds after it has
+    /// been read.
+    struct SlowStorage(Arc<InMemStorage<u64>>);
+
+    impl Storage<u64> for SlowStorage {
+        type Error = <InMemStorage<u64> as Storage<u64>>::Error;
+
+        fn remove_dialogue(
+            self: Arc<Self>,
+            key: ChatId,
+        ) -> BoxFuture<'static, Result<(), Self::Error>> {
+            Arc::clone(&self.0).remove_dialogue(key)
+        }
+
+        fn update_dialogue(
+            self: Arc<Self>,
+            key: ChatId,
+            dialogue: u64,
+        ) -> BoxFuture<'static, Result<(), Self::Error>> {
+            Box::pin(async move {
+                Arc::clone(&self.0).update_dialogue(key, dialogue).await?;
+                tokio::time::sleep(Duration::from_millis(dialogue)).await;
+                Ok(())
+            })
+        }
+
+        fn get_dialogue(
+            self: Arc<Self>,
+            key: ChatId,
+        ) -> BoxFuture<'static, Result<Option<u64>, Self::Error>> {
+            Box::pin(async move {
+                let dialogue = Arc::clone(&self.0).get_dialogue(key).await?;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                Ok(dialogue)
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cached_storage_concurrent_writes() {
+        let inner = InMemStorage::new();
+        let storage = CachedStorage::new(Arc::new(SlowStorage(Arc::clone(&inner))), 10);
+
+        // `20` is written first, but acknowledged last.
+        let first = tokio::spawn(Arc::clone(&storage).update_dialogue(ChatId(1), 20));
+        tokio::task::yield_now().await;
+        let second = tokio::spawn(Arc::clone(&storage).update_dialogue(ChatId(1), 10));
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+
+        assert_eq!(Arc::clone(&inner).get_dialogue(ChatId(1)).await.unwrap(), Some(10));
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cached_storage_concurrent_keys() {
+        let inner = InMemStorage::new();
+        let storage = CachedStorage::new(Arc::new(SlowStorage(Arc::clone(&inner))), 10);
+        Arc::clone(&inner).update_dialogue(ChatId(1), 1).await.unwrap();
+
+        // Another dialogue is written while `ChatId(1)` is being read.
+        let read = tokio::spawn(Arc::clone(&storage).get_dialogue(ChatId(1)));
+        tokio::task::yield_now().await;
+        Arc::clone(&storage).update_dialogue(ChatId(2), 2).await.unwrap();
+        assert_eq!(read.await.unwrap().unwrap(), Some(1));
+
+        // The read dialogue is cached anyway.
+        Arc::clone(&inner).update_dialogue(ChatId(1), 100).await.unwrap();
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(1));
+
+        // The same dialogue is written while it's being read, so the read one
+        // isn't cached.
+        storage.invalidate(&ChatId(1));
+        let read = tokio::spawn(Arc::clone(&storage).get_dialogue(ChatId(1)));
+        tokio::task::yield_now().await;
+        Arc::clone(&storage).update_dialogue(ChatId(1), 3).await.unwrap();
+        assert_eq!(read.await.unwrap().unwrap(), Some(100));
+        assert_eq!(Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(), Some(3));
+    }
+}
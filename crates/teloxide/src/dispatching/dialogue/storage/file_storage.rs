@@ -0,0 +1,776 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{Debug, Display},
+    io,
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use teloxide_core::types::{ChatId, UpdateId, UserId};
+use thiserror::Error;
+
+use crate::dispatching::{
+    data::{DataKey, DataStorage},
+    dedup::{DedupStorage, PERSISTENT_WINDOW_MILLIS, PRUNE_INTERVAL},
+    jobs::{Job, JobStorage},
+};
+
+use super::{
+    serializer::Serializer, unix_millis, DialogueVersion, EnumerableStorage, Storage, StorageKey,
+    VersionedDialogue, VersionedStorage,
+};
+
+use log_file::{LogFile, Record};
+
+mod log_file;
+
+/// The file is compacted automatically once it is larger than this...
+const AUTO_COMPACTION_MIN_SIZE: u64 = 1024 * 1024;
+
+/// ...and this many times larger than the live records in it.
+const AUTO_COMPACTION_RATIO: u64 = 4;
+
+/// An error returned from [`FileStorage`].
+#[derive(Debug, Error)]
+pub enum FileStorageError<SE>
+where
+    SE: Debug + Display,
+{
+    #[error("dialogue serialization error: {0}")]
+    SerdeError(SE),
+
+    #[error("file storage error: {0}")]
+    IoError(#[from] io::Error),
+
+    /// Returned from [`FileStorage::remove_dialogue`].
+    #[error("dialogue not found")]
+    DialogueNotFound,
+
+    /// Returned from [`FileStorage::update_dialogue_versioned`].
+    #[error("dialogue version conflict")]
+    VersionConflict,
+}
+
+/// A persistent dialogue storage kept in a single file, without an external
+/// database.
+///
+/// This storage is written in pure Rust and doesn't need any dependencies, so
+/// it is a good fit for small bots that are distributed as a single binary.
+/// Besides dialogues, it can keep [per-user and per-chat data], [jobs] and
+/// [seen update IDs].
+///
+/// All the records are kept in memory and every change is appended to the
+/// file, which is flushed to the disk before the change is acknowledged. A
+/// crash can at most lose a change that wasn't acknowledged yet; such a
+/// partially written change is discarded when the file is opened next time.
+///
+/// Since the file only grows, it is compacted, i.e. rewritten with only the
+/// live records, once it is a few times larger than them. Compaction can also
+/// be triggered manually via [`FileStorage::compact`]. Either way, the new file
+/// atomically replaces the old one.
+///
+/// Expired dialogues are evicted on access. To evict all of them at once, use
+/// [`FileStorage::remove_expired_dialogues`].
+///
+/// Dialogues are stored under their keys encoded via [`StorageKey`].
+///
+/// Only one `FileStorage` may use a file at a time.
+///
+/// [per-user and per-chat data]: crate::dispatching::data
+/// [jobs]: crate::dispatching::jobs
+/// [seen update IDs]: crate::dispatching::dedup
+pub struct FileStorage<S, K = ChatId> {
+    state: Mutex<State>,
+    serializer: S,
+    _key: PhantomData<fn(K) -> K>,
+}
+
+struct State {
+    file: LogFile,
+    dialogues: HashMap<String, StoredDialogue>,
+    data: HashMap<(String, String, i64), StoredData>,
+    jobs: HashMap<String, StoredData>,
+    updates: HashMap<(u64, u32), StoredUpdate>,
+    /// The total size of the frames of the records in all the maps above.
+    live_len: u64,
+}
+
+struct StoredDialogue {
+    dialogue: Vec<u8>,
+    expires_at: Option<i64>,
+    version: i64,
+    frame_len: u64,
+}
+
+impl StoredDialogue {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= unix_millis())
+    }
+}
+
+struct StoredData {
+    data: Vec<u8>,
+    frame_len: u64,
+}
+
+struct StoredUpdate {
+    seen_at: i64,
+    frame_len: u64,
+}
+
+impl<S, K> FileStorage<S, K> {
+    /// Opens the storage file at `path`, creating it if it doesn't exist.
+    ///
+    /// Parameters:
+    /// - path: path to the storage file, its directory must exist
+    /// - serializer: what [`Serializer`] will be used to encode the dialogue
+    ///   data. Available ones are: [`Json`], [`Bincode`], [`Cbor`]
+    ///
+    /// [`Json`]: crate::dispatching::dialogue::serializer::Json
+    /// [`Bincode`]: crate::dispatching::dialogue::serializer::Bincode
+    /// [`Cbor`]: crate::dispatching::dialogue::serializer::Cbor
+    pub async fn open(
+        path: impl AsRef<Path>,
+        serializer: S,
+    ) -> Result<Arc<Self>, FileStorageError<Infallible>> {
+        let path = path.as_ref().to_owned();
+        let state = blocking(move || State::open(&path)).await?;
+        Ok(Arc::new(Self { state: Mutex::new(state), serializer, _key: PhantomData }))
+    }
+
+    /// Rewrites the storage file with only the live records.
+    pub async fn compact(self: &Arc<Self>) -> Result<(), FileStorageError<Infallible>>
+    where
+        S: Send + Sync + 'static,
+        K: Send + 'static,
+    {
+        let this = Arc::clone(self);
+        blocking(move || this.state.lock().unwrap().compact()).await?;
+        Ok(())
+    }
+
+    /// Removes all dialogues whose time-to-live has passed.
+    pub async fn remove_expired_dialogues(
+        self: &Arc<Self>,
+    ) -> Result<(), FileStorageError<Infallible>>
+    where
+        S: Send + Sync + 'static,
+        K: Send + 'static,
+    {
+        let this = Arc::clone(self);
+        blocking(move || {
+            let mut state = this.state.lock().unwrap();
+            let expired: Vec<_> = state
+                .dialogues
+                .iter()
+                .filter(|(_, d)| d.is_expired())
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                state.remove_dialogue(key)?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Returns a non-expired dialogue, evicting an expired one.
+    async fn get_dialogue(self: Arc<Self>, key: String) -> io::Result<Option<(Vec<u8>, i64)>>
+    where
+        S: Send + Sync + 'static,
+        K: Send + 'static,
+    {
+        blocking(move || {
+            let mut state = self.state.lock().unwrap();
+            match state.dialogues.get(&key) {
+                None => Ok(None),
+                Some(d) if !d.is_expired() => Ok(Some((d.dialogue.clone(), d.version))),
+                Some(_) => {
+                    state.remove_dialogue(key)?;
+                    Ok(None)
+                }
+            }
+        })
+        .await
+    }
+
+    /// Reads the state. The state is locked by writers while they flush the
+    /// file, so it is read outside of the async runtime as well.
+    async fn read<T>(self: Arc<Self>, f: impl FnOnce(&State) -> T + Send + 'static) -> io::Result<T>
+    where
+        S: Send + Sync + 'static,
+        K: Send + 'static,
+        T: Send + 'static,
+    {
+        blocking(move || Ok(f(&self.state.lock().unwrap()))).await
+    }
+
+    async fn update(
+        self: Arc<Self>,
+        f: impl FnOnce(&mut State) -> io::Result<Option<i64>> + Send + 'static,
+    ) -> io::Result<Option<i64>>
+    where
+        S: Send + Sync + 'static,
+        K: Send + 'static,
+    {
+        blocking(move || f(&mut self.state.lock().unwrap())).await
+    }
+}
+
+impl State {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut dialogues = HashMap::new();
+        let mut data = HashMap::new();
+        let mut jobs = HashMap::new();
+        let mut updates = HashMap::new();
+
+        let file = LogFile::open(path, |record, frame_len| match record {
+            Record::Dialogue { key, version, expires_at, dialogue } => {
+                dialogues.insert(key, StoredDialogue { dialogue, expires_at, version, frame_len });
+            }
+            Record::RemoveDialogue { key } => {
+                dialogues.remove(&key);
+            }
+            Record::ClearDialogues => dialogues.clear(),
+            Record::Data { name, scope, id, data: value } => {
+                data.insert((name, scope, id), StoredData { data: value, frame_len });
+            }
+            Record::RemoveData { name, scope, id } => {
+                data.remove(&(name, scope, id));
+            }
+            Record::Job { id, job } => {
+                jobs.insert(id, StoredData { data: job, frame_len });
+            }
+            Record::RemoveJob { id } => {
+                jobs.remove(&id);
+            }
+            Record::Update { bot_id, update_id, seen_at } => {
+                updates.insert((bot_id, update_id), StoredUpdate { seen_at, frame_len });
+            }
+        })?;
+
+        // Old update IDs are not removed from the file, but dropped here and on
+        // compaction.
+        let seen_after = unix_millis() - PERSISTENT_WINDOW_MILLIS;
+        updates.retain(|_, u: &mut StoredUpdate| u.seen_at >= seen_after);
+
+        let live_len = dialogues.values().map(|d: &StoredDialogue| d.frame_len).sum::<u64>()
+            + data.values().map(|d: &StoredData| d.frame_len).sum::<u64>()
+            + jobs.values().map(|j: &StoredData| j.frame_len).sum::<u64>()
+            + updates.values().map(|u| u.frame_len).sum::<u64>();
+        let mut state = Self { file, dialogues, data, jobs, updates, live_len };
+        state.maybe_compact()?;
+        Ok(state)
+    }
+
+    /// Stores a dialogue, bumping the version of the previous one, and returns
+    /// the new version.
+    fn put_dialogue(
+        &mut self,
+        key: String,
+        dialogue: Vec<u8>,
+        expires_at: Option<i64>,
+    ) -> io::Result<i64> {
+        let version = self.dialogues.get(&key).map_or(1, |d| d.version + 1);
+        let record = Record::Dialogue { key, version, expires_at, dialogue };
+        let frame_len = self.file.append(&record)?;
+
+        let Record::Dialogue { key, dialogue, .. } = record else { unreachable!() };
+        let old =
+            self.dialogues.insert(key, StoredDialogue { dialogue, expires_at, version, frame_len });
+        self.live_len += frame_len;
+        self.live_len -= old.map_or(0, |d| d.frame_len);
+
+        self.maybe_compact()?;
+        Ok(version)
+    }
+
+    /// Removes a dialogue and returns whether it was present and not expired.
+    fn remove_dialogue(&mut self, key: String) -> io::Result<bool> {
+        let Some(is_expired) = self.dialogues.get(&key).map(StoredDialogue::is_expired) else {
+            return Ok(false);
+        };
+
+        let record = Record::RemoveDialogue { key };
+        self.file.append(&record)?;
+        let Record::RemoveDialogue { key } = record else { unreachable!() };
+        let old = self.dialogues.remove(&key).unwrap();
+        self.live_len -= old.frame_len;
+
+        self.maybe_compact()?;
+        Ok(!is_expired)
+    }
+
+    fn clear_dialogues(&mut self) -> io::Result<()> {
+        self.file.append(&Record::ClearDialogues)?;
+        self.live_len -= self.dialogues.drain().map(|(_, d)| d.frame_len).sum::<u64>();
+        self.maybe_compact()
+    }
+
+    fn set_data(&mut self, key: DataKey, data: Vec<u8>) -> io::Result<()> {
+        let (name, scope, id) = data_key(key);
+        let record = Record::Data { name, scope, id, data };
+        let frame_len = self.file.append(&record)?;
+
+        let Record::Data { name, scope, id, data } = record else { unreachable!() };
+        let old = self.data.insert((name, scope, id), StoredData { data, frame_len });
+        self.live_len += frame_len;
+        self.live_len -= old.map_or(0, |d| d.frame_len);
+
+        self.maybe_compact()
+    }
+
+    fn remove_data(&mut self, key: DataKey) -> io::Result<()> {
+        let key = data_key(key);
+        if !self.data.contains_key(&key) {
+            return Ok(());
+        }
+
+        let (name, scope, id) = key.clone();
+        self.file.append(&Record::RemoveData { name, scope, id })?;
+        let old = self.data.remove(&key).unwrap();
+        self.live_len -= old.frame_len;
+
+        self.maybe_compact()
+    }
+
+    fn save_job(&mut self, id: String, job: Vec<u8>) -> io::Result<()> {
+        let record = Record::Job { id, job };
+        let frame_len = self.file.append(&record)?;
+
+        let Record::Job { id, job } = record else { unreachable!() };
+        let old = self.jobs.insert(id, StoredData { data: job, frame_len });
+        self.live_len += frame_len;
+        self.live_len -= old.map_or(0, |j| j.frame_len);
+
+        self.maybe_compact()
+    }
+
+    fn remove_job(&mut self, id: String) -> io::Result<()> {
+        if !self.jobs.contains_key(&id) {
+            return Ok(());
+        }
+
+        let record = Record::RemoveJob { id };
+        self.file.append(&record)?;
+        let Record::RemoveJob { id } = record else { unreachable!() };
+        let old = self.jobs.remove(&id).unwrap();
+        self.live_len -= old.frame_len;
+
+        self.maybe_compact()
+    }
+
+    /// Records an update ID and returns whether it was not seen before.
+    fn mark_seen(&mut self, bot_id: u64, update_id: u32) -> io::Result<bool> {
+        let now = unix_millis();
+        if update_id % PRUNE_INTERVAL == 0 {
+            let seen_after = now - PERSISTENT_WINDOW_MILLIS;
+            let mut pruned = 0;
+            self.updates.retain(|_, u| {
+                let keep = u.seen_at >= seen_after;
+                pruned += if keep { 0 } else { u.frame_len };
+                keep
+            });
+            self.live_len -= pruned;
+        }
+
+        if self.updates.contains_key(&(bot_id, update_id)) {
+            return Ok(false);
+        }
+
+        let frame_len = self.file.append(&Record::Update { bot_id, update_id, seen_at: now })?;
+        self.updates.insert((bot_id, update_id), StoredUpdate { seen_at: now, frame_len });
+        self.live_len += frame_len;
+
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    fn maybe_compact(&mut self) -> io::Result<()> {
+        let len = self.file.len();
+        if len >= AUTO_COMPACTION_MIN_SIZE && len / AUTO_COMPACTION_RATIO >= self.live_len {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let dialogues = self.dialogues.iter().map(|(key, d)| Record::Dialogue {
+            key: key.clone(),
+            version: d.version,
+            expires_at: d.expires_at,
+            dialogue: d.dialogue.clone(),
+        });
+        let data = self.data.iter().map(|((name, scope, id), d)| Record::Data {
+            name: name.clone(),
+            scope: scope.clone(),
+            id: *id,
+            data: d.data.clone(),
+        });
+        let jobs =
+            self.jobs.iter().map(|(id, j)| Record::Job { id: id.clone(), job: j.data.clone() });
+        let updates = self.updates.iter().map(|(&(bot_id, update_id), u)| Record::Update {
+            bot_id,
+            update_id,
+            seen_at: u.seen_at,
+        });
+        self.file.rewrite(dialogues.chain(data).chain(jobs).chain(updates))
+    }
+}
+
+/// Returns the key of data in [`State::data`].
+fn data_key(key: DataKey) -> (String, String, i64) {
+    (key.name.to_owned(), key.scope().to_owned(), key.id())
+}
+
+/// Runs blocking file operations outside of the async runtime.
+async fn blocking<T>(f: impl FnOnce() -> io::Result<T> + Send + 'static) -> io::Result<T>
+where
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+impl<S, D, K> Storage<D, K> for FileStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    type Error = FileStorageError<<S as Serializer<D>>::Error>;
+
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let key = key.encode();
+        Box::pin(async move {
+            let removed = blocking(move || self.state.lock().unwrap().remove_dialogue(key)).await?;
+            if !removed {
+                return Err(FileStorageError::DialogueNotFound);
+            }
+            Ok(())
+        })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let key = key.encode();
+        Box::pin(async move {
+            let d = self.serializer.serialize(&dialogue).map_err(FileStorageError::SerdeError)?;
+            self.update(move |state| state.put_dialogue(key, d, None).map(Some)).await?;
+            Ok(())
+        })
+    }
+
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let key = key.encode();
+        Box::pin(async move {
+            let d = self.serializer.serialize(&dialogue).map_err(FileStorageError::SerdeError)?;
+            let expires_at = unix_millis().saturating_add(ttl.as_millis() as i64);
+            self.update(move |state| state.put_dialogue(key, d, Some(expires_at)).map(Some))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            self.clone()
+                .get_dialogue(key)
+                .await?
+                .map(|(d, _)| self.serializer.deserialize(&d).map_err(FileStorageError::SerdeError))
+                .transpose()
+        })
+    }
+}
+
+impl<S, D, K> VersionedStorage<D, K> for FileStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            self.clone()
+                .get_dialogue(key)
+                .await?
+                .map(|(d, version)| {
+                    let d =
+                        self.serializer.deserialize(&d).map_err(FileStorageError::SerdeError)?;
+                    Ok(VersionedDialogue { dialogue: d, version: DialogueVersion(version) })
+                })
+                .transpose()
+        })
+    }
+
+    fn update_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let key = key.encode();
+        Box::pin(async move {
+            let d = self.serializer.serialize(&dialogue).map_err(FileStorageError::SerdeError)?;
+
+            let version = self
+                .update(move |state| {
+                    // An expired dialogue is considered absent.
+                    let current = state
+                        .dialogues
+                        .get(&key)
+                        .filter(|d| !d.is_expired())
+                        .map(|d| DialogueVersion(d.version));
+                    if current != expected {
+                        return Ok(None);
+                    }
+                    state.put_dialogue(key, d, None).map(Some)
+                })
+                .await?;
+
+            version.map(DialogueVersion).ok_or(FileStorageError::VersionConflict)
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        matches!(error, FileStorageError::VersionConflict)
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for FileStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        let dialogues = Arc::clone(&self).read(|state| {
+            state
+                .dialogues
+                .iter()
+                .filter(|(_, d)| !d.is_expired())
+                .filter_map(|(key, d)| Some((K::decode(key)?, d.dialogue.clone())))
+                .collect::<Vec<_>>()
+        });
+
+        stream::once(dialogues)
+            .map_ok(move |dialogues| {
+                let this = Arc::clone(&self);
+                stream::iter(dialogues).map(move |(key, d)| {
+                    let d =
+                        this.serializer.deserialize(&d).map_err(FileStorageError::SerdeError)?;
+                    Ok((key, d))
+                })
+            })
+            .map_err(FileStorageError::from)
+            .try_flatten()
+            .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        Box::pin(async move {
+            let count = self
+                .read(|state| state.dialogues.values().filter(|d| !d.is_expired()).count())
+                .await?;
+            Ok(count as u64)
+        })
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            blocking(move || self.state.lock().unwrap().clear_dialogues()).await?;
+            Ok(())
+        })
+    }
+}
+
+impl<S, K, T> DataStorage<T> for FileStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + Serializer<T> + 'static,
+    T: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<T>>::Error: Debug + Display,
+{
+    type Error = FileStorageError<<S as Serializer<T>>::Error>;
+
+    fn get_data(
+        self: Arc<Self>,
+        key: DataKey,
+    ) -> BoxFuture<'static, Result<Option<T>, Self::Error>> {
+        Box::pin(async move {
+            let data = Arc::clone(&self)
+                .read(move |state| state.data.get(&data_key(key)).map(|d| d.data.clone()))
+                .await?;
+
+            data.map(|data| {
+                self.serializer.deserialize(&data).map_err(FileStorageError::SerdeError)
+            })
+            .transpose()
+        })
+    }
+
+    fn set_data(
+        self: Arc<Self>,
+        key: DataKey,
+        value: T,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let data = self.serializer.serialize(&value).map_err(FileStorageError::SerdeError)?;
+            blocking(move || self.state.lock().unwrap().set_data(key, data)).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_data(self: Arc<Self>, key: DataKey) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            blocking(move || self.state.lock().unwrap().remove_data(key)).await?;
+            Ok(())
+        })
+    }
+}
+
+impl<S, K> DedupStorage for FileStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + 'static,
+{
+    type Error = FileStorageError<Infallible>;
+
+    fn mark_seen(
+        self: Arc<Self>,
+        bot_id: UserId,
+        update_id: UpdateId,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            let unseen =
+                blocking(move || self.state.lock().unwrap().mark_seen(bot_id.0, update_id.0))
+                    .await?;
+            Ok(unseen)
+        })
+    }
+}
+
+impl<S, K> JobStorage for FileStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + 'static,
+{
+    type Error = FileStorageError<Infallible>;
+
+    fn save_job(self: Arc<Self>, job: Job) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let id = job.id().to_owned();
+            let job = job.encode();
+            blocking(move || self.state.lock().unwrap().save_job(id, job)).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_job(self: Arc<Self>, id: String) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            blocking(move || self.state.lock().unwrap().remove_job(id)).await?;
+            Ok(())
+        })
+    }
+
+    fn load_jobs(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<Job>, Self::Error>> {
+        Box::pin(async move {
+            let jobs = self
+                .read(|state| state.jobs.values().map(|j| j.data.clone()).collect::<Vec<_>>())
+                .await?;
+            Ok(Job::decode_all(jobs))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatching::{data::DataOwner, dialogue::serializer::Json};
+
+    #[tokio::test]
+    async fn compaction() {
+        let dir =
+            std::env::temp_dir().join(format!("teloxide_file_storage_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("storage");
+
+        let storage = FileStorage::open(&path, Json).await.unwrap();
+        for i in 0..100 {
+            Arc::clone(&storage).update_dialogue(ChatId(1), i).await.unwrap();
+        }
+        Arc::clone(&storage).update_dialogue(ChatId(2), 42).await.unwrap();
+        <_ as Storage<i32>>::remove_dialogue(Arc::clone(&storage), ChatId(2)).await.unwrap();
+        Arc::clone(&storage)
+            .set_data(DataKey { name: "n", owner: DataOwner::Chat(ChatId(1)) }, 7)
+            .await
+            .unwrap();
+        let job = Job::after("reminder", Duration::from_secs(60)).with_id("job");
+        Arc::clone(&storage).save_job(job.clone()).await.unwrap();
+        assert!(Arc::clone(&storage).mark_seen(UserId(1), UpdateId(1)).await.unwrap());
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        storage.compact().await.unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < len / 10);
+        drop(storage);
+
+        // The state survives reopening.
+        let storage = FileStorage::open(&path, Json).await.unwrap();
+        let VersionedDialogue { dialogue, version } =
+            Arc::clone(&storage).get_dialogue_versioned(ChatId(1)).await.unwrap().unwrap();
+        assert_eq!((dialogue, version), (99, DialogueVersion(100)));
+        assert_eq!(
+            <_ as Storage<i32>>::get_dialogue(Arc::clone(&storage), ChatId(2)).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            Arc::clone(&storage)
+                .get_data(DataKey { name: "n", owner: DataOwner::Chat(ChatId(1)) })
+                .await
+                .unwrap(),
+            Some(7)
+        );
+        assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap(), [job]);
+        assert!(!storage.mark_seen(UserId(1), UpdateId(1)).await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
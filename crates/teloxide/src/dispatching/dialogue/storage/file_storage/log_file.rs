@@ -0,0 +1,445 @@
+//! The on-disk format of [`FileStorage`].
+//!
+//! A storage file starts with [`MAGIC`] and is followed by frames, each of
+//! which consists of the payload length (`u32`), the CRC-32 checksum of the
+//! payload (`u32`) and the payload itself, which is an encoded [`Record`]. All
+//! integers are little-endian.
+//!
+//! Frames are only ever appended to the file, so a crash can at most leave an
+//! incomplete frame at its end. Such a frame fails the checksum and is cut off
+//! when the file is opened next time.
+//!
+//! [`FileStorage`]: super::FileStorage
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// The first bytes of every storage file, the last one being the format
+/// version.
+const MAGIC: &[u8; 8] = b"TLXSTOR\x01";
+
+/// The length of a frame header, which consists of the payload length and
+/// checksum.
+const FRAME_HEADER_LEN: u64 = 8;
+
+const TAG_DIALOGUE: u8 = 1;
+const TAG_REMOVE_DIALOGUE: u8 = 2;
+const TAG_CLEAR_DIALOGUES: u8 = 3;
+const TAG_DATA: u8 = 4;
+const TAG_REMOVE_DATA: u8 = 5;
+const TAG_JOB: u8 = 6;
+const TAG_REMOVE_JOB: u8 = 7;
+const TAG_UPDATE: u8 = 8;
+
+/// A single change to the state of a storage.
+#[derive(Debug, PartialEq)]
+pub(super) enum Record {
+    Dialogue { key: String, version: i64, expires_at: Option<i64>, dialogue: Vec<u8> },
+    RemoveDialogue { key: String },
+    ClearDialogues,
+    Data { name: String, scope: String, id: i64, data: Vec<u8> },
+    RemoveData { name: String, scope: String, id: i64 },
+    Job { id: String, job: Vec<u8> },
+    RemoveJob { id: String },
+    Update { bot_id: u64, update_id: u32, seen_at: i64 },
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Dialogue { key, version, expires_at, dialogue } => {
+                buf.push(TAG_DIALOGUE);
+                encode_key(buf, key);
+                buf.extend_from_slice(&version.to_le_bytes());
+                match expires_at {
+                    Some(expires_at) => {
+                        buf.push(1);
+                        buf.extend_from_slice(&expires_at.to_le_bytes());
+                    }
+                    None => buf.push(0),
+                }
+                buf.extend_from_slice(dialogue);
+            }
+            Self::RemoveDialogue { key } => {
+                buf.push(TAG_REMOVE_DIALOGUE);
+                encode_key(buf, key);
+            }
+            Self::ClearDialogues => buf.push(TAG_CLEAR_DIALOGUES),
+            Self::Data { name, scope, id, data } => {
+                buf.push(TAG_DATA);
+                encode_data_key(buf, name, scope, *id);
+                buf.extend_from_slice(data);
+            }
+            Self::RemoveData { name, scope, id } => {
+                buf.push(TAG_REMOVE_DATA);
+                encode_data_key(buf, name, scope, *id);
+            }
+            Self::Job { id, job } => {
+                buf.push(TAG_JOB);
+                encode_key(buf, id);
+                buf.extend_from_slice(job);
+            }
+            Self::RemoveJob { id } => {
+                buf.push(TAG_REMOVE_JOB);
+                encode_key(buf, id);
+            }
+            Self::Update { bot_id, update_id, seen_at } => {
+                buf.push(TAG_UPDATE);
+                buf.extend_from_slice(&bot_id.to_le_bytes());
+                buf.extend_from_slice(&update_id.to_le_bytes());
+                buf.extend_from_slice(&seen_at.to_le_bytes());
+            }
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (&tag, mut rest) = payload.split_first()?;
+
+        let record = match tag {
+            TAG_DIALOGUE => {
+                let key = decode_key(&mut rest)?;
+                let version = take_i64(&mut rest)?;
+                let expires_at = match take(&mut rest, 1)? {
+                    [0] => None,
+                    [1] => Some(take_i64(&mut rest)?),
+                    _ => return None,
+                };
+                Self::Dialogue { key, version, expires_at, dialogue: rest.to_vec() }
+            }
+            TAG_REMOVE_DIALOGUE => Self::RemoveDialogue { key: decode_key(&mut rest)? },
+            TAG_CLEAR_DIALOGUES => Self::ClearDialogues,
+            TAG_DATA => {
+                let (name, scope, id) = decode_data_key(&mut rest)?;
+                Self::Data { name, scope, id, data: rest.to_vec() }
+            }
+            TAG_REMOVE_DATA => {
+                let (name, scope, id) = decode_data_key(&mut rest)?;
+                Self::RemoveData { name, scope, id }
+            }
+            TAG_JOB => {
+                let id = decode_key(&mut rest)?;
+                Self::Job { id, job: rest.to_vec() }
+            }
+            TAG_REMOVE_JOB => Self::RemoveJob { id: decode_key(&mut rest)? },
+            TAG_UPDATE => {
+                let bot_id = take_i64(&mut rest)? as u64;
+                let update_id = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap());
+                let seen_at = take_i64(&mut rest)?;
+                Self::Update { bot_id, update_id, seen_at }
+            }
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+fn encode_key(buf: &mut Vec<u8>, key: &str) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+}
+
+fn decode_key(rest: &mut &[u8]) -> Option<String> {
+    let len = u32::from_le_bytes(take(rest, 4)?.try_into().unwrap());
+    String::from_utf8(take(rest, len as usize)?.to_vec()).ok()
+}
+
+fn encode_data_key(buf: &mut Vec<u8>, name: &str, scope: &str, id: i64) {
+    encode_key(buf, name);
+    buf.push(scope.len() as u8);
+    buf.extend_from_slice(scope.as_bytes());
+    buf.extend_from_slice(&id.to_le_bytes());
+}
+
+fn decode_data_key(rest: &mut &[u8]) -> Option<(String, String, i64)> {
+    let name = decode_key(rest)?;
+    let len = take(rest, 1)?[0];
+    let scope = String::from_utf8(take(rest, len.into())?.to_vec()).ok()?;
+    Some((name, scope, take_i64(rest)?))
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    Some(head)
+}
+
+fn take_i64(rest: &mut &[u8]) -> Option<i64> {
+    take(rest, 8).map(|bytes| i64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Encodes `record` into a frame.
+fn frame(record: &Record) -> Vec<u8> {
+    let mut buf = vec![0; FRAME_HEADER_LEN as usize];
+    record.encode(&mut buf);
+
+    let payload = &buf[FRAME_HEADER_LEN as usize..];
+    let len = payload.len() as u32;
+    let checksum = crc32(payload);
+    buf[..4].copy_from_slice(&len.to_le_bytes());
+    buf[4..8].copy_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+/// An append-only storage file.
+pub(super) struct LogFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl LogFile {
+    /// Opens or creates the file at `path` and calls `apply` with every record
+    /// in it along with the size of its frame.
+    pub(super) fn open(path: &Path, mut apply: impl FnMut(Record, u64)) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let file_len = file.metadata()?.len();
+
+        // A file that is a prefix of the magic could only be left by a crash right
+        // after the file was created.
+        if file_len < MAGIC.len() as u64 {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+            if !MAGIC.starts_with(&contents) {
+                return Err(not_a_storage_file(path));
+            }
+
+            file.set_len(0)?;
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+            sync_parent_dir(path)?;
+            return Ok(Self { path: path.to_owned(), file, len: MAGIC.len() as u64 });
+        }
+
+        let mut reader = BufReader::new(&file);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(not_a_storage_file(path));
+        }
+
+        let mut len = MAGIC.len() as u64;
+        let mut payload = Vec::new();
+        loop {
+            let mut header = [0; FRAME_HEADER_LEN as usize];
+            if reader.read_exact(&mut header).is_err() {
+                break;
+            }
+            let payload_len = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+            let frame_len = FRAME_HEADER_LEN + u64::from(payload_len);
+            if len + frame_len > file_len {
+                break;
+            }
+            payload.resize(payload_len as usize, 0);
+            reader.read_exact(&mut payload)?;
+
+            if crc32(&payload) != checksum {
+                break;
+            }
+            let record = Record::decode(&payload).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: malformed record at offset {len}", path.display()),
+                )
+            })?;
+            apply(record, frame_len);
+            len += frame_len;
+        }
+        drop(reader);
+
+        if len < file_len {
+            log::warn!(
+                "{}: discarding {} bytes of an incomplete or corrupted record",
+                path.display(),
+                file_len - len
+            );
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        Ok(Self { path: path.to_owned(), file, len })
+    }
+
+    /// Returns the size of the file.
+    pub(super) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Durably appends `record` to the file and returns the size of its frame.
+    pub(super) fn append(&mut self, record: &Record) -> io::Result<u64> {
+        let frame = frame(record);
+
+        let res = self.file.write_all(&frame).and_then(|()| self.file.sync_data());
+        if let Err(err) = res {
+            // Do not leave a partially written frame, otherwise subsequent records
+            // would be lost on the next open.
+            let _ = self.file.set_len(self.len);
+            return Err(err);
+        }
+
+        self.len += frame.len() as u64;
+        Ok(frame.len() as u64)
+    }
+
+    /// Atomically replaces the contents of the file with `records`.
+    ///
+    /// The records are written to a temporary file, which is then renamed over
+    /// the original one, so a crash leaves either the old or the new file.
+    pub(super) fn rewrite(&mut self, records: impl IntoIterator<Item = Record>) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut len = MAGIC.len() as u64;
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(MAGIC)?;
+            for record in records {
+                let frame = frame(&record);
+                writer.write_all(&frame)?;
+                len += frame.len() as u64;
+            }
+            writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.len = len;
+        Ok(())
+    }
+}
+
+fn not_a_storage_file(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is not a teloxide storage file", path.display()),
+    )
+}
+
+/// Makes the creation or renaming of the file at `path` durable.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories cannot be opened as files on other platforms.
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Computes the CRC-32 (IEEE) checksum of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !data
+        .iter()
+        .fold(!0, |crc, &byte| TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn torn_write() {
+        let dir = std::env::temp_dir().join(format!("teloxide_log_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("storage");
+
+        let records = [
+            Record::Dialogue {
+                key: "1".to_owned(),
+                version: 1,
+                expires_at: None,
+                dialogue: b"A".to_vec(),
+            },
+            Record::Data {
+                name: "settings".to_owned(),
+                scope: "user".to_owned(),
+                id: 2,
+                data: b"B".to_vec(),
+            },
+            Record::Job { id: "job".to_owned(), job: b"{}".to_vec() },
+            Record::Update { bot_id: 5, update_id: 6, seen_at: 7 },
+            Record::Dialogue {
+                key: "3:thread:4".to_owned(),
+                version: 7,
+                expires_at: Some(42),
+                dialogue: b"C".to_vec(),
+            },
+        ];
+
+        let mut log = LogFile::open(&path, |_, _| panic!("a new file must be empty")).unwrap();
+        for record in &records {
+            log.append(record).unwrap();
+        }
+        let full_len = log.len();
+        drop(log);
+
+        // Simulate a crash in the middle of writing the last record.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 3).unwrap();
+        drop(file);
+
+        let mut replayed = Vec::new();
+        let log = LogFile::open(&path, |record, _| replayed.push(record)).unwrap();
+        assert_eq!(replayed, records[..4]);
+        assert!(log.len() < full_len - 3);
+        assert_eq!(fs::metadata(&path).unwrap().len(), log.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn short_file() {
+        let dir = std::env::temp_dir().join(format!("teloxide_log_short_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("storage");
+
+        // A crash right after the file was created.
+        fs::write(&path, &MAGIC[..3]).unwrap();
+        let log = LogFile::open(&path, |_, _| panic!("the file must be empty")).unwrap();
+        assert_eq!(log.len(), MAGIC.len() as u64);
+        assert_eq!(fs::read(&path).unwrap(), MAGIC);
+
+        // Some other short file.
+        fs::write(&path, b"{}\n").unwrap();
+        let err = LogFile::open(&path, |_, _| {}).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), b"{}\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
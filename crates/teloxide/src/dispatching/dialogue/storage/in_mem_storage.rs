@@ -1,6 +1,17 @@
-use super::Storage;
-use futures::future::BoxFuture;
-use std::{collections::HashMap, sync::Arc};
+use super::{DialogueVersion, EnumerableStorage, Storage, VersionedDialogue, VersionedStorage};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt},
+};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use teloxide_core::types::ChatId;
 use thiserror::Error;
 use tokio::sync::Mutex;
@@ -11,37 +22,83 @@ pub enum InMemStorageError {
     /// Returned from [`InMemStorage::remove_dialogue`].
     #[error("row not found")]
     DialogueNotFound,
+
+    /// Returned from [`InMemStorage::update_dialogue_versioned`].
+    #[error("dialogue version conflict")]
+    VersionConflict,
 }
 
 /// A dialogue storage based on [`std::collections::HashMap`].
 ///
+/// Dialogues can be indexed by any hashable key, see [`Storage`].
+///
+/// Expired dialogues are evicted on access. To evict all of them at once, use
+/// [`InMemStorage::remove_expired_dialogues`].
+///
 /// ## Note
 /// All your dialogues will be lost after you restart your bot. If you need to
 /// store them somewhere on a drive, you should use e.g.
 /// [`super::SqliteStorage`] or implement your own.
 #[derive(Debug)]
-pub struct InMemStorage<D> {
-    map: Mutex<HashMap<ChatId, D>>,
+pub struct InMemStorage<D, K = ChatId> {
+    map: Mutex<HashMap<K, Entry<D>>>,
+    // Versions are storage-wide, so that a dialogue that was removed or has
+    // expired and then created again doesn't reuse an old version.
+    last_version: AtomicI64,
+}
+
+#[derive(Debug)]
+struct Entry<D> {
+    dialogue: D,
+    expires_at: Option<Instant>,
+    version: i64,
+}
+
+impl<D> Entry<D> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
-impl<S> InMemStorage<S> {
+impl<D, K> InMemStorage<D, K>
+where
+    K: Eq + Hash,
+{
     #[must_use]
     pub fn new() -> Arc<Self> {
-        Arc::new(Self { map: Mutex::new(HashMap::new()) })
+        Arc::new(Self { map: Mutex::new(HashMap::new()), last_version: AtomicI64::new(0) })
+    }
+
+    /// Inserts `dialogue` into `map`, the locked map of this storage, with a
+    /// new version and returns the version.
+    fn insert(
+        &self,
+        map: &mut HashMap<K, Entry<D>>,
+        key: K,
+        dialogue: D,
+        expires_at: Option<Instant>,
+    ) -> i64 {
+        let version = self.last_version.fetch_add(1, Ordering::Relaxed) + 1;
+        map.insert(key, Entry { dialogue, expires_at, version });
+        version
+    }
+
+    /// Removes all dialogues whose time-to-live has passed.
+    pub async fn remove_expired_dialogues(&self) {
+        let now = Instant::now();
+        self.map.lock().await.retain(|_, entry| !entry.is_expired(now));
     }
 }
 
-impl<D> Storage<D> for InMemStorage<D>
+impl<D, K> Storage<D, K> for InMemStorage<D, K>
 where
     D: Clone,
     D: Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
 {
     type Error = InMemStorageError;
 
-    fn remove_dialogue(
-        self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
@@ -49,29 +106,144 @@ where
             self.map
                 .lock()
                 .await
-                .remove(&chat_id)
+                .remove(&key)
+                .filter(|entry| !entry.is_expired(Instant::now()))
                 .map_or(Err(InMemStorageError::DialogueNotFound), |_| Ok(()))
         })
     }
 
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: ChatId,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
         Box::pin(async move {
-            self.map.lock().await.insert(chat_id, dialogue);
+            self.insert(&mut *self.map.lock().await, key, dialogue, None);
             Ok(())
         })
     }
 
-    fn get_dialogue(
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let expires_at = Instant::now().checked_add(ttl);
+            self.insert(&mut *self.map.lock().await, key, dialogue, expires_at);
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            let mut map = self.map.lock().await;
+
+            match map.get(&key) {
+                Some(entry) if entry.is_expired(Instant::now()) => {
+                    map.remove(&key);
+                    Ok(None)
+                }
+                entry => Ok(entry.map(|entry| entry.dialogue.clone())),
+            }
+        })
+    }
+}
+
+impl<D, K> VersionedStorage<D, K> for InMemStorage<D, K>
+where
+    D: Clone,
+    D: Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
+{
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        Box::pin(async move {
+            let map = self.map.lock().await;
+
+            Ok(map.get(&key).filter(|entry| !entry.is_expired(Instant::now())).map(|entry| {
+                VersionedDialogue {
+                    dialogue: entry.dialogue.clone(),
+                    version: DialogueVersion(entry.version),
+                }
+            }))
+        })
+    }
+
+    fn update_dialogue_versioned(
         self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
-        Box::pin(async move { Ok(self.map.lock().await.get(&chat_id).map(ToOwned::to_owned)) })
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let mut map = self.map.lock().await;
+
+            let current = map
+                .get(&key)
+                .filter(|entry| !entry.is_expired(Instant::now()))
+                .map(|entry| DialogueVersion(entry.version));
+            if current != expected {
+                return Err(InMemStorageError::VersionConflict);
+            }
+
+            Ok(DialogueVersion(self.insert(&mut map, key, dialogue, None)))
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        matches!(error, InMemStorageError::VersionConflict)
+    }
+}
+
+impl<D, K> EnumerableStorage<D, K> for InMemStorage<D, K>
+where
+    D: Clone,
+    D: Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        Box::pin(
+            stream::once(async move {
+                let now = Instant::now();
+                let dialogues: Vec<_> = self
+                    .map
+                    .lock()
+                    .await
+                    .iter()
+                    .filter(|(_, entry)| !entry.is_expired(now))
+                    .map(|(key, entry)| Ok((key.clone(), entry.dialogue.clone())))
+                    .collect();
+
+                stream::iter(dialogues)
+            })
+            .flatten(),
+        )
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            Ok(self.map.lock().await.values().filter(|entry| !entry.is_expired(now)).count() as u64)
+        })
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.map.lock().await.clear();
+            Ok(())
+        })
     }
 }
@@ -0,0 +1 @@
+DELETE FROM teloxide_dialogues
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS teloxide_data (
+    name VARCHAR(255) NOT NULL,
+    scope VARCHAR(16) NOT NULL,
+    id BIGINT NOT NULL,
+    data LONGBLOB NOT NULL,
+    PRIMARY KEY (name, scope, id)
+)
\ No newline at end of file
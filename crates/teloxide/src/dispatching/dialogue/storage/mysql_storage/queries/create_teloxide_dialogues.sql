@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS teloxide_dialogues (
+    dialogue_key VARCHAR(255) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin PRIMARY KEY,
+    dialogue LONGBLOB NOT NULL,
+    expires_at BIGINT,
+    version BIGINT NOT NULL DEFAULT 1
+)
\ No newline at end of file
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS teloxide_jobs (
+    id VARCHAR(255) NOT NULL PRIMARY KEY,
+    job LONGBLOB NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS teloxide_updates (
+    bot_id BIGINT NOT NULL,
+    update_id BIGINT NOT NULL,
+    seen_at BIGINT NOT NULL,
+    PRIMARY KEY (bot_id, update_id),
+    INDEX teloxide_updates_seen_at (seen_at)
+)
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT data FROM teloxide_data WHERE name = ? AND scope = ? AND id = ?
\ No newline at end of file
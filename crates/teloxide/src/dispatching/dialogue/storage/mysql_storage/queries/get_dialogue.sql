@@ -0,0 +1 @@
+SELECT dialogue, expires_at, version FROM teloxide_dialogues WHERE dialogue_key = ?
\ No newline at end of file
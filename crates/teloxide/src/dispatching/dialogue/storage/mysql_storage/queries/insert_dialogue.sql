@@ -0,0 +1 @@
+INSERT INTO teloxide_dialogues (dialogue_key, dialogue) VALUES (?, ?)
\ No newline at end of file
@@ -0,0 +1 @@
+INSERT IGNORE INTO teloxide_updates (bot_id, update_id, seen_at) VALUES (?, ?, ?)
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT dialogue_key, dialogue FROM teloxide_dialogues
+WHERE (? IS NULL OR dialogue_key > ?) AND (expires_at IS NULL OR expires_at > ?)
+ORDER BY dialogue_key LIMIT ?
\ No newline at end of file
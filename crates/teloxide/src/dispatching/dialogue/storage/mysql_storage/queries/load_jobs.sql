@@ -0,0 +1 @@
+SELECT job FROM teloxide_jobs
\ No newline at end of file
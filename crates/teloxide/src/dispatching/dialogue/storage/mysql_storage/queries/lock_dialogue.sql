@@ -0,0 +1 @@
+SELECT expires_at, version FROM teloxide_dialogues WHERE dialogue_key = ? FOR UPDATE
\ No newline at end of file
@@ -0,0 +1 @@
+DELETE FROM teloxide_dialogues WHERE dialogue_key = ? AND (expires_at IS NULL OR expires_at > ?)
\ No newline at end of file
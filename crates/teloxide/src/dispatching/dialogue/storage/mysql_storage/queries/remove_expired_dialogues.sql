@@ -0,0 +1 @@
+DELETE FROM teloxide_dialogues WHERE expires_at <= ?
\ No newline at end of file
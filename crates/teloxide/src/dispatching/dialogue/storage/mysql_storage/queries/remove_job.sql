@@ -0,0 +1 @@
+DELETE FROM teloxide_jobs WHERE id = ?
\ No newline at end of file
@@ -0,0 +1 @@
+DELETE FROM teloxide_updates WHERE seen_at < ?
\ No newline at end of file
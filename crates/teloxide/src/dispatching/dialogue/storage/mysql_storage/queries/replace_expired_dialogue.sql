@@ -0,0 +1,2 @@
+UPDATE teloxide_dialogues SET dialogue = ?, expires_at = NULL, version = version + 1
+WHERE dialogue_key = ?
\ No newline at end of file
@@ -0,0 +1,2 @@
+INSERT INTO teloxide_jobs (id, job) VALUES (?, ?)
+ON DUPLICATE KEY UPDATE job = VALUES(job)
\ No newline at end of file
@@ -0,0 +1,2 @@
+INSERT INTO teloxide_data (name, scope, id, data) VALUES (?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE data = VALUES(data)
\ No newline at end of file
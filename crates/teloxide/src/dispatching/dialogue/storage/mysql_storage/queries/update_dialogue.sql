@@ -0,0 +1,3 @@
+INSERT INTO teloxide_dialogues (dialogue_key, dialogue, expires_at) VALUES (?, ?, ?)
+ON DUPLICATE KEY UPDATE
+dialogue = VALUES(dialogue), expires_at = VALUES(expires_at), version = version + 1
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE teloxide_dialogues SET dialogue = ?, expires_at = NULL, version = version + 1
+WHERE dialogue_key = ? AND version = ? AND (expires_at IS NULL OR expires_at > ?)
\ No newline at end of file
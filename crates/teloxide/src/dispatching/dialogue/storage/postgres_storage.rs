@@ -1,17 +1,32 @@
 use std::{
     convert::Infallible,
     fmt::{Debug, Display},
+    marker::PhantomData,
     str,
     sync::Arc,
+    time::Duration,
 };
 
-use futures::future::BoxFuture;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
 use serde::{de::DeserializeOwned, Serialize};
 use sqlx::postgres::{PgPool, PgPoolOptions};
-use teloxide_core::types::ChatId;
+use teloxide_core::types::{ChatId, UpdateId, UserId};
 use thiserror::Error;
+use tokio::sync::OnceCell;
+
+use crate::dispatching::{
+    data::{DataKey, DataStorage},
+    dedup::{DedupStorage, PERSISTENT_WINDOW_MILLIS, PRUNE_INTERVAL},
+    jobs::{Job, JobStorage},
+};
 
-use super::{serializer::Serializer, Storage};
+use super::{
+    serializer::Serializer, unix_millis, DialogueVersion, EnumerableStorage, Storage, StorageKey,
+    VersionedDialogue, VersionedStorage,
+};
 
 /// An error returned from [`PostgresStorage`].
 #[derive(Debug, Error)]
@@ -28,17 +43,42 @@ where
     // TODO maybe add chat_id for the sake of completeness?
     #[error("row not found")]
     DialogueNotFound,
+
+    /// Returned from [`PostgresStorage::update_dialogue_versioned`].
+    #[error("dialogue version conflict")]
+    VersionConflict,
 }
 
 /// A persistent dialogue storage based on [PostgreSQL](https://www.postgresql.org/)
-pub struct PostgresStorage<S> {
+///
+/// Besides dialogues, it can keep [per-user and per-chat data], [seen update
+/// IDs] and [scheduled jobs] in separate tables, which are created when
+/// they're first used.
+///
+/// Dialogues are stored under their keys encoded via [`StorageKey`] in the
+/// `dialogue_key` column, so dialogues indexed by [`ChatId`] are stored under
+/// `<chat_id>` keys. Tables created by older versions of teloxide, which were
+/// indexed by `chat_id`, are migrated when the storage is opened.
+///
+/// Expired dialogues are evicted on access. To evict all of them at once, use
+/// [`PostgresStorage::remove_expired_dialogues`].
+///
+/// [per-user and per-chat data]: crate::dispatching::data
+/// [seen update IDs]: crate::dispatching::dedup
+/// [scheduled jobs]: crate::dispatching::jobs
+pub struct PostgresStorage<S, K = ChatId> {
     pool: PgPool,
     serializer: S,
+    data_table: OnceCell<()>,
+    updates_table: OnceCell<()>,
+    jobs_table: OnceCell<()>,
+    _key: PhantomData<fn(K) -> K>,
 }
 
-impl<S> PostgresStorage<S> {
-    /// Opens a connection pool to the [Postgres](https://www.postgresql.org/) database and creates the table
-    /// for storing dialogues.
+impl<S, K> PostgresStorage<S, K> {
+    /// Opens a connection pool to the [Postgres](https://www.postgresql.org/) database and creates the tables
+    /// for storing dialogues. The tables for data, update IDs and jobs are
+    /// created when they're first used.
     ///
     /// Parameters:
     /// - database_url: full url to the postgres database, for example
@@ -62,95 +102,508 @@ impl<S> PostgresStorage<S> {
         sqlx::query(include_str!("postgres_storage/queries/create_teloxide_dialogues.sql"))
             .execute(&pool)
             .await?;
+        // Tables created by older versions of teloxide lack some of the columns.
+        sqlx::query(include_str!("postgres_storage/queries/add_expires_at_column.sql"))
+            .execute(&pool)
+            .await?;
+        sqlx::query(include_str!("postgres_storage/queries/add_version_column.sql"))
+            .execute(&pool)
+            .await?;
+        // They were also indexed by chat IDs, which are encoded as is.
+        sqlx::query(include_str!("postgres_storage/queries/migrate_chat_id_column.sql"))
+            .execute(&pool)
+            .await?;
 
-        Ok(Arc::new(Self { pool, serializer }))
+        Ok(Arc::new(Self {
+            pool,
+            serializer,
+            data_table: OnceCell::new(),
+            updates_table: OnceCell::new(),
+            jobs_table: OnceCell::new(),
+            _key: PhantomData,
+        }))
+    }
+
+    /// Creates the `teloxide_data` table on first use, so that it doesn't
+    /// appear in databases which don't store data.
+    async fn create_data_table(&self) -> Result<(), sqlx::Error> {
+        self.data_table
+            .get_or_try_init(|| async {
+                sqlx::query(include_str!("postgres_storage/queries/create_teloxide_data.sql"))
+                    .execute(&self.pool)
+                    .await
+                    .map(drop)
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Creates the `teloxide_updates` table on first use, so that it doesn't
+    /// appear in databases which don't deduplicate updates.
+    async fn create_updates_table(&self) -> Result<(), sqlx::Error> {
+        self.updates_table
+            .get_or_try_init(|| async {
+                sqlx::query(include_str!("postgres_storage/queries/create_teloxide_updates.sql"))
+                    .execute(&self.pool)
+                    .await?;
+                sqlx::query(include_str!(
+                    "postgres_storage/queries/create_teloxide_updates_index.sql"
+                ))
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Creates the `teloxide_jobs` table on first use, so that it doesn't
+    /// appear in databases which don't store jobs.
+    async fn create_jobs_table(&self) -> Result<(), sqlx::Error> {
+        self.jobs_table
+            .get_or_try_init(|| async {
+                sqlx::query(include_str!("postgres_storage/queries/create_teloxide_jobs.sql"))
+                    .execute(&self.pool)
+                    .await
+                    .map(drop)
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Removes all dialogues whose time-to-live has passed.
+    pub async fn remove_expired_dialogues(&self) -> Result<(), PostgresStorageError<Infallible>> {
+        sqlx::query(include_str!("postgres_storage/queries/remove_expired_dialogues.sql"))
+            .bind(unix_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 
     async fn get_dialogue(
         self: Arc<Self>,
-        ChatId(chat_id): ChatId,
-    ) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        key: String,
+    ) -> Result<Option<(Vec<u8>, i64)>, sqlx::Error> {
         #[derive(sqlx::FromRow)]
         struct DialogueDbRow {
             dialogue: Vec<u8>,
+            expires_at: Option<i64>,
+            version: i64,
         }
 
-        let bytes = sqlx::query_as::<_, DialogueDbRow>(include_str!(
+        let row = sqlx::query_as::<_, DialogueDbRow>(include_str!(
             "postgres_storage/queries/get_dialogue.sql"
         ))
-        .bind(chat_id)
+        .bind(&key)
         .fetch_optional(&self.pool)
-        .await?
-        .map(|r| r.dialogue);
+        .await?;
 
-        Ok(bytes)
+        match row {
+            Some(DialogueDbRow { expires_at, .. }) if is_expired(expires_at) => {
+                // Do not touch the row if it was concurrently updated with a new TTL.
+                sqlx::query(include_str!("postgres_storage/queries/remove_expired_dialogue.sql"))
+                    .bind(key)
+                    .bind(unix_millis())
+                    .execute(&self.pool)
+                    .await?;
+                Ok(None)
+            }
+            row => Ok(row.map(|r| (r.dialogue, r.version))),
+        }
     }
+
+    async fn upsert_dialogue(
+        &self,
+        key: String,
+        dialogue: Vec<u8>,
+        expires_at: Option<i64>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(include_str!("postgres_storage/queries/update_dialogue.sql"))
+            .bind(key)
+            .bind(dialogue)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+fn is_expired(expires_at: Option<i64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= unix_millis())
 }
 
 // FIXME: these methods' bodies are almostly the same as SqliteStorage ones
 // (except actual queries) Maybe combine them somehow?
 
-impl<S, D> Storage<D> for PostgresStorage<S>
+impl<S, D, K> Storage<D, K> for PostgresStorage<S, K>
 where
+    K: StorageKey + Send + 'static,
     S: Send + Sync + Serializer<D> + 'static,
     D: Send + Serialize + DeserializeOwned + 'static,
     <S as Serializer<D>>::Error: Debug + Display,
 {
     type Error = PostgresStorageError<<S as Serializer<D>>::Error>;
 
-    fn remove_dialogue(
-        self: Arc<Self>,
-        ChatId(chat_id): ChatId,
-    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
+        let key = key.encode();
         Box::pin(async move {
-            let deleted_rows_count =
-                sqlx::query(include_str!("postgres_storage/queries/remove_dialogue.sql"))
-                    .bind(chat_id)
-                    .execute(&self.pool)
-                    .await?
-                    .rows_affected();
+            let deleted: Option<(Option<i64>,)> =
+                sqlx::query_as(include_str!("postgres_storage/queries/remove_dialogue.sql"))
+                    .bind(key)
+                    .fetch_optional(&self.pool)
+                    .await?;
 
-            if deleted_rows_count == 0 {
-                return Err(PostgresStorageError::DialogueNotFound);
+            match deleted {
+                Some((expires_at,)) if !is_expired(expires_at) => Ok(()),
+                _ => Err(PostgresStorageError::DialogueNotFound),
             }
+        })
+    }
 
+    fn update_dialogue(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let key = key.encode();
+        Box::pin(async move {
+            let d =
+                self.serializer.serialize(&dialogue).map_err(PostgresStorageError::SerdeError)?;
+            self.upsert_dialogue(key, d, None).await?;
             Ok(())
         })
     }
 
-    fn update_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        ChatId(chat_id): ChatId,
+        key: K,
         dialogue: D,
+        ttl: Duration,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
+        let key = key.encode();
         Box::pin(async move {
             let d =
                 self.serializer.serialize(&dialogue).map_err(PostgresStorageError::SerdeError)?;
-            sqlx::query(include_str!("postgres_storage/queries/update_dialogue.sql"))
-                .bind(chat_id)
-                .bind(d)
-                .execute(&self.pool)
-                .await?;
+            let expires_at = unix_millis().saturating_add(ttl.as_millis() as i64);
+            self.upsert_dialogue(key, d, Some(expires_at)).await?;
             Ok(())
         })
     }
 
-    fn get_dialogue(
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            self.clone()
+                .get_dialogue(key)
+                .await?
+                .map(|(d, _)| {
+                    self.serializer.deserialize(&d).map_err(PostgresStorageError::SerdeError)
+                })
+                .transpose()
+        })
+    }
+}
+
+impl<S, D, K> VersionedStorage<D, K> for PostgresStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn get_dialogue_versioned(
         self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        let key = key.encode();
         Box::pin(async move {
             self.clone()
-                .get_dialogue(chat_id)
+                .get_dialogue(key)
                 .await?
-                .map(|d| self.serializer.deserialize(&d).map_err(PostgresStorageError::SerdeError))
+                .map(|(d, version)| {
+                    let d = self
+                        .serializer
+                        .deserialize(&d)
+                        .map_err(PostgresStorageError::SerdeError)?;
+                    Ok(VersionedDialogue { dialogue: d, version: DialogueVersion(version) })
+                })
                 .transpose()
         })
     }
+
+    fn update_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let key = key.encode();
+        Box::pin(async move {
+            let d =
+                self.serializer.serialize(&dialogue).map_err(PostgresStorageError::SerdeError)?;
+
+            let version: Option<(i64,)> = match expected {
+                // An expired dialogue is considered absent, so it can be overwritten.
+                None => {
+                    sqlx::query_as(include_str!(
+                        "postgres_storage/queries/insert_dialogue_versioned.sql"
+                    ))
+                    .bind(key)
+                    .bind(d)
+                    .bind(unix_millis())
+                    .fetch_optional(&self.pool)
+                    .await?
+                }
+                Some(DialogueVersion(expected)) => {
+                    sqlx::query_as(include_str!(
+                        "postgres_storage/queries/update_dialogue_versioned.sql"
+                    ))
+                    .bind(d)
+                    .bind(key)
+                    .bind(expected)
+                    .bind(unix_millis())
+                    .fetch_optional(&self.pool)
+                    .await?
+                }
+            };
+
+            version
+                .map(|(version,)| DialogueVersion(version))
+                .ok_or(PostgresStorageError::VersionConflict)
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        matches!(error, PostgresStorageError::VersionConflict)
+    }
+}
+
+/// The number of dialogues fetched at once by
+/// [`PostgresStorage::list_dialogues`].
+const LIST_PAGE_SIZE: i64 = 100;
+
+/// Every row is considered to be a dialogue if its key can be decoded as `K`,
+/// so dialogues with keys of different types shouldn't share a database.
+impl<S, D, K> EnumerableStorage<D, K> for PostgresStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        let pool = self.pool.clone();
+
+        // Dialogues are fetched page by page, ordered by keys.
+        let pages = stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+            let pool = pool.clone();
+            async move {
+                let Some(after) = after else { return Ok(None) };
+
+                let rows: Vec<(String, Vec<u8>)> =
+                    sqlx::query_as(include_str!("postgres_storage/queries/list_dialogues.sql"))
+                        .bind(after)
+                        .bind(unix_millis())
+                        .bind(LIST_PAGE_SIZE)
+                        .fetch_all(&pool)
+                        .await?;
+
+                let next = match rows.last() {
+                    Some((key, _)) if rows.len() as i64 == LIST_PAGE_SIZE => {
+                        Some(Some(key.clone()))
+                    }
+                    _ => None,
+                };
+                Ok::<_, Self::Error>(Some((rows, next)))
+            }
+        });
+
+        pages
+            .map_ok(move |rows| {
+                let this = Arc::clone(&self);
+                let rows = rows.into_iter().filter_map(|(key, d)| Some((K::decode(&key)?, d)));
+                stream::iter(rows).map(move |(key, d)| {
+                    let d = this
+                        .serializer
+                        .deserialize(&d)
+                        .map_err(PostgresStorageError::SerdeError)?;
+                    Ok((key, d))
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        Box::pin(async move {
+            let (count,): (i64,) =
+                sqlx::query_as(include_str!("postgres_storage/queries/count_dialogues.sql"))
+                    .bind(unix_millis())
+                    .fetch_one(&self.pool)
+                    .await?;
+            Ok(count as u64)
+        })
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            sqlx::query(include_str!("postgres_storage/queries/clear_dialogues.sql"))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+impl<S, K, T> DataStorage<T> for PostgresStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + Serializer<T> + 'static,
+    T: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<T>>::Error: Debug + Display,
+{
+    type Error = PostgresStorageError<<S as Serializer<T>>::Error>;
+
+    fn get_data(
+        self: Arc<Self>,
+        key: DataKey,
+    ) -> BoxFuture<'static, Result<Option<T>, Self::Error>> {
+        Box::pin(async move {
+            self.create_data_table().await?;
+            let data: Option<(Vec<u8>,)> =
+                sqlx::query_as(include_str!("postgres_storage/queries/get_data.sql"))
+                    .bind(key.name)
+                    .bind(key.scope())
+                    .bind(key.id())
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+            data.map(|(data,)| {
+                self.serializer.deserialize(&data).map_err(PostgresStorageError::SerdeError)
+            })
+            .transpose()
+        })
+    }
+
+    fn set_data(
+        self: Arc<Self>,
+        key: DataKey,
+        value: T,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_data_table().await?;
+            let data =
+                self.serializer.serialize(&value).map_err(PostgresStorageError::SerdeError)?;
+            sqlx::query(include_str!("postgres_storage/queries/set_data.sql"))
+                .bind(key.name)
+                .bind(key.scope())
+                .bind(key.id())
+                .bind(data)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn remove_data(self: Arc<Self>, key: DataKey) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_data_table().await?;
+            sqlx::query(include_str!("postgres_storage/queries/remove_data.sql"))
+                .bind(key.name)
+                .bind(key.scope())
+                .bind(key.id())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+impl<S, K> DedupStorage for PostgresStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + 'static,
+{
+    type Error = PostgresStorageError<Infallible>;
+
+    fn mark_seen(
+        self: Arc<Self>,
+        bot_id: UserId,
+        update_id: UpdateId,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            self.create_updates_table().await?;
+            let now = unix_millis();
+            if update_id.0 % PRUNE_INTERVAL == 0 {
+                sqlx::query(include_str!("postgres_storage/queries/remove_old_update_ids.sql"))
+                    .bind(now - PERSISTENT_WINDOW_MILLIS)
+                    .execute(&self.pool)
+                    .await?;
+            }
+
+            let inserted =
+                sqlx::query(include_str!("postgres_storage/queries/insert_update_id.sql"))
+                    .bind(bot_id.0 as i64)
+                    .bind(i64::from(update_id.0))
+                    .bind(now)
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+            Ok(inserted == 1)
+        })
+    }
+}
+
+impl<S, K> JobStorage for PostgresStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + 'static,
+{
+    type Error = PostgresStorageError<Infallible>;
+
+    fn save_job(self: Arc<Self>, job: Job) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_jobs_table().await?;
+            sqlx::query(include_str!("postgres_storage/queries/save_job.sql"))
+                .bind(job.id())
+                .bind(job.encode())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn remove_job(self: Arc<Self>, id: String) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_jobs_table().await?;
+            sqlx::query(include_str!("postgres_storage/queries/remove_job.sql"))
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn load_jobs(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<Job>, Self::Error>> {
+        Box::pin(async move {
+            self.create_jobs_table().await?;
+            let jobs: Vec<(Vec<u8>,)> =
+                sqlx::query_as(include_str!("postgres_storage/queries/load_jobs.sql"))
+                    .fetch_all(&self.pool)
+                    .await?;
+            Ok(Job::decode_all(jobs.into_iter().map(|(job,)| job)))
+        })
+    }
 }
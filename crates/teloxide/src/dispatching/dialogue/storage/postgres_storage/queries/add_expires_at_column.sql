@@ -0,0 +1 @@
+ALTER TABLE teloxide_dialogues ADD COLUMN IF NOT EXISTS expires_at BIGINT
\ No newline at end of file
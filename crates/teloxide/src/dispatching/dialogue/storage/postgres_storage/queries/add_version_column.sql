@@ -0,0 +1 @@
+ALTER TABLE teloxide_dialogues ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1
\ No newline at end of file
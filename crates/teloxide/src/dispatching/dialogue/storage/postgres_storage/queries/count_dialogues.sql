@@ -0,0 +1 @@
+SELECT COUNT(*) FROM teloxide_dialogues WHERE expires_at IS NULL OR expires_at > $1
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS teloxide_data (
+    name TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    id BIGINT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (name, scope, id)
+)
\ No newline at end of file
@@ -1,4 +1,6 @@
 CREATE TABLE IF NOT EXISTS teloxide_dialogues (
-    chat_id BIGINT PRIMARY KEY,
-    dialogue BYTEA NOT NULL
+    dialogue_key TEXT PRIMARY KEY,
+    dialogue BYTEA NOT NULL,
+    expires_at BIGINT,
+    version BIGINT NOT NULL DEFAULT 1
 )
\ No newline at end of file
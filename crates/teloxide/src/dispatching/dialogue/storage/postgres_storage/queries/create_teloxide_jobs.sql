@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS teloxide_jobs (
+    id TEXT PRIMARY KEY,
+    job BYTEA NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS teloxide_updates (
+    bot_id BIGINT NOT NULL,
+    update_id BIGINT NOT NULL,
+    seen_at BIGINT NOT NULL,
+    PRIMARY KEY (bot_id, update_id)
+)
\ No newline at end of file
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS teloxide_updates_seen_at ON teloxide_updates (seen_at)
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT data FROM teloxide_data WHERE name = $1 AND scope = $2 AND id = $3
\ No newline at end of file
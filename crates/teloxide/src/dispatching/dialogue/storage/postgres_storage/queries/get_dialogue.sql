@@ -1 +1 @@
-SELECT dialogue FROM teloxide_dialogues WHERE chat_id = $1
\ No newline at end of file
+SELECT dialogue, expires_at, version FROM teloxide_dialogues WHERE dialogue_key = $1
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO teloxide_dialogues (dialogue_key, dialogue) VALUES ($1, $2)
+ON CONFLICT(dialogue_key) DO UPDATE
+SET dialogue=excluded.dialogue, expires_at=NULL, version=teloxide_dialogues.version + 1
+WHERE teloxide_dialogues.expires_at <= $3
+RETURNING version
\ No newline at end of file
@@ -0,0 +1,2 @@
+INSERT INTO teloxide_updates (bot_id, update_id, seen_at) VALUES ($1, $2, $3)
+ON CONFLICT(bot_id, update_id) DO NOTHING
\ No newline at end of file
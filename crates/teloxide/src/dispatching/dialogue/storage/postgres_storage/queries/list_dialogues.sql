@@ -0,0 +1,3 @@
+SELECT dialogue_key, dialogue FROM teloxide_dialogues
+WHERE ($1::TEXT IS NULL OR dialogue_key > $1) AND (expires_at IS NULL OR expires_at > $2)
+ORDER BY dialogue_key LIMIT $3
\ No newline at end of file
@@ -0,0 +1,13 @@
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_schema = current_schema()
+            AND table_name = 'teloxide_dialogues'
+            AND column_name = 'chat_id'
+    ) THEN
+        ALTER TABLE teloxide_dialogues ALTER COLUMN chat_id TYPE TEXT USING chat_id::TEXT;
+        ALTER TABLE teloxide_dialogues RENAME COLUMN chat_id TO dialogue_key;
+    END IF;
+END
+$$
\ No newline at end of file
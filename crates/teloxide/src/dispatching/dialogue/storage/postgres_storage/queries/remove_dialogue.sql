@@ -1 +1 @@
-DELETE FROM teloxide_dialogues WHERE chat_id = $1
\ No newline at end of file
+DELETE FROM teloxide_dialogues WHERE dialogue_key = $1 RETURNING expires_at
\ No newline at end of file
@@ -0,0 +1 @@
+DELETE FROM teloxide_dialogues WHERE dialogue_key = $1 AND expires_at <= $2
\ No newline at end of file
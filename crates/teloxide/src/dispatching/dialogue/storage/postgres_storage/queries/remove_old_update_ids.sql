@@ -0,0 +1 @@
+DELETE FROM teloxide_updates WHERE seen_at < $1
\ No newline at end of file
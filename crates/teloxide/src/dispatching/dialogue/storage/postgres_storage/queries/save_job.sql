@@ -0,0 +1,2 @@
+INSERT INTO teloxide_jobs (id, job) VALUES ($1, $2)
+ON CONFLICT(id) DO UPDATE SET job=excluded.job
\ No newline at end of file
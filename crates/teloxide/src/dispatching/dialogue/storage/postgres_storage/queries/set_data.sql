@@ -0,0 +1,2 @@
+INSERT INTO teloxide_data (name, scope, id, data) VALUES ($1, $2, $3, $4)
+ON CONFLICT(name, scope, id) DO UPDATE SET data=excluded.data
\ No newline at end of file
@@ -1,2 +1,3 @@
-INSERT INTO teloxide_dialogues VALUES ($1, $2)
-ON CONFLICT(chat_id) DO UPDATE SET dialogue=excluded.dialogue
\ No newline at end of file
+INSERT INTO teloxide_dialogues (dialogue_key, dialogue, expires_at) VALUES ($1, $2, $3)
+ON CONFLICT(dialogue_key) DO UPDATE
+SET dialogue=excluded.dialogue, expires_at=excluded.expires_at, version=teloxide_dialogues.version + 1
\ No newline at end of file
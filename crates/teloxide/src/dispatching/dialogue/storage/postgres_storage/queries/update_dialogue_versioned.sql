@@ -0,0 +1,3 @@
+UPDATE teloxide_dialogues SET dialogue = $1, expires_at = NULL, version = version + 1
+WHERE dialogue_key = $2 AND version = $3 AND (expires_at IS NULL OR expires_at > $4)
+RETURNING version
\ No newline at end of file
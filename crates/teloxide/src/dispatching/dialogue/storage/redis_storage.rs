@@ -1,16 +1,31 @@
-use super::{serializer::Serializer, Storage};
+use super::{
+    serializer::Serializer, DialogueVersion, EnumerableStorage, Storage, StorageKey,
+    VersionedDialogue, VersionedStorage,
+};
 use deadpool_redis::{redis, CreatePoolError, PoolError, Runtime};
-use futures::future::BoxFuture;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
 use redis::AsyncCommands;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
+    collections::HashSet,
     convert::Infallible,
     fmt::{Debug, Display},
+    marker::PhantomData,
     sync::Arc,
+    time::Duration,
 };
-use teloxide_core::types::ChatId;
+use teloxide_core::types::{ChatId, UpdateId, UserId};
 use thiserror::Error;
 
+use crate::dispatching::{
+    data::{DataKey, DataStorage},
+    dedup::{DedupStorage, PERSISTENT_WINDOW_MILLIS},
+    jobs::{Job, JobStorage},
+};
+
 /// An error returned from [`RedisStorage`].
 #[derive(Debug, Error)]
 pub enum RedisStorageError<SE>
@@ -32,15 +47,38 @@ where
     /// Returned from [`RedisStorage::remove_dialogue`].
     #[error("row not found")]
     DialogueNotFound,
+
+    /// Returned from [`RedisStorage::update_dialogue_versioned`].
+    #[error("dialogue version conflict")]
+    VersionConflict,
 }
 
 /// A dialogue storage based on [Redis](https://redis.io/).
-pub struct RedisStorage<S> {
+///
+/// Dialogues are stored under their keys encoded via [`StorageKey`], so
+/// dialogues indexed by [`ChatId`] are stored under `<chat_id>` keys.
+///
+/// Besides dialogues, it can keep [per-user and per-chat data] under
+/// `teloxide_data:<name>:user:<user_id>` and
+/// `teloxide_data:<name>:chat:<chat_id>` keys, and
+/// [seen update IDs] under `teloxide_update:<bot_id>:<update_id>` keys expiring
+/// in 24 hours, and [scheduled jobs] in the `teloxide_jobs` hash.
+///
+/// Dialogue expiration is handled natively by Redis. Dialogue versions (see
+/// [`VersionedStorage`]) are stored under separate
+/// `teloxide_dialogue_version:<key>` keys, which neither expire nor are removed
+/// along with dialogues, so that versions of a key are never reused.
+///
+/// [per-user and per-chat data]: crate::dispatching::data
+/// [seen update IDs]: crate::dispatching::dedup
+/// [scheduled jobs]: crate::dispatching::jobs
+pub struct RedisStorage<S, K = ChatId> {
     pool: deadpool_redis::Pool,
     serializer: S,
+    _key: PhantomData<fn(K) -> K>,
 }
 
-impl<S> RedisStorage<S> {
+impl<S, K> RedisStorage<S, K> {
     pub async fn open(
         url: &str,
         serializer: S,
@@ -48,27 +86,26 @@ impl<S> RedisStorage<S> {
         let config = deadpool_redis::Config::from_url(url);
         let pool = config.create_pool(Some(Runtime::Tokio1))?;
 
-        Ok(Arc::new(Self { pool, serializer }))
+        Ok(Arc::new(Self { pool, serializer, _key: PhantomData }))
     }
 }
 
-impl<S, D> Storage<D> for RedisStorage<S>
+impl<S, D, K> Storage<D, K> for RedisStorage<S, K>
 where
+    K: StorageKey + Send + 'static,
     S: Send + Sync + Serializer<D> + 'static,
     D: Send + Serialize + DeserializeOwned + 'static,
     <S as Serializer<D>>::Error: Debug + Display,
 {
     type Error = RedisStorageError<<S as Serializer<D>>::Error>;
 
-    fn remove_dialogue(
-        self: Arc<Self>,
-        ChatId(chat_id): ChatId,
-    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let key = key.encode();
         Box::pin(async move {
             let mut conn = self.pool.get().await?;
 
             let deleted_rows_count =
-                redis::pipe().atomic().del(chat_id).query_async(&mut conn).await?;
+                redis::pipe().atomic().del(&key).query_async(&mut conn).await?;
 
             if let redis::Value::Array(values) = deleted_rows_count {
                 // False positive
@@ -87,29 +124,362 @@ where
 
     fn update_dialogue(
         self: Arc<Self>,
-        ChatId(chat_id): ChatId,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let key = key.encode();
         Box::pin(async move {
             let dialogue =
                 self.serializer.serialize(&dialogue).map_err(RedisStorageError::SerdeError)?;
-            () = self.pool.get().await?.set::<_, Vec<u8>, _>(chat_id, dialogue).await?;
+            () = redis::pipe()
+                .atomic()
+                .set(&key, dialogue)
+                .ignore()
+                .incr(version_key(&key), 1)
+                .ignore()
+                .query_async(&mut self.pool.get().await?)
+                .await?;
             Ok(())
         })
     }
 
-    fn get_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        ChatId(chat_id): ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            let dialogue =
+                self.serializer.serialize(&dialogue).map_err(RedisStorageError::SerdeError)?;
+            // Redis rejects non-positive expiration times.
+            let millis = (ttl.as_millis() as u64).max(1);
+            () = redis::pipe()
+                .atomic()
+                .pset_ex(&key, dialogue, millis)
+                .ignore()
+                .incr(version_key(&key), 1)
+                .ignore()
+                .query_async(&mut self.pool.get().await?)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        let key = key.encode();
         Box::pin(async move {
             self.pool
                 .get()
                 .await?
-                .get::<_, Option<Vec<u8>>>(chat_id)
+                .get::<_, Option<Vec<u8>>>(&key)
                 .await?
                 .map(|d| self.serializer.deserialize(&d).map_err(RedisStorageError::SerdeError))
                 .transpose()
         })
     }
 }
+
+impl<S, D, K> VersionedStorage<D, K> for RedisStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            let (dialogue, version): (Option<Vec<u8>>, Option<i64>) = redis::pipe()
+                .atomic()
+                .get(&key)
+                .get(version_key(&key))
+                .query_async(&mut self.pool.get().await?)
+                .await?;
+
+            dialogue
+                .map(|d| {
+                    let d =
+                        self.serializer.deserialize(&d).map_err(RedisStorageError::SerdeError)?;
+                    // Dialogues stored by older versions of teloxide have no version key.
+                    Ok(VersionedDialogue {
+                        dialogue: d,
+                        version: DialogueVersion(version.unwrap_or(0)),
+                    })
+                })
+                .transpose()
+        })
+    }
+
+    fn update_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            let dialogue =
+                self.serializer.serialize(&dialogue).map_err(RedisStorageError::SerdeError)?;
+            let expected = expected.map_or_else(String::new, |DialogueVersion(v)| v.to_string());
+
+            let version: Option<i64> = redis::cmd("EVAL")
+                .arg(UPDATE_DIALOGUE_VERSIONED)
+                .arg(2)
+                .arg(&key)
+                .arg(version_key(&key))
+                .arg(dialogue)
+                .arg(expected)
+                .query_async(&mut self.pool.get().await?)
+                .await?;
+
+            version.map(DialogueVersion).ok_or(RedisStorageError::VersionConflict)
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        matches!(error, RedisStorageError::VersionConflict)
+    }
+}
+
+/// The number of keys requested at once from `SCAN`.
+const SCAN_COUNT: usize = 100;
+
+/// Dialogues are enumerated using [`SCAN`], so [`RedisStorage::list_dialogues`]
+/// may yield a dialogue more than once. Every key
+/// considered to be a dialogue if it can be decoded as `K`, so it's better to
+/// keep dialogues in a separate database.
+///
+/// [`SCAN`]: https://redis.io/docs/latest/commands/scan/
+impl<S, D, K> EnumerableStorage<D, K> for RedisStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        let pool = self.pool.clone();
+
+        let pages = stream::try_unfold(Some(0), move |cursor| {
+            let pool = pool.clone();
+            async move {
+                let Some(cursor) = cursor else { return Ok(None) };
+
+                let mut conn = pool.get().await?;
+                let (cursor, keys) = scan(&mut conn, cursor).await?;
+                let keys: Vec<String> =
+                    keys.into_iter().filter(|key| K::decode(key).is_some()).collect();
+                let dialogues: Vec<Option<Vec<u8>>> = if keys.is_empty() {
+                    Vec::new()
+                } else {
+                    redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?
+                };
+
+                // A dialogue may be removed between `SCAN` and `MGET`.
+                let page: Vec<_> = keys
+                    .into_iter()
+                    .zip(dialogues)
+                    .filter_map(|(key, d)| Some((K::decode(&key)?, d?)))
+                    .collect();
+
+                Ok::<_, Self::Error>(Some((page, (cursor != 0).then_some(cursor))))
+            }
+        });
+
+        pages
+            .map_ok(move |page| {
+                let this = Arc::clone(&self);
+                stream::iter(page).map(move |(key, d)| {
+                    let d =
+                        this.serializer.deserialize(&d).map_err(RedisStorageError::SerdeError)?;
+                    Ok((key, d))
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            // `SCAN` may return a key more than once.
+            let mut dialogue_keys = HashSet::new();
+            let mut cursor = 0;
+
+            loop {
+                let (next, keys) = scan(&mut conn, cursor).await?;
+                dialogue_keys.extend(keys.into_iter().filter(|key| K::decode(key).is_some()));
+
+                if next == 0 {
+                    return Ok(dialogue_keys.len() as u64);
+                }
+                cursor = next;
+            }
+        })
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            let mut cursor = 0;
+
+            loop {
+                let (next, keys) = scan(&mut conn, cursor).await?;
+                // Version keys are kept, so that versions are not reused.
+                let keys: Vec<String> =
+                    keys.into_iter().filter(|key| K::decode(key).is_some()).collect();
+                if !keys.is_empty() {
+                    () = conn.del(keys).await?;
+                }
+
+                if next == 0 {
+                    return Ok(());
+                }
+                cursor = next;
+            }
+        })
+    }
+}
+
+impl<S, K, T> DataStorage<T> for RedisStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + Serializer<T> + 'static,
+    T: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<T>>::Error: Debug + Display,
+{
+    type Error = RedisStorageError<<S as Serializer<T>>::Error>;
+
+    fn get_data(
+        self: Arc<Self>,
+        key: DataKey,
+    ) -> BoxFuture<'static, Result<Option<T>, Self::Error>> {
+        Box::pin(async move {
+            self.pool
+                .get()
+                .await?
+                .get::<_, Option<Vec<u8>>>(data_key(key))
+                .await?
+                .map(|d| self.serializer.deserialize(&d).map_err(RedisStorageError::SerdeError))
+                .transpose()
+        })
+    }
+
+    fn set_data(
+        self: Arc<Self>,
+        key: DataKey,
+        value: T,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let data = self.serializer.serialize(&value).map_err(RedisStorageError::SerdeError)?;
+            () = self.pool.get().await?.set(data_key(key), data).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_data(self: Arc<Self>, key: DataKey) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            () = self.pool.get().await?.del(data_key(key)).await?;
+            Ok(())
+        })
+    }
+}
+
+impl<S, K> DedupStorage for RedisStorage<S, K>
+where
+    S: Send + Sync + 'static,
+    K: Send + 'static,
+{
+    type Error = RedisStorageError<Infallible>;
+
+    fn mark_seen(
+        self: Arc<Self>,
+        bot_id: UserId,
+        update_id: UpdateId,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            let inserted: Option<String> = redis::cmd("SET")
+                .arg(format!("teloxide_update:{}:{}", bot_id.0, update_id.0))
+                .arg(1)
+                .arg("NX")
+                .arg("PX")
+                .arg(PERSISTENT_WINDOW_MILLIS)
+                .query_async(&mut self.pool.get().await?)
+                .await?;
+            Ok(inserted.is_some())
+        })
+    }
+}
+
+impl<S, K> JobStorage for RedisStorage<S, K>
+where
+    S: Send + Sync + 'static,
+    K: Send + 'static,
+{
+    type Error = RedisStorageError<Infallible>;
+
+    fn save_job(self: Arc<Self>, job: Job) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            () = conn.hset(JOBS_KEY, job.id(), job.encode()).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_job(self: Arc<Self>, id: String) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            () = conn.hdel(JOBS_KEY, id).await?;
+            Ok(())
+        })
+    }
+
+    fn load_jobs(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<Job>, Self::Error>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            let jobs: Vec<Vec<u8>> = conn.hvals(JOBS_KEY).await?;
+            Ok(Job::decode_all(jobs))
+        })
+    }
+}
+
+/// The hash of scheduled jobs, by their IDs.
+const JOBS_KEY: &str = "teloxide_jobs";
+
+/// Performs a single `SCAN` iteration, returning the next cursor and keys.
+async fn scan(
+    conn: &mut deadpool_redis::Connection,
+    cursor: u64,
+) -> redis::RedisResult<(u64, Vec<String>)> {
+    redis::cmd("SCAN").arg(cursor).arg("COUNT").arg(SCAN_COUNT).query_async(conn).await
+}
+
+/// Sets `KEYS[1]` to `ARGV[1]` if the version stored under `KEYS[2]` equals
+/// `ARGV[2]` (an empty string means that the dialogue must not exist).
+/// Returns the new version or `false` (a null reply) on a conflict.
+const UPDATE_DIALOGUE_VERSIONED: &str = r"
+local exists = redis.call('EXISTS', KEYS[1]) == 1
+local version = tonumber(redis.call('GET', KEYS[2]) or '0')
+if ARGV[2] == '' then
+    if exists then return false end
+elseif not exists or version ~= tonumber(ARGV[2]) then
+    return false
+end
+redis.call('SET', KEYS[1], ARGV[1])
+return redis.call('INCR', KEYS[2])
+";
+
+const VERSION_KEY_PREFIX: &str = "teloxide_dialogue_version:";
+
+fn version_key(key: &str) -> String {
+    format!("{VERSION_KEY_PREFIX}{key}")
+}
+
+fn data_key(key: DataKey) -> String {
+    format!("teloxide_data:{}:{}:{}", key.name, key.scope(), key.id())
+}
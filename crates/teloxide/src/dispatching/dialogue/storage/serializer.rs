@@ -1,7 +1,15 @@
 //! Various serializers for dialogue storages.
 
+#[cfg(feature = "encrypted-serializer")]
+mod encrypted;
+mod migrating;
+
 use serde::{de::DeserializeOwned, ser::Serialize};
 
+#[cfg(feature = "encrypted-serializer")]
+pub use encrypted::{Encrypted, EncryptedError, EncryptionKey};
+pub use migrating::{FallbackPolicy, Migrating, MigratingError};
+
 /// A serializer for memory storages.
 pub trait Serializer<D> {
     type Error;
@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use thiserror::Error;
+
+use super::Serializer;
+
+/// The version of the format produced by [`Encrypted`].
+const FORMAT_VERSION: u8 = 1;
+
+/// The length of the header, which consists of the format version and the key
+/// ID.
+const HEADER_LEN: usize = 1 + 4;
+
+/// An error returned from [`Encrypted`].
+#[derive(Debug, Error)]
+pub enum EncryptedError<E>
+where
+    E: Debug + Display,
+{
+    /// The underlying serializer has failed.
+    #[error("{0}")]
+    Serializer(E),
+
+    /// Encryption has failed.
+    #[error("failed to encrypt a dialogue")]
+    Encryption,
+
+    /// The data is malformed, was encrypted with a different key or was
+    /// tampered with.
+    #[error("failed to decrypt a dialogue")]
+    Decryption,
+
+    /// The data was encrypted with a key which is not known to the serializer.
+    #[error("unknown encryption key {0}")]
+    UnknownKey(u32),
+}
+
+/// A 256-bit key used by [`Encrypted`].
+///
+/// Every key has an ID, which is stored alongside the encrypted data, so that
+/// the right key can be picked for decryption after the key is rotated. IDs
+/// of different keys must be distinct.
+pub struct EncryptionKey {
+    id: u32,
+    key: LessSafeKey,
+}
+
+impl EncryptionKey {
+    /// Creates a key with the given ID from 32 bytes of key material.
+    ///
+    /// The key material must be secret and random, e.g. generated by a
+    /// cryptographically secure random number generator.
+    #[must_use]
+    pub fn new(id: u32, bytes: &[u8; 32]) -> Self {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, bytes)
+            .expect("ChaCha20-Poly1305 accepts 256-bit keys");
+        Self { id, key: LessSafeKey::new(key) }
+    }
+
+    /// Returns the ID of this key.
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// A serializer which encrypts data serialized by another serializer.
+///
+/// The data is encrypted with [ChaCha20-Poly1305], an authenticated cipher, so
+/// any modification of the stored data is detected on decryption. Every
+/// dialogue is encrypted with a fresh random nonce.
+///
+/// Since all bundled storages serialize dialogues through their
+/// [`Serializer`], wrapping the serializer passed to e.g.
+/// [`SqliteStorage::open`] or [`RedisStorage::open`] is enough to encrypt
+/// dialogues at rest.
+///
+/// **Note**: a serializer doesn't know the key of the dialogue it serializes,
+/// so the encrypted data is not bound to it. Someone who can write to the
+/// storage can't read or forge dialogues, but can copy the encrypted dialogue
+/// of one chat to another chat, where it's decrypted successfully. If this
+/// matters, store the chat ID in the dialogue and check it after loading.
+///
+/// ## Key rotation
+///
+/// Dialogues are always encrypted with the current key, while keys added via
+/// [`Encrypted::with_previous_key`] are only used to decrypt dialogues that
+/// were written before the rotation. A dialogue is re-encrypted with the
+/// current key on its next update, after which a previous key can be removed.
+///
+/// ```
+/// use teloxide::dispatching::dialogue::serializer::{Encrypted, EncryptionKey, Json};
+/// # let (old_key, new_key) = ([0; 32], [1; 32]);
+///
+/// let serializer = Encrypted::new(Json, EncryptionKey::new(2, &new_key))
+///     .with_previous_key(EncryptionKey::new(1, &old_key));
+/// ```
+///
+/// [ChaCha20-Poly1305]: https://datatracker.ietf.org/doc/html/rfc8439
+/// [`SqliteStorage::open`]: crate::dispatching::dialogue::SqliteStorage::open
+/// [`RedisStorage::open`]: crate::dispatching::dialogue::RedisStorage::open
+pub struct Encrypted<S> {
+    inner: S,
+    key: EncryptionKey,
+    previous_keys: HashMap<u32, EncryptionKey>,
+    rng: SystemRandom,
+}
+
+impl<S> Encrypted<S> {
+    /// Creates a serializer which encrypts data serialized by `inner` with
+    /// `key`.
+    #[must_use]
+    pub fn new(inner: S, key: EncryptionKey) -> Self {
+        Self { inner, key, previous_keys: HashMap::new(), rng: SystemRandom::new() }
+    }
+
+    /// Adds a key which was used before the current one to decrypt existing
+    /// dialogues.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `key` has the same ID as the current key.
+    #[must_use]
+    pub fn with_previous_key(mut self, key: EncryptionKey) -> Self {
+        assert_ne!(key.id, self.key.id, "a previous key must have a distinct ID");
+        self.previous_keys.insert(key.id, key);
+        self
+    }
+
+    fn key(&self, id: u32) -> Option<&EncryptionKey> {
+        if id == self.key.id {
+            Some(&self.key)
+        } else {
+            self.previous_keys.get(&id)
+        }
+    }
+}
+
+impl<S, D> Serializer<D> for Encrypted<S>
+where
+    S: Serializer<D>,
+    S::Error: Debug + Display,
+{
+    type Error = EncryptedError<S::Error>;
+
+    fn serialize(&self, val: &D) -> Result<Vec<u8>, Self::Error> {
+        let plaintext = self.inner.serialize(val).map_err(EncryptedError::Serializer)?;
+
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| EncryptedError::Encryption)?;
+
+        let mut header = [0; HEADER_LEN];
+        header[0] = FORMAT_VERSION;
+        header[1..].copy_from_slice(&self.key.id.to_be_bytes());
+
+        let mut in_out = plaintext;
+        self.key
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(header),
+                &mut in_out,
+            )
+            .map_err(|_| EncryptedError::Encryption)?;
+
+        let mut data = Vec::with_capacity(HEADER_LEN + NONCE_LEN + in_out.len());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&in_out);
+        Ok(data)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<D, Self::Error> {
+        if data.len() < HEADER_LEN + NONCE_LEN || data[0] != FORMAT_VERSION {
+            return Err(EncryptedError::Decryption);
+        }
+        let (header, rest) = data.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let id = u32::from_be_bytes(header[1..].try_into().unwrap());
+        let key = self.key(id).ok_or(EncryptedError::UnknownKey(id))?;
+
+        let mut in_out = ciphertext.to_vec();
+        let nonce =
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| EncryptedError::Decryption)?;
+        let plaintext = key
+            .key
+            .open_in_place(nonce, Aad::from(header), &mut in_out)
+            .map_err(|_| EncryptedError::Decryption)?;
+
+        self.inner.deserialize(plaintext).map_err(EncryptedError::Serializer)
+    }
+}
+
+impl<S> Debug for Encrypted<S>
+where
+    S: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encrypted")
+            .field("inner", &self.inner)
+            .field("key", &self.key)
+            .field("previous_keys", &self.previous_keys.values())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatching::dialogue::serializer::Json;
+
+    #[test]
+    fn roundtrip() {
+        let serializer = Encrypted::new(Json, EncryptionKey::new(1, &[42; 32]));
+
+        let data = serializer.serialize(&"secret".to_owned()).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+        assert_ne!(data, serializer.serialize(&"secret".to_owned()).unwrap());
+        assert_eq!(Serializer::<String>::deserialize(&serializer, &data).unwrap(), "secret");
+    }
+
+    #[test]
+    fn tampering() {
+        let serializer = Encrypted::new(Json, EncryptionKey::new(1, &[42; 32]));
+        let mut data = serializer.serialize(&"secret".to_owned()).unwrap();
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(matches!(
+            Serializer::<String>::deserialize(&serializer, &data).unwrap_err(),
+            EncryptedError::Decryption
+        ));
+        assert!(matches!(
+            Serializer::<String>::deserialize(&serializer, b"\"secret\"").unwrap_err(),
+            EncryptedError::Decryption
+        ));
+    }
+
+    #[test]
+    fn key_rotation() {
+        let old = Encrypted::new(Json, EncryptionKey::new(1, &[1; 32]));
+        let data = old.serialize(&"secret".to_owned()).unwrap();
+
+        let new = Encrypted::new(Json, EncryptionKey::new(2, &[2; 32]));
+        assert!(matches!(
+            Serializer::<String>::deserialize(&new, &data).unwrap_err(),
+            EncryptedError::UnknownKey(1)
+        ));
+
+        let new = new.with_previous_key(EncryptionKey::new(1, &[1; 32]));
+        assert_eq!(Serializer::<String>::deserialize(&new, &data).unwrap(), "secret");
+
+        // New data is encrypted with the current key.
+        let data = new.serialize(&"secret".to_owned()).unwrap();
+        assert!(Serializer::<String>::deserialize(&old, &data).is_err());
+
+        // A key with a known ID but different material is rejected.
+        let wrong = Encrypted::new(Json, EncryptionKey::new(2, &[3; 32]));
+        assert!(matches!(
+            Serializer::<String>::deserialize(&wrong, &data).unwrap_err(),
+            EncryptedError::Decryption
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+use thiserror::Error;
+
+use super::Serializer;
+
+/// Bytes which prefix every state serialized by [`Migrating`].
+///
+/// `0xFF` cannot start a JSON or CBOR document, so states written by other
+/// serializers are never mistaken for enveloped ones.
+const MAGIC: &[u8; 4] = b"\xffTLX";
+
+/// An error returned from [`Migrating`].
+#[derive(Debug, Error)]
+pub enum MigratingError<E>
+where
+    E: Debug + Display,
+{
+    /// The underlying serializer has failed.
+    #[error("{0}")]
+    Serializer(E),
+
+    /// The state was written with a schema version newer than the current one.
+    #[error("unknown schema version {0}")]
+    UnknownVersion(u32),
+
+    /// There is no migration registered for the given schema version.
+    #[error("no migration from schema version {0}")]
+    MissingMigration(u32),
+}
+
+/// What to do with a stored state that cannot be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum FallbackPolicy {
+    /// Return an error, so that the storage fails to read the dialogue.
+    #[default]
+    Error,
+
+    /// Replace the state with `D::default()`, starting the dialogue over.
+    Reset,
+}
+
+type Migration<E> = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, E> + Send + Sync>;
+
+/// A serializer which wraps states into a versioned envelope and upgrades
+/// states written with older schema versions on read.
+///
+/// Every state is serialized by the underlying serializer `S` and prefixed with
+/// the current schema version. When a state with an older version is read,
+/// migrations registered via [`Migrating::migration`] are applied one by one
+/// until the state reaches the current version. States written without an
+/// envelope (e.g. before switching to `Migrating`) are considered to have
+/// version `0`.
+///
+/// Since storages deserialize states through their [`Serializer`], wrapping
+/// the serializer passed to [`SqliteStorage::open`], [`PostgresStorage::open`]
+/// or [`RedisStorage::open`] is enough to make them run migrations
+/// transparently. Note that upgraded states are written back only on the next
+/// update of the dialogue.
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use teloxide::dispatching::dialogue::serializer::{FallbackPolicy, Json, Migrating};
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum StateV0 {
+///     Start,
+///     ReceiveName,
+/// }
+///
+/// #[derive(Clone, Default, Serialize, Deserialize)]
+/// enum State {
+///     #[default]
+///     Start,
+///     ReceiveFullName,
+/// }
+///
+/// let serializer = Migrating::new(Json, 1)
+///     .migration(0, |old: StateV0| match old {
+///         StateV0::Start => State::Start,
+///         StateV0::ReceiveName => State::ReceiveFullName,
+///     })
+///     .fallback(FallbackPolicy::Reset);
+/// # let _: Migrating<Json, State> = serializer;
+/// ```
+///
+/// [`SqliteStorage::open`]: crate::dispatching::dialogue::SqliteStorage::open
+/// [`PostgresStorage::open`]: crate::dispatching::dialogue::PostgresStorage::open
+/// [`RedisStorage::open`]: crate::dispatching::dialogue::RedisStorage::open
+pub struct Migrating<S, D>
+where
+    S: Serializer<D>,
+{
+    inner: Arc<S>,
+    version: u32,
+    migrations: BTreeMap<u32, Migration<S::Error>>,
+    reset: Option<fn() -> D>,
+    _phantom: PhantomData<fn(D) -> D>,
+}
+
+impl<S, D> Migrating<S, D>
+where
+    S: Serializer<D>,
+{
+    /// Creates a serializer which writes states with the `version` schema
+    /// version using `inner`.
+    #[must_use]
+    pub fn new(inner: S, version: u32) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            version,
+            migrations: BTreeMap::new(),
+            reset: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Registers a migration of states from the `from` schema version to
+    /// `from + 1`.
+    ///
+    /// `Old` is the state type of version `from`, `New` is the state type of
+    /// version `from + 1`, which is `D` for the last migration. Both are
+    /// (de)serialized by the underlying serializer.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `from` is not less than the current version.
+    #[must_use]
+    pub fn migration<Old, New, F>(mut self, from: u32, f: F) -> Self
+    where
+        S: Serializer<Old, Error = <S as Serializer<D>>::Error>
+            + Serializer<New, Error = <S as Serializer<D>>::Error>
+            + Send
+            + Sync
+            + 'static,
+        F: Fn(Old) -> New + Send + Sync + 'static,
+    {
+        assert!(from < self.version, "cannot migrate from version {from} to a newer one");
+
+        let inner = Arc::clone(&self.inner);
+        self.migrations.insert(
+            from,
+            Box::new(move |data| {
+                let old: Old = inner.deserialize(data)?;
+                inner.serialize(&f(old))
+            }),
+        );
+        self
+    }
+
+    /// Sets the policy for states that cannot be decoded or migrated.
+    ///
+    /// [`FallbackPolicy::Error`] is used by default.
+    #[must_use]
+    pub fn fallback(mut self, policy: FallbackPolicy) -> Self
+    where
+        D: Default,
+    {
+        self.reset = match policy {
+            FallbackPolicy::Error => None,
+            FallbackPolicy::Reset => Some(D::default),
+        };
+        self
+    }
+
+    /// Decodes `data` of any supported version without applying the fallback
+    /// policy.
+    fn decode(&self, data: &[u8]) -> Result<D, MigratingError<S::Error>>
+    where
+        S::Error: Debug + Display,
+    {
+        let (mut version, payload) = match data.strip_prefix(MAGIC.as_slice()) {
+            Some([a, b, c, d, payload @ ..]) => (u32::from_be_bytes([*a, *b, *c, *d]), payload),
+            _ => (0, data),
+        };
+
+        if version > self.version {
+            return Err(MigratingError::UnknownVersion(version));
+        }
+
+        let mut payload = payload.to_vec();
+        while version < self.version {
+            let migration =
+                self.migrations.get(&version).ok_or(MigratingError::MissingMigration(version))?;
+            payload = migration(&payload).map_err(MigratingError::Serializer)?;
+            version += 1;
+        }
+
+        self.inner.deserialize(&payload).map_err(MigratingError::Serializer)
+    }
+}
+
+impl<S, D> Serializer<D> for Migrating<S, D>
+where
+    S: Serializer<D>,
+    S::Error: Debug + Display,
+{
+    type Error = MigratingError<S::Error>;
+
+    fn serialize(&self, val: &D) -> Result<Vec<u8>, Self::Error> {
+        let payload = self.inner.serialize(val).map_err(MigratingError::Serializer)?;
+
+        let mut data = Vec::with_capacity(MAGIC.len() + 4 + payload.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.extend_from_slice(&payload);
+        Ok(data)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<D, Self::Error> {
+        match (self.decode(data), self.reset) {
+            (Err(err), Some(reset)) => {
+                log::warn!("Resetting a dialogue state that cannot be decoded: {err}");
+                Ok(reset())
+            }
+            (res, _) => res,
+        }
+    }
+}
+
+impl<S, D> Debug for Migrating<S, D>
+where
+    S: Serializer<D> + Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migrating")
+            .field("inner", &self.inner)
+            .field("version", &self.version)
+            .field("migrations", &self.migrations.keys())
+            .field("fallback", &self.reset.map_or(FallbackPolicy::Error, |_| FallbackPolicy::Reset))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::dispatching::dialogue::serializer::Json;
+
+    #[derive(Serialize, Deserialize)]
+    struct V0 {
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct V1 {
+        first_name: String,
+    }
+
+    #[derive(Debug, PartialEq, Default, Serialize, Deserialize)]
+    struct V2 {
+        first_name: String,
+        age: u8,
+    }
+
+    fn serializer() -> Migrating<Json, V2> {
+        Migrating::new(Json, 2)
+            .migration(0, |V0 { name }| V1 { first_name: name })
+            .migration(1, |V1 { first_name }| V2 { first_name, age: 18 })
+    }
+
+    #[test]
+    fn roundtrip() {
+        let serializer = serializer();
+        let state = V2 { first_name: "Ferris".to_owned(), age: 7 };
+
+        let data = serializer.serialize(&state).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(serializer.deserialize(&data).unwrap(), state);
+    }
+
+    #[test]
+    fn migrates_old_states() {
+        let serializer = serializer();
+        let expected = V2 { first_name: "Ferris".to_owned(), age: 18 };
+
+        // A state written before the envelope was introduced.
+        let data = serde_json::to_vec(&V0 { name: "Ferris".to_owned() }).unwrap();
+        assert_eq!(serializer.deserialize(&data).unwrap(), expected);
+
+        let data = Migrating::<_, V1>::new(Json, 1)
+            .serialize(&V1 { first_name: "Ferris".to_owned() })
+            .unwrap();
+        assert_eq!(serializer.deserialize(&data).unwrap(), expected);
+    }
+
+    #[test]
+    fn fallback() {
+        let data = Migrating::<_, V2>::new(Json, 3).serialize(&V2::default()).unwrap();
+        assert!(matches!(
+            serializer().deserialize(&data).unwrap_err(),
+            MigratingError::UnknownVersion(3)
+        ));
+
+        let serializer = Migrating::<_, V2>::new(Json, 2);
+        assert!(matches!(
+            serializer.deserialize(b"{}").unwrap_err(),
+            MigratingError::MissingMigration(0)
+        ));
+
+        let serializer = serializer.fallback(FallbackPolicy::Reset);
+        assert_eq!(serializer.deserialize(b"{}").unwrap(), V2::default());
+        assert_eq!(serializer.deserialize(b"garbage").unwrap(), V2::default());
+    }
+}
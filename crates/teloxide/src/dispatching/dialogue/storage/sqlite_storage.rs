@@ -1,20 +1,55 @@
-use super::{serializer::Serializer, Storage};
-use futures::future::BoxFuture;
+use super::{
+    serializer::Serializer, unix_millis, DialogueVersion, EnumerableStorage, Storage, StorageKey,
+    VersionedDialogue, VersionedStorage,
+};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
 use serde::{de::DeserializeOwned, Serialize};
 use sqlx::{sqlite::SqlitePool, Executor};
 use std::{
     convert::Infallible,
     fmt::{Debug, Display},
+    marker::PhantomData,
     str,
     sync::Arc,
+    time::Duration,
 };
-use teloxide_core::types::ChatId;
+use teloxide_core::types::{ChatId, UpdateId, UserId};
 use thiserror::Error;
+use tokio::sync::OnceCell;
+
+use crate::dispatching::{
+    data::{DataKey, DataStorage},
+    dedup::{DedupStorage, PERSISTENT_WINDOW_MILLIS, PRUNE_INTERVAL},
+    jobs::{Job, JobStorage},
+};
 
 /// A persistent dialogue storage based on [SQLite](https://www.sqlite.org/).
-pub struct SqliteStorage<S> {
+///
+/// Besides dialogues, it can keep [per-user and per-chat data], [seen update
+/// IDs] and [scheduled jobs] in separate tables, which are created when
+/// they're first used.
+///
+/// Dialogues are stored under their keys encoded via [`StorageKey`] in the
+/// `dialogue_key` column, so dialogues indexed by [`ChatId`] are stored under
+/// `<chat_id>` keys. Tables created by older versions of teloxide, which were
+/// indexed by `chat_id`, are migrated when the storage is opened.
+///
+/// Expired dialogues are evicted on access. To evict all of them at once, use
+/// [`SqliteStorage::remove_expired_dialogues`].
+///
+/// [per-user and per-chat data]: crate::dispatching::data
+/// [seen update IDs]: crate::dispatching::dedup
+/// [scheduled jobs]: crate::dispatching::jobs
+pub struct SqliteStorage<S, K = ChatId> {
     pool: SqlitePool,
     serializer: S,
+    data_table: OnceCell<()>,
+    updates_table: OnceCell<()>,
+    jobs_table: OnceCell<()>,
+    _key: PhantomData<fn(K) -> K>,
 }
 
 /// An error returned from [`SqliteStorage`].
@@ -32,9 +67,13 @@ where
     /// Returned from [`SqliteStorage::remove_dialogue`].
     #[error("row not found")]
     DialogueNotFound,
+
+    /// Returned from [`SqliteStorage::update_dialogue_versioned`].
+    #[error("dialogue version conflict")]
+    VersionConflict,
 }
 
-impl<S> SqliteStorage<S> {
+impl<S, K> SqliteStorage<S, K> {
     pub async fn open(
         path: &str,
         serializer: S,
@@ -43,20 +82,149 @@ impl<S> SqliteStorage<S> {
         sqlx::query(
             "
 CREATE TABLE IF NOT EXISTS teloxide_dialogues (
-    chat_id BIGINT PRIMARY KEY,
-    dialogue BLOB NOT NULL
+    dialogue_key TEXT PRIMARY KEY,
+    dialogue BLOB NOT NULL,
+    expires_at BIGINT,
+    version BIGINT NOT NULL DEFAULT 1
 );
         ",
         )
         .execute(&pool)
         .await?;
 
-        Ok(Arc::new(Self { pool, serializer }))
+        // Tables created by older versions of teloxide lack some of the columns.
+        for (column, definition) in
+            [("expires_at", "BIGINT"), ("version", "BIGINT NOT NULL DEFAULT 1")]
+        {
+            if !has_dialogues_column(&pool, column).await? {
+                let alter =
+                    format!("ALTER TABLE teloxide_dialogues ADD COLUMN {column} {definition}");
+                sqlx::query(&alter).execute(&pool).await?;
+            }
+        }
+
+        // They were also indexed by chat IDs, which are encoded as is. SQLite can't
+        // change the primary key of a table, so it's recreated.
+        if has_dialogues_column(&pool, "chat_id").await? {
+            let mut tx = pool.begin().await?;
+            for query in [
+                "
+CREATE TABLE teloxide_dialogues_new (
+    dialogue_key TEXT PRIMARY KEY,
+    dialogue BLOB NOT NULL,
+    expires_at BIGINT,
+    version BIGINT NOT NULL DEFAULT 1
+);
+                ",
+                "
+INSERT INTO teloxide_dialogues_new (dialogue_key, dialogue, expires_at, version)
+SELECT CAST(chat_id AS TEXT), dialogue, expires_at, version FROM teloxide_dialogues;
+                ",
+                "DROP TABLE teloxide_dialogues;",
+                "ALTER TABLE teloxide_dialogues_new RENAME TO teloxide_dialogues;",
+            ] {
+                sqlx::query(query).execute(&mut *tx).await?;
+            }
+            tx.commit().await?;
+        }
+
+        Ok(Arc::new(Self {
+            pool,
+            serializer,
+            data_table: OnceCell::new(),
+            updates_table: OnceCell::new(),
+            jobs_table: OnceCell::new(),
+            _key: PhantomData,
+        }))
+    }
+
+    /// Creates the `teloxide_data` table on first use, so that it doesn't
+    /// appear in databases which don't store data.
+    async fn create_data_table(&self) -> Result<(), sqlx::Error> {
+        self.data_table
+            .get_or_try_init(|| async {
+                sqlx::query(
+                    "
+CREATE TABLE IF NOT EXISTS teloxide_data (
+    name TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    id BIGINT NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (name, scope, id)
+);
+                    ",
+                )
+                .execute(&self.pool)
+                .await
+                .map(drop)
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Creates the `teloxide_updates` table on first use, so that it doesn't
+    /// appear in databases which don't deduplicate updates.
+    async fn create_updates_table(&self) -> Result<(), sqlx::Error> {
+        self.updates_table
+            .get_or_try_init(|| async {
+                sqlx::query(
+                    "
+CREATE TABLE IF NOT EXISTS teloxide_updates (
+    bot_id BIGINT NOT NULL,
+    update_id BIGINT NOT NULL,
+    seen_at BIGINT NOT NULL,
+    PRIMARY KEY (bot_id, update_id)
+);
+                    ",
+                )
+                .execute(&self.pool)
+                .await?;
+                sqlx::query(
+                    "CREATE INDEX IF NOT EXISTS teloxide_updates_seen_at ON teloxide_updates \
+                     (seen_at)",
+                )
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Creates the `teloxide_jobs` table on first use, so that it doesn't
+    /// appear in databases which don't store jobs.
+    async fn create_jobs_table(&self) -> Result<(), sqlx::Error> {
+        self.jobs_table
+            .get_or_try_init(|| async {
+                sqlx::query(
+                    "
+CREATE TABLE IF NOT EXISTS teloxide_jobs (
+    id TEXT PRIMARY KEY,
+    job BLOB NOT NULL
+);
+                    ",
+                )
+                .execute(&self.pool)
+                .await
+                .map(drop)
+            })
+            .await
+            .map(drop)
+    }
+
+    /// Removes all dialogues whose time-to-live has passed.
+    pub async fn remove_expired_dialogues(&self) -> Result<(), SqliteStorageError<Infallible>> {
+        sqlx::query("DELETE FROM teloxide_dialogues WHERE expires_at <= ?")
+            .bind(unix_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 }
 
-impl<S, D> Storage<D> for SqliteStorage<S>
+impl<S, D, K> Storage<D, K> for SqliteStorage<S, K>
 where
+    K: StorageKey + Send + 'static,
     S: Send + Sync + Serializer<D> + 'static,
     D: Send + Serialize + DeserializeOwned + 'static,
     <S as Serializer<D>>::Error: Debug + Display,
@@ -64,81 +232,532 @@ where
     type Error = SqliteStorageError<<S as Serializer<D>>::Error>;
 
     /// Returns [`sqlx::Error::RowNotFound`] if a dialogue does not exist.
-    fn remove_dialogue(
-        self: Arc<Self>,
-        ChatId(chat_id): ChatId,
-    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let key = key.encode();
         Box::pin(async move {
-            let deleted_rows_count =
-                sqlx::query("DELETE FROM teloxide_dialogues WHERE chat_id = ?")
-                    .bind(chat_id)
-                    .execute(&self.pool)
-                    .await?
-                    .rows_affected();
+            let deleted: Option<(Option<i64>,)> = sqlx::query_as(
+                "DELETE FROM teloxide_dialogues WHERE dialogue_key = ? RETURNING expires_at",
+            )
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
 
-            if deleted_rows_count == 0 {
-                return Err(SqliteStorageError::DialogueNotFound);
+            match deleted {
+                Some((expires_at,)) if !is_expired(expires_at) => Ok(()),
+                _ => Err(SqliteStorageError::DialogueNotFound),
             }
+        })
+    }
 
+    fn update_dialogue(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            let d = self.serializer.serialize(&dialogue).map_err(SqliteStorageError::SerdeError)?;
+            update_dialogue(&self.pool, &key, d, None).await?;
             Ok(())
         })
     }
 
-    fn update_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        ChatId(chat_id): ChatId,
+        key: K,
         dialogue: D,
+        ttl: Duration,
     ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        let key = key.encode();
         Box::pin(async move {
             let d = self.serializer.serialize(&dialogue).map_err(SqliteStorageError::SerdeError)?;
+            let expires_at = unix_millis().saturating_add(ttl.as_millis() as i64);
+            update_dialogue(&self.pool, &key, d, Some(expires_at)).await?;
+            Ok(())
+        })
+    }
 
-            self.pool
-                .acquire()
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            get_dialogue(&self.pool, &key)
                 .await?
-                .execute(
-                    sqlx::query(
+                .map(|(d, _)| {
+                    self.serializer.deserialize(&d).map_err(SqliteStorageError::SerdeError)
+                })
+                .transpose()
+        })
+    }
+}
+
+impl<S, D, K> VersionedStorage<D, K> for SqliteStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            get_dialogue(&self.pool, &key)
+                .await?
+                .map(|(d, version)| {
+                    let d =
+                        self.serializer.deserialize(&d).map_err(SqliteStorageError::SerdeError)?;
+                    Ok(VersionedDialogue { dialogue: d, version: DialogueVersion(version) })
+                })
+                .transpose()
+        })
+    }
+
+    fn update_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>> {
+        let key = key.encode();
+        Box::pin(async move {
+            let d = self.serializer.serialize(&dialogue).map_err(SqliteStorageError::SerdeError)?;
+
+            let version: Option<(i64,)> = match expected {
+                // An expired dialogue is considered absent, so it can be overwritten.
+                None => {
+                    sqlx::query_as(
                         "
-            INSERT INTO teloxide_dialogues VALUES (?, ?)
-            ON CONFLICT(chat_id) DO UPDATE SET dialogue=excluded.dialogue
-                                ",
+            INSERT INTO teloxide_dialogues (dialogue_key, dialogue) VALUES (?, ?)
+            ON CONFLICT(dialogue_key) DO UPDATE
+            SET dialogue=excluded.dialogue, expires_at=NULL, version=teloxide_dialogues.version + 1
+            WHERE teloxide_dialogues.expires_at <= ?
+            RETURNING version
+                        ",
                     )
-                    .bind(chat_id)
-                    .bind(d),
+                    .bind(key)
+                    .bind(d)
+                    .bind(unix_millis())
+                    .fetch_optional(&self.pool)
+                    .await?
+                }
+                Some(DialogueVersion(expected)) => {
+                    sqlx::query_as(
+                        "
+            UPDATE teloxide_dialogues SET dialogue = ?, expires_at = NULL, version = version + 1
+            WHERE dialogue_key = ? AND version = ? AND (expires_at IS NULL OR expires_at > ?)
+            RETURNING version
+                        ",
+                    )
+                    .bind(d)
+                    .bind(key)
+                    .bind(expected)
+                    .bind(unix_millis())
+                    .fetch_optional(&self.pool)
+                    .await?
+                }
+            };
+
+            version
+                .map(|(version,)| DialogueVersion(version))
+                .ok_or(SqliteStorageError::VersionConflict)
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        matches!(error, SqliteStorageError::VersionConflict)
+    }
+}
+
+/// The number of dialogues fetched at once by
+/// [`SqliteStorage::list_dialogues`].
+const LIST_PAGE_SIZE: i64 = 100;
+
+/// Every row is considered to be a dialogue if its key can be decoded as `K`,
+/// so dialogues with keys of different types shouldn't share a database.
+impl<S, D, K> EnumerableStorage<D, K> for SqliteStorage<S, K>
+where
+    K: StorageKey + Send + 'static,
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<D>>::Error: Debug + Display,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        let pool = self.pool.clone();
+
+        // Dialogues are fetched page by page, ordered by keys.
+        let pages = stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+            let pool = pool.clone();
+            async move {
+                let Some(after) = after else { return Ok(None) };
+
+                let rows: Vec<(String, Vec<u8>)> = sqlx::query_as(
+                    "
+            SELECT dialogue_key, dialogue FROM teloxide_dialogues
+            WHERE (?1 IS NULL OR dialogue_key > ?1) AND (expires_at IS NULL OR expires_at > ?2)
+            ORDER BY dialogue_key LIMIT ?3
+                    ",
                 )
+                .bind(after)
+                .bind(unix_millis())
+                .bind(LIST_PAGE_SIZE)
+                .fetch_all(&pool)
                 .await?;
+
+                let next = match rows.last() {
+                    Some((key, _)) if rows.len() as i64 == LIST_PAGE_SIZE => {
+                        Some(Some(key.clone()))
+                    }
+                    _ => None,
+                };
+                Ok::<_, Self::Error>(Some((rows, next)))
+            }
+        });
+
+        pages
+            .map_ok(move |rows| {
+                let this = Arc::clone(&self);
+                let rows = rows.into_iter().filter_map(|(key, d)| Some((K::decode(&key)?, d)));
+                stream::iter(rows).map(move |(key, d)| {
+                    let d =
+                        this.serializer.deserialize(&d).map_err(SqliteStorageError::SerdeError)?;
+                    Ok((key, d))
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        Box::pin(async move {
+            let (count,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM teloxide_dialogues WHERE expires_at IS NULL OR expires_at > \
+                 ?",
+            )
+            .bind(unix_millis())
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(count as u64)
+        })
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM teloxide_dialogues").execute(&self.pool).await?;
             Ok(())
         })
     }
+}
+
+impl<S, K, T> DataStorage<T> for SqliteStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + Serializer<T> + 'static,
+    T: Send + Serialize + DeserializeOwned + 'static,
+    <S as Serializer<T>>::Error: Debug + Display,
+{
+    type Error = SqliteStorageError<<S as Serializer<T>>::Error>;
+
+    fn get_data(
+        self: Arc<Self>,
+        key: DataKey,
+    ) -> BoxFuture<'static, Result<Option<T>, Self::Error>> {
+        Box::pin(async move {
+            self.create_data_table().await?;
+            let data: Option<(Vec<u8>,)> = sqlx::query_as(
+                "SELECT data FROM teloxide_data WHERE name = ? AND scope = ? AND id = ?",
+            )
+            .bind(key.name)
+            .bind(key.scope())
+            .bind(key.id())
+            .fetch_optional(&self.pool)
+            .await?;
+
+            data.map(|(data,)| {
+                self.serializer.deserialize(&data).map_err(SqliteStorageError::SerdeError)
+            })
+            .transpose()
+        })
+    }
 
-    fn get_dialogue(
+    fn set_data(
         self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        key: DataKey,
+        value: T,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
         Box::pin(async move {
-            get_dialogue(&self.pool, chat_id)
-                .await?
-                .map(|d| self.serializer.deserialize(&d).map_err(SqliteStorageError::SerdeError))
-                .transpose()
+            self.create_data_table().await?;
+            let data = self.serializer.serialize(&value).map_err(SqliteStorageError::SerdeError)?;
+            sqlx::query(
+                "
+INSERT INTO teloxide_data (name, scope, id, data) VALUES (?, ?, ?, ?)
+ON CONFLICT(name, scope, id) DO UPDATE SET data = excluded.data
+                ",
+            )
+            .bind(key.name)
+            .bind(key.scope())
+            .bind(key.id())
+            .bind(data)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn remove_data(self: Arc<Self>, key: DataKey) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_data_table().await?;
+            sqlx::query("DELETE FROM teloxide_data WHERE name = ? AND scope = ? AND id = ?")
+                .bind(key.name)
+                .bind(key.scope())
+                .bind(key.id())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+impl<S, K> DedupStorage for SqliteStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + 'static,
+{
+    type Error = SqliteStorageError<Infallible>;
+
+    fn mark_seen(
+        self: Arc<Self>,
+        bot_id: UserId,
+        update_id: UpdateId,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            self.create_updates_table().await?;
+            let now = unix_millis();
+            if update_id.0 % PRUNE_INTERVAL == 0 {
+                sqlx::query("DELETE FROM teloxide_updates WHERE seen_at < ?")
+                    .bind(now - PERSISTENT_WINDOW_MILLIS)
+                    .execute(&self.pool)
+                    .await?;
+            }
+
+            let inserted = sqlx::query(
+                "
+INSERT INTO teloxide_updates (bot_id, update_id, seen_at) VALUES (?, ?, ?)
+ON CONFLICT(bot_id, update_id) DO NOTHING
+                ",
+            )
+            .bind(bot_id.0 as i64)
+            .bind(i64::from(update_id.0))
+            .bind(now)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+            Ok(inserted == 1)
+        })
+    }
+}
+
+impl<S, K> JobStorage for SqliteStorage<S, K>
+where
+    K: Send + 'static,
+    S: Send + Sync + 'static,
+{
+    type Error = SqliteStorageError<Infallible>;
+
+    fn save_job(self: Arc<Self>, job: Job) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_jobs_table().await?;
+            sqlx::query(
+                "
+INSERT INTO teloxide_jobs (id, job) VALUES (?, ?)
+ON CONFLICT(id) DO UPDATE SET job = excluded.job
+                ",
+            )
+            .bind(job.id())
+            .bind(job.encode())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn remove_job(self: Arc<Self>, id: String) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.create_jobs_table().await?;
+            sqlx::query("DELETE FROM teloxide_jobs WHERE id = ?")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn load_jobs(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<Job>, Self::Error>> {
+        Box::pin(async move {
+            self.create_jobs_table().await?;
+            let jobs: Vec<(Vec<u8>,)> =
+                sqlx::query_as("SELECT job FROM teloxide_jobs").fetch_all(&self.pool).await?;
+            Ok(Job::decode_all(jobs.into_iter().map(|(job,)| job)))
         })
     }
 }
 
-async fn get_dialogue(
+async fn has_dialogues_column(pool: &SqlitePool, column: &str) -> Result<bool, sqlx::Error> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('teloxide_dialogues') WHERE name = ?",
+    )
+    .bind(column)
+    .fetch_one(pool)
+    .await?;
+    Ok(exists)
+}
+
+async fn update_dialogue(
     pool: &SqlitePool,
-    ChatId(chat_id): ChatId,
-) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    key: &str,
+    dialogue: Vec<u8>,
+    expires_at: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    pool.acquire()
+        .await?
+        .execute(
+            sqlx::query(
+                "
+            INSERT INTO teloxide_dialogues (dialogue_key, dialogue, expires_at) VALUES (?, ?, ?)
+            ON CONFLICT(dialogue_key) DO UPDATE
+            SET dialogue=excluded.dialogue, expires_at=excluded.expires_at,
+                version=teloxide_dialogues.version + 1
+                                ",
+            )
+            .bind(key)
+            .bind(dialogue)
+            .bind(expires_at),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn get_dialogue(pool: &SqlitePool, key: &str) -> Result<Option<(Vec<u8>, i64)>, sqlx::Error> {
     #[derive(sqlx::FromRow)]
     struct DialogueDbRow {
         dialogue: Vec<u8>,
+        expires_at: Option<i64>,
+        version: i64,
     }
 
-    let bytes = sqlx::query_as::<_, DialogueDbRow>(
-        "SELECT dialogue FROM teloxide_dialogues WHERE chat_id = ?",
+    let row = sqlx::query_as::<_, DialogueDbRow>(
+        "SELECT dialogue, expires_at, version FROM teloxide_dialogues WHERE dialogue_key = ?",
     )
-    .bind(chat_id)
+    .bind(key)
     .fetch_optional(pool)
-    .await?
-    .map(|r| r.dialogue);
+    .await?;
 
-    Ok(bytes)
+    match row {
+        Some(DialogueDbRow { expires_at, .. }) if is_expired(expires_at) => {
+            // Do not touch the row if it was concurrently updated with a new TTL.
+            sqlx::query(
+                "DELETE FROM teloxide_dialogues WHERE dialogue_key = ? AND expires_at <= ?",
+            )
+            .bind(key)
+            .bind(unix_millis())
+            .execute(pool)
+            .await?;
+            Ok(None)
+        }
+        row => Ok(row.map(|r| (r.dialogue, r.version))),
+    }
+}
+
+fn is_expired(expires_at: Option<i64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= unix_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatching::{
+        data::DataOwner,
+        dialogue::{serializer::Json, DialogueKey},
+    };
+
+    #[tokio::test]
+    async fn chat_id_migration() {
+        let dir = std::env::temp_dir().join(format!("teloxide_sqlite_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("storage.sqlite");
+        let path = path.to_str().unwrap();
+
+        // The schema of older versions of teloxide.
+        let pool = SqlitePool::connect(&format!("sqlite:{path}?mode=rwc")).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE teloxide_dialogues (chat_id BIGINT PRIMARY KEY, dialogue BLOB NOT NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        for (chat_id, dialogue) in [(-100, "\"A\""), (7, "\"B\"")] {
+            sqlx::query("INSERT INTO teloxide_dialogues VALUES (?, ?)")
+                .bind(chat_id)
+                .bind(dialogue.as_bytes())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        pool.close().await;
+
+        let storage: Arc<SqliteStorage<_, DialogueKey>> =
+            SqliteStorage::open(path, Json).await.unwrap();
+        let VersionedDialogue { dialogue, version } = Arc::clone(&storage)
+            .get_dialogue_versioned(DialogueKey::Chat(ChatId(-100)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((dialogue, version), ("A".to_owned(), DialogueVersion(1)));
+        drop(storage);
+
+        // Reopening doesn't migrate the table again.
+        let storage: Arc<SqliteStorage<_>> = SqliteStorage::open(path, Json).await.unwrap();
+        assert_eq!(
+            Arc::clone(&storage).get_dialogue(ChatId(7)).await.unwrap(),
+            Some("B".to_owned())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tables_on_first_use() {
+        async fn has_table(storage: &SqliteStorage<Json>, table: &str) -> bool {
+            sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+                .bind(table)
+                .fetch_optional(&storage.pool)
+                .await
+                .unwrap()
+                .is_some()
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("teloxide_sqlite_tables_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("storage.sqlite");
+        let storage = SqliteStorage::open(path.to_str().unwrap(), Json).await.unwrap();
+
+        assert!(has_table(&storage, "teloxide_dialogues").await);
+        assert!(!has_table(&storage, "teloxide_data").await);
+
+        let key = DataKey { name: "n", owner: DataOwner::Chat(ChatId(1)) };
+        assert_eq!(Arc::clone(&storage).get_data(key).await.unwrap(), None::<i32>);
+        assert!(has_table(&storage, "teloxide_data").await);
+
+        assert!(!has_table(&storage, "teloxide_updates").await);
+        assert!(Arc::clone(&storage).mark_seen(UserId(1), UpdateId(1)).await.unwrap());
+        assert!(has_table(&storage, "teloxide_updates").await);
+
+        assert!(!has_table(&storage, "teloxide_jobs").await);
+        assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap(), []);
+        assert!(has_table(&storage, "teloxide_jobs").await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
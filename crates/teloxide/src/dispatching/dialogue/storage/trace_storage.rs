@@ -1,9 +1,14 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::{Debug, Display},
+    sync::Arc,
+    time::Duration,
+};
 
-use futures::future::BoxFuture;
-use teloxide_core::types::ChatId;
+use futures::{future::BoxFuture, stream::BoxStream};
 
-use crate::dispatching::dialogue::Storage;
+use crate::dispatching::dialogue::{
+    DialogueVersion, EnumerableStorage, Storage, VersionedDialogue, VersionedStorage,
+};
 
 /// A dialogue storage wrapper which logs all actions performed on an underlying
 /// storage.
@@ -25,45 +30,131 @@ impl<S> TraceStorage<S> {
     }
 }
 
-impl<S, D> Storage<D> for TraceStorage<S>
+impl<S, D, K> Storage<D, K> for TraceStorage<S>
 where
     D: Debug,
-    S: Storage<D> + Send + Sync + 'static,
+    K: Display + Send + 'static,
+    S: Storage<D, K> + Send + Sync + 'static,
 {
-    type Error = <S as Storage<D>>::Error;
+    type Error = <S as Storage<D, K>>::Error;
 
-    fn remove_dialogue(
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        log::trace!("Removing dialogue #{}", key);
+        <S as Storage<D, K>>::remove_dialogue(self.inner.clone(), key)
+    }
+
+    fn update_dialogue(
         self: Arc<Self>,
-        chat_id: ChatId,
+        key: K,
+        dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
-        log::trace!("Removing dialogue #{}", chat_id);
-        <S as Storage<D>>::remove_dialogue(self.inner.clone(), chat_id)
+        Box::pin(async move {
+            let to = format!("{dialogue:#?}");
+            let key_str = key.to_string();
+            <S as Storage<D, K>>::update_dialogue(self.inner.clone(), key, dialogue).await?;
+            log::trace!("Updated a dialogue #{}: {:#?}", key_str, to);
+            Ok(())
+        })
     }
 
-    fn update_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        chat_id: ChatId,
+        key: K,
         dialogue: D,
+        ttl: Duration,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
         Box::pin(async move {
             let to = format!("{dialogue:#?}");
-            <S as Storage<D>>::update_dialogue(self.inner.clone(), chat_id, dialogue).await?;
-            log::trace!("Updated a dialogue #{}: {:#?}", chat_id, to);
+            let key_str = key.to_string();
+            <S as Storage<D, K>>::update_dialogue_with_ttl(self.inner.clone(), key, dialogue, ttl)
+                .await?;
+            log::trace!("Updated a dialogue #{} (expires in {:?}): {:#?}", key_str, ttl, to);
             Ok(())
         })
     }
 
-    fn get_dialogue(
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        log::trace!("Requested a dialogue #{}", key);
+        <S as Storage<D, K>>::get_dialogue(self.inner.clone(), key)
+    }
+}
+
+impl<S, D, K> VersionedStorage<D, K> for TraceStorage<S>
+where
+    D: Debug,
+    K: Display + Send + 'static,
+    S: VersionedStorage<D, K> + Send + Sync + 'static,
+{
+    fn get_dialogue_versioned(
+        self: Arc<Self>,
+        key: K,
+    ) -> BoxFuture<'static, Result<Option<VersionedDialogue<D>>, Self::Error>> {
+        log::trace!("Requested a versioned dialogue #{}", key);
+        <S as VersionedStorage<D, K>>::get_dialogue_versioned(self.inner.clone(), key)
+    }
+
+    fn update_dialogue_versioned(
         self: Arc<Self>,
-        chat_id: ChatId,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
-        log::trace!("Requested a dialogue #{}", chat_id);
-        <S as Storage<D>>::get_dialogue(self.inner.clone(), chat_id)
+        key: K,
+        dialogue: D,
+        expected: Option<DialogueVersion>,
+    ) -> BoxFuture<'static, Result<DialogueVersion, Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let to = format!("{dialogue:#?}");
+            let key_str = key.to_string();
+            let version = <S as VersionedStorage<D, K>>::update_dialogue_versioned(
+                self.inner.clone(),
+                key,
+                dialogue,
+                expected,
+            )
+            .await?;
+            log::trace!(
+                "Updated a dialogue #{} from version {:?} to {:?}: {:#?}",
+                key_str,
+                expected,
+                version,
+                to
+            );
+            Ok(version)
+        })
+    }
+
+    fn is_version_conflict(error: &Self::Error) -> bool {
+        S::is_version_conflict(error)
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for TraceStorage<S>
+where
+    D: Debug,
+    K: Display + Send + 'static,
+    S: EnumerableStorage<D, K> + Send + Sync + 'static,
+{
+    fn list_dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        log::trace!("Listing all dialogues");
+        <S as EnumerableStorage<D, K>>::list_dialogues(self.inner.clone())
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<u64, Self::Error>> {
+        log::trace!("Counting all dialogues");
+        <S as EnumerableStorage<D, K>>::count_dialogues(self.inner.clone())
+    }
+
+    fn clear_all(self: Arc<Self>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        log::trace!("Removing all dialogues");
+        <S as EnumerableStorage<D, K>>::clear_all(self.inner.clone())
     }
 }
@@ -1,22 +1,29 @@
 use crate::{
     dispatching::{
-        distribution::default_distribution_function, DefaultKey, DpHandlerDescription,
-        ShutdownToken,
+        dedup::DedupStorage,
+        distribution::default_distribution_function,
+        isolation::{HandlerFailure, Isolation},
+        jobs::{JobRunner, JobScheduler, JobStorage, Jobs},
+        lanes::{Lane, Scheduler},
+        middleware::{Middleware, Middlewares, Next},
+        workers::WorkerMetrics,
+        DefaultKey, DpHandlerDescription, ShutdownToken,
     },
     error_handlers::{ErrorHandler, LoggingErrorHandler},
     requests::{Request, Requester},
-    stop::StopToken,
-    types::{Update, UpdateKind},
+    stop::{mk_stop_token, StopToken},
+    types::{Update, UpdateId, UpdateKind, UserId},
     update_listeners::{self, UpdateListener},
 };
 
-use dptree::di::{DependencyMap, DependencySupplier};
+use dptree::di::DependencyMap;
 use either::Either;
 use futures::{
     future::{self, BoxFuture},
-    stream::FuturesUnordered,
+    stream::{self, FuturesUnordered},
     FutureExt as _, StreamExt as _,
 };
+use tokio::sync::{mpsc::error::SendError, Notify};
 use tokio_stream::wrappers::ReceiverStream;
 
 use std::{
@@ -24,12 +31,13 @@ use std::{
     fmt::Debug,
     future::Future,
     hash::Hash,
-    ops::{ControlFlow, Deref},
+    ops::Deref,
     pin::pin,
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 /// The builder for [`Dispatcher`].
@@ -37,7 +45,7 @@ use std::{
 /// See also: ["Dispatching or
 /// REPLs?"](../dispatching/index.html#dispatching-or-repls)
 pub struct DispatcherBuilder<R, Err, Key> {
-    bot: R,
+    bots: Vec<R>,
     dependencies: DependencyMap,
     handler: Arc<UpdateHandler<Err>>,
     default_handler: DefaultHandler,
@@ -45,7 +53,15 @@ pub struct DispatcherBuilder<R, Err, Key> {
     ctrlc_handler: bool,
     distribution_f: fn(&Update) -> Option<Key>,
     worker_queue_size: usize,
+    worker_idle_timeout: Option<Duration>,
+    max_workers: Option<usize>,
     stack_size: usize,
+    isolation: Isolation<Err>,
+    middlewares: Vec<Arc<dyn Middleware<Err> + Send + Sync>>,
+    dedup: Option<Dedup>,
+    lanes: Vec<Lane>,
+    max_in_flight: Option<usize>,
+    jobs: Option<Arc<dyn JobRunner<Err>>>,
 }
 
 impl<R, Err, Key> DispatcherBuilder<R, Err, Key>
@@ -73,6 +89,41 @@ where
         }
     }
 
+    /// Adds another bot that is served by the same handler tree.
+    ///
+    /// Updates of all bots are dispatched together, sharing workers, limits
+    /// and shutdown, while the bot that received an update and its
+    /// [`crate::types::Me`] are passed to handlers. Updates of different bots
+    /// are never grouped together (see [update grouping]).
+    ///
+    /// [update grouping]: DispatcherBuilder::distribution_function#update-grouping
+    ///
+    /// ## Examples
+    ///
+    /// ```no_run
+    /// use teloxide::{dispatching::Dispatcher, prelude::*, types::Me};
+    ///
+    /// # async fn run() {
+    /// let handler = Update::filter_message().endpoint(|bot: Bot, me: Me, msg: Message| async move {
+    ///     bot.send_message(msg.chat.id, format!("Hi from @{}!", me.username())).await?;
+    ///     Ok::<_, teloxide::RequestError>(())
+    /// });
+    ///
+    /// Dispatcher::builder(Bot::new("TOKEN_1"), handler)
+    ///     .bot(Bot::new("TOKEN_2"))
+    ///     .bot(Bot::new("TOKEN_3"))
+    ///     .enable_ctrlc_handler()
+    ///     .build()
+    ///     .dispatch()
+    ///     .await;
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn bot(mut self, bot: R) -> Self {
+        self.bots.push(bot);
+        self
+    }
+
     /// Specifies a handler that will be called on a handler error.
     ///
     /// By default, it is [`LoggingErrorHandler`].
@@ -81,6 +132,68 @@ where
         Self { error_handler: handler, ..self }
     }
 
+    /// Adds a middleware around the handling of every update.
+    ///
+    /// Middlewares run in the order they are added, i.e. the first one added
+    /// is the outermost. See [`Middleware`] for more information.
+    #[must_use]
+    pub fn middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware<Err> + Send + Sync + 'static,
+    {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Skips updates that were already received, e.g. re-sent by Telegram.
+    ///
+    /// Update IDs are recorded in `storage` before updates are handled, so an
+    /// update whose handling was interrupted by a crash is not handled again.
+    /// If `storage` fails, the error is logged and the update is handled. See
+    /// the [`dedup`] module for more information.
+    ///
+    /// By default, updates are not deduplicated.
+    ///
+    /// [`dedup`]: crate::dispatching::dedup
+    #[must_use]
+    pub fn dedup<S>(self, storage: Arc<S>) -> Self
+    where
+        S: DedupStorage + Send + Sync + 'static,
+    {
+        let dedup: Dedup = Arc::new(move |bot_id, update_id| {
+            let seen = Arc::clone(&storage).mark_seen(bot_id, update_id);
+            Box::pin(async move {
+                seen.await.unwrap_or_else(|err| {
+                    log::error!("Cannot deduplicate update {}: {:?}", update_id.0, err);
+                    true
+                })
+            })
+        });
+        Self { dedup: Some(dedup), ..self }
+    }
+
+    /// Runs jobs of `scheduler` with `handler`, see the [`jobs`] module.
+    ///
+    /// `handler` receives the same dependencies as the update handler, except
+    /// that a [`Job`] is passed instead of an [`Update`], and its errors are
+    /// passed to the error handler. The scheduler is also added to
+    /// dependencies of both handlers.
+    ///
+    /// By default, there are no jobs.
+    ///
+    /// [`jobs`]: crate::dispatching::jobs
+    /// [`Job`]: crate::dispatching::jobs::Job
+    #[must_use]
+    pub fn jobs<S>(self, scheduler: JobScheduler<S>, handler: UpdateHandler<Err>) -> Self
+    where
+        S: JobStorage + Send + Sync + 'static,
+        S::Error: Send,
+        Err: Send + Sync + 'static,
+    {
+        let jobs = Jobs { scheduler, handler: Arc::new(handler) };
+        Self { jobs: Some(Arc::new(jobs)), ..self }
+    }
+
     /// Specifies dependencies that can be used inside of handlers.
     ///
     /// By default, there is no dependencies.
@@ -106,6 +219,41 @@ where
         Self { worker_queue_size: size, ..self }
     }
 
+    /// Stops workers which haven't received updates for `timeout`.
+    ///
+    /// A worker is spawned for every chat (see [update grouping]) and, by
+    /// default, is stopped only when there are more workers than the number of
+    /// updates that were ever handled at once. Idle workers of a bot with many
+    /// chats can hold a lot of memory between spikes of activity; this stops
+    /// them regardless. A new worker is spawned on the next update of the chat.
+    ///
+    /// By default, there is no timeout.
+    ///
+    /// [update grouping]: DispatcherBuilder::distribution_function#update-grouping
+    #[must_use]
+    pub fn worker_idle_timeout(self, timeout: Duration) -> Self {
+        Self { worker_idle_timeout: Some(timeout), ..self }
+    }
+
+    /// Specifies the maximum number of workers.
+    ///
+    /// When an update needs a new worker and there are already `max` of them,
+    /// idle workers are stopped. If all of them are busy, the dispatcher stops
+    /// receiving updates until one of them finishes its updates, which bounds
+    /// the memory used by workers at the cost of throughput. This is reported
+    /// by [`WorkerMetrics::backpressure_waits`].
+    ///
+    /// By default, it's unlimited.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `max` is zero.
+    #[must_use]
+    pub fn max_workers(self, max: usize) -> Self {
+        assert_ne!(max, 0, "the limit must be positive");
+        Self { max_workers: Some(max), ..self }
+    }
+
     /// Specifies the stack size available to the dispatcher.
     ///
     /// By default, it's 8 * 1024 * 1024 bytes (8 MiB).
@@ -114,6 +262,71 @@ where
         Self { stack_size: size, ..self }
     }
 
+    /// Catches panics of handlers.
+    ///
+    /// A panic is reported to the [error handler] as
+    /// [`HandlerFailure::Panic`] and the worker that processes updates of the
+    /// same chat keeps running. By default, a panic takes down the worker.
+    ///
+    /// [error handler]: DispatcherBuilder::error_handler
+    #[must_use]
+    pub fn catch_panics(self) -> Self
+    where
+        Err: From<HandlerFailure>,
+    {
+        let isolation =
+            Isolation { catch_panics: true, into_error: Some(Err::from), ..self.isolation };
+        Self { isolation, ..self }
+    }
+
+    /// Specifies the maximum time an update can be handled for.
+    ///
+    /// Handling of an update that takes longer is cancelled and reported to
+    /// the [error handler] as [`HandlerFailure::Timeout`], so a hung handler
+    /// doesn't block the updates of the same chat. This includes the time
+    /// spent in the default handler, but not in the error handler.
+    ///
+    /// By default, there is no timeout.
+    ///
+    /// [error handler]: DispatcherBuilder::error_handler
+    #[must_use]
+    pub fn update_timeout(self, timeout: Duration) -> Self
+    where
+        Err: From<HandlerFailure>,
+    {
+        let isolation =
+            Isolation { timeout: Some(timeout), into_error: Some(Err::from), ..self.isolation };
+        Self { isolation, ..self }
+    }
+
+    /// Adds a lane with its own priority and concurrency limit.
+    ///
+    /// An update belongs to the first added lane that matches it. See
+    /// [`Lane`] for more information.
+    #[must_use]
+    pub fn lane(mut self, lane: Lane) -> Self {
+        self.lanes.push(lane);
+        self
+    }
+
+    /// Specifies the maximum number of updates handled at once.
+    ///
+    /// When the limit is reached, updates wait for their turn, and updates of
+    /// lanes with a higher [priority] are handled first.
+    ///
+    /// By default, it's unlimited.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `max` is zero.
+    ///
+    /// [priority]: Lane::priority
+    #[must_use]
+    pub fn max_in_flight(self, max: usize) -> Self {
+        assert_ne!(max, 0, "the limit must be positive");
+        Self { max_in_flight: Some(max), ..self }
+    }
+
     /// Specifies the distribution function that decides how updates are grouped
     /// before execution.
     ///
@@ -178,7 +391,7 @@ where
         K: Hash + Eq,
     {
         let Self {
-            bot,
+            bots,
             dependencies,
             handler,
             default_handler,
@@ -186,11 +399,19 @@ where
             ctrlc_handler,
             distribution_f: _,
             worker_queue_size,
+            worker_idle_timeout,
+            max_workers,
             stack_size,
+            isolation,
+            middlewares,
+            dedup,
+            lanes,
+            max_in_flight,
+            jobs,
         } = self;
 
         DispatcherBuilder {
-            bot,
+            bots,
             dependencies,
             handler,
             default_handler,
@@ -198,7 +419,15 @@ where
             ctrlc_handler,
             distribution_f: f,
             worker_queue_size,
+            worker_idle_timeout,
+            max_workers,
             stack_size,
+            isolation,
+            middlewares,
+            dedup,
+            lanes,
+            max_in_flight,
+            jobs,
         }
     }
 
@@ -206,22 +435,31 @@ where
     #[must_use]
     pub fn build(self) -> Dispatcher<R, Err, Key> {
         let Self {
-            bot,
+            bots,
             dependencies,
             handler,
             default_handler,
             error_handler,
             distribution_f,
             worker_queue_size,
+            worker_idle_timeout,
+            max_workers,
             ctrlc_handler,
             stack_size,
+            isolation,
+            middlewares,
+            dedup,
+            lanes,
+            max_in_flight,
+            jobs,
         } = self;
 
         // If the `ctrlc_handler` feature is not enabled, don't emit a warning.
         let _ = ctrlc_handler;
 
         let dp = Dispatcher {
-            bot,
+            bots,
+            contexts: Vec::new(),
             dependencies,
             handler,
             default_handler,
@@ -229,11 +467,20 @@ where
             state: ShutdownToken::new(),
             distribution_f,
             worker_queue_size,
+            worker_idle_timeout,
+            max_workers,
             stack_size,
+            isolation,
+            middlewares: middlewares.into(),
+            dedup,
+            scheduler: Arc::new(Scheduler::new(lanes, max_in_flight)),
+            jobs,
             workers: HashMap::new(),
             default_worker: None,
             current_number_of_active_workers: Default::default(),
             max_number_of_active_workers: Default::default(),
+            worker_metrics: WorkerMetrics::default(),
+            worker_idle: Arc::new(Notify::new()),
         };
 
         #[cfg(feature = "ctrlc_handler")]
@@ -264,19 +511,32 @@ where
 ///
 /// [`distribution_function`]: DispatcherBuilder::distribution_function
 pub struct Dispatcher<R, Err, Key> {
-    bot: R,
+    bots: Vec<R>,
     dependencies: DependencyMap,
+    // Set up for each bot when dispatching starts.
+    contexts: Vec<BotContext>,
 
     handler: Arc<UpdateHandler<Err>>,
     default_handler: DefaultHandler,
 
     distribution_f: fn(&Update) -> Option<Key>,
     worker_queue_size: usize,
+    worker_idle_timeout: Option<Duration>,
+    max_workers: Option<usize>,
     stack_size: usize,
+    isolation: Isolation<Err>,
+    middlewares: Middlewares<Err>,
+    dedup: Option<Dedup>,
+    scheduler: Arc<Scheduler>,
+    jobs: Option<Arc<dyn JobRunner<Err>>>,
     current_number_of_active_workers: Arc<AtomicU32>,
     max_number_of_active_workers: Arc<AtomicU32>,
-    // Tokio TX channel parts associated with chat IDs that consume updates sequentially.
-    workers: HashMap<Key, Worker>,
+    worker_metrics: WorkerMetrics,
+    // Notified each time a worker becomes idle.
+    worker_idle: Arc<Notify>,
+    // Tokio TX channel parts associated with bots and chat IDs that consume updates
+    // sequentially.
+    workers: HashMap<(usize, Key), Worker>,
     // The default TX part that consume updates concurrently.
     default_worker: Option<Worker>,
 
@@ -286,19 +546,26 @@ pub struct Dispatcher<R, Err, Key> {
 }
 
 struct Worker {
-    tx: tokio::sync::mpsc::Sender<Update>,
+    tx: tokio::sync::mpsc::Sender<(Update, Arc<DependencyMap>)>,
     handle: tokio::task::JoinHandle<()>,
     is_waiting: Arc<AtomicBool>,
 }
 
-// TODO: it is allowed to return message as response on telegram request in
-// webhooks, so we can allow this too. See more there: https://core.telegram.org/bots/api#making-requests-when-getting-updates
+struct BotContext {
+    id: UserId,
+    // The dependencies with the bot and its `Me` added.
+    deps: Arc<DependencyMap>,
+}
 
 /// A handler that processes updates from Telegram.
 pub type UpdateHandler<Err> =
     dptree::Handler<'static, DependencyMap, Result<(), Err>, DpHandlerDescription>;
 
-type DefaultHandler = Arc<dyn Fn(Arc<Update>) -> BoxFuture<'static, ()> + Send + Sync>;
+pub(crate) type DefaultHandler = Arc<dyn Fn(Arc<Update>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Records an update ID of a bot, returning whether it was seen for the first
+/// time.
+type Dedup = Arc<dyn Fn(UserId, UpdateId) -> BoxFuture<'static, bool> + Send + Sync>;
 
 impl<R, Err> Dispatcher<R, Err, DefaultKey>
 where
@@ -315,7 +582,7 @@ where
         const DEFAULT_STACK_SIZE: usize = 8 * 1024 * 1024;
 
         DispatcherBuilder {
-            bot,
+            bots: vec![bot],
             dependencies: DependencyMap::new(),
             handler: Arc::new(handler),
             default_handler: Arc::new(|upd| {
@@ -325,8 +592,16 @@ where
             error_handler: LoggingErrorHandler::new(),
             ctrlc_handler: false,
             worker_queue_size: DEFAULT_WORKER_QUEUE_SIZE,
+            worker_idle_timeout: None,
+            max_workers: None,
             distribution_f: default_distribution_function,
             stack_size: DEFAULT_STACK_SIZE,
+            isolation: Isolation::new(),
+            middlewares: Vec::new(),
+            dedup: None,
+            lanes: Vec::new(),
+            max_in_flight: None,
+            jobs: None,
         }
     }
 }
@@ -340,15 +615,18 @@ where
     /// Starts your bot with the default parameters.
     ///
     /// The default parameters are a long polling update listener and log all
-    /// errors produced by this listener.
+    /// errors produced by this listener. If there are several bots (see
+    /// [`DispatcherBuilder::bot`]), each of them gets its own listener.
     ///
     /// Each time a handler is invoked, [`Dispatcher`] adds the following
     /// dependencies (in addition to those passed to
     /// [`DispatcherBuilder::dependencies`]):
     ///
-    ///  - Your bot passed to [`Dispatcher::builder`];
+    ///  - Your bot passed to [`Dispatcher::builder`] or
+    ///    [`DispatcherBuilder::bot`] that received the update;
     ///  - An update from Telegram;
-    ///  - [`crate::types::Me`] (can be used in [`HandlerExt::filter_command`]).
+    ///  - [`crate::types::Me`] of the bot (can be used in
+    ///    [`HandlerExt::filter_command`]).
     ///
     /// [`HandlerExt::filter_command`]: crate::dispatching::HandlerExt::filter_command
     pub async fn dispatch(&mut self)
@@ -356,17 +634,25 @@ where
         R: Requester + Clone,
         <R as Requester>::GetUpdates: Send,
     {
-        let listener = update_listeners::polling_default(self.bot.clone()).await;
+        let mut listeners = Vec::with_capacity(self.bots.len());
+        for bot in &self.bots {
+            listeners.push(update_listeners::polling_default(bot.clone()).await);
+        }
         let error_handler =
             LoggingErrorHandler::with_custom_text("An error from the update listener");
 
-        self.dispatch_with_listener(listener, error_handler).await;
+        self.dispatch_with_listeners(listeners, error_handler).await;
     }
 
     /// Starts your bot with custom `update_listener` and
     /// `update_listener_error_handler`.
     ///
     /// This method adds the same dependencies as [`Dispatcher::dispatch`].
+    ///
+    /// ## Panics
+    ///
+    /// Panics if there are several bots, use
+    /// [`Dispatcher::dispatch_with_listeners`] instead.
     pub async fn dispatch_with_listener<'a, UListener, Eh>(
         &'a mut self,
         update_listener: UListener,
@@ -388,9 +674,14 @@ where
     /// `update_listener_error_handler`.
     ///
     /// This method adds the same dependencies as [`Dispatcher::dispatch`].
+    ///
+    /// ## Panics
+    ///
+    /// Panics if there are several bots, use
+    /// [`Dispatcher::try_dispatch_with_listeners`] instead.
     pub async fn try_dispatch_with_listener<'a, UListener, Eh>(
         &'a mut self,
-        mut update_listener: UListener,
+        update_listener: UListener,
         update_listener_error_handler: Arc<Eh>,
     ) -> Result<(), R::Err>
     where
@@ -398,17 +689,82 @@ where
         Eh: ErrorHandler<UListener::Err> + Send + Sync + 'a,
         UListener::Err: Debug,
     {
-        // FIXME: there should be a way to check if dependency is already inserted
-        let me = self.bot.get_me().send().await?;
-        self.dependencies.insert(me);
-        self.dependencies.insert(self.bot.clone());
+        self.try_dispatch_with_listeners(vec![update_listener], update_listener_error_handler).await
+    }
+
+    /// Starts your bots with custom `update_listeners`, one per bot, and
+    /// `update_listener_error_handler`.
+    ///
+    /// The listeners correspond to the bots in the order they were added: the
+    /// first one receives updates of the bot passed to [`Dispatcher::builder`],
+    /// the rest receive updates of the bots passed to
+    /// [`DispatcherBuilder::bot`].
+    ///
+    /// This method adds the same dependencies as [`Dispatcher::dispatch`].
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the number of listeners differs from the number of bots.
+    pub async fn dispatch_with_listeners<'a, UListener, Eh>(
+        &'a mut self,
+        update_listeners: Vec<UListener>,
+        update_listener_error_handler: Arc<Eh>,
+    ) where
+        UListener: UpdateListener + Send + 'a,
+        Eh: ErrorHandler<UListener::Err> + Send + Sync + 'a,
+        UListener::Err: Debug,
+    {
+        self.try_dispatch_with_listeners(update_listeners, update_listener_error_handler)
+            .await
+            .expect("Couldn't prepare dispatching context")
+    }
+
+    /// Same as `dispatch_with_listeners` but returns a `Err(_)` instead of
+    /// panicking when the initial telegram api call (`get_me`) fails.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the number of listeners differs from the number of bots.
+    pub async fn try_dispatch_with_listeners<'a, UListener, Eh>(
+        &'a mut self,
+        mut update_listeners: Vec<UListener>,
+        update_listener_error_handler: Arc<Eh>,
+    ) -> Result<(), R::Err>
+    where
+        UListener: UpdateListener + Send + 'a,
+        Eh: ErrorHandler<UListener::Err> + Send + Sync + 'a,
+        UListener::Err: Debug,
+    {
+        assert_eq!(
+            update_listeners.len(),
+            self.bots.len(),
+            "there must be exactly one update listener per bot"
+        );
+
+        self.contexts.clear();
+        for bot in &self.bots {
+            let me = bot.get_me().send().await?;
+            let id = me.id;
+
+            let mut deps = self.dependencies.clone();
+            if let Some(jobs) = &self.jobs {
+                jobs.insert_into(&mut deps);
+            }
+            deps.insert(me);
+            deps.insert(bot.clone());
+            self.contexts.push(BotContext { id, deps: Arc::new(deps) });
+        }
 
         let description = self.handler.description();
         let allowed_updates = description.allowed_updates();
         log::debug!("hinting allowed updates: {:?}", allowed_updates);
-        update_listener.hint_allowed_updates(&mut allowed_updates.into_iter());
-
-        let stop_token = Some(update_listener.stop_token());
+        let stop_tokens = update_listeners
+            .iter_mut()
+            .map(|listener| {
+                listener.hint_allowed_updates(&mut allowed_updates.iter().copied());
+                listener.stop_token()
+            })
+            .collect();
 
         // We create a new Tokio runtime in order to set the correct stack size. We do
         // it a scoped thread because Tokio runtimes cannot be nested. We need a scoped
@@ -425,9 +781,9 @@ where
                     .unwrap();
 
                 runtime.block_on(self.start_listening(
-                    update_listener,
+                    update_listeners,
                     update_listener_error_handler,
-                    stop_token,
+                    stop_tokens,
                 ));
             });
         });
@@ -436,9 +792,9 @@ where
 
     async fn start_listening<'a, UListener, Eh>(
         &'a mut self,
-        mut update_listener: UListener,
+        mut update_listeners: Vec<UListener>,
         update_listener_error_handler: Arc<Eh>,
-        mut stop_token: Option<StopToken>,
+        mut stop_tokens: Vec<StopToken>,
     ) where
         UListener: UpdateListener + 'a,
         Eh: ErrorHandler<UListener::Err> + 'a,
@@ -446,8 +802,19 @@ where
     {
         self.state.start_dispatching();
 
-        let stream = update_listener.as_stream();
-        tokio::pin!(stream);
+        let jobs = self.jobs.as_ref().map(|jobs| {
+            let (token, flag) = mk_stop_token();
+            let bots = self.contexts.iter().map(|ctx| (ctx.id, Arc::clone(&ctx.deps))).collect();
+            (token, tokio::spawn(jobs.run(bots, Arc::clone(&self.error_handler), flag)))
+        });
+
+        // Updates are tagged with the index of the bot that received them.
+        let mut stream = stream::select_all(
+            update_listeners
+                .iter_mut()
+                .enumerate()
+                .map(|(bot, listener)| listener.as_stream().map(move |upd| (bot, upd)).boxed()),
+        );
 
         loop {
             self.remove_inactive_workers_if_needed().await;
@@ -459,23 +826,28 @@ where
 
             match res {
                 Either::Left(upd) => match upd {
-                    Some(upd) => self.process_update(upd, &update_listener_error_handler).await,
+                    Some((bot, upd)) => {
+                        self.process_update(bot, upd, &update_listener_error_handler).await
+                    }
                     None => break,
                 },
                 Either::Right(()) => {
-                    if self.state.is_shutting_down() {
-                        if let Some(token) = stop_token.take() {
-                            log::debug!("Start shutting down dispatching...");
-                            token.stop();
-                        }
+                    if self.state.is_shutting_down() && !stop_tokens.is_empty() {
+                        log::debug!("Start shutting down dispatching...");
+                        stop_tokens.drain(..).for_each(|token| token.stop());
                     }
                 }
             }
         }
 
+        if let Some((token, handle)) = jobs {
+            token.stop();
+            handle.await.expect("Failed to wait for jobs.");
+        }
+
         self.workers
             .drain()
-            .map(|(_chat_id, worker)| worker.handle)
+            .map(|(_key, worker)| worker.handle)
             .chain(self.default_worker.take().map(|worker| worker.handle))
             .collect::<FuturesUnordered<_>>()
             .for_each(|res| async {
@@ -488,6 +860,7 @@ where
 
     async fn process_update<LErr, LErrHandler>(
         &mut self,
+        bot: usize,
         update: Result<Update, LErr>,
         err_handler: &Arc<LErrHandler>,
     ) where
@@ -505,46 +878,111 @@ where
                     return;
                 }
 
-                let worker = match (self.distribution_f)(&upd) {
-                    Some(key) => self.workers.entry(key).or_insert_with(|| {
-                        let deps = self.dependencies.clone();
-                        let handler = Arc::clone(&self.handler);
-                        let default_handler = Arc::clone(&self.default_handler);
-                        let error_handler = Arc::clone(&self.error_handler);
+                let BotContext { id: bot_id, deps } = &self.contexts[bot];
+                let deps = Arc::clone(deps);
 
-                        spawn_worker(
-                            deps,
-                            handler,
-                            default_handler,
-                            error_handler,
-                            Arc::clone(&self.current_number_of_active_workers),
-                            Arc::clone(&self.max_number_of_active_workers),
-                            self.worker_queue_size,
-                        )
-                    }),
-                    None => self.default_worker.get_or_insert_with(|| {
-                        let deps = self.dependencies.clone();
+                if let Some(dedup) = &self.dedup {
+                    if !dedup(*bot_id, upd.id).await {
+                        log::debug!("Skipping duplicate update {}", upd.id.0);
+                        return;
+                    }
+                }
+
+                let Some(key) = (self.distribution_f)(&upd) else {
+                    let worker = self.default_worker.get_or_insert_with(|| {
                         let handler = Arc::clone(&self.handler);
                         let default_handler = Arc::clone(&self.default_handler);
                         let error_handler = Arc::clone(&self.error_handler);
 
                         spawn_default_worker(
-                            deps,
+                            Arc::clone(&self.middlewares),
                             handler,
                             default_handler,
                             error_handler,
                             self.worker_queue_size,
+                            self.isolation,
+                            Arc::clone(&self.scheduler),
                         )
-                    }),
+                    });
+                    worker.tx.send((upd, deps)).await.expect("TX is dead");
+                    return;
                 };
 
-                worker.tx.send(upd).await.expect("TX is dead");
+                let key = (bot, key);
+                let mut message = (upd, deps);
+                loop {
+                    if !self.workers.contains_key(&key) {
+                        self.make_room_for_worker().await;
+                        let worker = self.spawn_worker();
+                        self.workers.insert(key.clone(), worker);
+                    }
+
+                    match self.workers[&key].tx.send(message).await {
+                        Ok(()) => break,
+                        // The worker has stopped after being idle for too long. Let it
+                        // handle the updates sent before that, so that updates of the
+                        // chat are still handled in order, and start a new one.
+                        Err(SendError(returned)) => {
+                            message = returned;
+                            let worker = self.workers.remove(&key).unwrap();
+                            let _ = worker.handle.await;
+                        }
+                    }
+                }
             }
             Err(err) => err_handler.clone().handle_error(err).await,
         }
     }
 
+    fn spawn_worker(&self) -> Worker {
+        spawn_worker(
+            Arc::clone(&self.middlewares),
+            Arc::clone(&self.handler),
+            Arc::clone(&self.default_handler),
+            Arc::clone(&self.error_handler),
+            Arc::clone(&self.current_number_of_active_workers),
+            Arc::clone(&self.max_number_of_active_workers),
+            self.worker_queue_size,
+            self.isolation,
+            Arc::clone(&self.scheduler),
+            WorkerLifecycle {
+                idle_timeout: self.worker_idle_timeout,
+                metrics: self.worker_metrics.clone(),
+                idle: Arc::clone(&self.worker_idle),
+            },
+        )
+    }
+
+    /// Waits until a new worker fits into [`DispatcherBuilder::max_workers`].
+    async fn make_room_for_worker(&mut self) {
+        let Some(max) = self.max_workers else { return };
+        if self.workers.len() < max {
+            return;
+        }
+
+        self.remove_inactive_workers().await;
+        if self.workers.len() < max {
+            return;
+        }
+
+        log::debug!("All {max} workers are busy, waiting for one of them to become idle");
+        self.worker_metrics.backpressure_wait();
+        while self.workers.len() >= max {
+            self.worker_idle.notified().await;
+            self.remove_inactive_workers().await;
+        }
+    }
+
     async fn remove_inactive_workers_if_needed(&mut self) {
+        let workers = self.workers.len();
+
+        // Workers that stopped after being idle are removed lazily, once they
+        // make up a half of all workers.
+        if self.worker_idle_timeout.is_some() && workers as u64 > 2 * self.worker_metrics.live() {
+            self.workers
+                .retain(|_, worker| !(worker.tx.is_closed() && worker.handle.is_finished()));
+        }
+
         let workers = self.workers.len();
         let max = self.max_number_of_active_workers.load(Ordering::Relaxed) as usize;
 
@@ -561,8 +999,9 @@ where
             .workers
             .iter()
             .filter(|(_, worker)| {
-                worker.tx.capacity() == self.worker_queue_size
-                    && worker.is_waiting.load(Ordering::Relaxed)
+                worker.tx.is_closed()
+                    || (worker.tx.capacity() == self.worker_queue_size
+                        && worker.is_waiting.load(Ordering::Relaxed))
             })
             .map(|(k, _)| k)
             .cloned()
@@ -571,6 +1010,11 @@ where
             .map(|key| {
                 let Worker { tx, handle, .. } = self.workers.remove(&key).unwrap();
 
+                // Workers with a closed channel have already stopped by themselves.
+                if !tx.is_closed() {
+                    self.worker_metrics.worker_evicted();
+                }
+
                 // Close channel, worker should stop almost immediately
                 // (it's been supposedly waiting on the channel)
                 drop(tx);
@@ -586,6 +1030,14 @@ where
         }
     }
 
+    /// Returns counters of the per-chat workers, which can be read while
+    /// dispatching.
+    ///
+    /// See [`WorkerMetrics`] for more information.
+    pub fn worker_metrics(&self) -> WorkerMetrics {
+        self.worker_metrics.clone()
+    }
+
     /// Returns a shutdown token, which can later be used to
     /// [`ShutdownToken::shutdown`].
     pub fn shutdown_token(&self) -> ShutdownToken {
@@ -594,6 +1046,16 @@ where
 }
 
 impl<R, Err, Key> Dispatcher<R, Err, Key> {
+    #[cfg(feature = "testing")]
+    pub(crate) fn bots(&self) -> &[R] {
+        &self.bots
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn dependencies(&self) -> &DependencyMap {
+        &self.dependencies
+    }
+
     #[cfg(feature = "ctrlc_handler")]
     fn setup_ctrlc_handler_inner(&mut self) {
         let token = self.state.clone();
@@ -616,14 +1078,18 @@ impl<R, Err, Key> Dispatcher<R, Err, Key> {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_worker<Err>(
-    deps: DependencyMap,
+    middlewares: Middlewares<Err>,
     handler: Arc<UpdateHandler<Err>>,
     default_handler: DefaultHandler,
     error_handler: Arc<dyn ErrorHandler<Err> + Send + Sync>,
     current_number_of_active_workers: Arc<AtomicU32>,
     max_number_of_active_workers: Arc<AtomicU32>,
     queue_size: usize,
+    isolation: Isolation<Err>,
+    scheduler: Arc<Scheduler>,
+    lifecycle: WorkerLifecycle,
 ) -> Worker
 where
     Err: Send + Sync + 'static,
@@ -631,54 +1097,88 @@ where
     let (tx, mut rx) = tokio::sync::mpsc::channel(queue_size);
     let is_waiting = Arc::new(AtomicBool::new(true));
     let is_waiting_local = Arc::clone(&is_waiting);
-
-    let deps = Arc::new(deps);
+    let WorkerLifecycle { idle_timeout, metrics, idle } = lifecycle;
+    let live = metrics.worker_started();
 
     let handle = tokio::spawn(async move {
-        while let Some(update) = rx.recv().await {
+        let _live = live;
+        let mut closed = false;
+
+        loop {
+            let next = match idle_timeout {
+                Some(timeout) if !closed => match tokio::time::timeout(timeout, rx.recv()).await {
+                    Ok(next) => next,
+                    Err(_elapsed) => {
+                        // Updates sent before closing are still received.
+                        rx.close();
+                        closed = true;
+                        metrics.worker_evicted();
+                        continue;
+                    }
+                },
+                _ => rx.recv().await,
+            };
+            let Some((update, deps)) = next else { break };
+
             is_waiting_local.store(false, Ordering::Relaxed);
             {
                 let current = current_number_of_active_workers.fetch_add(1, Ordering::Relaxed) + 1;
                 max_number_of_active_workers.fetch_max(current, Ordering::Relaxed);
             }
 
-            let deps = Arc::clone(&deps);
-            let handler = Arc::clone(&handler);
-            let default_handler = Arc::clone(&default_handler);
+            let next = Next::new(
+                Arc::clone(&middlewares),
+                Arc::clone(&handler),
+                Arc::clone(&default_handler),
+            );
             let error_handler = Arc::clone(&error_handler);
 
-            handle_update(update, deps, handler, default_handler, error_handler).await;
+            let scheduler = Arc::clone(&scheduler);
+            handle_update(update, deps, next, error_handler, isolation, scheduler).await;
 
             current_number_of_active_workers.fetch_sub(1, Ordering::Relaxed);
             is_waiting_local.store(true, Ordering::Relaxed);
+            idle.notify_one();
         }
     });
 
     Worker { tx, handle, is_waiting }
 }
 
+/// How a worker is stopped and accounted for.
+#[derive(Default)]
+struct WorkerLifecycle {
+    idle_timeout: Option<Duration>,
+    metrics: WorkerMetrics,
+    idle: Arc<Notify>,
+}
+
 fn spawn_default_worker<Err>(
-    deps: DependencyMap,
+    middlewares: Middlewares<Err>,
     handler: Arc<UpdateHandler<Err>>,
     default_handler: DefaultHandler,
     error_handler: Arc<dyn ErrorHandler<Err> + Send + Sync>,
     queue_size: usize,
+    isolation: Isolation<Err>,
+    scheduler: Arc<Scheduler>,
 ) -> Worker
 where
     Err: Send + Sync + 'static,
 {
     let (tx, rx) = tokio::sync::mpsc::channel(queue_size);
 
-    let deps = Arc::new(deps);
-
-    let handle = tokio::spawn(ReceiverStream::new(rx).for_each_concurrent(None, move |update| {
-        let deps = Arc::clone(&deps);
-        let handler = Arc::clone(&handler);
-        let default_handler = Arc::clone(&default_handler);
-        let error_handler = Arc::clone(&error_handler);
+    let handle =
+        tokio::spawn(ReceiverStream::new(rx).for_each_concurrent(None, move |(update, deps)| {
+            let next = Next::new(
+                Arc::clone(&middlewares),
+                Arc::clone(&handler),
+                Arc::clone(&default_handler),
+            );
+            let error_handler = Arc::clone(&error_handler);
+            let scheduler = Arc::clone(&scheduler);
 
-        handle_update(update, deps, handler, default_handler, error_handler)
-    }));
+            handle_update(update, deps, next, error_handler, isolation, scheduler)
+        }));
 
     Worker { tx, handle, is_waiting: Arc::new(AtomicBool::new(true)) }
 }
@@ -686,22 +1186,21 @@ where
 async fn handle_update<Err>(
     update: Update,
     deps: Arc<DependencyMap>,
-    handler: Arc<UpdateHandler<Err>>,
-    default_handler: DefaultHandler,
+    next: Next<Err>,
     error_handler: Arc<dyn ErrorHandler<Err> + Send + Sync>,
+    isolation: Isolation<Err>,
+    scheduler: Arc<Scheduler>,
 ) where
     Err: Send + Sync + 'static,
 {
+    let _permit = scheduler.acquire(&update).await;
+
+    let update_id = update.id;
     let mut deps = deps.deref().clone();
     deps.insert(update);
 
-    match handler.dispatch(deps).await {
-        ControlFlow::Break(Ok(())) => {}
-        ControlFlow::Break(Err(err)) => error_handler.clone().handle_error(err).await,
-        ControlFlow::Continue(deps) => {
-            let update = deps.get();
-            (default_handler)(update).await;
-        }
+    if let Err(err) = isolation.run(update_id, next.run(deps)).await {
+        error_handler.clone().handle_error(err).await;
     }
 }
 
@@ -733,4 +1232,213 @@ mod tests {
         .await
         .unwrap();
     }
+
+    #[tokio::test]
+    async fn test_isolation() {
+        use std::time::Duration;
+
+        use crate::types::UpdateId;
+
+        let handler = dptree::endpoint(|upd: Update| async move {
+            match upd.id.0 {
+                1 => panic!("oops"),
+                2 => std::future::pending().await,
+                _ => Ok(()),
+            }
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let error_handler = Arc::new(move |err: HandlerFailure| {
+            tx.send(err).unwrap();
+            async {}
+        });
+        let isolation = Isolation {
+            catch_panics: true,
+            timeout: Some(Duration::from_millis(100)),
+            into_error: Some(std::convert::identity),
+        };
+
+        let worker = spawn_worker(
+            Arc::new([]),
+            Arc::new(handler),
+            Arc::new(|_| Box::pin(async {})),
+            error_handler,
+            Default::default(),
+            Default::default(),
+            8,
+            isolation,
+            Arc::new(Scheduler::new(Vec::new(), None)),
+            Default::default(),
+        );
+        for id in 1..=3 {
+            let update = Update { id: UpdateId(id), kind: UpdateKind::Error(Default::default()) };
+            worker.tx.send((update, Arc::new(DependencyMap::new()))).await.unwrap();
+        }
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(HandlerFailure::Panic { update_id: UpdateId(1), message }) if message == "oops"
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(HandlerFailure::Timeout { update_id: UpdateId(2), .. })
+        ));
+
+        // The worker is still alive.
+        drop(worker.tx);
+        worker.handle.await.unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_worker_idle_timeout() {
+        use crate::types::UpdateId;
+
+        let metrics = WorkerMetrics::default();
+        let worker = spawn_worker(
+            Arc::new([]),
+            Arc::new(dptree::endpoint(|| async { Ok::<_, Infallible>(()) })),
+            Arc::new(|_| Box::pin(async {})),
+            LoggingErrorHandler::new(),
+            Default::default(),
+            Default::default(),
+            8,
+            Isolation::new(),
+            Arc::new(Scheduler::new(Vec::new(), None)),
+            WorkerLifecycle {
+                idle_timeout: Some(Duration::from_millis(50)),
+                metrics: metrics.clone(),
+                idle: Arc::new(Notify::new()),
+            },
+        );
+        let update = Update { id: UpdateId(1), kind: UpdateKind::Error(Default::default()) };
+        worker.tx.send((update, Arc::new(DependencyMap::new()))).await.unwrap();
+        assert_eq!(metrics.live(), 1);
+
+        worker.handle.await.unwrap();
+        assert!(worker.tx.is_closed());
+        assert_eq!((metrics.created(), metrics.evicted(), metrics.live()), (1, 1, 0));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_max_workers() {
+        use std::sync::atomic::AtomicUsize;
+
+        use crate::{
+            error_handlers::IgnoringErrorHandler,
+            testing::{MockBot, MockMessage},
+            types::ChatId,
+            update_listeners::Replay,
+        };
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handler = dptree::endpoint(|handled: Arc<AtomicUsize>| async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            handled.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, Infallible>(())
+        });
+        let mut dp = Dispatcher::builder(MockBot::new(), handler)
+            .dependencies(dptree::deps![Arc::clone(&handled)])
+            .max_workers(2)
+            .build();
+        let metrics = dp.worker_metrics();
+
+        let updates = (1..=6).map(|chat| MockMessage::text("hi").chat_id(ChatId(chat)).into());
+        dp.dispatch_with_listener(Replay::new(updates), IgnoringErrorHandler::new()).await;
+
+        assert_eq!(handled.load(Ordering::Relaxed), 6);
+        assert_eq!(metrics.created(), 6);
+        assert_eq!(metrics.evicted(), 4);
+        assert_eq!(metrics.live(), 0);
+        assert!(metrics.backpressure_waits() > 0);
+    }
+
+    #[cfg(feature = "testing")]
+    fn mock_bot(id: u64, username: &str) -> crate::testing::MockBot {
+        let mut me = crate::testing::MockBot::new().me();
+        me.user.id = UserId(id);
+        me.user.username = Some(username.to_owned());
+        crate::testing::MockBot::with_me(me)
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_several_bots() {
+        use std::{sync::Mutex, time::Duration};
+
+        use tokio::sync::Notify;
+
+        use crate::{
+            error_handlers::IgnoringErrorHandler,
+            testing::{MockBot, MockMessage},
+            types::{ChatId, Me},
+            update_listeners::Replay,
+        };
+
+        // The updates handled, along with the bots and `Me` they were handled with.
+        type Seen = Arc<Mutex<Vec<(UpdateId, UserId, String, bool)>>>;
+
+        let seen = Seen::default();
+        let second_handled = Arc::new(Notify::new());
+        let handler = dptree::endpoint(
+            |bot: MockBot, me: Me, upd: Update, seen: Seen, second_handled: Arc<Notify>| async move {
+                let waited = match me.user.id {
+                    // The update of the second bot is from the same chat, so it would never be
+                    // handled if workers were shared between bots.
+                    UserId(1) => {
+                        tokio::time::timeout(Duration::from_secs(5), second_handled.notified())
+                            .await
+                            .is_ok()
+                    }
+                    _ => {
+                        second_handled.notify_one();
+                        true
+                    }
+                };
+                seen.lock().unwrap().push((
+                    upd.id,
+                    bot.me().user.id,
+                    me.username().to_owned(),
+                    waited,
+                ));
+                Ok::<_, Infallible>(())
+            },
+        );
+
+        let mut dp = Dispatcher::builder(mock_bot(1, "first_bot"), handler)
+            .bot(mock_bot(2, "second_bot"))
+            .dependencies(dptree::deps![Arc::clone(&seen), second_handled])
+            .build();
+
+        let update = |id| {
+            let mut update: Update = MockMessage::text("hi").chat_id(ChatId(10)).into();
+            update.id = UpdateId(id);
+            update
+        };
+        let listeners = vec![Replay::new([update(1)]), Replay::new([update(2)])];
+        dp.dispatch_with_listeners(listeners, IgnoringErrorHandler::new()).await;
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort_by_key(|(id, ..)| id.0);
+        assert_eq!(
+            seen,
+            [
+                (UpdateId(1), UserId(1), "first_bot".to_owned(), true),
+                (UpdateId(2), UserId(2), "second_bot".to_owned(), true),
+            ]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    #[should_panic(expected = "there must be exactly one update listener per bot")]
+    async fn test_several_bots_listener_count() {
+        use crate::{error_handlers::IgnoringErrorHandler, update_listeners::Replay};
+
+        let handler = dptree::endpoint(|| async { Ok::<_, Infallible>(()) });
+        let mut dp = Dispatcher::builder(mock_bot(1, "first_bot"), handler)
+            .bot(mock_bot(2, "second_bot"))
+            .build();
+        dp.dispatch_with_listeners(vec![Replay::new([])], IgnoringErrorHandler::new()).await;
+    }
 }
@@ -5,7 +5,7 @@
 use dptree::{di::DependencyMap, Handler};
 
 use crate::{
-    dispatching::DpHandlerDescription,
+    dispatching::{DpHandlerDescription, LeafKind},
     types::{AllowedUpdate, Message, Update, UpdateKind},
 };
 
@@ -30,16 +30,26 @@ macro_rules! define_ext {
     };
 
     (@impl $for_ty:ty, $func:ident, $proj_fn:expr, $Allowed:ident) => {
+        #[track_caller]
         fn $func() -> Handler<'static, DependencyMap, Out, DpHandlerDescription> {
-            dptree::filter_map_with_description(DpHandlerDescription::of(AllowedUpdate::$Allowed), move |input: $for_ty| {
+            let description = DpHandlerDescription::of(
+                AllowedUpdate::$Allowed,
+                concat!(stringify!($for_ty), "::", stringify!($func)),
+            );
+            dptree::filter_map_with_description(description, move |input: $for_ty| {
                 $proj_fn(input)
             })
         }
     };
 
     (@impl $for_ty:ty, $func:ident, $proj_fn:expr) => {
+        #[track_caller]
         fn $func() -> Handler<'static, DependencyMap, Out, DpHandlerDescription> {
-            dptree::filter_map(move |input: $for_ty| {
+            let description = DpHandlerDescription::labeled(
+                LeafKind::FilterMap,
+                concat!(stringify!($for_ty), "::", stringify!($func)).to_owned(),
+            );
+            dptree::filter_map_with_description(description, move |input: $for_ty| {
                 $proj_fn(input)
             })
         }
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, panic::Location, sync::Arc};
 
 use dptree::{
     description::{EventKind, InterestSet},
@@ -6,41 +6,134 @@ use dptree::{
 };
 use teloxide_core::types::AllowedUpdate;
 
+use crate::dispatching::handler_tree::{HandlerLeaf, HandlerTree, LeafKind, RawNode};
+
 /// Handler description that is used by [`Dispatcher`].
 ///
+/// Besides the kinds of updates a handler is interested in, it records the
+/// structure of the handler, see [`DpHandlerDescription::tree`].
+///
 /// [`Dispatcher`]: crate::dispatching::Dispatcher
 #[derive(Debug, Clone)]
 pub struct DpHandlerDescription {
     allowed: InterestSet<Kind>,
+    node: Arc<RawNode>,
 }
 
 impl DpHandlerDescription {
-    pub(crate) fn of(allowed: AllowedUpdate) -> Self {
+    #[track_caller]
+    pub(crate) fn of(allowed: AllowedUpdate, label: &str) -> Self {
         let mut set = HashSet::with_capacity(1);
         set.insert(Kind(allowed));
-        Self { allowed: InterestSet::new_filter(set) }
+        let node = leaf(LeafKind::FilterMap, Some(label.to_owned()));
+        Self { allowed: InterestSet::new_filter(set), node }
+    }
+
+    /// A description of a handler created by teloxide, e.g. of
+    /// [`HandlerExt::filter_command`], labeled for [`HandlerTree`].
+    ///
+    /// [`HandlerExt::filter_command`]: crate::dispatching::HandlerExt::filter_command
+    #[track_caller]
+    pub(crate) fn labeled(kind: LeafKind, label: String) -> Self {
+        Self { allowed: HandlerDescription::user_defined(), node: leaf(kind, Some(label)) }
     }
 
     pub(crate) fn allowed_updates(&self) -> Vec<AllowedUpdate> {
         self.allowed.observed.iter().map(|&Kind(x)| x).collect()
     }
+
+    /// Returns the structure of the handler.
+    ///
+    /// See [`HandlerTree`] for the ways to print it.
+    #[must_use]
+    pub fn tree(&self) -> HandlerTree {
+        HandlerTree::new(&self.node, self.allowed_updates())
+    }
+
+    #[track_caller]
+    fn user_defined_leaf(kind: LeafKind) -> Self {
+        Self { allowed: HandlerDescription::user_defined(), node: leaf(kind, None) }
+    }
+}
+
+#[track_caller]
+fn leaf(kind: LeafKind, label: Option<String>) -> Arc<RawNode> {
+    Arc::new(RawNode::Leaf(HandlerLeaf { kind, label, location: Some(Location::caller()) }))
 }
 
 impl HandlerDescription for DpHandlerDescription {
     fn entry() -> Self {
-        Self { allowed: HandlerDescription::entry() }
+        Self { allowed: HandlerDescription::entry(), node: Arc::new(RawNode::Entry) }
     }
 
     fn user_defined() -> Self {
-        Self { allowed: HandlerDescription::user_defined() }
+        // `dptree::from_fn` doesn't track its caller, so the location would point
+        // into `dptree`.
+        let node = Arc::new(RawNode::Leaf(HandlerLeaf {
+            kind: LeafKind::UserDefined,
+            label: None,
+            location: None,
+        }));
+        Self { allowed: HandlerDescription::user_defined(), node }
     }
 
     fn merge_chain(&self, other: &Self) -> Self {
-        Self { allowed: self.allowed.merge_chain(&other.allowed) }
+        Self {
+            allowed: self.allowed.merge_chain(&other.allowed),
+            node: Arc::new(RawNode::Chain(Arc::clone(&self.node), Arc::clone(&other.node))),
+        }
     }
 
     fn merge_branch(&self, other: &Self) -> Self {
-        Self { allowed: self.allowed.merge_branch(&other.allowed) }
+        Self {
+            allowed: self.allowed.merge_branch(&other.allowed),
+            node: Arc::new(RawNode::Branch(Arc::clone(&self.node), Arc::clone(&other.node))),
+        }
+    }
+
+    #[track_caller]
+    fn map() -> Self {
+        Self::user_defined_leaf(LeafKind::Map)
+    }
+
+    #[track_caller]
+    fn map_async() -> Self {
+        Self::user_defined_leaf(LeafKind::MapAsync)
+    }
+
+    #[track_caller]
+    fn filter() -> Self {
+        Self::user_defined_leaf(LeafKind::Filter)
+    }
+
+    #[track_caller]
+    fn filter_async() -> Self {
+        Self::user_defined_leaf(LeafKind::FilterAsync)
+    }
+
+    #[track_caller]
+    fn filter_map() -> Self {
+        Self::user_defined_leaf(LeafKind::FilterMap)
+    }
+
+    #[track_caller]
+    fn filter_map_async() -> Self {
+        Self::user_defined_leaf(LeafKind::FilterMapAsync)
+    }
+
+    #[track_caller]
+    fn inspect() -> Self {
+        Self::user_defined_leaf(LeafKind::Inspect)
+    }
+
+    #[track_caller]
+    fn inspect_async() -> Self {
+        Self::user_defined_leaf(LeafKind::InspectAsync)
+    }
+
+    #[track_caller]
+    fn endpoint() -> Self {
+        Self::user_defined_leaf(LeafKind::Endpoint)
     }
 }
 
@@ -1,7 +1,8 @@
 use crate::{
     dispatching::{
         dialogue::{GetChatId, Storage},
-        DpHandlerDescription,
+        handler_tree::short_type_name,
+        DpHandlerDescription, LeafKind,
     },
     types::{Me, Message},
     utils::command::BotCommands,
@@ -60,12 +61,37 @@ pub trait HandlerExt<Output> {
         <S as Storage<D>>::Error: Debug + Send,
         D: Default + Send + Sync + 'static,
         Upd: GetChatId + Clone + Send + Sync + 'static;
+
+    /// Passes [`Dialogue<D, S, K>`] and `D` as handler dependencies, indexing
+    /// the dialogue by a key extracted via `key`.
+    ///
+    /// Works like [`HandlerExt::enter_dialogue`], except that the rest of the
+    /// chain is not executed if `key` returns `None`. See
+    /// [`DialogueKey`] for ready-made extractors.
+    ///
+    /// ## Dependency requirements
+    ///
+    ///  - `Arc<S>`
+    ///  - `Upd`
+    ///
+    /// [`Dialogue<D, S, K>`]: super::dialogue::Dialogue
+    /// [`DialogueKey`]: super::dialogue::DialogueKey
+    #[must_use]
+    fn enter_dialogue_with<Upd, S, D, K, F>(self, key: F) -> Self
+    where
+        S: Storage<D, K> + ?Sized + Send + Sync + 'static,
+        <S as Storage<D, K>>::Error: Debug + Send,
+        D: Default + Send + Sync + 'static,
+        K: Clone + Send + Sync + 'static,
+        Upd: Clone + Send + Sync + 'static,
+        F: Fn(&Upd) -> Option<K> + Send + Sync + 'static;
 }
 
 impl<Output> HandlerExt<Output> for Handler<'static, DependencyMap, Output, DpHandlerDescription>
 where
     Output: Send + Sync + 'static,
 {
+    #[track_caller]
     fn filter_command<C>(self) -> Self
     where
         C: BotCommands + Send + Sync + 'static,
@@ -73,6 +99,7 @@ where
         self.chain(filter_command::<C, Output>())
     }
 
+    #[track_caller]
     fn filter_mention_command<C>(self) -> Self
     where
         C: BotCommands + Send + Sync + 'static,
@@ -80,6 +107,7 @@ where
         self.chain(filter_mention_command::<C, Output>())
     }
 
+    #[track_caller]
     fn enter_dialogue<Upd, S, D>(self) -> Self
     where
         S: Storage<D> + ?Sized + Send + Sync + 'static,
@@ -89,6 +117,19 @@ where
     {
         self.chain(super::dialogue::enter::<Upd, S, D, Output>())
     }
+
+    #[track_caller]
+    fn enter_dialogue_with<Upd, S, D, K, F>(self, key: F) -> Self
+    where
+        S: Storage<D, K> + ?Sized + Send + Sync + 'static,
+        <S as Storage<D, K>>::Error: Debug + Send,
+        D: Default + Send + Sync + 'static,
+        K: Clone + Send + Sync + 'static,
+        Upd: Clone + Send + Sync + 'static,
+        F: Fn(&Upd) -> Option<K> + Send + Sync + 'static,
+    {
+        self.chain(super::dialogue::enter_with::<Upd, S, D, K, Output, F>(key))
+    }
 }
 
 /// Returns a handler that accepts a parsed command `C`.
@@ -102,12 +143,14 @@ where
 ///  - [`crate::types::Message`]
 ///  - [`crate::types::Me`]
 #[must_use]
+#[track_caller]
 pub fn filter_command<C, Output>() -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
 where
     C: BotCommands + Send + Sync + 'static,
     Output: Send + Sync + 'static,
 {
-    dptree::filter_map(move |message: Message, me: Me| {
+    let description = command_description::<C>("filter_command");
+    dptree::filter_map_with_description(description, move |message: Message, me: Me| {
         let bot_name = me.user.username.expect("Bots must have a username");
         message.text().and_then(|text| C::parse(text, &bot_name).ok())
     })
@@ -126,13 +169,15 @@ where
 ///  - [`crate::types::Message`]
 ///  - [`crate::types::Me`]
 #[must_use]
+#[track_caller]
 pub fn filter_mention_command<C, Output>(
 ) -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
 where
     C: BotCommands + Send + Sync + 'static,
     Output: Send + Sync + 'static,
 {
-    dptree::filter_map(move |message: Message, me: Me| {
+    let description = command_description::<C>("filter_mention_command");
+    dptree::filter_map_with_description(description, move |message: Message, me: Me| {
         let bot_name = me.user.username.expect("Bots must have a username");
 
         let command = message.text().and_then(|text| C::parse(text, &bot_name).ok());
@@ -148,6 +193,15 @@ where
     })
 }
 
+/// Labels a command filter with the commands it accepts, e.g.
+/// `filter_command::<Command> (/help, /start)`.
+#[track_caller]
+fn command_description<C: BotCommands>(filter: &str) -> DpHandlerDescription {
+    let commands: Vec<_> = C::bot_commands().into_iter().map(|c| c.command).collect();
+    let label = format!("{filter}::<{}> ({})", short_type_name::<C>(), commands.join(", "));
+    DpHandlerDescription::labeled(LeafKind::FilterMap, label)
+}
+
 #[cfg(test)]
 #[cfg(feature = "macros")]
 mod tests {
@@ -269,6 +323,9 @@ mod tests {
             .branch(Update::filter_message().filter_command::<Cmd>().endpoint(|| async {}));
         let me = make_me();
 
+        let tree = h.description().tree().to_string();
+        assert!(tree.contains("filter_map filter_command::<Cmd> (/test) at "), "{tree}");
+
         let update = make_update("/test@".to_owned() + me.username());
         let result = h.dispatch(deps![update, me.clone()]).await;
         assert!(result.is_break());
@@ -0,0 +1,596 @@
+use std::{
+    fmt::{self, Display, Write as _},
+    panic::Location,
+    sync::Arc,
+};
+
+use teloxide_core::types::AllowedUpdate;
+
+/// The structure of a handler, as returned from [`DpHandlerDescription::tree`].
+///
+/// It can be printed as an indented tree via [`Display`], or exported as a
+/// [Graphviz] or a [Mermaid] diagram via [`HandlerTree::to_dot`] and
+/// [`HandlerTree::to_mermaid`]. Handlers which can never run, e.g. branches
+/// after a branch which always ends with an endpoint, are marked as
+/// unreachable.
+///
+/// ```
+/// use teloxide::prelude::*;
+///
+/// let handler: teloxide::dispatching::UpdateHandler<()> = Update::filter_message()
+///     .branch(dptree::endpoint(|| async { Ok(()) }))
+///     .branch(dptree::filter(|msg: Message| msg.text().is_some()).endpoint(|| async { Ok(()) }));
+///
+/// let tree = handler.description().tree();
+/// assert_eq!(tree.unreachable().len(), 2);
+/// println!("{tree}");
+/// ```
+///
+/// prints
+///
+/// ```text
+/// allowed updates: message
+/// filter_map Update::filter_message at src/main.rs:3:88
+/// - endpoint at src/main.rs:4:13
+/// - filter at src/main.rs:5:13 (unreachable)
+///   endpoint at src/main.rs:5:69 (unreachable)
+/// ```
+///
+/// [`DpHandlerDescription::tree`]: crate::dispatching::DpHandlerDescription::tree
+/// [Graphviz]: https://graphviz.org/
+/// [Mermaid]: https://mermaid.js.org/
+#[derive(Clone, Debug)]
+pub struct HandlerTree {
+    root: HandlerNode,
+    allowed_updates: Vec<AllowedUpdate>,
+    // Whether each leaf, in the depth-first order, can ever run.
+    reachable: Vec<bool>,
+}
+
+/// A node of a [`HandlerTree`].
+#[derive(Clone, Debug)]
+pub enum HandlerNode {
+    /// A single handler, such as a filter or an endpoint.
+    Leaf(HandlerLeaf),
+
+    /// Handlers executed one after another, as built by
+    /// [`Handler::chain`]. An empty chain is [`dptree::entry`].
+    ///
+    /// [`Handler::chain`]: dptree::Handler::chain
+    Chain(Vec<HandlerNode>),
+
+    /// Alternatives tried in order until one of them handles an update, as
+    /// built by [`Handler::branch`].
+    ///
+    /// [`Handler::branch`]: dptree::Handler::branch
+    Branch(Vec<HandlerNode>),
+}
+
+/// A single handler in a [`HandlerTree`].
+#[derive(Clone, Debug)]
+pub struct HandlerLeaf {
+    /// The kind of the handler.
+    pub kind: LeafKind,
+
+    /// What the handler does, if known, e.g. `Update::filter_message`.
+    pub label: Option<String>,
+
+    /// Where the handler was created.
+    pub location: Option<&'static Location<'static>>,
+}
+
+/// The kind of a [`HandlerLeaf`], after the `dptree` function which created
+/// it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LeafKind {
+    Filter,
+    FilterAsync,
+    FilterMap,
+    FilterMapAsync,
+    Map,
+    MapAsync,
+    Inspect,
+    InspectAsync,
+    Endpoint,
+    /// A handler created via [`dptree::from_fn`], which can do anything.
+    UserDefined,
+}
+
+impl LeafKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Filter => "filter",
+            Self::FilterAsync => "filter_async",
+            Self::FilterMap => "filter_map",
+            Self::FilterMapAsync => "filter_map_async",
+            Self::Map => "map",
+            Self::MapAsync => "map_async",
+            Self::Inspect => "inspect",
+            Self::InspectAsync => "inspect_async",
+            Self::Endpoint => "endpoint",
+            Self::UserDefined => "from_fn",
+        }
+    }
+
+    /// Returns whether the handler can pass an update back without calling
+    /// the rest of the chain.
+    fn can_reject(self) -> bool {
+        !matches!(
+            self,
+            Self::Map | Self::MapAsync | Self::Inspect | Self::InspectAsync | Self::Endpoint
+        )
+    }
+
+    /// Returns whether the handler can call the rest of the chain.
+    fn can_continue(self) -> bool {
+        self != Self::Endpoint
+    }
+}
+
+/// The tree as it's built by `dptree`, see [`HandlerNode`] for the meaning of
+/// the nodes.
+///
+/// Merging descriptions happens every time a handler is built, so it must be
+/// cheap; the tree is flattened only when it's requested.
+#[derive(Debug)]
+pub(crate) enum RawNode {
+    Entry,
+    Leaf(HandlerLeaf),
+    Chain(Arc<RawNode>, Arc<RawNode>),
+    Branch(Arc<RawNode>, Arc<RawNode>),
+}
+
+/// Returns the name of `T` without module paths, e.g. `Vec<State>` instead of
+/// `alloc::vec::Vec<my_bot::State>`.
+pub(crate) fn short_type_name<T: ?Sized>() -> String {
+    let full = std::any::type_name::<T>();
+    let mut out = String::with_capacity(full.len());
+    let mut segment_start = 0;
+    for (i, c) in full.char_indices() {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            continue;
+        }
+        out.push_str(last_segment(&full[segment_start..i]));
+        out.push(c);
+        segment_start = i + c.len_utf8();
+    }
+    out.push_str(last_segment(&full[segment_start..]));
+    out
+}
+
+fn last_segment(path: &str) -> &str {
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+impl RawNode {
+    fn flatten(&self) -> HandlerNode {
+        match self {
+            Self::Entry => HandlerNode::Chain(Vec::new()),
+            Self::Leaf(leaf) => HandlerNode::Leaf(leaf.clone()),
+            Self::Chain(first, second) => {
+                let mut items = first.flatten().into_chain();
+                items.extend(second.flatten().into_chain());
+                HandlerNode::from_chain(items)
+            }
+            // `a.branch(b).branch(c)` runs `a`, then tries `b` and `c` in turn.
+            Self::Branch(first, second) => {
+                let mut items = first.flatten().into_chain();
+                let alternative = second.flatten();
+                match items.last_mut() {
+                    Some(HandlerNode::Branch(alternatives)) => alternatives.push(alternative),
+                    _ => items.push(HandlerNode::Branch(vec![alternative])),
+                }
+                HandlerNode::from_chain(items)
+            }
+        }
+    }
+}
+
+impl HandlerNode {
+    fn into_chain(self) -> Vec<HandlerNode> {
+        match self {
+            Self::Chain(items) => items,
+            node => vec![node],
+        }
+    }
+
+    fn from_chain(mut items: Vec<HandlerNode>) -> Self {
+        if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Self::Chain(items)
+        }
+    }
+}
+
+/// How a handler can finish.
+#[derive(Clone, Copy)]
+struct Flow {
+    rejects: bool,
+    continues: bool,
+}
+
+impl Flow {
+    /// Returns whether the handler handles every update which reaches it.
+    fn is_total(self) -> bool {
+        !self.rejects && !self.continues
+    }
+}
+
+/// Computes how `node` can finish, recording whether its leaves are
+/// reachable.
+fn analyze(node: &HandlerNode, reachable: bool, out: &mut Vec<bool>) -> Flow {
+    match node {
+        HandlerNode::Leaf(leaf) => {
+            out.push(reachable);
+            Flow { rejects: leaf.kind.can_reject(), continues: leaf.kind.can_continue() }
+        }
+        HandlerNode::Chain(items) => {
+            let mut flow = Flow { rejects: false, continues: true };
+            for item in items {
+                let item_flow = analyze(item, reachable && flow.continues, out);
+                if flow.continues {
+                    flow = Flow { rejects: flow.rejects || item_flow.rejects, ..item_flow };
+                }
+            }
+            flow
+        }
+        HandlerNode::Branch(alternatives) => {
+            // An update which no alternative handles goes on.
+            let mut continues = true;
+            for alternative in alternatives {
+                let flow = analyze(alternative, reachable && continues, out);
+                continues &= !flow.is_total();
+            }
+            Flow { rejects: false, continues }
+        }
+    }
+}
+
+impl HandlerTree {
+    pub(crate) fn new(raw: &RawNode, mut allowed_updates: Vec<AllowedUpdate>) -> Self {
+        let root = raw.flatten();
+        let mut reachable = Vec::new();
+        analyze(&root, true, &mut reachable);
+        allowed_updates.sort_by_key(|&update| update_name(update));
+
+        Self { root, allowed_updates, reachable }
+    }
+
+    /// Returns the root of the tree.
+    #[must_use]
+    pub fn root(&self) -> &HandlerNode {
+        &self.root
+    }
+
+    /// Returns the kinds of updates the handler is interested in.
+    #[must_use]
+    pub fn allowed_updates(&self) -> &[AllowedUpdate] {
+        &self.allowed_updates
+    }
+
+    /// Returns the leaves which can never run.
+    #[must_use]
+    pub fn unreachable(&self) -> Vec<&HandlerLeaf> {
+        self.leaves()
+            .zip(&self.reachable)
+            .filter(|(_, &reachable)| !reachable)
+            .map(|(leaf, _)| leaf)
+            .collect()
+    }
+
+    /// Returns the leaves in the depth-first order.
+    fn leaves(&self) -> impl Iterator<Item = &HandlerLeaf> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            match node {
+                HandlerNode::Leaf(leaf) => leaves.push(leaf),
+                HandlerNode::Chain(items) | HandlerNode::Branch(items) => {
+                    stack.extend(items.iter().rev())
+                }
+            }
+        }
+        leaves.into_iter()
+    }
+
+    /// Returns the tree as a [Graphviz] graph.
+    ///
+    /// [Graphviz]: https://graphviz.org/
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let graph = Graph::new(self);
+        let mut out = String::from("digraph handler {\n    node [shape=box];\n");
+        for (id, node) in graph.nodes.iter().enumerate() {
+            let label = node.label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            let attrs = match node.shape {
+                Shape::Start => ", shape=circle",
+                Shape::Branch => ", shape=diamond",
+                Shape::Leaf => "",
+                Shape::Endpoint => ", style=\"rounded,bold\"",
+            };
+            let unreachable = if node.reachable { "" } else { ", color=red, fontcolor=red" };
+            writeln!(out, "    n{id} [label=\"{label}\"{attrs}{unreachable}];").unwrap();
+        }
+        for (from, to, label) in &graph.edges {
+            match label {
+                Some(label) => writeln!(out, "    n{from} -> n{to} [label=\"{label}\"];").unwrap(),
+                None => writeln!(out, "    n{from} -> n{to};").unwrap(),
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Returns the tree as a [Mermaid] flowchart.
+    ///
+    /// [Mermaid]: https://mermaid.js.org/
+    #[must_use]
+    pub fn to_mermaid(&self) -> String {
+        let graph = Graph::new(self);
+        let mut out = String::from("flowchart TD\n");
+        for (id, node) in graph.nodes.iter().enumerate() {
+            let label = node
+                .label
+                .replace('"', "#quot;")
+                .replace('<', "#lt;")
+                .replace('>', "#gt;")
+                .replace('\n', "<br/>");
+            let (open, close) = match node.shape {
+                Shape::Start => ("((", "))"),
+                Shape::Branch => ("{", "}"),
+                Shape::Leaf => ("[", "]"),
+                Shape::Endpoint => ("([", "])"),
+            };
+            writeln!(out, "    n{id}{open}\"{label}\"{close}").unwrap();
+        }
+        for (from, to, label) in &graph.edges {
+            match label {
+                Some(label) => writeln!(out, "    n{from} -->|{label}| n{to}").unwrap(),
+                None => writeln!(out, "    n{from} --> n{to}").unwrap(),
+            }
+        }
+        let unreachable: Vec<_> = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !node.reachable)
+            .map(|(id, _)| format!("n{id}"))
+            .collect();
+        if !unreachable.is_empty() {
+            out.push_str("    classDef unreachable stroke:#f00,color:#f00\n");
+            writeln!(out, "    class {} unreachable", unreachable.join(",")).unwrap();
+        }
+        out
+    }
+}
+
+impl Display for HandlerTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let allowed: Vec<_> =
+            self.allowed_updates.iter().map(|&update| update_name(update)).collect();
+        writeln!(f, "allowed updates: {}", allowed.join(", "))?;
+
+        let mut reachable = self.reachable.iter().copied();
+        write_node(f, &self.root, "", "", &mut reachable)
+    }
+}
+
+/// Writes `node` with the first line prefixed by `first` and the rest by
+/// `rest`.
+fn write_node(
+    f: &mut fmt::Formatter<'_>,
+    node: &HandlerNode,
+    first: &str,
+    rest: &str,
+    reachable: &mut impl Iterator<Item = bool>,
+) -> fmt::Result {
+    match node {
+        HandlerNode::Leaf(leaf) => {
+            write!(f, "{first}{leaf}")?;
+            if reachable.next() == Some(false) {
+                write!(f, " (unreachable)")?;
+            }
+            writeln!(f)
+        }
+        HandlerNode::Chain(items) if items.is_empty() => writeln!(f, "{first}entry"),
+        HandlerNode::Chain(items) => {
+            for (i, item) in items.iter().enumerate() {
+                write_node(f, item, if i == 0 { first } else { rest }, rest, reachable)?;
+            }
+            Ok(())
+        }
+        HandlerNode::Branch(alternatives) => {
+            let nested = format!("{rest}  ");
+            for (i, alternative) in alternatives.iter().enumerate() {
+                let prefix = if i == 0 { format!("{first}- ") } else { format!("{rest}- ") };
+                write_node(f, alternative, &prefix, &nested, reachable)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl Display for HandlerLeaf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.kind.name())?;
+        if let Some(label) = &self.label {
+            write!(f, " {label}")?;
+        }
+        if let Some(location) = self.location {
+            write!(f, " at {location}")?;
+        }
+        Ok(())
+    }
+}
+
+fn update_name(update: AllowedUpdate) -> String {
+    match serde_json::to_value(update) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{update:?}"),
+    }
+}
+
+/// A handler tree as a graph, shared by [`HandlerTree::to_dot`] and
+/// [`HandlerTree::to_mermaid`].
+struct Graph {
+    nodes: Vec<GraphNode>,
+    edges: Vec<(usize, usize, Option<String>)>,
+}
+
+struct GraphNode {
+    label: String,
+    shape: Shape,
+    reachable: bool,
+}
+
+enum Shape {
+    Start,
+    Branch,
+    Leaf,
+    Endpoint,
+}
+
+/// An edge which is yet to be connected to the next node.
+type Exit = (usize, Option<String>);
+
+impl Graph {
+    fn new(tree: &HandlerTree) -> Self {
+        let mut graph = Self { nodes: Vec::new(), edges: Vec::new() };
+        let start = graph.add("update".to_owned(), Shape::Start, true);
+        let mut reachable = tree.reachable.iter().copied();
+        graph.add_node(&tree.root, vec![(start, None)], &mut reachable);
+        graph
+    }
+
+    fn add(&mut self, label: String, shape: Shape, reachable: bool) -> usize {
+        self.nodes.push(GraphNode { label, shape, reachable });
+        self.nodes.len() - 1
+    }
+
+    fn connect(&mut self, exits: Vec<Exit>, to: usize) {
+        self.edges.extend(exits.into_iter().map(|(from, label)| (from, to, label)));
+    }
+
+    /// Adds `node` after `exits`, returning the exits of `node`.
+    fn add_node(
+        &mut self,
+        node: &HandlerNode,
+        exits: Vec<Exit>,
+        reachable: &mut impl Iterator<Item = bool>,
+    ) -> Vec<Exit> {
+        match node {
+            HandlerNode::Leaf(leaf) => {
+                let mut label = leaf.kind.name().to_owned();
+                if let Some(text) = &leaf.label {
+                    write!(label, " {text}").unwrap();
+                }
+                if let Some(location) = leaf.location {
+                    write!(label, "\n{location}").unwrap();
+                }
+                let (shape, next) = match leaf.kind {
+                    LeafKind::Endpoint => (Shape::Endpoint, false),
+                    _ => (Shape::Leaf, true),
+                };
+
+                let id = self.add(label, shape, reachable.next().unwrap_or(true));
+                self.connect(exits, id);
+                if next {
+                    vec![(id, None)]
+                } else {
+                    Vec::new()
+                }
+            }
+            HandlerNode::Chain(items) => {
+                items.iter().fold(exits, |exits, item| self.add_node(item, exits, reachable))
+            }
+            HandlerNode::Branch(alternatives) => {
+                let reachable_branch = !exits.is_empty();
+                let id = self.add("branch".to_owned(), Shape::Branch, reachable_branch);
+                self.connect(exits, id);
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    // Alternatives which don't handle an update pass it to the next one, which is
+                    // implied by the order of the edges.
+                    self.add_node(alternative, vec![(id, Some((i + 1).to_string()))], reachable);
+                }
+                vec![(id, Some("else".to_owned()))]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dispatching::{UpdateFilterExt, UpdateHandler},
+        types::{AllowedUpdate, Message, Update},
+    };
+
+    fn labels(tree: &super::HandlerTree) -> Vec<String> {
+        tree.unreachable()
+            .iter()
+            .map(|leaf| format!("{} {}", leaf.kind.name(), leaf.location.unwrap().line()))
+            .collect()
+    }
+
+    #[test]
+    fn tree() {
+        let line = line!();
+        let handler: UpdateHandler<()> = dptree::entry()
+            .branch(
+                Update::filter_message()
+                    .branch(
+                        dptree::filter(|m: Message| m.text().is_some())
+                            .endpoint(|| async { Ok(()) }),
+                    )
+                    .endpoint(|| async { Ok(()) }),
+            )
+            .branch(Update::filter_callback_query().endpoint(|| async { Ok(()) }))
+            .branch(dptree::endpoint(|| async { Ok(()) }))
+            .branch(Update::filter_edited_message().endpoint(|| async { Ok(()) }));
+        let tree = handler.description().tree();
+
+        let super::HandlerNode::Branch(alternatives) = tree.root() else {
+            panic!("expected a branch, got {:?}", tree.root())
+        };
+        assert_eq!(alternatives.len(), 4);
+        assert!(tree.allowed_updates().contains(&AllowedUpdate::CallbackQuery));
+
+        // The last branch is shadowed by the endpoint before it.
+        assert_eq!(
+            labels(&tree),
+            [format!("filter_map {}", line + 12), format!("endpoint {}", line + 12)]
+        );
+
+        let dump = tree.to_string();
+        assert!(dump.contains("- filter_map Update::filter_message at "), "{dump}");
+        assert!(dump.contains("  - filter at "), "{dump}");
+        assert!(dump.ends_with("(unreachable)\n"), "{dump}");
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph handler {"));
+        assert!(dot.contains("[label=\"4\"]"), "{dot}");
+        assert!(dot.contains("color=red"), "{dot}");
+
+        let mermaid = tree.to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("-->|else|"), "{mermaid}");
+        assert!(mermaid.contains("class "), "{mermaid}");
+    }
+
+    #[test]
+    fn short_type_name() {
+        assert_eq!(super::short_type_name::<Vec<Message>>(), "Vec<Message>");
+        assert_eq!(super::short_type_name::<(u8, Update)>(), "(u8, Update)");
+    }
+
+    #[test]
+    fn chain_after_endpoint() {
+        let handler: UpdateHandler<()> =
+            dptree::endpoint(|| async { Ok(()) }).chain(dptree::filter(|| true));
+        let tree = handler.description().tree();
+        assert_eq!(tree.unreachable().len(), 1);
+        assert_eq!(tree.unreachable()[0].kind, super::LeafKind::Filter);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{any::Any, future::Future, panic::AssertUnwindSafe, time::Duration};
+
+use futures::FutureExt as _;
+use thiserror::Error;
+
+use crate::types::UpdateId;
+
+/// An abnormal termination of update handling.
+///
+/// [`Dispatcher`] reports it to the error handler if
+/// [`DispatcherBuilder::catch_panics`] or [`DispatcherBuilder::update_timeout`]
+/// is enabled.
+///
+/// [`Dispatcher`]: crate::dispatching::Dispatcher
+/// [`DispatcherBuilder::catch_panics`]: crate::dispatching::DispatcherBuilder::catch_panics
+/// [`DispatcherBuilder::update_timeout`]: crate::dispatching::DispatcherBuilder::update_timeout
+#[derive(Debug, Error)]
+pub enum HandlerFailure {
+    /// A handler has panicked.
+    #[error("a handler panicked while processing update {}: {message}", .update_id.0)]
+    Panic {
+        /// The ID of the update which was being processed.
+        update_id: UpdateId,
+        /// The panic message, if it is a string.
+        message: String,
+    },
+
+    /// A handler didn't finish in time and was cancelled.
+    #[error("a handler timed out after {timeout:?} while processing update {}", .update_id.0)]
+    Timeout {
+        /// The ID of the update which was being processed.
+        update_id: UpdateId,
+        /// The configured timeout.
+        timeout: Duration,
+    },
+}
+
+/// Guards update handling against panics and hangs.
+pub(crate) struct Isolation<Err> {
+    pub(crate) catch_panics: bool,
+    pub(crate) timeout: Option<Duration>,
+    /// Set once either of the above is enabled.
+    pub(crate) into_error: Option<fn(HandlerFailure) -> Err>,
+}
+
+impl<Err> Isolation<Err> {
+    pub(crate) fn new() -> Self {
+        Self { catch_panics: false, timeout: None, into_error: None }
+    }
+
+    /// Runs `fut`, turning its panic or timeout into an error.
+    pub(crate) async fn run<F>(self, update_id: UpdateId, fut: F) -> Result<(), Err>
+    where
+        F: Future<Output = Result<(), Err>>,
+    {
+        let Some(into_error) = self.into_error else { return fut.await };
+
+        let guarded = async {
+            if self.catch_panics {
+                AssertUnwindSafe(fut).catch_unwind().await.map_err(|payload| {
+                    HandlerFailure::Panic { update_id, message: panic_message(&*payload) }
+                })
+            } else {
+                Ok(fut.await)
+            }
+        };
+
+        let res = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, guarded)
+                .await
+                .unwrap_or(Err(HandlerFailure::Timeout { update_id, timeout })),
+            None => guarded.await,
+        };
+
+        res.unwrap_or_else(|failure| Err(into_error(failure)))
+    }
+}
+
+impl<Err> Clone for Isolation<Err> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Err> Copy for Isolation<Err> {}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_owned()
+    }
+}
@@ -0,0 +1,813 @@
+//! Scheduled and delayed jobs.
+//!
+//! Not everything a bot does is a response to an update: reminders, notices
+//! like "your order expires in 1 hour" and daily digests need to happen at a
+//! certain time. A [`Job`] describes such a task: its name, an optional
+//! payload and a schedule, which is either a one-shot delay ([`Job::after`],
+//! [`Job::at`]), an interval ([`Job::every`]) or a [cron expression]
+//! ([`Job::cron`]).
+//!
+//! Jobs are scheduled via a [`JobScheduler`], which saves them in a
+//! [`JobStorage`] so they survive restarts. [`JobStorage`] is implemented for
+//! [`InMemJobStorage`] and, depending on enabled features, for
+//! [`SqliteStorage`], [`PostgresStorage`], [`MySqlStorage`], [`RedisStorage`]
+//! and [`FileStorage`], so the same storage can hold both dialogues and jobs.
+//!
+//! The jobs are run by [`Dispatcher`], registered via
+//! [`DispatcherBuilder::jobs`] along with a handler for them. The job handler
+//! receives the same dependencies as update handlers, except that the [`Job`]
+//! takes the place of the [`Update`], and its errors are passed to the error
+//! handler of the dispatcher. The [`JobScheduler`] itself is also added to
+//! dependencies, so update handlers can schedule jobs.
+//!
+//! When dispatching is [shut down], no new jobs are started and the running
+//! ones are waited for. Jobs that were due while the bot was not running are
+//! run as soon as it starts, once. A job is removed from the storage (or, if
+//! it repeats, rescheduled) only after it completes, so a job interrupted by
+//! a crash runs again after a restart.
+//!
+//! **Note**: jobs are loaded from the storage once, when dispatching starts,
+//! and are not claimed when they are run. So a job storage must back exactly
+//! one running dispatcher: if several processes share it, e.g. to split the
+//! work via a [distributed queue], each of them runs every saved job, and jobs
+//! scheduled by one process are not run by the others. Schedule and run jobs
+//! in a single process in this case.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use teloxide::{
+//!     dispatching::jobs::{InMemJobStorage, Job, JobScheduler},
+//!     prelude::*,
+//! };
+//!
+//! type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+//! type Scheduler = JobScheduler<InMemJobStorage>;
+//!
+//! async fn remind_later(msg: Message, scheduler: Scheduler) -> HandlerResult {
+//!     let job =
+//!         Job::after("reminder", Duration::from_secs(60 * 60)).with_payload(&msg.chat.id)?;
+//!     scheduler.schedule(job).await?;
+//!     Ok(())
+//! }
+//!
+//! async fn run_job(bot: Bot, job: Job) -> HandlerResult {
+//!     match job.name() {
+//!         "reminder" => {
+//!             bot.send_message(job.payload::<ChatId>()?, "Time is up!").await?;
+//!         }
+//!         "digest" => { /* ... */ }
+//!         _ => {}
+//!     }
+//!     Ok(())
+//! }
+//!
+//! # async fn run() -> HandlerResult {
+//! let scheduler = JobScheduler::new(InMemJobStorage::new());
+//! // Every day at 9:00 UTC, replacing the job saved by the previous run.
+//! scheduler.schedule(Job::cron("digest", "0 9 * * *")?.with_id("digest")).await?;
+//!
+//! let bot = Bot::from_env();
+//! let handler = Update::filter_message().endpoint(remind_later);
+//! Dispatcher::builder(bot, handler)
+//!     .jobs(scheduler, dptree::endpoint(run_job))
+//!     .enable_ctrlc_handler()
+//!     .build()
+//!     .dispatch()
+//!     .await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [cron expression]: Job::cron
+//! [distributed queue]: crate::dispatching::queue
+//! [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+//! [`PostgresStorage`]: crate::dispatching::dialogue::PostgresStorage
+//! [`MySqlStorage`]: crate::dispatching::dialogue::MySqlStorage
+//! [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+//! [`FileStorage`]: crate::dispatching::dialogue::FileStorage
+//! [`Dispatcher`]: crate::dispatching::Dispatcher
+//! [`DispatcherBuilder::jobs`]: crate::dispatching::DispatcherBuilder::jobs
+//! [`Update`]: crate::types::Update
+//! [shut down]: crate::dispatching::ShutdownToken::shutdown
+
+pub use cron::CronError;
+pub use in_mem_job_storage::InMemJobStorage;
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+    ops::ControlFlow,
+    panic::AssertUnwindSafe,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use dptree::di::DependencyMap;
+use futures::{
+    future::{self, BoxFuture, Either},
+    FutureExt as _,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use teloxide_core::types::UserId;
+use tokio::{
+    sync::{Mutex as AsyncMutex, Notify, OwnedMutexGuard},
+    task::JoinSet,
+};
+
+use crate::{
+    dispatching::{isolation::panic_message, UpdateHandler},
+    error_handlers::ErrorHandler,
+    stop::StopFlag,
+};
+
+use self::cron::Cron;
+
+mod cron;
+mod in_mem_job_storage;
+
+/// The delay before loading jobs again after a storage error.
+const LOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest time [`JobRunner::run`] sleeps at once, so that it doesn't
+/// overflow timers.
+const MAX_SLEEP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A task to run at a certain time, see the [module-level
+/// documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    id: String,
+    name: String,
+    schedule: Schedule,
+    // Unix time in milliseconds.
+    next_run: i64,
+    // JSON.
+    payload: String,
+    bot_id: Option<UserId>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Schedule {
+    Once,
+    Every { period_millis: i64 },
+    Cron { expr: String },
+}
+
+impl Job {
+    /// Creates a job which runs once after `delay`.
+    #[must_use]
+    pub fn after(name: &str, delay: Duration) -> Self {
+        Self::new(name, Schedule::Once, unix_millis() + millis(delay))
+    }
+
+    /// Creates a job which runs once at `time`.
+    #[must_use]
+    pub fn at(name: &str, time: DateTime<Utc>) -> Self {
+        Self::new(name, Schedule::Once, time.timestamp_millis())
+    }
+
+    /// Creates a job which runs every `period`, starting after one period.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `period` is zero.
+    #[must_use]
+    pub fn every(name: &str, period: Duration) -> Self {
+        let period_millis = millis(period);
+        assert_ne!(period_millis, 0, "the period must be positive");
+        Self::new(name, Schedule::Every { period_millis }, unix_millis() + period_millis)
+    }
+
+    /// Creates a job which runs at times matching a cron expression.
+    ///
+    /// The expression consists of 5 fields: minute (0-59), hour (0-23), day
+    /// of month (1-31), month (1-12) and day of week (0-7, both 0 and 7 are
+    /// Sunday). A field is `*` or a comma-separated list of values, ranges
+    /// (`1-5`) and steps (`*/15`, `0-30/10`). Shortcuts `@yearly`,
+    /// `@monthly`, `@weekly`, `@daily` and `@hourly` are also accepted.
+    ///
+    /// Times are in UTC. If both the day of month and the day of week are
+    /// restricted, a day matching either of them matches, as in the classic
+    /// cron.
+    pub fn cron(name: &str, expr: &str) -> Result<Self, CronError> {
+        let cron: Cron = expr.parse()?;
+        let next_run = cron.next_after(Utc::now()).map_or(i64::MAX, |time| time.timestamp_millis());
+        Ok(Self::new(name, Schedule::Cron { expr: expr.to_owned() }, next_run))
+    }
+
+    fn new(name: &str, schedule: Schedule, next_run: i64) -> Self {
+        Self {
+            id: generate_id(),
+            name: name.to_owned(),
+            schedule,
+            next_run,
+            payload: "null".to_owned(),
+            bot_id: None,
+        }
+    }
+
+    /// Sets the ID of the job.
+    ///
+    /// Scheduling a job replaces the job with the same ID, which is useful for
+    /// jobs scheduled on every start, or for postponing a job. By default, a
+    /// unique ID is generated.
+    #[must_use]
+    pub fn with_id(self, id: &str) -> Self {
+        Self { id: id.to_owned(), ..self }
+    }
+
+    /// Attaches a payload to the job, which is stored as JSON.
+    pub fn with_payload<T>(self, payload: &T) -> Result<Self, serde_json::Error>
+    where
+        T: Serialize,
+    {
+        Ok(Self { payload: serde_json::to_string(payload)?, ..self })
+    }
+
+    /// Specifies the bot which the job is run with, if [`Dispatcher`] serves
+    /// several bots.
+    ///
+    /// By default, it's the bot passed to [`Dispatcher::builder`].
+    ///
+    /// [`Dispatcher`]: crate::dispatching::Dispatcher
+    /// [`Dispatcher::builder`]: crate::dispatching::Dispatcher::builder
+    #[must_use]
+    pub fn for_bot(self, bot_id: UserId) -> Self {
+        Self { bot_id: Some(bot_id), ..self }
+    }
+
+    /// Returns the ID of the job.
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the name of the job.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Decodes the payload of the job.
+    ///
+    /// A job without a payload has the `null` payload, which decodes e.g. as
+    /// `()` or `None`.
+    pub fn payload<T>(&self) -> Result<T, serde_json::Error>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_str(&self.payload)
+    }
+
+    /// Returns the ID of the bot which the job is run with, if specified via
+    /// [`Job::for_bot`].
+    #[must_use]
+    pub fn bot_id(&self) -> Option<UserId> {
+        self.bot_id
+    }
+
+    /// Returns the time of the next run of the job.
+    #[must_use]
+    pub fn next_run(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.next_run).unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// Returns the time of the run after the one that has just completed, or
+    /// `None` if the job doesn't repeat.
+    ///
+    /// Runs missed in the meantime are skipped.
+    fn reschedule(&self, now: i64) -> Option<i64> {
+        match &self.schedule {
+            Schedule::Once => None,
+            Schedule::Every { period_millis } => {
+                let next = self.next_run.saturating_add(*period_millis);
+                Some(if next > now { next } else { now + period_millis })
+            }
+            Schedule::Cron { expr } => {
+                let now = DateTime::from_timestamp_millis(now)?;
+                let cron: Cron = expr.parse().ok()?;
+                cron.next_after(now).map(|time| time.timestamp_millis())
+            }
+        }
+    }
+
+    /// Encodes the job for persistent storages.
+    #[cfg(any(
+        feature = "sqlite-storage-nativetls",
+        feature = "sqlite-storage-rustls",
+        feature = "postgres-storage-nativetls",
+        feature = "mysql-storage-nativetls",
+        feature = "mysql-storage-rustls",
+        feature = "redis-storage",
+        feature = "file-storage"
+    ))]
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("jobs are always serializable")
+    }
+
+    /// Decodes jobs encoded via [`Job::encode`], skipping invalid ones.
+    #[cfg(any(
+        feature = "sqlite-storage-nativetls",
+        feature = "sqlite-storage-rustls",
+        feature = "postgres-storage-nativetls",
+        feature = "mysql-storage-nativetls",
+        feature = "mysql-storage-rustls",
+        feature = "redis-storage",
+        feature = "file-storage"
+    ))]
+    pub(crate) fn decode_all<I, D>(jobs: I) -> Vec<Self>
+    where
+        I: IntoIterator<Item = D>,
+        D: AsRef<[u8]>,
+    {
+        jobs.into_iter()
+            .filter_map(|job| {
+                serde_json::from_slice(job.as_ref())
+                    .map_err(|err| log::error!("Cannot decode a stored job: {err}"))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// A storage of scheduled [`Job`]s.
+///
+/// See the [module-level documentation](self) for more information.
+pub trait JobStorage {
+    type Error: Debug;
+
+    /// Saves `job`, replacing the job with the same ID.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn save_job(self: Arc<Self>, job: Job) -> BoxFuture<'static, Result<(), Self::Error>>;
+
+    /// Removes the job with `id`.
+    ///
+    /// Does nothing if there is no such job.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn remove_job(self: Arc<Self>, id: String) -> BoxFuture<'static, Result<(), Self::Error>>;
+
+    /// Returns all the saved jobs.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn load_jobs(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<Job>, Self::Error>>;
+}
+
+/// A handle for scheduling and cancelling [`Job`]s.
+///
+/// Jobs are saved in a [`JobStorage`] and run by the [`Dispatcher`] which the
+/// scheduler is registered with via [`DispatcherBuilder::jobs`]. Jobs can be
+/// scheduled before dispatching starts; the ones saved in the storage earlier
+/// are loaded when it starts.
+///
+/// [`Dispatcher`]: crate::dispatching::Dispatcher
+/// [`DispatcherBuilder::jobs`]: crate::dispatching::DispatcherBuilder::jobs
+pub struct JobScheduler<S> {
+    storage: Arc<S>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Wakes up the loop of `run` when the earliest job may have changed.
+    notify: Notify,
+    // Serializes changes of jobs with the same ID, so that the storage ends up
+    // with the same job as `state`.
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl Shared {
+    async fn lock(&self, id: &str) -> JobLock<'_> {
+        let lock = Arc::clone(self.locks.lock().unwrap().entry(id.to_owned()).or_default());
+        JobLock { shared: self, id: id.to_owned(), _guard: lock.lock_owned().await }
+    }
+}
+
+/// Holds the lock of a job ID, see `Shared::locks`.
+struct JobLock<'a> {
+    shared: &'a Shared,
+    id: String,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for JobLock<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.shared.locks.lock().unwrap();
+        // Only the map and this guard refer to the lock, i.e. nobody waits for it.
+        if locks.get(&self.id).is_some_and(|lock| Arc::strong_count(lock) == 2) {
+            locks.remove(&self.id);
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    jobs: HashMap<String, Entry>,
+    // The jobs which are not running, by the time of their next run.
+    queue: BTreeSet<(i64, String)>,
+    next_generation: u64,
+}
+
+struct Entry {
+    job: Job,
+    // Distinguishes a job from a later one with the same ID.
+    generation: u64,
+}
+
+// `#[derive]` requires `S` to implement `Clone`, but it's wrapped around `Arc`.
+impl<S> Clone for JobScheduler<S> {
+    fn clone(&self) -> Self {
+        Self { storage: Arc::clone(&self.storage), shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl<S> JobScheduler<S>
+where
+    S: JobStorage + Send + Sync + 'static,
+{
+    /// Creates a scheduler which saves jobs in `storage`.
+    #[must_use]
+    pub fn new(storage: Arc<S>) -> Self {
+        let shared =
+            Shared { state: Mutex::default(), notify: Notify::new(), locks: Mutex::default() };
+        Self { storage, shared: Arc::new(shared) }
+    }
+
+    /// Schedules `job`, replacing the job with the same ID.
+    pub async fn schedule(&self, job: Job) -> Result<(), S::Error> {
+        let _lock = self.shared.lock(&job.id).await;
+        Arc::clone(&self.storage).save_job(job.clone()).await?;
+        self.insert(job);
+        Ok(())
+    }
+
+    /// Cancels the job with `id`.
+    ///
+    /// Does nothing if there is no such job. A running job is not interrupted,
+    /// but doesn't run again.
+    pub async fn cancel(&self, id: &str) -> Result<(), S::Error> {
+        let _lock = self.shared.lock(id).await;
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if let Some(Entry { job, .. }) = state.jobs.remove(id) {
+                state.queue.remove(&(job.next_run, job.id));
+            }
+        }
+        Arc::clone(&self.storage).remove_job(id.to_owned()).await
+    }
+
+    fn insert(&self, job: Job) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if let Some(Entry { job, .. }) = state.jobs.remove(&job.id) {
+                state.queue.remove(&(job.next_run, job.id));
+            }
+
+            let generation = state.next_generation;
+            state.next_generation += 1;
+            state.queue.insert((job.next_run, job.id.clone()));
+            state.jobs.insert(job.id.clone(), Entry { job, generation });
+        }
+        self.shared.notify.notify_one();
+    }
+
+    /// Takes the jobs which are due at `now` from the queue, returning them
+    /// along with the time of the next job.
+    fn take_due(&self, now: i64) -> (Vec<(Job, u64)>, Option<i64>) {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut due = Vec::new();
+        while let Some((next_run, _)) = state.queue.first() {
+            if *next_run > now {
+                break;
+            }
+            let (_, id) = state.queue.pop_first().unwrap();
+            let entry = &state.jobs[&id];
+            due.push((entry.job.clone(), entry.generation));
+        }
+
+        (due, state.queue.first().map(|&(next_run, _)| next_run))
+    }
+
+    /// Reschedules or removes a job after it has run.
+    async fn complete(&self, job: Job, generation: u64) {
+        // Otherwise, the job could be saved after being replaced or cancelled.
+        let _lock = self.shared.lock(&job.id).await;
+        let next_run = job.reschedule(unix_millis());
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            // The job was cancelled or replaced while running.
+            if !matches!(state.jobs.get(&job.id), Some(entry) if entry.generation == generation) {
+                return;
+            }
+
+            match next_run {
+                Some(next_run) => {
+                    let entry = state.jobs.get_mut(&job.id).unwrap();
+                    entry.job.next_run = next_run;
+                    state.queue.insert((next_run, job.id.clone()));
+                }
+                None => {
+                    state.jobs.remove(&job.id);
+                }
+            }
+        }
+        self.shared.notify.notify_one();
+
+        let res = match next_run {
+            Some(next_run) => Arc::clone(&self.storage).save_job(Job { next_run, ..job }).await,
+            None => Arc::clone(&self.storage).remove_job(job.id).await,
+        };
+        if let Err(err) = res {
+            log::error!("Cannot save a completed job: {err:?}");
+        }
+    }
+
+    /// Loads the saved jobs, keeping the ones scheduled since the scheduler
+    /// was created.
+    async fn load(&self, stop_flag: &StopFlag) -> bool {
+        loop {
+            let load = Arc::clone(&self.storage).load_jobs();
+            let res = match future::select(load, stop_flag.clone()).await {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => return false,
+            };
+
+            match res {
+                Ok(jobs) => {
+                    let saved: Vec<_> = {
+                        let state = self.shared.state.lock().unwrap();
+                        jobs.into_iter().filter(|job| !state.jobs.contains_key(&job.id)).collect()
+                    };
+                    saved.into_iter().for_each(|job| self.insert(job));
+                    return true;
+                }
+                Err(err) => log::error!("Cannot load jobs: {err:?}"),
+            }
+
+            let delay = pin!(tokio::time::sleep(LOAD_RETRY_DELAY));
+            if let Either::Right(_) = future::select(delay, stop_flag.clone()).await {
+                return false;
+            }
+        }
+    }
+}
+
+/// Runs jobs of a [`JobScheduler`] with a handler, see
+/// [`DispatcherBuilder::jobs`].
+///
+/// [`DispatcherBuilder::jobs`]: crate::dispatching::DispatcherBuilder::jobs
+pub(crate) trait JobRunner<Err>: Send + Sync {
+    /// Adds the scheduler to `deps`.
+    fn insert_into(&self, deps: &mut DependencyMap);
+
+    /// Runs jobs until `stop_flag` is stopped, then waits for the running
+    /// ones. `bots` are the IDs of bots along with their dependencies, the
+    /// first one being the default.
+    fn run(
+        &self,
+        bots: Vec<(UserId, Arc<DependencyMap>)>,
+        error_handler: Arc<dyn ErrorHandler<Err> + Send + Sync>,
+        stop_flag: StopFlag,
+    ) -> BoxFuture<'static, ()>;
+}
+
+pub(crate) struct Jobs<S, Err> {
+    pub(crate) scheduler: JobScheduler<S>,
+    pub(crate) handler: Arc<UpdateHandler<Err>>,
+}
+
+impl<S, Err> JobRunner<Err> for Jobs<S, Err>
+where
+    S: JobStorage + Send + Sync + 'static,
+    S::Error: Send,
+    Err: Send + Sync + 'static,
+{
+    fn insert_into(&self, deps: &mut DependencyMap) {
+        deps.insert(self.scheduler.clone());
+    }
+
+    fn run(
+        &self,
+        bots: Vec<(UserId, Arc<DependencyMap>)>,
+        error_handler: Arc<dyn ErrorHandler<Err> + Send + Sync>,
+        stop_flag: StopFlag,
+    ) -> BoxFuture<'static, ()> {
+        let scheduler = self.scheduler.clone();
+        let handler = Arc::clone(&self.handler);
+        let bots: Arc<[_]> = bots.into();
+
+        Box::pin(async move {
+            if !scheduler.load(&stop_flag).await {
+                return;
+            }
+
+            let mut running = JoinSet::new();
+            loop {
+                while running.try_join_next().is_some() {}
+
+                let (due, next_run) = scheduler.take_due(unix_millis());
+                for (job, generation) in due {
+                    let scheduler = scheduler.clone();
+                    let handler = Arc::clone(&handler);
+                    let error_handler = Arc::clone(&error_handler);
+                    let bots = Arc::clone(&bots);
+
+                    running.spawn(async move {
+                        run_job(&job, &bots, handler, error_handler).await;
+                        scheduler.complete(job, generation).await;
+                    });
+                }
+
+                let delay = match next_run {
+                    Some(next_run) => {
+                        Duration::from_millis((next_run - unix_millis()).max(0) as u64)
+                    }
+                    // Nothing to wait for but new jobs.
+                    None => Duration::MAX,
+                };
+                let sleep = pin!(tokio::time::sleep(delay.min(MAX_SLEEP)));
+                let notified = pin!(scheduler.shared.notify.notified());
+                let wake = future::select(sleep, notified);
+                if let Either::Right(_) = future::select(wake, stop_flag.clone()).await {
+                    break;
+                }
+            }
+
+            while running.join_next().await.is_some() {}
+        })
+    }
+}
+
+async fn run_job<Err>(
+    job: &Job,
+    bots: &[(UserId, Arc<DependencyMap>)],
+    handler: Arc<UpdateHandler<Err>>,
+    error_handler: Arc<dyn ErrorHandler<Err> + Send + Sync>,
+) where
+    Err: Send + Sync + 'static,
+{
+    let bot = match job.bot_id {
+        Some(bot_id) => bots.iter().find(|(id, _)| *id == bot_id),
+        None => bots.first(),
+    };
+    let Some((_, deps)) = bot else {
+        log::error!("Cannot run job {:?}: unknown bot {:?}", job.id, job.bot_id);
+        return;
+    };
+
+    let mut deps = DependencyMap::clone(deps);
+    deps.insert(job.clone());
+    // A panic must not prevent the job from being rescheduled or removed.
+    match AssertUnwindSafe(handler.dispatch(deps)).catch_unwind().await {
+        Ok(ControlFlow::Break(Ok(()))) => {}
+        Ok(ControlFlow::Break(Err(err))) => error_handler.handle_error(err).await,
+        Ok(ControlFlow::Continue(_)) => log::warn!("Unhandled job: {job:?}"),
+        Err(payload) => {
+            log::error!("Job {:?} panicked: {}", job.id, panic_message(&*payload));
+        }
+    }
+}
+
+fn generate_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{n:x}", unix_millis(), std::process::id())
+}
+
+#[cfg(not(test))]
+fn unix_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Follows the clock of tokio, so that tests can pause time.
+#[cfg(test)]
+fn unix_millis() -> i64 {
+    use std::sync::OnceLock;
+
+    static START: OnceLock<(SystemTime, tokio::time::Instant)> = OnceLock::new();
+    let (system, instant) = *START.get_or_init(|| (SystemTime::now(), tokio::time::Instant::now()));
+    let now = system + instant.elapsed();
+    now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
+
+fn millis(duration: Duration) -> i64 {
+    duration.as_millis().try_into().unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+    use crate::{error_handlers::IgnoringErrorHandler, stop::mk_stop_token};
+
+    #[test]
+    fn rescheduling() {
+        let job = Job::every("tick", Duration::from_secs(10));
+        let next_run = job.next_run;
+        assert_eq!(job.reschedule(next_run), Some(next_run + 10_000));
+        // Missed runs are skipped.
+        assert_eq!(job.reschedule(next_run + 25_000), Some(next_run + 35_000));
+
+        assert_eq!(Job::after("once", Duration::ZERO).reschedule(0), None);
+
+        let job = Job::cron("hourly", "@hourly").unwrap();
+        let now = "2024-01-01T10:30:00Z".parse::<DateTime<Utc>>().unwrap().timestamp_millis();
+        let expected = "2024-01-01T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(job.reschedule(now), Some(expected.timestamp_millis()));
+    }
+
+    #[test]
+    fn payload() {
+        let job = Job::after("reminder", Duration::ZERO);
+        assert_eq!(job.payload::<Option<i64>>().unwrap(), None);
+        let job = job.with_payload(&42).unwrap();
+        assert_eq!(job.payload::<i64>().unwrap(), 42);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running() {
+        let storage = InMemJobStorage::new();
+        let scheduler = JobScheduler::new(Arc::clone(&storage));
+        // Saved by a previous run and overdue.
+        let overdue = Job::at("once", Utc::now() - chrono::Duration::hours(1)).with_id("overdue");
+        Arc::clone(&storage).save_job(overdue).await.unwrap();
+        scheduler
+            .schedule(Job::every("tick", Duration::from_millis(20)).with_id("tick"))
+            .await
+            .unwrap();
+        let cancelled = Job::after("cancelled", Duration::from_millis(20)).with_id("cancelled");
+        scheduler.schedule(cancelled).await.unwrap();
+        scheduler.cancel("cancelled").await.unwrap();
+
+        let ticks = Arc::new(AtomicU32::new(0));
+        let overdue = Arc::new(AtomicU32::new(0));
+        let handler: UpdateHandler<()> = dptree::endpoint({
+            let ticks = Arc::clone(&ticks);
+            let overdue = Arc::clone(&overdue);
+            move |job: Job, bot: UserId| {
+                assert_eq!(bot, UserId(1));
+                match job.name() {
+                    "tick" => ticks.fetch_add(1, Ordering::Relaxed),
+                    "once" => overdue.fetch_add(1, Ordering::Relaxed),
+                    name => panic!("unexpected job {name}"),
+                };
+                async { Ok(()) }
+            }
+        });
+
+        let jobs = Jobs { scheduler, handler: Arc::new(handler) };
+        let (stop_token, stop_flag) = mk_stop_token();
+        let bots = vec![(UserId(1), Arc::new(dptree::deps![UserId(1)]))];
+        let run = tokio::spawn(jobs.run(bots, IgnoringErrorHandler::new(), stop_flag));
+
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        stop_token.stop();
+        run.await.unwrap();
+
+        assert_eq!(overdue.load(Ordering::Relaxed), 1);
+        // At 20, 40, 60, 80 and 100 ms.
+        assert_eq!(ticks.load(Ordering::Relaxed), 5);
+        // The one-shot job is removed and the interval one is kept.
+        let saved = Arc::clone(&storage).load_jobs().await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].id(), "tick");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicking() {
+        let storage = InMemJobStorage::new();
+        let scheduler = JobScheduler::new(Arc::clone(&storage));
+        scheduler
+            .schedule(Job::every("tick", Duration::from_millis(20)).with_id("tick"))
+            .await
+            .unwrap();
+
+        let runs = Arc::new(AtomicU32::new(0));
+        let handler: UpdateHandler<()> = dptree::endpoint({
+            let runs = Arc::clone(&runs);
+            move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+                async { panic!("the job has failed") }
+            }
+        });
+
+        let jobs = Jobs { scheduler, handler: Arc::new(handler) };
+        let (stop_token, stop_flag) = mk_stop_token();
+        let bots = vec![(UserId(1), Arc::new(DependencyMap::new()))];
+        let run = tokio::spawn(jobs.run(bots, IgnoringErrorHandler::new(), stop_flag));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop_token.stop();
+        run.await.unwrap();
+
+        // The job is rescheduled after a panic.
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+        assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,200 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, DurationRound, TimeZone, Timelike, Utc};
+use thiserror::Error;
+
+/// An error returned when a cron expression cannot be parsed.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error("invalid cron expression `{expr}`: {reason}")]
+pub struct CronError {
+    expr: String,
+    reason: &'static str,
+}
+
+/// A parsed cron expression, see [`Job::cron`].
+///
+/// [`Job::cron`]: super::Job::cron
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day of month or the day of week is `*`, which changes how the
+    // two are combined.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// How far [`Cron::next_after`] looks for a matching time, in days.
+const SEARCH_DAYS: i64 = 366 * 8;
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(expr: &str) -> Result<Self, CronError> {
+        let error = |reason| CronError { expr: expr.to_owned(), reason };
+
+        let expanded = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        };
+        let fields: Vec<_> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays_field] = fields[..] else {
+            return Err(error("expected 5 fields"));
+        };
+
+        let mut weekdays = parse_field(weekdays_field, 0, 7).map_err(error)?;
+        // Both 0 and 7 mean Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        let cron = Self {
+            minutes: parse_field(minutes, 0, 59).map_err(error)?,
+            hours: parse_field(hours, 0, 23).map_err(error)?,
+            days: parse_field(days, 1, 31).map_err(error)?,
+            months: parse_field(months, 1, 12).map_err(error)?,
+            weekdays,
+            any_day: days == "*",
+            any_weekday: weekdays_field == "*",
+        };
+        if cron.next_after(Utc.timestamp_opt(0, 0).unwrap()).is_none() {
+            return Err(error("never matches"));
+        }
+
+        Ok(cron)
+    }
+}
+
+/// Parses a comma-separated list of values, ranges (`a-b`) and steps (`*/n`,
+/// `a-b/n`, `a/n`) into a bit set.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, &'static str> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| "invalid step")?;
+                if step == 0 {
+                    return Err("step must be positive");
+                }
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start, min, max)?, parse_value(end, min, max)?),
+                // `a/n` means from `a` to the maximum.
+                None if step.is_some() => (parse_value(range, min, max)?, max),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err("invalid range");
+        }
+
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            set |= 1 << value;
+        }
+    }
+
+    Ok(set)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, &'static str> {
+    match value.parse() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err("value out of range"),
+    }
+}
+
+fn contains(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+impl Cron {
+    /// Returns the first matching time strictly after `after`, in UTC.
+    pub(crate) fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let minute = Duration::minutes(1);
+        let mut time = after.duration_trunc(minute).ok()? + minute;
+        let limit = time + Duration::days(SEARCH_DAYS);
+
+        while time < limit {
+            if !contains(self.months, time.month()) {
+                let (year, month) = if time.month() == 12 {
+                    (time.year() + 1, 1)
+                } else {
+                    (time.year(), time.month() + 1)
+                };
+                time = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.day_matches(time) {
+                time = time.duration_trunc(Duration::days(1)).ok()? + Duration::days(1);
+            } else if !contains(self.hours, time.hour()) {
+                time = time.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+            } else if !contains(self.minutes, time.minute()) {
+                time += minute;
+            } else {
+                return Some(time);
+            }
+        }
+
+        None
+    }
+
+    fn day_matches(&self, time: DateTime<Utc>) -> bool {
+        let day = contains(self.days, time.day());
+        let weekday = contains(self.weekdays, time.weekday().num_days_from_sunday());
+
+        // If both fields are restricted, a day matching either of them matches.
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn next(expr: &str, after: &str) -> DateTime<Utc> {
+        expr.parse::<Cron>().unwrap().next_after(time(after)).unwrap()
+    }
+
+    #[test]
+    fn next_after() {
+        assert_eq!(next("* * * * *", "2024-01-01T10:00:30Z"), time("2024-01-01T10:01:00Z"));
+        assert_eq!(next("*/15 * * * *", "2024-01-01T10:00:00Z"), time("2024-01-01T10:15:00Z"));
+        assert_eq!(next("30 9 * * 1-5", "2024-01-05T10:00:00Z"), time("2024-01-08T09:30:00Z"));
+        assert_eq!(next("0 0 29 2 *", "2024-03-01T00:00:00Z"), time("2028-02-29T00:00:00Z"));
+        assert_eq!(next("@monthly", "2024-12-15T00:00:00Z"), time("2025-01-01T00:00:00Z"));
+        // Sunday can be written as 7.
+        assert_eq!(next("0 12 * * 7", "2024-01-01T00:00:00Z"), time("2024-01-07T12:00:00Z"));
+        // Restricted days of month and of week are combined with OR.
+        assert_eq!(next("0 0 15 * 1", "2024-01-02T00:00:00Z"), time("2024-01-08T00:00:00Z"));
+    }
+
+    #[test]
+    fn invalid() {
+        for expr in
+            ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "0 0 30 2 *", "x * * * *"]
+        {
+            assert!(expr.parse::<Cron>().is_err(), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+
+use super::{Job, JobStorage};
+
+/// A job storage that keeps jobs in memory.
+///
+/// ## Note
+/// All the jobs are lost after you restart your bot. If you need to keep them,
+/// you should use e.g. [`SqliteStorage`] or implement your own.
+///
+/// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+#[derive(Debug, Default)]
+pub struct InMemJobStorage {
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl InMemJobStorage {
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+impl JobStorage for InMemJobStorage {
+    type Error = Infallible;
+
+    fn save_job(self: Arc<Self>, job: Job) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self.jobs.lock().unwrap().insert(job.id.clone(), job);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove_job(self: Arc<Self>, id: String) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self.jobs.lock().unwrap().remove(&id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load_jobs(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<Job>, Self::Error>> {
+        let jobs = self.jobs.lock().unwrap().values().cloned().collect();
+        Box::pin(async { Ok(jobs) })
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::types::{AllowedUpdate, Update, UpdateKind};
+
+/// A class of updates with its own priority and concurrency limit.
+///
+/// Lanes are registered via [`DispatcherBuilder::lane`]. Each update belongs
+/// to the first lane that matches it, or to the default lane, which has
+/// priority `0` and no concurrency limit.
+///
+/// At most [`Lane::max_concurrency`] updates of a lane are handled at once,
+/// the rest wait for their turn. When the total number of handled updates is
+/// limited via [`DispatcherBuilder::max_in_flight`], waiting updates of lanes
+/// with a higher [`Lane::priority`] are handled first.
+///
+/// Note that updates still go through their worker (see [update grouping]),
+/// so an update that waits for its lane also holds up the next updates of the
+/// same chat.
+///
+/// ## Examples
+///
+/// Making sure that a flood of inline queries doesn't delay payments, which
+/// must be answered within 10 seconds:
+///
+/// ```
+/// use teloxide::{
+///     dispatching::{Dispatcher, Lane},
+///     dptree,
+///     types::AllowedUpdate,
+///     Bot,
+/// };
+///
+/// let bot = Bot::new("TOKEN");
+/// let handler = dptree::entry() /* ... */;
+/// let dp = Dispatcher::builder(bot, handler)
+///     .lane(
+///         Lane::kinds([AllowedUpdate::PreCheckoutQuery, AllowedUpdate::ShippingQuery])
+///             .priority(10),
+///     )
+///     .lane(Lane::kinds([AllowedUpdate::InlineQuery]).max_concurrency(8))
+///     .max_in_flight(64)
+///     .build();
+/// # let _: Dispatcher<_, (), _> = dp;
+/// ```
+///
+/// [`DispatcherBuilder::lane`]: crate::dispatching::DispatcherBuilder::lane
+/// [`DispatcherBuilder::max_in_flight`]: crate::dispatching::DispatcherBuilder::max_in_flight
+/// [update grouping]: crate::dispatching::DispatcherBuilder::distribution_function#update-grouping
+#[derive(Clone, Debug)]
+pub struct Lane {
+    matcher: Matcher,
+    priority: i32,
+    max_concurrency: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Kinds(Vec<AllowedUpdate>),
+    Custom(fn(&Update) -> bool),
+}
+
+impl Lane {
+    /// Creates a lane for updates of the given kinds.
+    #[must_use]
+    pub fn kinds<I>(kinds: I) -> Self
+    where
+        I: IntoIterator<Item = AllowedUpdate>,
+    {
+        Self::with_matcher(Matcher::Kinds(kinds.into_iter().collect()))
+    }
+
+    /// Creates a lane for updates for which `f` returns `true`.
+    #[must_use]
+    pub fn matching(f: fn(&Update) -> bool) -> Self {
+        Self::with_matcher(Matcher::Custom(f))
+    }
+
+    fn with_matcher(matcher: Matcher) -> Self {
+        Self { matcher, priority: 0, max_concurrency: None }
+    }
+
+    /// Specifies the priority of the lane, the higher the sooner.
+    ///
+    /// By default, it's `0`.
+    #[must_use]
+    pub fn priority(self, priority: i32) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// Specifies the maximum number of updates of the lane handled at once.
+    ///
+    /// By default, it's unlimited.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `max` is zero.
+    #[must_use]
+    pub fn max_concurrency(self, max: usize) -> Self {
+        assert_ne!(max, 0, "the concurrency limit must be positive");
+        Self { max_concurrency: Some(max), ..self }
+    }
+
+    fn matches(&self, update: &Update) -> bool {
+        match &self.matcher {
+            Matcher::Kinds(kinds) => kind(update).is_some_and(|kind| kinds.contains(&kind)),
+            Matcher::Custom(f) => f(update),
+        }
+    }
+}
+
+/// Admits updates for handling according to their lanes.
+pub(crate) struct Scheduler {
+    lanes: Vec<(Lane, Option<Arc<Semaphore>>)>,
+    in_flight: Option<Arc<PrioritySemaphore>>,
+}
+
+/// Allows an update to be handled until dropped.
+pub(crate) struct Permit {
+    _lane: Option<OwnedSemaphorePermit>,
+    _in_flight: Option<PriorityPermit>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(lanes: Vec<Lane>, max_in_flight: Option<usize>) -> Self {
+        let lanes = lanes
+            .into_iter()
+            .map(|lane| {
+                let semaphore = lane.max_concurrency.map(|max| Arc::new(Semaphore::new(max)));
+                (lane, semaphore)
+            })
+            .collect();
+        let in_flight = max_in_flight.map(|max| Arc::new(PrioritySemaphore::new(max)));
+
+        Self { lanes, in_flight }
+    }
+
+    /// Waits until `update` can be handled.
+    pub(crate) async fn acquire(&self, update: &Update) -> Permit {
+        let (priority, semaphore) = self
+            .lanes
+            .iter()
+            .find(|(lane, _)| lane.matches(update))
+            .map_or((0, None), |(lane, semaphore)| (lane.priority, semaphore.clone()));
+
+        let lane = match semaphore {
+            Some(semaphore) => Some(semaphore.acquire_owned().await.expect("semaphore is closed")),
+            None => None,
+        };
+        let in_flight = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.acquire(priority).await),
+            None => None,
+        };
+
+        Permit { _lane: lane, _in_flight: in_flight }
+    }
+}
+
+/// A semaphore that hands out permits to waiters with a higher priority first,
+/// and in the FIFO order among waiters with the same priority.
+struct PrioritySemaphore {
+    state: Mutex<PriorityState>,
+}
+
+struct PriorityState {
+    available: usize,
+    waiters: BTreeMap<(Reverse<i32>, u64), oneshot::Sender<()>>,
+    next_waiter: u64,
+}
+
+struct PriorityPermit {
+    semaphore: Arc<PrioritySemaphore>,
+}
+
+/// A pending [`PrioritySemaphore::acquire`], which returns a permit that was
+/// handed to it but not received if cancelled.
+struct Waiter {
+    semaphore: Arc<PrioritySemaphore>,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl PrioritySemaphore {
+    fn new(permits: usize) -> Self {
+        let state = PriorityState { available: permits, waiters: BTreeMap::new(), next_waiter: 0 };
+        Self { state: Mutex::new(state) }
+    }
+
+    async fn acquire(self: &Arc<Self>, priority: i32) -> PriorityPermit {
+        let mut waiter = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.waiters.is_empty() {
+                state.available -= 1;
+                return PriorityPermit { semaphore: Arc::clone(self) };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let key = (Reverse(priority), state.next_waiter);
+            state.next_waiter += 1;
+            state.waiters.insert(key, tx);
+            Waiter { semaphore: Arc::clone(self), rx: Some(rx) }
+        };
+
+        let rx = waiter.rx.as_mut().unwrap();
+        rx.await.expect("the sender is only dropped after sending");
+        waiter.rx = None;
+        PriorityPermit { semaphore: Arc::clone(self) }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some((_, tx)) = state.waiters.pop_first() {
+            // Fails if the waiter was cancelled.
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+impl Drop for PriorityPermit {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        // `rx` is taken after the permit is received, so this only succeeds if
+        // it was handed to a cancelled waiter. The channel is closed first, so
+        // that a concurrent `release` can't hand a permit after the check.
+        let Some(rx) = self.rx.as_mut() else { return };
+        rx.close();
+        if rx.try_recv().is_ok() {
+            self.semaphore.release();
+        }
+    }
+}
+
+/// Returns the kind of an update, or `None` if it failed to parse.
+fn kind(update: &Update) -> Option<AllowedUpdate> {
+    use UpdateKind::*;
+
+    let kind = match update.kind {
+        Message(_) => AllowedUpdate::Message,
+        EditedMessage(_) => AllowedUpdate::EditedMessage,
+        ChannelPost(_) => AllowedUpdate::ChannelPost,
+        EditedChannelPost(_) => AllowedUpdate::EditedChannelPost,
+        BusinessConnection(_) => AllowedUpdate::BusinessConnection,
+        BusinessMessage(_) => AllowedUpdate::BusinessMessage,
+        EditedBusinessMessage(_) => AllowedUpdate::EditedBusinessMessage,
+        DeletedBusinessMessages(_) => AllowedUpdate::DeletedBusinessMessages,
+        MessageReaction(_) => AllowedUpdate::MessageReaction,
+        MessageReactionCount(_) => AllowedUpdate::MessageReactionCount,
+        InlineQuery(_) => AllowedUpdate::InlineQuery,
+        ChosenInlineResult(_) => AllowedUpdate::ChosenInlineResult,
+        CallbackQuery(_) => AllowedUpdate::CallbackQuery,
+        ShippingQuery(_) => AllowedUpdate::ShippingQuery,
+        PreCheckoutQuery(_) => AllowedUpdate::PreCheckoutQuery,
+        Poll(_) => AllowedUpdate::Poll,
+        PollAnswer(_) => AllowedUpdate::PollAnswer,
+        MyChatMember(_) => AllowedUpdate::MyChatMember,
+        ChatMember(_) => AllowedUpdate::ChatMember,
+        ChatJoinRequest(_) => AllowedUpdate::ChatJoinRequest,
+        ChatBoost(_) => AllowedUpdate::ChatBoost,
+        RemovedChatBoost(_) => AllowedUpdate::RemovedChatBoost,
+        Error(_) => return None,
+    };
+    Some(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::types::UpdateId;
+
+    fn update(id: u32) -> Update {
+        Update { id: UpdateId(id), kind: UpdateKind::Error(Default::default()) }
+    }
+
+    #[tokio::test]
+    async fn priorities() {
+        let scheduler = Arc::new(Scheduler::new(
+            vec![
+                Lane::matching(|upd| upd.id.0 >= 10).priority(1),
+                Lane::matching(|upd| upd.id.0 >= 5).max_concurrency(1),
+            ],
+            Some(1),
+        ));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let first = scheduler.acquire(&update(0)).await;
+        for id in [1, 10, 11, 2] {
+            let scheduler = Arc::clone(&scheduler);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _permit = scheduler.acquire(&update(id)).await;
+                tx.send(id).unwrap();
+            });
+            // Let the task start waiting.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // A cancelled waiter doesn't take a permit.
+        let cancelled = tokio::time::timeout(Duration::ZERO, scheduler.acquire(&update(3))).await;
+        assert!(cancelled.is_err());
+
+        drop(first);
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(order, [10, 11, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn concurrency() {
+        let scheduler =
+            Scheduler::new(vec![Lane::matching(|upd| upd.id.0 < 10).max_concurrency(2)], None);
+
+        let _first = scheduler.acquire(&update(1)).await;
+        let second = scheduler.acquire(&update(2)).await;
+        let third = update(3);
+        let third = tokio::time::timeout(Duration::from_millis(10), scheduler.acquire(&third));
+        assert!(third.await.is_err());
+        // Updates of other lanes are not limited.
+        let _other = scheduler.acquire(&update(10)).await;
+
+        drop(second);
+        let _third = scheduler.acquire(&update(3)).await;
+    }
+
+    #[test]
+    fn cancellation_during_release() {
+        use futures::{task::noop_waker_ref, FutureExt};
+        use std::task::Context;
+
+        const WAITERS: usize = 100;
+
+        for _ in 0..1000 {
+            let semaphore = Arc::new(PrioritySemaphore::new(0));
+            let mut waiters: Vec<_> = (0..WAITERS)
+                .map(|_| {
+                    let semaphore = Arc::clone(&semaphore);
+                    async move { semaphore.acquire(0).await }.boxed()
+                })
+                .collect();
+            for waiter in &mut waiters {
+                let poll = waiter.poll_unpin(&mut Context::from_waker(noop_waker_ref()));
+                assert!(poll.is_pending());
+            }
+
+            // Cancel the waiters while permits are being released to them.
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let cancel = std::thread::spawn({
+                let barrier = Arc::clone(&barrier);
+                move || {
+                    barrier.wait();
+                    waiters.into_iter().for_each(drop);
+                }
+            });
+            barrier.wait();
+            for _ in 0..WAITERS {
+                semaphore.release();
+            }
+            cancel.join().unwrap();
+
+            assert_eq!(semaphore.state.lock().unwrap().available, WAITERS);
+        }
+    }
+}
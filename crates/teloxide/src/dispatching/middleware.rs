@@ -0,0 +1,199 @@
+use std::{future::Future, ops::ControlFlow, sync::Arc};
+
+use dptree::di::{DependencyMap, DependencySupplier};
+use futures::future::BoxFuture;
+
+use crate::dispatching::dispatcher::{DefaultHandler, UpdateHandler};
+
+/// A layer of behaviour around the handling of every update.
+///
+/// Middlewares are registered via [`DispatcherBuilder::middleware`] and wrap
+/// each other like an onion: a middleware receives the dependencies of an
+/// update, including the [`Update`] itself, and decides whether to pass them
+/// further via [`Next::run`], which runs the rest of the middlewares and then
+/// the handler. This way, a middleware can:
+///
+///  - inspect the update and the other dependencies;
+///  - insert new dependencies before calling [`Next::run`];
+///  - short-circuit by not calling [`Next::run`] at all;
+///  - observe the result of the handler or replace it.
+///
+/// If the handler doesn't handle the update, the default handler is called
+/// instead, and [`Next::run`] returns `Ok(())`. Errors returned from the
+/// outermost middleware are passed to the error handler.
+///
+/// This trait is implemented for asynchronous functions that accept
+/// [`DependencyMap`] and [`Next`].
+///
+/// ## Examples
+///
+/// Measuring the time of handling and dropping updates from banned users:
+///
+/// ```
+/// use std::time::Instant;
+///
+/// use teloxide::{
+///     dispatching::{Dispatcher, Next},
+///     dptree::di::{DependencyMap, DependencySupplier},
+///     types::{Update, UserId},
+///     Bot,
+/// };
+///
+/// type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+///
+/// async fn timing(
+///     deps: DependencyMap,
+///     next: Next<Box<dyn std::error::Error + Send + Sync>>,
+/// ) -> HandlerResult {
+///     let update: std::sync::Arc<Update> = deps.get();
+///     let started = Instant::now();
+///     let res = next.run(deps).await;
+///     log::info!("update {} took {:?}", update.id.0, started.elapsed());
+///     res
+/// }
+///
+/// async fn ban(
+///     mut deps: DependencyMap,
+///     next: Next<Box<dyn std::error::Error + Send + Sync>>,
+/// ) -> HandlerResult {
+///     let update: std::sync::Arc<Update> = deps.get();
+///     if update.from().is_some_and(|user| user.id == UserId(666)) {
+///         return Ok(());
+///     }
+///     deps.insert(Instant::now());
+///     next.run(deps).await
+/// }
+///
+/// let handler = teloxide::dptree::endpoint(|_started: Instant| async { HandlerResult::Ok(()) });
+/// let dp =
+///     Dispatcher::builder(Bot::new("TOKEN"), handler).middleware(timing).middleware(ban).build();
+/// # let _ = dp;
+/// ```
+///
+/// [`DispatcherBuilder::middleware`]: crate::dispatching::DispatcherBuilder::middleware
+/// [`Update`]: crate::types::Update
+pub trait Middleware<Err> {
+    #[must_use]
+    fn handle(
+        self: Arc<Self>,
+        deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, Result<(), Err>>;
+}
+
+impl<Err, F, Fut> Middleware<Err> for F
+where
+    F: Fn(DependencyMap, Next<Err>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), Err>> + Send + 'static,
+{
+    fn handle(
+        self: Arc<Self>,
+        deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, Result<(), Err>> {
+        Box::pin(self(deps, next))
+    }
+}
+
+pub(crate) type Middlewares<Err> = Arc<[Arc<dyn Middleware<Err> + Send + Sync>]>;
+
+/// The rest of the middlewares and the handler, see [`Middleware`].
+#[must_use = "the update is not handled unless `Next::run` is called"]
+pub struct Next<Err> {
+    middlewares: Middlewares<Err>,
+    index: usize,
+    handler: Arc<UpdateHandler<Err>>,
+    default_handler: DefaultHandler,
+}
+
+impl<Err> Clone for Next<Err> {
+    fn clone(&self) -> Self {
+        Self {
+            middlewares: Arc::clone(&self.middlewares),
+            index: self.index,
+            handler: Arc::clone(&self.handler),
+            default_handler: Arc::clone(&self.default_handler),
+        }
+    }
+}
+
+impl<Err> Next<Err>
+where
+    Err: Send + Sync + 'static,
+{
+    pub(crate) fn new(
+        middlewares: Middlewares<Err>,
+        handler: Arc<UpdateHandler<Err>>,
+        default_handler: DefaultHandler,
+    ) -> Self {
+        Self { middlewares, index: 0, handler, default_handler }
+    }
+
+    /// Runs the rest of the middlewares and the handler with `deps`.
+    pub fn run(self, deps: DependencyMap) -> BoxFuture<'static, Result<(), Err>> {
+        match self.middlewares.get(self.index).cloned() {
+            Some(middleware) => middleware.handle(deps, Self { index: self.index + 1, ..self }),
+            None => Box::pin(async move {
+                match self.handler.dispatch(deps).await {
+                    ControlFlow::Break(res) => res,
+                    ControlFlow::Continue(deps) => {
+                        (self.default_handler)(deps.get()).await;
+                        Ok(())
+                    }
+                }
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    #[tokio::test]
+    async fn onion() {
+        let log = Log::default();
+
+        // Observes the result of the rest of the chain.
+        let outer = |deps: DependencyMap, next: Next<String>| async move {
+            let log: Arc<Log> = deps.get();
+            let res = next.run(deps).await;
+            log.lock().unwrap().push(format!("result: {res:?}"));
+            res
+        };
+
+        // Short-circuits on zero, injects a dependency otherwise.
+        let inner = |mut deps: DependencyMap, next: Next<String>| async move {
+            let n: Arc<i32> = deps.get();
+            if *n == 0 {
+                return Ok(());
+            }
+            deps.insert(format!("injected {n}"));
+            next.run(deps).await
+        };
+
+        let handler = dptree::endpoint(|s: String, log: Log| async move {
+            log.lock().unwrap().push(s.clone());
+            Err(s)
+        });
+        let middlewares: Middlewares<String> = Arc::new([Arc::new(outer), Arc::new(inner)]);
+        let next = || {
+            Next::new(
+                Arc::clone(&middlewares),
+                Arc::new(handler.clone()),
+                Arc::new(|_| Box::pin(async {})),
+            )
+        };
+
+        assert_eq!(next().run(dptree::deps![1, Arc::clone(&log)]).await, Err("injected 1".into()));
+        assert_eq!(*log.lock().unwrap(), ["injected 1", "result: Err(\"injected 1\")"]);
+
+        log.lock().unwrap().clear();
+        assert_eq!(next().run(dptree::deps![0, Arc::clone(&log)]).await, Ok(()));
+        assert_eq!(*log.lock().unwrap(), ["result: Ok(())"]);
+    }
+}
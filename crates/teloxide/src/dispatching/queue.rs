@@ -0,0 +1,294 @@
+//! Distributing updates between processes via a queue.
+//!
+//! A single [`Dispatcher`] handles updates in one process. To scale it out
+//! without breaking sequential processing of updates of the same chat, the
+//! work can be split between:
+//!
+//!  - a receiver process, which gets updates from an update listener and pushes
+//!    them into an [`UpdateQueue`] via [`forward`];
+//!  - worker processes, which run [`Dispatcher`]s with [`QueueListener`]s.
+//!
+//! The queue is split into a fixed number of partitions. [`forward`] puts
+//! updates with the same distribution key (see [update grouping]) into the
+//! same partition, and each partition must be consumed by exactly one worker
+//! at a time, so updates of a chat are handled in order.
+//!
+//! Updates are delivered at least once: a worker acknowledges an update only
+//! after it's handled, and the updates it didn't acknowledge, e.g. because of
+//! a crash, are delivered again when it restarts. See [`QueueListener`] for
+//! how failed updates are retried.
+//!
+//! [`RedisQueue`] implements [`UpdateQueue`] on top of [Redis Streams].
+//!
+//! ## Examples
+//!
+//! The receiver:
+//!
+//! ```no_run
+//! # #[cfg(feature = "redis-queue")]
+//! # async fn run() {
+//! use teloxide::{
+//!     dispatching::queue::{self, RedisQueue},
+//!     error_handlers::LoggingErrorHandler,
+//!     prelude::*,
+//!     update_listeners,
+//! };
+//!
+//! let bot = Bot::from_env();
+//! let queue = RedisQueue::open("redis://127.0.0.1:6379", "updates", 16).await.unwrap();
+//! let listener = update_listeners::polling_default(bot).await;
+//!
+//! queue::forward(
+//!     listener,
+//!     queue,
+//!     |upd| upd.chat().map(|chat| chat.id),
+//!     LoggingErrorHandler::new(),
+//! )
+//! .await;
+//! # }
+//! ```
+//!
+//! A worker, which handles the first half of the partitions:
+//!
+//! ```no_run
+//! # #[cfg(feature = "redis-queue")]
+//! # async fn run() {
+//! use teloxide::{
+//!     dispatching::queue::{QueueListener, RedisQueue},
+//!     error_handlers::LoggingErrorHandler,
+//!     prelude::*,
+//! };
+//!
+//! let bot = Bot::from_env();
+//! let queue = RedisQueue::open("redis://127.0.0.1:6379", "updates", 16).await.unwrap();
+//! let listener = QueueListener::new(queue, "worker-1", 0..8);
+//!
+//! let handler = Update::filter_message().endpoint(|bot: Bot, msg: Message| async move {
+//!     bot.send_message(msg.chat.id, "Hi!").await?;
+//!     Ok::<_, teloxide::RequestError>(())
+//! });
+//!
+//! Dispatcher::builder(bot, handler)
+//!     .middleware(listener.acker())
+//!     .build()
+//!     .dispatch_with_listener(listener, LoggingErrorHandler::new())
+//!     .await;
+//! # }
+//! ```
+//!
+//! [`Dispatcher`]: crate::dispatching::Dispatcher
+//! [update grouping]: crate::dispatching::DispatcherBuilder::distribution_function#update-grouping
+//! [Redis Streams]: https://redis.io/docs/latest/develop/data-types/streams/
+
+#[cfg(feature = "redis-queue")]
+pub use redis_queue::{RedisQueue, RedisQueueError};
+
+pub use queue_listener::{QueueAcker, QueueListener, QueueListenerError};
+
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, StreamExt as _};
+use thiserror::Error;
+
+use crate::{error_handlers::ErrorHandler, types::Update, update_listeners::UpdateListener};
+
+mod queue_listener;
+#[cfg(feature = "redis-queue")]
+mod redis_queue;
+
+/// A partitioned queue of updates, see the [module-level documentation](self).
+pub trait UpdateQueue {
+    type Err;
+
+    /// Returns the number of partitions.
+    fn partitions(&self) -> u32;
+
+    /// Appends `update` to the end of `partition`.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn push(
+        self: Arc<Self>,
+        partition: u32,
+        update: Update,
+    ) -> BoxFuture<'static, Result<(), Self::Err>>;
+
+    /// Returns the updates of `partitions` which were delivered to `consumer`
+    /// but not acknowledged, in the order they were pushed.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn pull_unacked(
+        self: Arc<Self>,
+        consumer: Arc<str>,
+        partitions: Arc<[u32]>,
+    ) -> BoxFuture<'static, Result<Vec<Delivery>, Self::Err>>;
+
+    /// Delivers new updates of `partitions` to `consumer`, in the order they
+    /// were pushed.
+    ///
+    /// Waits for a while if there are no new updates, and may return an empty
+    /// list.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn pull(
+        self: Arc<Self>,
+        consumer: Arc<str>,
+        partitions: Arc<[u32]>,
+    ) -> BoxFuture<'static, Result<Vec<Delivery>, Self::Err>>;
+
+    /// Acknowledges that a delivered update was handled, so it's not delivered
+    /// again.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn ack(self: Arc<Self>, tag: DeliveryTag) -> BoxFuture<'static, Result<(), Self::Err>>;
+}
+
+/// An update delivered from an [`UpdateQueue`].
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    /// Identifies the delivery for [`UpdateQueue::ack`].
+    pub tag: DeliveryTag,
+
+    /// The delivered update.
+    pub update: Update,
+
+    /// How many times the update was delivered, including this time.
+    pub attempt: u32,
+}
+
+/// Identifies a [`Delivery`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeliveryTag {
+    /// The partition which the update was pulled from.
+    pub partition: u32,
+
+    /// The ID of the update in the partition, specific to the queue.
+    pub id: String,
+}
+
+/// An error passed to the error handler of [`forward`].
+#[derive(Debug, Error)]
+pub enum ForwardError<LE, QE> {
+    /// An error from the update listener.
+    #[error("update listener error: {0:?}")]
+    Listener(LE),
+
+    /// An error pushing an update into the queue. The update is pushed again
+    /// after a delay.
+    #[error("update queue error: {0:?}")]
+    Queue(QE),
+}
+
+/// The delay before pushing an update again after [`ForwardError::Queue`].
+const PUSH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Pushes updates from `listener` into `queue`, partitioned by
+/// `distribution_f`, until the listener stops.
+///
+/// Updates for which `distribution_f` returns the same key are pushed into the
+/// same partition. To keep the order of updates, an update that cannot be
+/// pushed is retried until it succeeds. To stop forwarding, use the
+/// [`StopToken`] of `listener`.
+///
+/// Note that partitions are computed from the [`Hash`] implementation of the
+/// key, which must be the same in the receiver and the workers if they also
+/// use partitions to route updates.
+///
+/// [`StopToken`]: crate::stop::StopToken
+pub async fn forward<L, Q, K, Eh>(
+    mut listener: L,
+    queue: Arc<Q>,
+    distribution_f: fn(&Update) -> Option<K>,
+    error_handler: Arc<Eh>,
+) where
+    L: UpdateListener,
+    Q: UpdateQueue,
+    K: Hash,
+    Eh: ErrorHandler<ForwardError<L::Err, Q::Err>>,
+{
+    let partitions = queue.partitions();
+    let stream = listener.as_stream();
+    tokio::pin!(stream);
+
+    while let Some(update) = stream.next().await {
+        let update = match update {
+            Ok(update) => update,
+            Err(err) => {
+                Arc::clone(&error_handler).handle_error(ForwardError::Listener(err)).await;
+                continue;
+            }
+        };
+
+        let partition = partition(&update, distribution_f, partitions);
+        while let Err(err) = Arc::clone(&queue).push(partition, update.clone()).await {
+            Arc::clone(&error_handler).handle_error(ForwardError::Queue(err)).await;
+            tokio::time::sleep(PUSH_RETRY_DELAY).await;
+        }
+    }
+}
+
+/// Returns the partition of `update`, out of `partitions`.
+///
+/// Updates without a distribution key are spread evenly by their IDs.
+pub(crate) fn partition<K>(
+    update: &Update,
+    distribution_f: fn(&Update) -> Option<K>,
+    partitions: u32,
+) -> u32
+where
+    K: Hash,
+{
+    let hash = match distribution_f(update) {
+        Some(key) => {
+            let mut hasher = StableHasher::default();
+            key.hash(&mut hasher);
+            hasher.finish()
+        }
+        None => u64::from(update.id.0),
+    };
+
+    (hash % u64::from(partitions)) as u32
+}
+
+/// The 64-bit FNV-1a hash, which unlike the standard hasher gives the same
+/// results in all processes.
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ChatId, UpdateId, UpdateKind};
+
+    #[test]
+    fn partitioning() {
+        let update = |id| Update { id: UpdateId(id), kind: UpdateKind::Error(Default::default()) };
+
+        // The same key always goes to the same partition.
+        let by_chat = |upd: &Update| Some(ChatId(i64::from(upd.id.0 / 100)));
+        assert_eq!(partition(&update(1), by_chat, 16), partition(&update(2), by_chat, 16));
+        assert_eq!(partition(&update(1), by_chat, 16), 5);
+        assert_eq!(partition(&update(101), by_chat, 16), 4);
+
+        // Updates without a key are spread by their IDs.
+        let no_key = |_: &Update| None::<ChatId>;
+        assert_eq!(partition(&update(17), no_key, 16), 1);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dptree::di::{DependencyMap, DependencySupplier};
+use futures::{
+    future::{self, BoxFuture, Either},
+    stream::{self, BoxStream},
+    StreamExt as _,
+};
+use thiserror::Error;
+
+use crate::{
+    dispatching::{
+        queue::{Delivery, DeliveryTag, UpdateQueue},
+        Middleware, Next,
+    },
+    stop::{mk_stop_token, StopFlag, StopToken},
+    types::{Update, UpdateId},
+    update_listeners::{AsUpdateStream, UpdateListener},
+};
+
+/// The delay before pulling updates again after [`QueueListenerError::Queue`].
+const PULL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An error returned from the stream of [`QueueListener`].
+#[derive(Debug, Error)]
+pub enum QueueListenerError<E> {
+    /// An error pulling or acknowledging updates.
+    #[error("update queue error: {0:?}")]
+    Queue(E),
+
+    /// An update was delivered more times than allowed by
+    /// [`QueueListener::max_attempts`], e.g. because it crashed the worker
+    /// every time. It was acknowledged without being handled.
+    #[error("update {} was dropped after {attempts} delivery attempts", .update_id.0)]
+    TooManyAttempts {
+        /// The ID of the dropped update.
+        update_id: UpdateId,
+        /// How many times the update was delivered.
+        attempts: u32,
+    },
+}
+
+/// Updates delivered to a listener which are not handled yet.
+type Deliveries = Arc<Mutex<HashMap<UpdateId, (DeliveryTag, u32)>>>;
+
+/// An update listener which pulls updates from an [`UpdateQueue`].
+///
+/// Updates are acknowledged by [`QueueAcker`], which must be added to the
+/// [`Dispatcher`] as a middleware via [`QueueListener::acker`]. Until an
+/// update is acknowledged, it's delivered again to the same consumer every time
+/// the listener starts.
+///
+/// If a handler returns an error, the update is handled again right away,
+/// until it succeeds or [`QueueListener::max_attempts`] is reached. Only the
+/// last error is passed to the error handler of the [`Dispatcher`], and the
+/// update is acknowledged either way. An update that was delivered more than
+/// `max_attempts` times is dropped and reported as
+/// [`QueueListenerError::TooManyAttempts`] to the update listener error
+/// handler.
+///
+/// See the [module-level documentation](super) for an example.
+///
+/// [`Dispatcher`]: crate::dispatching::Dispatcher
+pub struct QueueListener<Q> {
+    queue: Arc<Q>,
+    consumer: Arc<str>,
+    partitions: Arc<[u32]>,
+    max_attempts: u32,
+    deliveries: Deliveries,
+    stop_token: StopToken,
+    stop_flag: StopFlag,
+}
+
+impl<Q> QueueListener<Q>
+where
+    Q: UpdateQueue,
+{
+    /// Creates a listener which pulls updates of `partitions` from `queue` as
+    /// `consumer`.
+    ///
+    /// `consumer` identifies the worker in the queue, so that it gets its
+    /// unacknowledged updates back when it restarts.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `partitions` is empty or contains a partition which `queue`
+    /// doesn't have.
+    #[must_use]
+    pub fn new<I>(queue: Arc<Q>, consumer: &str, partitions: I) -> Self
+    where
+        I: IntoIterator<Item = u32>,
+    {
+        let partitions: Arc<[u32]> = partitions.into_iter().collect();
+        assert!(!partitions.is_empty(), "there must be at least one partition");
+        assert!(
+            partitions.iter().all(|&partition| partition < queue.partitions()),
+            "the queue has only {} partitions",
+            queue.partitions()
+        );
+
+        let (stop_token, stop_flag) = mk_stop_token();
+        Self {
+            queue,
+            consumer: consumer.into(),
+            partitions,
+            max_attempts: 3,
+            deliveries: Arc::default(),
+            stop_token,
+            stop_flag,
+        }
+    }
+
+    /// Specifies how many times an update is handled or delivered at most.
+    ///
+    /// By default, it's 3.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `max` is zero.
+    #[must_use]
+    pub fn max_attempts(self, max: u32) -> Self {
+        assert_ne!(max, 0, "the number of attempts must be positive");
+        Self { max_attempts: max, ..self }
+    }
+
+    /// Returns a middleware which acknowledges updates of this listener after
+    /// they are handled.
+    ///
+    /// It should be called after [`QueueListener::max_attempts`] and added as
+    /// the outermost middleware, so that it retries the whole handling.
+    #[must_use]
+    pub fn acker(&self) -> QueueAcker<Q> {
+        QueueAcker {
+            queue: Arc::clone(&self.queue),
+            max_attempts: self.max_attempts,
+            deliveries: Arc::clone(&self.deliveries),
+        }
+    }
+}
+
+impl<Q> UpdateListener for QueueListener<Q>
+where
+    Q: UpdateQueue + Send + Sync + 'static,
+    Q::Err: Send,
+{
+    type Err = QueueListenerError<Q::Err>;
+
+    fn stop_token(&mut self) -> StopToken {
+        self.stop_token.clone()
+    }
+}
+
+impl<'a, Q> AsUpdateStream<'a> for QueueListener<Q>
+where
+    Q: UpdateQueue + Send + Sync + 'static,
+    Q::Err: Send,
+{
+    type StreamErr = QueueListenerError<Q::Err>;
+    type Stream = BoxStream<'a, Result<Update, Self::StreamErr>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let state = StreamState {
+            queue: Arc::clone(&self.queue),
+            consumer: Arc::clone(&self.consumer),
+            partitions: Arc::clone(&self.partitions),
+            max_attempts: self.max_attempts,
+            deliveries: Arc::clone(&self.deliveries),
+            stop_flag: self.stop_flag.clone(),
+            unacked: true,
+            failed: false,
+            buffer: VecDeque::new(),
+        };
+
+        stream::unfold(state, StreamState::next).boxed()
+    }
+}
+
+struct StreamState<Q: UpdateQueue> {
+    queue: Arc<Q>,
+    consumer: Arc<str>,
+    partitions: Arc<[u32]>,
+    max_attempts: u32,
+    deliveries: Deliveries,
+    stop_flag: StopFlag,
+    // Whether unacknowledged updates are still to be pulled.
+    unacked: bool,
+    // Whether the last pull failed.
+    failed: bool,
+    buffer: VecDeque<Result<Update, QueueListenerError<Q::Err>>>,
+}
+
+impl<Q> StreamState<Q>
+where
+    Q: UpdateQueue,
+{
+    async fn next(mut self) -> Option<(Result<Update, QueueListenerError<Q::Err>>, Self)> {
+        loop {
+            if let Some(item) = self.buffer.pop_front() {
+                return Some((item, self));
+            }
+
+            let pull = if self.unacked {
+                Arc::clone(&self.queue)
+                    .pull_unacked(Arc::clone(&self.consumer), Arc::clone(&self.partitions))
+            } else {
+                Arc::clone(&self.queue)
+                    .pull(Arc::clone(&self.consumer), Arc::clone(&self.partitions))
+            };
+            let delay = if self.failed { PULL_RETRY_DELAY } else { Duration::ZERO };
+            let pull = async move {
+                tokio::time::sleep(delay).await;
+                pull.await
+            };
+
+            let res = match future::select(Box::pin(pull), self.stop_flag.clone()).await {
+                Either::Left((res, _)) => res,
+                Either::Right(((), _)) => return None,
+            };
+
+            self.failed = res.is_err();
+            match res {
+                Ok(deliveries) => {
+                    self.unacked = false;
+                    for delivery in deliveries {
+                        self.accept(delivery).await;
+                    }
+                }
+                Err(err) => self.buffer.push_back(Err(QueueListenerError::Queue(err))),
+            }
+        }
+    }
+
+    async fn accept(&mut self, Delivery { tag, update, attempt }: Delivery) {
+        if attempt <= self.max_attempts {
+            self.deliveries.lock().unwrap().insert(update.id, (tag, attempt));
+            self.buffer.push_back(Ok(update));
+            return;
+        }
+
+        let dropped =
+            QueueListenerError::TooManyAttempts { update_id: update.id, attempts: attempt };
+        self.buffer.push_back(Err(dropped));
+        if let Err(err) = Arc::clone(&self.queue).ack(tag).await {
+            self.buffer.push_back(Err(QueueListenerError::Queue(err)));
+        }
+    }
+}
+
+/// A middleware which acknowledges updates of a [`QueueListener`] after they
+/// are handled and retries failed ones, see [`QueueListener::acker`].
+pub struct QueueAcker<Q> {
+    queue: Arc<Q>,
+    max_attempts: u32,
+    deliveries: Deliveries,
+}
+
+impl<Q, Err> Middleware<Err> for QueueAcker<Q>
+where
+    Q: UpdateQueue + Send + Sync + 'static,
+    Q::Err: Debug,
+    Err: Debug + Send + Sync + 'static,
+{
+    fn handle(
+        self: Arc<Self>,
+        deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, Result<(), Err>> {
+        Box::pin(async move {
+            let update: Arc<Update> = deps.get();
+            let delivery = self.deliveries.lock().unwrap().get(&update.id).cloned();
+            let Some((tag, mut attempt)) = delivery else {
+                return next.run(deps).await;
+            };
+
+            let res = loop {
+                let res = next.clone().run(deps.clone()).await;
+                match res {
+                    Err(err) if attempt < self.max_attempts => {
+                        log::warn!(
+                            "Handling of update {} failed on attempt {attempt}, retrying: {err:?}",
+                            update.id.0
+                        );
+                        attempt += 1;
+                    }
+                    res => break res,
+                }
+            };
+
+            self.deliveries.lock().unwrap().remove(&update.id);
+            if let Err(err) = Arc::clone(&self.queue).ack(tag).await {
+                log::error!("Cannot acknowledge update {}: {err:?}", update.id.0);
+            }
+
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::types::UpdateKind;
+
+    /// A queue with a single partition which keeps everything in memory.
+    #[derive(Default)]
+    struct MemQueue {
+        state: Mutex<MemState>,
+    }
+
+    #[derive(Default)]
+    struct MemState {
+        updates: Vec<Update>,
+        // The number of deliveries of each update.
+        attempts: Vec<u32>,
+        acked: Vec<bool>,
+    }
+
+    impl UpdateQueue for MemQueue {
+        type Err = String;
+
+        fn partitions(&self) -> u32 {
+            1
+        }
+
+        fn push(self: Arc<Self>, _: u32, update: Update) -> BoxFuture<'static, Result<(), String>> {
+            let mut state = self.state.lock().unwrap();
+            state.updates.push(update);
+            state.attempts.push(0);
+            state.acked.push(false);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn pull_unacked(
+            self: Arc<Self>,
+            _: Arc<str>,
+            _: Arc<[u32]>,
+        ) -> BoxFuture<'static, Result<Vec<Delivery>, String>> {
+            let res = self.deliver(|state, i| state.attempts[i] > 0 && !state.acked[i]);
+            Box::pin(async { Ok(res) })
+        }
+
+        fn pull(
+            self: Arc<Self>,
+            _: Arc<str>,
+            _: Arc<[u32]>,
+        ) -> BoxFuture<'static, Result<Vec<Delivery>, String>> {
+            let res = self.deliver(|state, i| state.attempts[i] == 0);
+            Box::pin(async move {
+                if res.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Ok(res)
+            })
+        }
+
+        fn ack(self: Arc<Self>, tag: DeliveryTag) -> BoxFuture<'static, Result<(), String>> {
+            self.state.lock().unwrap().acked[tag.id.parse::<usize>().unwrap()] = true;
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl MemQueue {
+        fn deliver(&self, filter: impl Fn(&MemState, usize) -> bool) -> Vec<Delivery> {
+            let mut state = self.state.lock().unwrap();
+            (0..state.updates.len())
+                .filter(|&i| filter(&state, i))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|i| {
+                    state.attempts[i] += 1;
+                    Delivery {
+                        tag: DeliveryTag { partition: 0, id: i.to_string() },
+                        update: state.updates[i].clone(),
+                        attempt: state.attempts[i],
+                    }
+                })
+                .collect()
+        }
+    }
+
+    fn update(id: u32) -> Update {
+        Update { id: UpdateId(id), kind: UpdateKind::Error(Default::default()) }
+    }
+
+    #[tokio::test]
+    async fn redelivery() {
+        let queue = Arc::new(MemQueue::default());
+        for id in 1..=3 {
+            Arc::clone(&queue).push(0, update(id)).await.unwrap();
+        }
+        // Updates 1 and 2 were delivered before a crash, update 1 twice.
+        queue.deliver(|_, i| i < 2);
+        queue.deliver(|_, i| i < 1);
+
+        let mut listener = QueueListener::new(Arc::clone(&queue), "worker", [0]).max_attempts(2);
+        let acker = Arc::new(listener.acker());
+        let calls = Arc::new(AtomicU32::new(0));
+        let handler: crate::dispatching::UpdateHandler<String> = dptree::endpoint({
+            let calls = Arc::clone(&calls);
+            move |upd: Update| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if upd.id.0 == 3 {
+                        Err("oops".to_owned())
+                    } else {
+                        Ok(())
+                    }
+                }
+            }
+        });
+        let handler = Arc::new(handler);
+
+        let stream = listener.as_stream();
+        tokio::pin!(stream);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(QueueListenerError::TooManyAttempts { update_id: UpdateId(1), attempts: 3 }))
+        ));
+
+        for id in [2, 3] {
+            let upd = stream.next().await.unwrap().unwrap();
+            assert_eq!(upd.id, UpdateId(id));
+
+            let next =
+                Next::new(Arc::new([]), Arc::clone(&handler), Arc::new(|_| Box::pin(async {})));
+            let res = Arc::clone(&acker).handle(dptree::deps![upd], next).await;
+            assert_eq!(res.is_ok(), id == 2);
+        }
+
+        // Update 3 was retried once, and all the updates are acknowledged.
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert_eq!(queue.state.lock().unwrap().acked, [true; 3]);
+    }
+}
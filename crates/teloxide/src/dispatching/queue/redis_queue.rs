@@ -0,0 +1,358 @@
+use std::{collections::HashMap, sync::Arc};
+
+use deadpool_redis::{redis, CreatePoolError, PoolError, Runtime};
+use futures::future::BoxFuture;
+use redis::{from_redis_value, ErrorKind, RedisResult, Value};
+use thiserror::Error;
+
+use crate::{
+    dispatching::queue::{Delivery, DeliveryTag, UpdateQueue},
+    types::Update,
+};
+
+/// The consumer group of all workers.
+const GROUP: &str = "teloxide";
+
+/// The maximum number of updates pulled from a partition at once.
+const PULL_COUNT: usize = 100;
+
+/// How long [`RedisQueue::pull`] waits for new updates.
+const PULL_BLOCK_MILLIS: u64 = 1000;
+
+/// An error returned from [`RedisQueue`].
+#[derive(Debug, Error)]
+pub enum RedisQueueError {
+    #[error("parsing/serializing error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("error from Redis: {0}")]
+    RedisError(#[from] redis::RedisError),
+
+    #[error("error creating redis pool: {0}")]
+    CreatePoolError(#[from] CreatePoolError),
+
+    #[error("redis pool error: {0}")]
+    PoolError(#[from] PoolError),
+}
+
+/// An [`UpdateQueue`] based on [Redis Streams].
+///
+/// Each partition is a stream under the `<name>:<partition>` key, consumed by
+/// the `teloxide` consumer group. Updates are stored as JSON in the `update`
+/// field of stream entries, which are deleted once acknowledged.
+///
+/// Requires Redis 6.2 or newer.
+///
+/// [Redis Streams]: https://redis.io/docs/latest/develop/data-types/streams/
+pub struct RedisQueue {
+    pool: deadpool_redis::Pool,
+    name: String,
+    partitions: u32,
+}
+
+impl RedisQueue {
+    /// Opens the queue `name` with `partitions` partitions, creating its
+    /// streams if needed.
+    ///
+    /// The receiver and all the workers must agree on the number of
+    /// partitions.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `partitions` is zero.
+    pub async fn open(
+        url: &str,
+        name: &str,
+        partitions: u32,
+    ) -> Result<Arc<Self>, RedisQueueError> {
+        assert_ne!(partitions, 0, "there must be at least one partition");
+
+        let config = deadpool_redis::Config::from_url(url);
+        let pool = config.create_pool(Some(Runtime::Tokio1))?;
+        let this = Self { pool, name: name.to_owned(), partitions };
+
+        let mut conn = this.pool.get().await?;
+        for partition in 0..partitions {
+            let res: RedisResult<()> = redis::cmd("XGROUP")
+                .arg("CREATE")
+                .arg(this.key(partition))
+                .arg(GROUP)
+                .arg("0")
+                .arg("MKSTREAM")
+                .query_async(&mut conn)
+                .await;
+            match res {
+                Err(err) if err.code() == Some("BUSYGROUP") => {}
+                res => res?,
+            }
+        }
+
+        Ok(Arc::new(this))
+    }
+
+    fn key(&self, partition: u32) -> String {
+        format!("{}:{partition}", self.name)
+    }
+
+    /// Turns stream entries into deliveries, acknowledging the ones which
+    /// cannot be decoded.
+    async fn deliveries(
+        &self,
+        conn: &mut deadpool_redis::Connection,
+        partition: u32,
+        entries: Vec<Entry>,
+        attempts: &HashMap<String, u32>,
+    ) -> Result<Vec<Delivery>, RedisQueueError> {
+        let mut deliveries = Vec::with_capacity(entries.len());
+        for Entry { id, update } in entries {
+            let update = update.map(|update| serde_json::from_str::<Update>(&update));
+            match update {
+                Some(Ok(update)) => {
+                    let attempt = attempts.get(&id).copied().unwrap_or(1);
+                    let tag = DeliveryTag { partition, id };
+                    deliveries.push(Delivery { tag, update, attempt });
+                }
+                _ => {
+                    log::error!("Cannot decode entry {id} of {}, dropping it", self.key(partition));
+                    ack(conn, &self.key(partition), &id).await?;
+                }
+            }
+        }
+
+        Ok(deliveries)
+    }
+}
+
+impl UpdateQueue for RedisQueue {
+    type Err = RedisQueueError;
+
+    fn partitions(&self) -> u32 {
+        self.partitions
+    }
+
+    fn push(
+        self: Arc<Self>,
+        partition: u32,
+        update: Update,
+    ) -> BoxFuture<'static, Result<(), Self::Err>> {
+        Box::pin(async move {
+            let update = serde_json::to_string(&update)?;
+            let mut conn = self.pool.get().await?;
+            let _: String = redis::cmd("XADD")
+                .arg(self.key(partition))
+                .arg("*")
+                .arg("update")
+                .arg(update)
+                .query_async(&mut conn)
+                .await?;
+            Ok(())
+        })
+    }
+
+    /// Claims pending entries of `consumer` again via `XCLAIM`, which counts
+    /// their deliveries.
+    fn pull_unacked(
+        self: Arc<Self>,
+        consumer: Arc<str>,
+        partitions: Arc<[u32]>,
+    ) -> BoxFuture<'static, Result<Vec<Delivery>, Self::Err>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            let mut deliveries = Vec::new();
+
+            for &partition in partitions.iter() {
+                let key = self.key(partition);
+                let mut start = "-".to_owned();
+                loop {
+                    let pending: Vec<(String, String, u64, u32)> = redis::cmd("XPENDING")
+                        .arg(&key)
+                        .arg(GROUP)
+                        .arg(&start)
+                        .arg("+")
+                        .arg(PULL_COUNT)
+                        .arg(&*consumer)
+                        .query_async(&mut conn)
+                        .await?;
+                    let Some((last, ..)) = pending.last() else { break };
+                    start = format!("({last}");
+
+                    // `XCLAIM` below is one more delivery.
+                    let attempts: HashMap<_, _> =
+                        pending.iter().map(|(id, _, _, count)| (id.clone(), count + 1)).collect();
+                    let claimed: Value = redis::cmd("XCLAIM")
+                        .arg(&key)
+                        .arg(GROUP)
+                        .arg(&*consumer)
+                        .arg(0)
+                        .arg(pending.iter().map(|(id, ..)| id).collect::<Vec<_>>())
+                        .query_async(&mut conn)
+                        .await?;
+                    let entries = parse_entries(&claimed)?;
+                    deliveries
+                        .extend(self.deliveries(&mut conn, partition, entries, &attempts).await?);
+
+                    if pending.len() < PULL_COUNT {
+                        break;
+                    }
+                }
+            }
+
+            Ok(deliveries)
+        })
+    }
+
+    fn pull(
+        self: Arc<Self>,
+        consumer: Arc<str>,
+        partitions: Arc<[u32]>,
+    ) -> BoxFuture<'static, Result<Vec<Delivery>, Self::Err>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            let reply: Value = redis::cmd("XREADGROUP")
+                .arg("GROUP")
+                .arg(GROUP)
+                .arg(&*consumer)
+                .arg("COUNT")
+                .arg(PULL_COUNT)
+                .arg("BLOCK")
+                .arg(PULL_BLOCK_MILLIS)
+                .arg("STREAMS")
+                .arg(partitions.iter().map(|&p| self.key(p)).collect::<Vec<_>>())
+                .arg(vec![">"; partitions.len()])
+                .query_async(&mut conn)
+                .await?;
+
+            let mut deliveries = Vec::new();
+            for (key, entries) in parse_streams(&reply)? {
+                let partition = partition_of(&key)?;
+                deliveries
+                    .extend(self.deliveries(&mut conn, partition, entries, &HashMap::new()).await?);
+            }
+
+            Ok(deliveries)
+        })
+    }
+
+    fn ack(self: Arc<Self>, tag: DeliveryTag) -> BoxFuture<'static, Result<(), Self::Err>> {
+        Box::pin(async move {
+            let mut conn = self.pool.get().await?;
+            ack(&mut conn, &self.key(tag.partition), &tag.id).await
+        })
+    }
+}
+
+async fn ack(
+    conn: &mut deadpool_redis::Connection,
+    key: &str,
+    id: &str,
+) -> Result<(), RedisQueueError> {
+    () = redis::pipe()
+        .atomic()
+        .cmd("XACK")
+        .arg(key)
+        .arg(GROUP)
+        .arg(id)
+        .ignore()
+        .cmd("XDEL")
+        .arg(key)
+        .arg(id)
+        .ignore()
+        .query_async(conn)
+        .await?;
+    Ok(())
+}
+
+/// A stream entry, with the `update` field if the entry wasn't deleted.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    id: String,
+    update: Option<String>,
+}
+
+/// Parses an `XREADGROUP` reply into entries of each stream.
+fn parse_streams(reply: &Value) -> RedisResult<Vec<(String, Vec<Entry>)>> {
+    let streams = match reply {
+        Value::Nil => return Ok(Vec::new()),
+        Value::Map(streams) => streams.iter().map(|(key, entries)| (key, entries)).collect(),
+        Value::Array(streams) => streams
+            .iter()
+            .map(|stream| match stream {
+                Value::Array(pair) if pair.len() == 2 => Ok((&pair[0], &pair[1])),
+                _ => Err(unexpected(stream)),
+            })
+            .collect::<RedisResult<Vec<_>>>()?,
+        _ => return Err(unexpected(reply)),
+    };
+
+    streams
+        .into_iter()
+        .map(|(key, entries)| Ok((from_redis_value(key)?, parse_entries(entries)?)))
+        .collect()
+}
+
+/// Parses a list of stream entries, each of which is an ID and a flat list of
+/// fields and values.
+fn parse_entries(entries: &Value) -> RedisResult<Vec<Entry>> {
+    let Value::Array(entries) = entries else { return Err(unexpected(entries)) };
+
+    entries
+        .iter()
+        .map(|entry| {
+            let (id, fields) = match entry {
+                Value::Array(pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+                _ => return Err(unexpected(entry)),
+            };
+            let mut fields: Option<HashMap<String, String>> = from_redis_value(fields)?;
+            let update = fields.as_mut().and_then(|fields| fields.remove("update"));
+            Ok(Entry { id: from_redis_value(id)?, update })
+        })
+        .collect()
+}
+
+fn partition_of(key: &str) -> RedisResult<u32> {
+    key.rsplit_once(':')
+        .and_then(|(_, partition)| partition.parse().ok())
+        .ok_or_else(|| (ErrorKind::TypeError, "unexpected stream key", key.to_owned()).into())
+}
+
+fn unexpected(value: &Value) -> redis::RedisError {
+    (ErrorKind::TypeError, "unexpected reply", format!("{value:?}")).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(s: &str) -> Value {
+        Value::BulkString(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn parsing() {
+        let entries = Value::Array(vec![
+            Value::Array(vec![
+                bulk("1-0"),
+                Value::Array(vec![bulk("update"), bulk("{}"), bulk("other"), bulk("x")]),
+            ]),
+            // A deleted entry.
+            Value::Array(vec![bulk("2-0"), Value::Nil]),
+        ]);
+        let reply = Value::Array(vec![Value::Array(vec![bulk("updates:3"), entries])]);
+
+        let streams = parse_streams(&reply).unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].0, "updates:3");
+        assert_eq!(partition_of(&streams[0].0).unwrap(), 3);
+        assert_eq!(
+            streams[0].1,
+            [
+                Entry { id: "1-0".to_owned(), update: Some("{}".to_owned()) },
+                Entry { id: "2-0".to_owned(), update: None },
+            ]
+        );
+
+        // `XREADGROUP` returns nil on timeout.
+        assert!(parse_streams(&Value::Nil).unwrap().is_empty());
+        assert!(parse_streams(&bulk("oops")).is_err());
+    }
+}
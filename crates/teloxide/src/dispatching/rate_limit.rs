@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dptree::{
+    di::{DependencyMap, DependencySupplier},
+    Handler,
+};
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+
+use crate::{
+    dispatching::{DpHandlerDescription, Middleware, Next},
+    types::{ChatId, Update, UserId},
+};
+
+/// The number of checks after which buckets of idle keys are evicted.
+const EVICTION_INTERVAL: u32 = 1024;
+
+/// A limit on the rate of incoming updates, see [`RateLimiter`].
+///
+/// Updates are allowed at a steady rate, one per [`Quota::period`], but up to
+/// [`Quota::burst`] of them can arrive at once after a period of inactivity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    period: Duration,
+    burst: u32,
+}
+
+impl Quota {
+    /// Allows `n` updates per second, in a burst of up to `n` updates.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `n` is zero.
+    #[must_use]
+    pub fn per_second(n: u32) -> Self {
+        Self::per(Duration::from_secs(1), n)
+    }
+
+    /// Allows `n` updates per minute, in a burst of up to `n` updates.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `n` is zero.
+    #[must_use]
+    pub fn per_minute(n: u32) -> Self {
+        Self::per(Duration::from_secs(60), n)
+    }
+
+    /// Allows one update per `period`, without bursts.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `period` is zero.
+    #[must_use]
+    pub fn with_period(period: Duration) -> Self {
+        assert!(!period.is_zero(), "the period must be positive");
+        Self { period, burst: 1 }
+    }
+
+    fn per(duration: Duration, n: u32) -> Self {
+        assert_ne!(n, 0, "the number of updates must be positive");
+        Self { period: duration / n, burst: n }
+    }
+
+    /// Specifies the maximum number of updates that are allowed at once.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `burst` is zero.
+    #[must_use]
+    pub fn burst(self, burst: u32) -> Self {
+        assert_ne!(burst, 0, "the burst must be positive");
+        Self { burst, ..self }
+    }
+
+    /// Returns the interval at which updates are allowed.
+    #[must_use]
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Returns the maximum number of updates that are allowed at once.
+    #[must_use]
+    pub fn burst_size(&self) -> u32 {
+        self.burst
+    }
+}
+
+type NotifyHandler = Arc<dyn Fn(Arc<Update>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// What to do with an update that exceeds the [`Quota`].
+enum OnLimit {
+    Drop,
+    Delay,
+    Notify(NotifyHandler),
+}
+
+/// A limiter of the rate of incoming updates per user, chat or another key.
+///
+/// Each key gets its own [token bucket] with the given [`Quota`]. Updates that
+/// exceed the quota are dropped by default, which can be changed via
+/// [`RateLimiter::delay`] and [`RateLimiter::notify`]. Updates without a key
+/// are not limited.
+///
+/// A limiter can be used either as a [`Middleware`], which applies to all
+/// updates, or as a [`dptree`] filter via [`RateLimiter::filter`], which
+/// applies only to a branch of the handler tree. Unlike the [`Throttle`]
+/// adaptor, which limits outgoing requests, it limits incoming updates.
+///
+/// ## Examples
+///
+/// ```
+/// use teloxide::{
+///     dispatching::{Dispatcher, Quota, RateLimiter},
+///     prelude::*,
+/// };
+///
+/// let bot = Bot::new("TOKEN");
+/// let handler = dptree::entry() /* ... */;
+/// let limiter = RateLimiter::per_user(Quota::per_second(1).burst(5)).notify({
+///     let bot = bot.clone();
+///     move |upd| {
+///         let bot = bot.clone();
+///         async move {
+///             if let Some(chat) = upd.chat() {
+///                 let _ = bot.send_message(chat.id, "Slow down!").await;
+///             }
+///         }
+///     }
+/// });
+///
+/// let dp = Dispatcher::builder(bot, handler).middleware(limiter).build();
+/// # let _: Dispatcher<_, (), _> = dp;
+/// ```
+///
+/// [token bucket]: https://en.wikipedia.org/wiki/Token_bucket
+/// [`Throttle`]: crate::adaptors::Throttle
+pub struct RateLimiter<K> {
+    quota: Quota,
+    key: fn(&Update) -> Option<K>,
+    on_limit: OnLimit,
+    state: Mutex<State<K>>,
+}
+
+struct State<K> {
+    buckets: HashMap<K, Bucket>,
+    checks: u32,
+}
+
+/// The state of a token bucket, stored as the time at which it would become
+/// full (the theoretical arrival time of GCRA).
+struct Bucket {
+    full_at: Instant,
+    notified: bool,
+}
+
+/// The result of [`RateLimiter::acquire`].
+enum Decision {
+    Allow,
+    Wait(Duration),
+    Reject { notify: bool },
+}
+
+impl RateLimiter<UserId> {
+    /// Creates a limiter with a bucket per user who caused an update.
+    #[must_use]
+    pub fn per_user(quota: Quota) -> Self {
+        Self::new(quota, |upd| upd.from().map(|user| user.id))
+    }
+}
+
+impl RateLimiter<ChatId> {
+    /// Creates a limiter with a bucket per chat of an update.
+    #[must_use]
+    pub fn per_chat(quota: Quota) -> Self {
+        Self::new(quota, |upd| upd.chat().map(|chat| chat.id))
+    }
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Hash + Eq + Send + 'static,
+{
+    /// Creates a limiter with a bucket per key returned from `key`.
+    #[must_use]
+    pub fn new(quota: Quota, key: fn(&Update) -> Option<K>) -> Self {
+        let state = State { buckets: HashMap::new(), checks: 0 };
+        Self { quota, key, on_limit: OnLimit::Drop, state: Mutex::new(state) }
+    }
+
+    /// Delays updates that exceed the quota instead of dropping them.
+    ///
+    /// Note that a delayed update holds up the updates of the same chat if the
+    /// [`Dispatcher`] processes them sequentially (the default).
+    ///
+    /// [`Dispatcher`]: crate::dispatching::Dispatcher
+    #[must_use]
+    pub fn delay(self) -> Self {
+        Self { on_limit: OnLimit::Delay, ..self }
+    }
+
+    /// Drops updates that exceed the quota, calling `handler` with the first
+    /// dropped update of a key.
+    ///
+    /// `handler` is not called again for the key until one of its updates is
+    /// allowed, so it can be used to warn users without flooding them.
+    #[must_use]
+    pub fn notify<H, Fut>(self, handler: H) -> Self
+    where
+        H: Fn(Arc<Update>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler: NotifyHandler = Arc::new(move |upd| Box::pin(handler(upd)));
+        Self { on_limit: OnLimit::Notify(handler), ..self }
+    }
+
+    /// Checks whether `update` fits into the quota, waiting for it if
+    /// [`RateLimiter::delay`] is enabled.
+    ///
+    /// Returns `false` if the update should be dropped.
+    pub async fn check(&self, update: Arc<Update>) -> bool {
+        let Some(key) = (self.key)(&update) else { return true };
+
+        match self.acquire(key) {
+            Decision::Allow => true,
+            Decision::Wait(duration) => {
+                tokio::time::sleep(duration).await;
+                true
+            }
+            Decision::Reject { notify } => {
+                log::debug!("Dropping update {} exceeding the rate limit", update.id.0);
+                if let (true, OnLimit::Notify(handler)) = (notify, &self.on_limit) {
+                    handler(update).await;
+                }
+                false
+            }
+        }
+    }
+
+    /// Returns a handler that lets through only the updates that fit into the
+    /// quota.
+    ///
+    /// ## Dependency requirements
+    ///
+    ///  - [`crate::types::Update`]
+    #[must_use]
+    pub fn filter<Output>(self) -> Handler<'static, DependencyMap, Output, DpHandlerDescription>
+    where
+        K: Sync,
+        Output: Send + Sync + 'static,
+    {
+        let this = Arc::new(self);
+        dptree::filter_async(move |upd: Update| {
+            let this = Arc::clone(&this);
+            async move { this.check(Arc::new(upd)).await }
+        })
+    }
+
+    fn acquire(&self, key: K) -> Decision {
+        let now = Instant::now();
+        let Quota { period, burst } = self.quota;
+        // How far in the future a bucket may become full for an update to fit.
+        let tolerance = period * burst;
+
+        let mut state = self.state.lock().unwrap();
+        state.checks += 1;
+        if state.checks >= EVICTION_INTERVAL {
+            state.checks = 0;
+            state.buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = state.buckets.entry(key).or_insert(Bucket { full_at: now, notified: false });
+        let full_at = bucket.full_at.max(now) + period;
+
+        if full_at <= now + tolerance {
+            bucket.full_at = full_at;
+            bucket.notified = false;
+            return Decision::Allow;
+        }
+
+        match self.on_limit {
+            OnLimit::Delay => {
+                bucket.full_at = full_at;
+                Decision::Wait(full_at - (now + tolerance))
+            }
+            OnLimit::Drop | OnLimit::Notify(_) => {
+                let notify = !bucket.notified;
+                bucket.notified = true;
+                Decision::Reject { notify }
+            }
+        }
+    }
+}
+
+impl<K, Err> Middleware<Err> for RateLimiter<K>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    Err: Send + Sync + 'static,
+{
+    fn handle(
+        self: Arc<Self>,
+        deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, Result<(), Err>> {
+        Box::pin(async move {
+            if self.check(deps.get()).await {
+                next.run(deps).await
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+impl<K> Debug for RateLimiter<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_limit = match self.on_limit {
+            OnLimit::Drop => "Drop",
+            OnLimit::Delay => "Delay",
+            OnLimit::Notify(_) => "Notify",
+        };
+        f.debug_struct("RateLimiter")
+            .field("quota", &self.quota)
+            .field("on_limit", &on_limit)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::types::{UpdateId, UpdateKind};
+
+    fn update(id: u32) -> Arc<Update> {
+        Arc::new(Update { id: UpdateId(id), kind: UpdateKind::Error(Default::default()) })
+    }
+
+    fn key(upd: &Update) -> Option<u32> {
+        Some(upd.id.0 % 2)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drop() {
+        let limiter = RateLimiter::new(Quota::per_second(2).burst(3), key);
+
+        for _ in 0..3 {
+            assert!(limiter.check(update(0)).await);
+        }
+        assert!(!limiter.check(update(0)).await);
+        // Other keys have their own buckets.
+        assert!(limiter.check(update(1)).await);
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.check(update(0)).await);
+        assert!(!limiter.check(update(0)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay() {
+        let limiter = RateLimiter::new(Quota::per_second(10).burst(2), key).delay();
+
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert!(limiter.check(update(0)).await);
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn notify() {
+        let notified = Arc::new(AtomicU32::new(0));
+        let limiter = RateLimiter::new(Quota::with_period(Duration::from_secs(1)), key).notify({
+            let notified = Arc::clone(&notified);
+            move |_| {
+                notified.fetch_add(1, Ordering::Relaxed);
+                async {}
+            }
+        });
+
+        assert!(limiter.check(update(0)).await);
+        assert!(!limiter.check(update(0)).await);
+        assert!(!limiter.check(update(0)).await);
+        assert_eq!(notified.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check(update(0)).await);
+        assert!(!limiter.check(update(0)).await);
+        assert_eq!(notified.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Counters of the per-chat workers of a [`Dispatcher`].
+///
+/// [`Dispatcher`] processes updates with the same distribution key (see
+/// [update grouping]) sequentially, in a worker task spawned on the first such
+/// update. Workers are stopped when they are idle for
+/// [`DispatcherBuilder::worker_idle_timeout`], when there are too many of them
+/// (see [`DispatcherBuilder::max_workers`]) and when dispatching finishes.
+///
+/// The counters are shared with the dispatcher, so they can be read while it
+/// is running, e.g. to export them to a monitoring system. Updates which don't
+/// have a distribution key are handled by a separate worker, which is not
+/// counted.
+///
+/// ## Examples
+///
+/// ```
+/// use teloxide::{dispatching::Dispatcher, dptree, Bot};
+///
+/// let bot = Bot::new("TOKEN");
+/// let handler = dptree::entry() /* ... */;
+/// let dp = Dispatcher::builder(bot, handler)
+///     .worker_idle_timeout(std::time::Duration::from_secs(600))
+///     .max_workers(100_000)
+///     .build();
+/// # let _: Dispatcher<_, (), _> = dp;
+///
+/// let metrics = dp.worker_metrics();
+/// // Somewhere else, while the dispatcher is running...
+/// log::info!("{} workers, {} evicted so far", metrics.live(), metrics.evicted());
+/// ```
+///
+/// [`Dispatcher`]: crate::dispatching::Dispatcher
+/// [update grouping]: crate::dispatching::DispatcherBuilder::distribution_function#update-grouping
+/// [`DispatcherBuilder::worker_idle_timeout`]: crate::dispatching::DispatcherBuilder::worker_idle_timeout
+/// [`DispatcherBuilder::max_workers`]: crate::dispatching::DispatcherBuilder::max_workers
+#[derive(Clone, Debug, Default)]
+pub struct WorkerMetrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    created: AtomicU64,
+    evicted: AtomicU64,
+    live: AtomicU64,
+    backpressure_waits: AtomicU64,
+}
+
+impl WorkerMetrics {
+    /// Returns the number of workers created so far.
+    #[must_use]
+    pub fn created(&self) -> u64 {
+        self.inner.created.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of workers stopped before dispatching finished,
+    /// either because they were idle or to make room for new workers.
+    #[must_use]
+    pub fn evicted(&self) -> u64 {
+        self.inner.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of currently running workers.
+    #[must_use]
+    pub fn live(&self) -> u64 {
+        self.inner.live.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many times the dispatcher had to stop receiving updates
+    /// because [`DispatcherBuilder::max_workers`] workers were busy.
+    ///
+    /// [`DispatcherBuilder::max_workers`]: crate::dispatching::DispatcherBuilder::max_workers
+    #[must_use]
+    pub fn backpressure_waits(&self) -> u64 {
+        self.inner.backpressure_waits.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new worker, which is live until the returned guard is dropped.
+    pub(crate) fn worker_started(&self) -> LiveWorker {
+        self.inner.created.fetch_add(1, Ordering::Relaxed);
+        self.inner.live.fetch_add(1, Ordering::Relaxed);
+        LiveWorker(Arc::clone(&self.inner))
+    }
+
+    pub(crate) fn worker_evicted(&self) {
+        self.inner.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn backpressure_wait(&self) {
+        self.inner.backpressure_waits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct LiveWorker(Arc<Counters>);
+
+impl Drop for LiveWorker {
+    fn drop(&mut self) {
+        self.0.live.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -141,6 +141,8 @@ pub mod prelude;
 pub mod repls;
 pub mod stop;
 pub mod sugar;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod update_listeners;
 pub mod utils;
 
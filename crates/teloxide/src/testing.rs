@@ -0,0 +1,403 @@
+//! Utilities for testing handlers without Telegram.
+//!
+//! [`TestDispatcher`] runs a [`Dispatcher`] with a [`MockBot`], which records
+//! requests instead of sending them, and feeds it updates made with
+//! [`MockMessage`] and [`MockCallbackQuery`]. After each update, it returns
+//! the requests sent while handling it, so that a conversation with a bot can
+//! be scripted and checked step by step:
+//!
+//! ```
+//! use teloxide::{
+//!     dispatching::dialogue::InMemStorage,
+//!     prelude::*,
+//!     testing::{MockBot, MockMessage, TestDispatcher},
+//! };
+//!
+//! #[derive(Clone, Default, Debug, PartialEq)]
+//! enum State {
+//!     #[default]
+//!     Start,
+//!     ReceiveName,
+//! }
+//!
+//! type MyDialogue = Dialogue<State, InMemStorage<State>>;
+//!
+//! fn schema() -> teloxide::dispatching::UpdateHandler<teloxide::RequestError> {
+//!     Update::filter_message()
+//!         .enter_dialogue::<Message, InMemStorage<State>, State>()
+//!         .branch(dptree::case![State::Start].endpoint(
+//!             |bot: MockBot, dialogue: MyDialogue, msg: Message| async move {
+//!                 bot.send_message(msg.chat.id, "What's your name?").await?;
+//!                 dialogue.update(State::ReceiveName).await.unwrap();
+//!                 Ok(())
+//!             },
+//!         ))
+//!         .branch(dptree::case![State::ReceiveName].endpoint(
+//!             |bot: MockBot, dialogue: MyDialogue, msg: Message| async move {
+//!                 let name = msg.text().unwrap_or_default();
+//!                 bot.send_message(msg.chat.id, format!("Hi, {name}!")).await?;
+//!                 dialogue.exit().await.unwrap();
+//!                 Ok(())
+//!             },
+//!         ))
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let bot = MockBot::new();
+//! let builder = Dispatcher::builder(bot, schema())
+//!     .dependencies(dptree::deps![InMemStorage::<State>::new()]);
+//! let mut dispatcher = TestDispatcher::new(builder);
+//!
+//! let sent = dispatcher.dispatch(MockMessage::text("/start")).await;
+//! assert_eq!(sent[0].text(), Some("What's your name?"));
+//! let chat_id = MockMessage::CHAT_ID;
+//! assert_eq!(dispatcher.dialogue_state::<State>(chat_id).await, Some(State::ReceiveName));
+//!
+//! let sent = dispatcher.dispatch(MockMessage::text("Alice")).await;
+//! assert_eq!(sent[0].text(), Some("Hi, Alice!"));
+//! assert_eq!(dispatcher.dialogue_state::<State>(chat_id).await, None);
+//! # }
+//! ```
+//!
+//! [`Dispatcher`]: crate::dispatching::Dispatcher
+
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dptree::di::DependencySupplier;
+use serde_json::{json, Value};
+
+use crate::{
+    dispatching::{
+        dialogue::{InMemStorage, Storage},
+        DefaultKey, Dispatcher, DispatcherBuilder,
+    },
+    error_handlers::IgnoringErrorHandler,
+    types::{CallbackQuery, ChatId, Message, MessageId, Update, UpdateId, UpdateKind, UserId},
+    update_listeners::Replay,
+};
+
+pub use teloxide_core::mock::{MockBot, MockRequest, SentRequest};
+
+/// A builder of a text [`Message`].
+///
+/// By default, the message is sent by [`MockMessage::USER_ID`] in a private
+/// chat with them.
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct MockMessage {
+    id: MessageId,
+    chat_id: ChatId,
+    user_id: UserId,
+    text: String,
+}
+
+impl MockMessage {
+    /// The default sender of messages and callback queries.
+    pub const USER_ID: UserId = UserId(100);
+
+    /// The default chat of messages, a private chat with
+    /// [`MockMessage::USER_ID`].
+    pub const CHAT_ID: ChatId = ChatId(100);
+
+    /// Creates a message with `text`.
+    pub fn text<T>(text: T) -> Self
+    where
+        T: Into<String>,
+    {
+        Self { id: MessageId(1), chat_id: Self::CHAT_ID, user_id: Self::USER_ID, text: text.into() }
+    }
+
+    /// Sets the ID of the message.
+    pub fn id(self, id: MessageId) -> Self {
+        Self { id, ..self }
+    }
+
+    /// Sets the chat of the message.
+    ///
+    /// Positive IDs make private chats, negative IDs make supergroups.
+    pub fn chat_id(self, chat_id: ChatId) -> Self {
+        Self { chat_id, ..self }
+    }
+
+    /// Sets the sender of the message.
+    pub fn user_id(self, user_id: UserId) -> Self {
+        Self { user_id, ..self }
+    }
+
+    /// Builds the message.
+    #[must_use]
+    pub fn build(self) -> Message {
+        let message = json!({
+            "message_id": self.id.0,
+            "date": unix_time(),
+            "chat": chat(self.chat_id),
+            "from": user(self.user_id, false),
+            "text": self.text,
+        });
+        serde_json::from_value(message).expect("Cannot build a message")
+    }
+}
+
+impl From<MockMessage> for Update {
+    fn from(message: MockMessage) -> Self {
+        Update { id: UpdateId(0), kind: UpdateKind::Message(message.build()) }
+    }
+}
+
+/// A builder of a [`CallbackQuery`].
+///
+/// By default, the query is sent by [`MockMessage::USER_ID`] from a message of
+/// the bot in a private chat with them.
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct MockCallbackQuery {
+    id: String,
+    user_id: UserId,
+    data: String,
+    message: Option<Message>,
+}
+
+impl MockCallbackQuery {
+    /// Creates a callback query with `data`.
+    pub fn new<T>(data: T) -> Self
+    where
+        T: Into<String>,
+    {
+        Self { id: "1".to_owned(), user_id: MockMessage::USER_ID, data: data.into(), message: None }
+    }
+
+    /// Sets the ID of the query.
+    pub fn id<T>(self, id: T) -> Self
+    where
+        T: Into<String>,
+    {
+        Self { id: id.into(), ..self }
+    }
+
+    /// Sets the sender of the query.
+    pub fn user_id(self, user_id: UserId) -> Self {
+        Self { user_id, ..self }
+    }
+
+    /// Sets the message with the button which was pressed.
+    pub fn message(self, message: Message) -> Self {
+        Self { message: Some(message), ..self }
+    }
+
+    /// Builds the query.
+    #[must_use]
+    pub fn build(self) -> CallbackQuery {
+        let message = match self.message {
+            Some(message) => serde_json::to_value(message).expect("Cannot serialize a message"),
+            None => json!({
+                "message_id": 1,
+                "date": unix_time(),
+                "chat": chat(ChatId(self.user_id.0 as i64)),
+                "from": user(UserId(1), true),
+                "text": "Message",
+            }),
+        };
+        let query = json!({
+            "id": self.id,
+            "from": user(self.user_id, false),
+            "chat_instance": "1",
+            "message": message,
+            "data": self.data,
+        });
+        serde_json::from_value(query).expect("Cannot build a callback query")
+    }
+}
+
+impl From<MockCallbackQuery> for Update {
+    fn from(query: MockCallbackQuery) -> Self {
+        Update { id: UpdateId(0), kind: UpdateKind::CallbackQuery(query.build()) }
+    }
+}
+
+fn user(id: UserId, is_bot: bool) -> Value {
+    json!({ "id": id.0, "is_bot": is_bot, "first_name": "User" })
+}
+
+fn chat(id: ChatId) -> Value {
+    if id.is_user() {
+        json!({ "id": id.0, "type": "private", "first_name": "User" })
+    } else {
+        json!({ "id": id.0, "type": "supergroup", "title": "Chat" })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A driver which pushes updates through a [`Dispatcher`] with a [`MockBot`],
+/// one at a time.
+///
+/// See the [module-level documentation](self) for an example.
+pub struct TestDispatcher<Err, Key = DefaultKey> {
+    bot: MockBot,
+    dispatcher: Dispatcher<MockBot, Err, Key>,
+    errors: Arc<Mutex<Vec<Err>>>,
+    last_update_id: u32,
+}
+
+impl<Err, Key> TestDispatcher<Err, Key>
+where
+    Err: Debug + Send + Sync + 'static,
+    Key: Hash + Eq + Clone + Send + 'static,
+{
+    /// Builds a dispatcher from `builder`.
+    ///
+    /// The error handler of the builder is replaced, so that errors of
+    /// handlers are returned from [`TestDispatcher::try_dispatch`].
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the builder has several bots.
+    #[must_use]
+    pub fn new(builder: DispatcherBuilder<MockBot, Err, Key>) -> Self {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let dispatcher = builder
+            .error_handler(Arc::new({
+                let errors = Arc::clone(&errors);
+                move |error| {
+                    errors.lock().unwrap().push(error);
+                    async {}
+                }
+            }))
+            .build();
+        let [bot] = dispatcher.bots() else { panic!("there must be exactly one bot") };
+        let bot = bot.clone();
+
+        Self { bot, dispatcher, errors, last_update_id: 0 }
+    }
+
+    /// Returns the bot of the dispatcher, e.g. to set its responses.
+    #[must_use]
+    pub fn bot(&self) -> &MockBot {
+        &self.bot
+    }
+
+    /// Handles `update` and returns the requests sent while handling it.
+    ///
+    /// Updates get consecutive IDs, starting from 1.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if a handler returns an error.
+    pub async fn dispatch<U>(&mut self, update: U) -> Vec<SentRequest>
+    where
+        U: Into<Update>,
+    {
+        match self.try_dispatch(update).await {
+            Ok(sent) => sent,
+            Err(error) => panic!("a handler returned an error: {error:?}"),
+        }
+    }
+
+    /// Handles `update` and returns the requests sent while handling it, or an
+    /// error returned from a handler.
+    pub async fn try_dispatch<U>(&mut self, update: U) -> Result<Vec<SentRequest>, Err>
+    where
+        U: Into<Update>,
+    {
+        self.last_update_id += 1;
+        let mut update = update.into();
+        update.id = UpdateId(self.last_update_id);
+
+        self.bot.take_sent();
+        self.dispatcher
+            .dispatch_with_listener(Replay::new([update]), IgnoringErrorHandler::new())
+            .await;
+
+        let mut sent = self.bot.take_sent();
+        // The dispatcher requests `GetMe` before the handlers run.
+        if sent.first().is_some_and(|request| request.method == "GetMe") {
+            sent.remove(0);
+        }
+
+        match self.errors.lock().unwrap().drain(..).next() {
+            Some(error) => Err(error),
+            None => Ok(sent),
+        }
+    }
+
+    /// Returns the dialogue state of `chat_id` in the [`InMemStorage<S>`] of
+    /// the dependencies.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if there's no `Arc<InMemStorage<S>>` in the dependencies.
+    pub async fn dialogue_state<S>(&self, chat_id: ChatId) -> Option<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let storage: Arc<Arc<InMemStorage<S>>> = self.dispatcher.dependencies().get();
+        Arc::clone(&*storage).get_dialogue(chat_id).await.expect("`InMemStorage` never fails")
+    }
+
+    /// Sets the dialogue state of `chat_id` in the [`InMemStorage<S>`] of the
+    /// dependencies, e.g. to start a test in the middle of a dialogue.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if there's no `Arc<InMemStorage<S>>` in the dependencies.
+    pub async fn set_dialogue_state<S>(&self, chat_id: ChatId, state: S)
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let storage: Arc<Arc<InMemStorage<S>>> = self.dispatcher.dependencies().get();
+        Arc::clone(&*storage)
+            .update_dialogue(chat_id, state)
+            .await
+            .expect("`InMemStorage` never fails");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide_core::{payloads::AnswerCallbackQuery, ApiError};
+
+    use super::*;
+    use crate::{dispatching::UpdateHandler, prelude::*, RequestError};
+
+    fn schema() -> UpdateHandler<RequestError> {
+        dptree::entry()
+            .branch(Update::filter_message().endpoint(|bot: MockBot, msg: Message| async move {
+                bot.send_message(msg.chat.id, format!("echo: {}", msg.text().unwrap())).await?;
+                Ok(())
+            }))
+            .branch(Update::filter_callback_query().endpoint(
+                |bot: MockBot, query: CallbackQuery| async move {
+                    bot.answer_callback_query(query.id).await?;
+                    Ok(())
+                },
+            ))
+    }
+
+    #[tokio::test]
+    async fn dispatch() {
+        let mut dispatcher = TestDispatcher::new(Dispatcher::builder(MockBot::new(), schema()));
+
+        let sent = dispatcher.dispatch(MockMessage::text("hi").chat_id(ChatId(-5))).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, "SendMessage");
+        assert_eq!(sent[0].chat_id(), Some(ChatId(-5)));
+        assert_eq!(sent[0].text(), Some("echo: hi"));
+
+        let sent = dispatcher.dispatch(MockCallbackQuery::new("data").id("42")).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, "AnswerCallbackQuery");
+        assert_eq!(sent[0].payload["callback_query_id"], "42");
+
+        dispatcher.bot().fail_with::<AnswerCallbackQuery>(ApiError::InvalidQueryId);
+        let res = dispatcher.try_dispatch(MockCallbackQuery::new("data")).await;
+        assert!(matches!(res, Err(RequestError::Api(ApiError::InvalidQueryId))));
+    }
+}
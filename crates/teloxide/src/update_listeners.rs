@@ -36,11 +36,13 @@ use crate::{
     types::{AllowedUpdate, Update},
 };
 
+mod journal;
 mod polling;
 mod stateful_listener;
 
 #[allow(deprecated)]
 pub use self::{
+    journal::{Recorder, RecorderStream, Replay, ReplayError},
     polling::{polling_default, Polling, PollingBuilder, PollingStream},
     stateful_listener::StatefulListener,
 };
@@ -0,0 +1,431 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{
+    future::{self, Either},
+    stream::{self, BoxStream},
+    Stream, StreamExt as _,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    stop::{mk_stop_token, StopFlag, StopToken},
+    types::{AllowedUpdate, Update},
+    update_listeners::{AsUpdateStream, UpdateListener},
+};
+
+/// A line of a journal written by [`Recorder`].
+#[derive(Serialize, Deserialize)]
+struct Entry<U> {
+    received_at: String,
+    update: U,
+}
+
+/// An update listener which writes every update of another listener to a
+/// journal file.
+///
+/// The journal is in the [JSON Lines] format: each line is an object with the
+/// time the update was received, in RFC 3339, and the update itself, as
+/// Telegram sends it:
+///
+/// ```json
+/// {"received_at":"2024-06-01T12:00:00.123Z","update":{"update_id":1,"message":{...}}}
+/// ```
+///
+/// Updates are written as they pass through, before they are handled, so the
+/// journal of a bot that crashed ends with the update that crashed it. It can
+/// be fed back into a [`Dispatcher`] via [`Replay`]. Errors writing the
+/// journal are logged and don't interrupt the listener.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use teloxide::{
+///     prelude::*,
+///     update_listeners::{self, Recorder},
+/// };
+///
+/// # async fn run() -> std::io::Result<()> {
+/// let bot = Bot::from_env();
+/// let listener = update_listeners::polling_default(bot.clone()).await;
+/// let listener = Recorder::new(listener, "updates.jsonl")?;
+///
+/// let handler = Update::filter_message().endpoint(|| async { Ok::<_, ()>(()) });
+/// Dispatcher::builder(bot, handler)
+///     .build()
+///     .dispatch_with_listener(listener, LoggingErrorHandler::new())
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [JSON Lines]: https://jsonlines.org/
+/// [`Dispatcher`]: crate::dispatching::Dispatcher
+pub struct Recorder<L> {
+    inner: L,
+    file: File,
+}
+
+impl<L> Recorder<L> {
+    /// Wraps `listener`, appending its updates to the journal at `path`.
+    ///
+    /// The file is created if it doesn't exist.
+    pub fn new<P>(listener: L, path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { inner: listener, file })
+    }
+
+    /// Returns the wrapped listener.
+    #[must_use]
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+}
+
+/// Appends `update` to `file` as a single line.
+fn record(file: &mut File, update: &Update) -> io::Result<()> {
+    let entry =
+        Entry { received_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), update };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    // A single write, so that concurrent writers don't interleave lines.
+    file.write_all(&line)
+}
+
+impl<L> UpdateListener for Recorder<L>
+where
+    L: UpdateListener,
+{
+    type Err = L::Err;
+
+    fn stop_token(&mut self) -> StopToken {
+        self.inner.stop_token()
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        self.inner.hint_allowed_updates(hint);
+    }
+}
+
+impl<'a, L> AsUpdateStream<'a> for Recorder<L>
+where
+    L: UpdateListener,
+{
+    type StreamErr = L::Err;
+    type Stream = RecorderStream<'a, <L as AsUpdateStream<'a>>::Stream>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        RecorderStream { inner: self.inner.as_stream(), file: &mut self.file }
+    }
+}
+
+/// A stream returned by [`Recorder`].
+#[pin_project::pin_project]
+pub struct RecorderStream<'a, S> {
+    #[pin]
+    inner: S,
+    file: &'a mut File,
+}
+
+impl<S, E> Stream for RecorderStream<'_, S>
+where
+    S: Stream<Item = Result<Update, E>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let res = ready!(this.inner.poll_next(cx));
+        if let Some(Ok(update)) = &res {
+            if let Err(err) = record(this.file, update) {
+                log::error!("Cannot record update {}: {err}", update.id.0);
+            }
+        }
+        Poll::Ready(res)
+    }
+}
+
+/// An error returned when a journal cannot be read by [`Replay`].
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("error reading the journal: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("invalid journal entry on line {line}: {error}")]
+    InvalidEntry { line: usize, error: serde_json::Error },
+
+    #[error("invalid time on line {line}: {error}")]
+    InvalidTime { line: usize, error: chrono::ParseError },
+}
+
+/// An update listener which feeds updates recorded by [`Recorder`] back, e.g.
+/// into [`Dispatcher::dispatch_with_listener`].
+///
+/// By default, updates are replayed as fast as they are handled; with
+/// [`Replay::at_recorded_speed`], they are replayed with the same intervals as
+/// they were received. The listener stops after the last update, so
+/// dispatching returns once all of them are handled.
+///
+/// If stopped earlier, the listener keeps the updates that were not returned,
+/// and the next stream starts with them. Like with [`Polling`], the stop token
+/// must be taken again before the next stream.
+///
+/// Combined with a bot which doesn't make real requests, it can turn a
+/// recorded bug report into a regression test.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use teloxide::{prelude::*, update_listeners::Replay};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let bot = Bot::from_env();
+/// let listener = Replay::open("updates.jsonl").await?.at_recorded_speed();
+///
+/// let handler = Update::filter_message().endpoint(|| async { Ok::<_, ()>(()) });
+/// Dispatcher::builder(bot, handler)
+///     .build()
+///     .dispatch_with_listener(listener, LoggingErrorHandler::new())
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Dispatcher::dispatch_with_listener`]: crate::dispatching::Dispatcher::dispatch_with_listener
+/// [`Polling`]: crate::update_listeners::Polling
+pub struct Replay {
+    // Updates along with the time they were received, if known.
+    updates: VecDeque<(Option<DateTime<Utc>>, Update)>,
+    recorded_speed: bool,
+    stop_token: StopToken,
+    // Taken by a stream, a new one is made for the next stream.
+    stop_flag: Option<StopFlag>,
+}
+
+impl Replay {
+    /// Reads the journal at `path`.
+    pub async fn open<P>(path: P) -> Result<Self, ReplayError>
+    where
+        P: AsRef<Path>,
+    {
+        let journal = tokio::fs::read_to_string(path).await?;
+        Self::from_journal(&journal)
+    }
+
+    /// Parses a journal, skipping empty lines.
+    pub fn from_journal(journal: &str) -> Result<Self, ReplayError> {
+        let updates = journal
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let line_number = i + 1;
+                let entry: Entry<Update> = serde_json::from_str(line)
+                    .map_err(|error| ReplayError::InvalidEntry { line: line_number, error })?;
+                let received_at = DateTime::parse_from_rfc3339(&entry.received_at)
+                    .map_err(|error| ReplayError::InvalidTime { line: line_number, error })?;
+                Ok((Some(received_at.to_utc()), entry.update))
+            })
+            .collect::<Result<_, ReplayError>>()?;
+
+        Ok(Self::with_times(updates))
+    }
+
+    /// Creates a listener which returns `updates`.
+    ///
+    /// Since the updates have no recorded times, they are always returned as
+    /// fast as possible.
+    #[must_use]
+    pub fn new<I>(updates: I) -> Self
+    where
+        I: IntoIterator<Item = Update>,
+    {
+        Self::with_times(updates.into_iter().map(|update| (None, update)).collect())
+    }
+
+    fn with_times(updates: VecDeque<(Option<DateTime<Utc>>, Update)>) -> Self {
+        let (stop_token, stop_flag) = mk_stop_token();
+        Self { updates, recorded_speed: false, stop_token, stop_flag: Some(stop_flag) }
+    }
+
+    fn reinit_stop_flag_if_needed(&mut self) {
+        if self.stop_flag.is_none() {
+            let (stop_token, stop_flag) = mk_stop_token();
+            self.stop_token = stop_token;
+            self.stop_flag = Some(stop_flag);
+        }
+    }
+
+    /// Replays updates with the same intervals as they were received.
+    #[must_use]
+    pub fn at_recorded_speed(self) -> Self {
+        Self { recorded_speed: true, ..self }
+    }
+
+    /// Returns the number of updates left to replay.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    /// Returns `true` if there are no updates left to replay.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+}
+
+impl UpdateListener for Replay {
+    type Err = std::convert::Infallible;
+
+    fn stop_token(&mut self) -> StopToken {
+        self.reinit_stop_flag_if_needed();
+        self.stop_token.clone()
+    }
+}
+
+impl<'a> AsUpdateStream<'a> for Replay {
+    type StreamErr = std::convert::Infallible;
+    type Stream = BoxStream<'a, Result<Update, Self::StreamErr>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let recorded_speed = self.recorded_speed;
+        self.reinit_stop_flag_if_needed();
+        let stop_flag = self.stop_flag.take().unwrap();
+
+        // Updates are taken out only once they are returned, so that a listener
+        // stopped halfway resumes from where it stopped.
+        let previous: Option<DateTime<Utc>> = None;
+        stream::unfold((&mut self.updates, previous), move |(updates, previous)| {
+            let stop_flag = stop_flag.clone();
+            async move {
+                let &(received_at, _) = updates.front()?;
+                let delay = match (recorded_speed, previous, received_at) {
+                    (true, Some(previous), Some(received_at)) => {
+                        (received_at - previous).to_std().unwrap_or(Duration::ZERO)
+                    }
+                    _ => Duration::ZERO,
+                };
+
+                // The stop flag goes first, so that a stopped listener doesn't return
+                // updates without a delay.
+                let sleep = std::pin::pin!(tokio::time::sleep(delay));
+                if let Either::Left(_) = future::select(stop_flag, sleep).await {
+                    return None;
+                }
+
+                let (_, update) = updates.pop_front().unwrap();
+                Some((Ok(update), (updates, received_at)))
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+    use crate::update_listeners::StatefulListener;
+
+    fn update(id: u32) -> Update {
+        serde_json::from_value(serde_json::json!({
+            "update_id": id,
+            "message": {
+                "message_id": id,
+                "date": 1717243200,
+                "chat": { "id": 1, "type": "private", "first_name": "A" },
+                "text": format!("message {id}"),
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("teloxide_journal_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("updates.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let (stop_token, _) = mk_stop_token();
+        let listener = StatefulListener::new(
+            (),
+            |_: &mut ()| stream::iter([1, 2].map(|id| Ok::<_, ()>(update(id)))),
+            move |_: &mut ()| stop_token.clone(),
+        );
+        let mut recorder = Recorder::new(listener, &path).unwrap();
+        let recorded: Vec<_> = recorder.as_stream().collect().await;
+        assert_eq!(recorded, [Ok(update(1)), Ok(update(2))]);
+
+        // Simulate a 10-second pause between the updates.
+        let journal = std::fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<Entry<serde_json::Value>> =
+            journal.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        lines[0].received_at = "2024-06-01T12:00:00Z".to_owned();
+        lines[1].received_at = "2024-06-01T12:00:10Z".to_owned();
+        let journal: String =
+            lines.iter().map(|line| serde_json::to_string(line).unwrap() + "\n").collect();
+
+        let mut replay = Replay::from_journal(&journal).unwrap();
+        let replayed: Vec<_> = replay.as_stream().map(Result::unwrap).collect().await;
+        assert_eq!(replayed, [update(1), update(2)]);
+        assert!(replay.is_empty());
+
+        let mut replay = Replay::from_journal(&journal).unwrap().at_recorded_speed();
+        let started = tokio::time::Instant::now();
+        assert_eq!(replay.as_stream().count().await, 2);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        assert!(matches!(
+            Replay::from_journal("\n{}"),
+            Err(ReplayError::InvalidEntry { line: 2, .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_and_resume() {
+        let journal: String = [(1, "12:00:00"), (2, "12:00:10"), (3, "12:00:20")]
+            .into_iter()
+            .map(|(id, time)| {
+                let received_at = format!("2024-06-01T{time}Z");
+                serde_json::to_string(&Entry { received_at, update: update(id) }).unwrap() + "\n"
+            })
+            .collect();
+        let mut replay = Replay::from_journal(&journal).unwrap().at_recorded_speed();
+
+        // Stop while waiting for the second update.
+        let stop_token = replay.stop_token();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            stop_token.stop();
+        });
+        let mut stream = replay.as_stream();
+        assert_eq!(stream.next().await.unwrap().unwrap(), update(1));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert_eq!(replay.len(), 2);
+
+        // The next stream starts with the update that was not returned.
+        let _ = replay.stop_token();
+        let started = tokio::time::Instant::now();
+        let replayed: Vec<_> = replay.as_stream().map(Result::unwrap).collect().await;
+        assert_eq!(replayed, [update(2), update(3)]);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(replay.is_empty());
+    }
+}
@@ -131,13 +131,38 @@ impl Options {
     }
 }
 
+pub use self::{
+    handler::{handler, handler_no_setup, WebhookHandler, WebhookResponse, SECRET_TOKEN_HEADER},
+    reply::{InlineReplies, InlineReply},
+};
+
+#[cfg(feature = "webhooks-actix")]
+pub use self::actix::{actix_from_handler, actix_no_setup, actix_to_service, ActixWebhook};
+#[cfg(feature = "webhooks-axum-tls")]
+pub use self::axum::axum_tls;
 #[cfg(feature = "webhooks-axum")]
-pub use self::axum::{axum, axum_no_setup, axum_to_router};
+pub use self::axum::{axum, axum_from_handler, axum_no_setup, axum_to_router};
+#[cfg(feature = "webhooks-hyper")]
+pub use self::hyper::{hyper_from_handler, hyper_no_setup, hyper_to_service, WebhookService};
+#[cfg(feature = "webhooks-warp")]
+pub use self::warp::{warp_from_handler, warp_no_setup, warp_to_filter};
 
+#[cfg(feature = "webhooks-axum-tls")]
+pub use self::tls::{TlsConfig, TlsError, TlsListener};
+
+mod handler;
+mod reply;
+
+#[cfg(feature = "webhooks-actix")]
+mod actix;
 #[cfg(feature = "webhooks-axum")]
 mod axum;
-
-// TODO: add different implementation (for example: warp)
+#[cfg(feature = "webhooks-hyper")]
+mod hyper;
+#[cfg(feature = "webhooks-axum-tls")]
+mod tls;
+#[cfg(feature = "webhooks-warp")]
+mod warp;
 
 /// Calls `set_webhook` with arguments from `options`.
 ///
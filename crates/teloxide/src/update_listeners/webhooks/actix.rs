@@ -0,0 +1,164 @@
+use std::{convert::Infallible, future::Future};
+
+use actix_web::{
+    dev::{AppService, HttpServiceFactory},
+    http::StatusCode,
+    web, HttpRequest, HttpResponse,
+};
+
+use crate::{
+    requests::Requester,
+    update_listeners::{
+        webhooks::{self, Options, WebhookHandler, SECRET_TOKEN_HEADER},
+        UpdateListener,
+    },
+};
+
+/// Webhook implementation based on the [mod@actix_web] framework.
+///
+/// This function does most of the work necessary for webhook to work, it:
+/// - Calls [`set_webhook`], so telegram starts sending updates our way
+/// - When the update listener is [`stop`]ped, calls [`delete_webhook`]
+///
+/// The only missing part is registering the returned [`ActixWebhook`] in an
+/// [`actix_web::App`] served on [`options.address`].
+///
+/// [`set_webhook`]: crate::payloads::SetWebhook
+/// [`delete_webhook`]: crate::payloads::DeleteWebhook
+/// [`stop`]: crate::stop::StopToken::stop
+/// [`options.address`]: Options::address
+///
+/// ## Returns
+///
+/// A update listener, stop-future, actix service triplet on success.
+///
+/// The "stop-future" is resolved after [`stop`] is called on the stop token of
+/// the returned update listener.
+///
+/// ## Fails
+///
+/// If `set_webhook()` fails.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use actix_web::{App, HttpServer};
+/// use teloxide::{prelude::*, update_listeners::webhooks};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let bot = Bot::from_env();
+/// let address = ([0, 0, 0, 0], 8443).into();
+/// let url = "https://example.com/webhook".parse()?;
+///
+/// let (listener, _stop, webhook) =
+///     webhooks::actix_to_service(bot.clone(), webhooks::Options::new(address, url)).await?;
+///
+/// let server = HttpServer::new(move || App::new().service(webhook.clone())).bind(address)?.run();
+///
+/// let handler = Update::filter_message().endpoint(|| async { Ok::<_, ()>(()) });
+/// let mut dispatcher = Dispatcher::builder(bot, handler).build();
+/// let dispatching = dispatcher.dispatch_with_listener(listener, LoggingErrorHandler::new());
+///
+/// let (served, ()) = tokio::join!(server, dispatching);
+/// served?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## See also
+///
+/// [`actix_no_setup`] for a lower-level version of this function.
+pub async fn actix_to_service<R>(
+    bot: R,
+    options: Options,
+) -> Result<
+    (impl UpdateListener<Err = Infallible>, impl Future<Output = ()> + Send, ActixWebhook),
+    R::Err,
+>
+where
+    R: Requester + Send,
+    <R as Requester>::DeleteWebhook: Send,
+{
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler(bot, options).await?;
+
+    Ok((listener, stop_flag, actix_from_handler(&path, handler)))
+}
+
+/// Webhook implementation based on the [mod@actix_web] framework that doesn't
+/// perform any setup work.
+///
+/// ## Note about the stop-future
+///
+/// This function returns a future that is resolved when `.stop()` is called on
+/// a stop token of the update listener. Note that even if the future is not
+/// used, after `.stop()` is called, update listener will not produce new
+/// updates.
+///
+/// ## See also
+///
+/// [`actix_to_service`] for a higher-level version of this function.
+pub fn actix_no_setup(
+    options: Options,
+) -> (impl UpdateListener<Err = Infallible>, impl Future<Output = ()>, ActixWebhook) {
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler_no_setup(options);
+
+    (listener, stop_flag, actix_from_handler(&path, handler))
+}
+
+/// Webhook implementation based on the [mod@actix_web] framework that serves an
+/// existing [`WebhookHandler`].
+///
+/// Requests to `path` are passed to `handler`. This is useful to configure the
+/// handler before it's served, e.g. to enable [inline replies].
+///
+/// [inline replies]: WebhookHandler::inline_replies
+///
+/// ## See also
+///
+/// [`actix_to_service`] for a version which also creates the handler.
+pub fn actix_from_handler(path: &str, handler: WebhookHandler) -> ActixWebhook {
+    ActixWebhook { handler, path: path.to_owned() }
+}
+
+/// An [mod@actix_web] resource accepting webhook requests at
+/// [`Options::path`], returned from [`actix_to_service`], [`actix_no_setup`]
+/// and [`actix_from_handler`].
+///
+/// Register it via [`actix_web::App::service`]. Since an app is created for
+/// every worker thread, it's cheap to clone.
+#[derive(Clone)]
+pub struct ActixWebhook {
+    handler: WebhookHandler,
+    path: String,
+}
+
+impl HttpServiceFactory for ActixWebhook {
+    fn register(self, config: &mut AppService) {
+        let Self { handler, path } = self;
+
+        let telegram_request = move |req: HttpRequest, body: web::Bytes| {
+            let handler = handler.clone();
+            async move {
+                let secret_token =
+                    req.headers().get(SECRET_TOKEN_HEADER).map(|value| value.as_bytes());
+                let Ok(body) = std::str::from_utf8(&body) else {
+                    return HttpResponse::BadRequest().finish();
+                };
+
+                let response = handler.handle(secret_token, body).await;
+                let status = StatusCode::from_u16(response.status())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                match response.body() {
+                    Some(body) => HttpResponse::build(status)
+                        .content_type("application/json")
+                        .body(body.to_owned()),
+                    None => HttpResponse::build(status).finish(),
+                }
+            }
+        };
+
+        web::resource(path).route(web::post().to(telegram_request)).register(config)
+    }
+}
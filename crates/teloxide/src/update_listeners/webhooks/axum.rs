@@ -1,17 +1,19 @@
 use std::{convert::Infallible, future::Future};
 
 use axum::{
-    extract::{FromRequestParts, State},
-    http::{request::Parts, status::StatusCode},
+    extract::State,
+    http::{header::CONTENT_TYPE, status::StatusCode, HeaderMap, HeaderValue},
 };
-use tokio::sync::mpsc;
 
 use crate::{
     requests::Requester,
-    stop::StopFlag,
-    types::{Update, UpdateKind},
-    update_listeners::{webhooks::Options, UpdateListener},
+    update_listeners::{
+        webhooks::{self, Options, WebhookHandler, SECRET_TOKEN_HEADER},
+        UpdateListener,
+    },
 };
+#[cfg(feature = "webhooks-axum-tls")]
+use crate::{types::InputFile, update_listeners::webhooks::TlsConfig};
 
 /// Webhook implementation based on the [mod@axum] framework.
 ///
@@ -37,7 +39,7 @@ use crate::{
 /// ## See also
 ///
 /// [`axum_to_router`] and [`axum_no_setup`] for lower-level versions of this
-/// function.
+/// function, [`axum_tls`] for a version that terminates TLS.
 pub async fn axum<R>(
     bot: R,
     options: Options,
@@ -66,6 +68,72 @@ where
     Ok(update_listener)
 }
 
+/// Webhook implementation based on the [mod@axum] framework that terminates
+/// TLS with the certificate and the key from `tls`, so that a reverse proxy
+/// is not needed.
+///
+/// This function does the same as [`fn@axum`], except that the server accepts
+/// TLS connections. If the certificate is [self-signed], it's also uploaded to
+/// Telegram, unless another one is set via [`Options::certificate`].
+///
+/// [self-signed]: TlsConfig::self_signed
+///
+/// ## Panics
+///
+/// If binding to the [address] fails or the TLS certificate or key cannot be
+/// loaded.
+///
+/// [address]: Options::address
+///
+/// ## Fails
+///
+/// If `set_webhook()` fails.
+///
+/// ## See also
+///
+/// [`TlsConfig::listener`] for serving the router returned from
+/// [`axum_to_router`] over TLS.
+#[cfg(feature = "webhooks-axum-tls")]
+pub async fn axum_tls<R>(
+    bot: R,
+    mut options: Options,
+    tls: TlsConfig,
+) -> Result<impl UpdateListener<Err = Infallible>, R::Err>
+where
+    R: Requester + Send + 'static,
+    <R as Requester>::DeleteWebhook: Send,
+{
+    if options.certificate.is_none() && tls.is_self_signed() {
+        options.certificate = Some(InputFile::file(tls.cert_path()));
+    }
+    let Options { address, .. } = options;
+
+    // Load the certificate before the webhook is set up, to not leave a webhook
+    // that can't be served.
+    let acceptor = tls.acceptor().expect("Couldn't load the TLS certificate");
+
+    let (mut update_listener, stop_flag, app) = axum_to_router(bot, options).await?;
+    let stop_token = update_listener.stop_token();
+
+    tokio::spawn(async move {
+        let tcp_listener = tokio::net::TcpListener::bind(address)
+            .await
+            .inspect_err(|_| stop_token.stop())
+            .expect("Couldn't bind to the address");
+        let tls_listener = acceptor
+            .listen(tcp_listener)
+            .inspect_err(|_| stop_token.stop())
+            .expect("Couldn't bind to the address");
+        axum::serve(tls_listener, app)
+            .with_graceful_shutdown(stop_flag)
+            .await
+            .inspect_err(|_| stop_token.stop())
+            .expect("Axum server error");
+    });
+
+    Ok(update_listener)
+}
+
 /// Webhook implementation based on the [mod@axum] framework that can reuse
 /// existing [mod@axum] server.
 ///
@@ -109,7 +177,7 @@ where
 /// versions of this function.
 pub async fn axum_to_router<R>(
     bot: R,
-    mut options: Options,
+    options: Options,
 ) -> Result<
     (impl UpdateListener<Err = Infallible>, impl Future<Output = ()> + Send, axum::Router),
     R::Err,
@@ -118,24 +186,10 @@ where
     R: Requester + Send,
     <R as Requester>::DeleteWebhook: Send,
 {
-    use crate::{requests::Request, update_listeners::webhooks::setup_webhook};
-    use futures::FutureExt;
-
-    setup_webhook(&bot, &mut options).await?;
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler(bot, options).await?;
 
-    let (listener, stop_flag, router) = axum_no_setup(options);
-
-    let stop_flag = stop_flag.then(move |()| async move {
-        // This assignment is needed to not require `R: Sync` since without it `&bot`
-        // temporary lives across `.await` points.
-        let req = bot.delete_webhook().send();
-        let res = req.await;
-        if let Err(err) = res {
-            log::error!("Couldn't delete webhook: {}", err);
-        }
-    });
-
-    Ok((listener, stop_flag, router))
+    Ok((listener, stop_flag, axum_from_handler(&path, handler)))
 }
 
 /// Webhook implementation based on the [mod@axum] framework that doesn't
@@ -155,141 +209,50 @@ where
 pub fn axum_no_setup(
     options: Options,
 ) -> (impl UpdateListener<Err = Infallible>, impl Future<Output = ()>, axum::Router) {
-    use crate::{
-        stop::{mk_stop_token, StopToken},
-        update_listeners::{webhooks::tuple_first_mut, StatefulListener},
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler_no_setup(options);
+
+    (listener, stop_flag, axum_from_handler(&path, handler))
+}
+
+/// Webhook implementation based on the [mod@axum] framework that serves an
+/// existing [`WebhookHandler`].
+///
+/// Requests to `path` are passed to `handler`. This is useful to configure the
+/// handler before it's served, e.g. to enable [inline replies].
+///
+/// [inline replies]: WebhookHandler::inline_replies
+///
+/// ## See also
+///
+/// [`axum_to_router`] for a version which also creates the handler.
+pub fn axum_from_handler(path: &str, handler: WebhookHandler) -> axum::Router {
+    use axum::{
+        response::{IntoResponse, Response},
+        routing::post,
     };
-    use axum::{response::IntoResponse, routing::post};
-    use tokio_stream::wrappers::UnboundedReceiverStream;
     use tower_http::trace::TraceLayer;
 
-    let (tx, rx): (UpdateSender, _) = mpsc::unbounded_channel();
-
     async fn telegram_request(
-        State(WebhookState { secret, flag, mut tx }): State<WebhookState>,
-        secret_header: XTelegramBotApiSecretToken,
+        State(handler): State<WebhookHandler>,
+        headers: HeaderMap,
         input: String,
-    ) -> impl IntoResponse {
-        // FIXME: use constant time comparison here
-        if secret_header.0.as_deref() != secret.as_deref().map(str::as_bytes) {
-            return StatusCode::UNAUTHORIZED;
-        }
-
-        let tx = match tx.get() {
-            None => return StatusCode::SERVICE_UNAVAILABLE,
-            // Do not process updates after `.stop()` is called even if the server is still
-            // running (useful for when you need to stop the bot but can't stop the server).
-            _ if flag.is_stopped() => {
-                tx.close();
-                return StatusCode::SERVICE_UNAVAILABLE;
-            }
-            Some(tx) => tx,
-        };
-
-        match serde_json::from_str::<Update>(&input) {
-            Ok(mut update) => {
-                // See HACK comment in
-                // `teloxide_core::net::request::process_response::{closure#0}`
-                if let UpdateKind::Error(value) = &mut update.kind {
-                    *value = serde_json::from_str(&input).unwrap_or_default();
-                }
-
-                tx.send(Ok(update)).expect("Cannot send an incoming update from the webhook")
+    ) -> Response {
+        let secret_token = headers.get(SECRET_TOKEN_HEADER).map(HeaderValue::as_bytes);
+        let response = handler.handle(secret_token, &input).await;
+
+        let status =
+            StatusCode::from_u16(response.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        match response.body() {
+            Some(body) => {
+                (status, [(CONTENT_TYPE, "application/json")], body.to_owned()).into_response()
             }
-            Err(error) => {
-                log::error!(
-                    "Cannot parse an update.\nError: {:?}\nValue: {}\n\
-                     This is a bug in teloxide-core, please open an issue here: \
-                     https://github.com/teloxide/teloxide/issues.",
-                    error,
-                    input
-                );
-            }
-        };
-
-        StatusCode::OK
+            None => status.into_response(),
+        }
     }
 
-    let (stop_token, stop_flag) = mk_stop_token();
-
-    let app = axum::Router::new()
-        .route(&options.path, post(telegram_request))
+    axum::Router::new()
+        .route(path, post(telegram_request))
         .layer(TraceLayer::new_for_http())
-        .with_state(WebhookState {
-            tx: ClosableSender::new(tx),
-            flag: stop_flag.clone(),
-            secret: options.secret_token,
-        });
-
-    let stream = UnboundedReceiverStream::new(rx);
-
-    // FIXME: this should support `hint_allowed_updates()`
-    let listener = StatefulListener::new(
-        (stream, stop_token),
-        tuple_first_mut,
-        |state: &mut (_, StopToken)| state.1.clone(),
-    );
-
-    (listener, stop_flag, app)
-}
-
-type UpdateSender = mpsc::UnboundedSender<Result<Update, std::convert::Infallible>>;
-type UpdateCSender = ClosableSender<Result<Update, std::convert::Infallible>>;
-
-#[derive(Clone)]
-struct WebhookState {
-    tx: UpdateCSender,
-    flag: StopFlag,
-    secret: Option<String>,
-}
-
-/// A terrible workaround to drop axum extension
-struct ClosableSender<T> {
-    origin: std::sync::Arc<std::sync::RwLock<Option<mpsc::UnboundedSender<T>>>>,
-}
-
-impl<T> Clone for ClosableSender<T> {
-    fn clone(&self) -> Self {
-        Self { origin: self.origin.clone() }
-    }
-}
-
-impl<T> ClosableSender<T> {
-    fn new(sender: mpsc::UnboundedSender<T>) -> Self {
-        Self { origin: std::sync::Arc::new(std::sync::RwLock::new(Some(sender))) }
-    }
-
-    fn get(&self) -> Option<mpsc::UnboundedSender<T>> {
-        self.origin.read().unwrap().clone()
-    }
-
-    fn close(&mut self) {
-        self.origin.write().unwrap().take();
-    }
-}
-
-struct XTelegramBotApiSecretToken(Option<Vec<u8>>);
-
-impl<S> FromRequestParts<S> for XTelegramBotApiSecretToken {
-    type Rejection = StatusCode;
-
-    fn from_request_parts(
-        req: &mut Parts,
-        _state: &S,
-    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
-        use crate::update_listeners::webhooks::check_secret;
-
-        let res = req
-            .headers
-            .remove("x-telegram-bot-api-secret-token")
-            .map(|header| {
-                check_secret(header.as_bytes())
-                    .map(<_>::to_owned)
-                    .map_err(|_| StatusCode::BAD_REQUEST)
-            })
-            .transpose()
-            .map(Self);
-
-        async { res }
-    }
+        .with_state(handler)
 }
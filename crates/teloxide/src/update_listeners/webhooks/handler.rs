@@ -0,0 +1,322 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    sync::{Arc, RwLock},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    requests::Requester,
+    stop::StopFlag,
+    types::{Update, UpdateKind, UserId},
+    update_listeners::{
+        webhooks::{InlineReplies, Options},
+        UpdateListener,
+    },
+};
+
+/// The name of the header with the secret token, see
+/// [`Options::secret_token()`].
+pub const SECRET_TOKEN_HEADER: &str = "x-telegram-bot-api-secret-token";
+
+/// The framework-independent part of a webhook server.
+///
+/// It checks the secret token of a request, parses the update from its body
+/// and passes it to the update listener returned along with the handler from
+/// [`handler`] or [`handler_no_setup`]. After the listener is stopped, updates
+/// are rejected.
+///
+/// Adapters for web frameworks, such as [`axum_to_router`], only route
+/// requests to [`WebhookHandler::handle`] and convert the [`WebhookResponse`]
+/// into a response of the framework. Use it to support a framework which
+/// doesn't have an adapter yet. A handler can also be configured, e.g. with
+/// [`WebhookHandler::inline_replies`], and passed to an adapter, e.g. via
+/// [`axum_from_handler`].
+///
+/// [`axum_from_handler`]: crate::update_listeners::webhooks::axum_from_handler
+/// [`axum_to_router`]: crate::update_listeners::webhooks::axum_to_router
+#[derive(Clone)]
+pub struct WebhookHandler {
+    tx: UpdateCSender,
+    flag: StopFlag,
+    secret: Option<String>,
+    inline_replies: Option<(UserId, InlineReplies)>,
+}
+
+/// The response to a webhook request, returned from
+/// [`WebhookHandler::handle`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookResponse {
+    status: u16,
+    body: Option<String>,
+}
+
+impl WebhookResponse {
+    const OK: Self = Self { status: 200, body: None };
+    const BAD_REQUEST: Self = Self { status: 400, body: None };
+    const UNAUTHORIZED: Self = Self { status: 401, body: None };
+    const SERVICE_UNAVAILABLE: Self = Self { status: 503, body: None };
+
+    /// Returns the HTTP status code of the response.
+    #[must_use]
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the body of the response, a JSON-encoded method call, see
+    /// [`InlineReplies`].
+    ///
+    /// If it's `Some(_)`, the response must be sent with the
+    /// `application/json` content type.
+    #[must_use]
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_deref()
+    }
+}
+
+impl WebhookHandler {
+    /// Lets handlers answer webhook requests for the bot with the ID `bot_id`
+    /// with a method call via [`InlineReply`].
+    ///
+    /// The same `replies` must be registered as a middleware of the
+    /// dispatcher, see [`InlineReplies`] for details. The handler is then
+    /// served by an adapter which accepts a handler, such as
+    /// [`axum_from_handler`].
+    ///
+    /// [`InlineReply`]: crate::update_listeners::webhooks::InlineReply
+    /// [`axum_from_handler`]: crate::update_listeners::webhooks::axum_from_handler
+    #[must_use]
+    pub fn inline_replies(self, bot_id: UserId, replies: InlineReplies) -> Self {
+        Self { inline_replies: Some((bot_id, replies)), ..self }
+    }
+
+    /// Handles a webhook request with the value of the [secret token header]
+    /// `secret_token` and the body `body`.
+    ///
+    /// With [inline replies], this waits until the update is handled, but not
+    /// longer than their timeout.
+    ///
+    /// [secret token header]: SECRET_TOKEN_HEADER
+    /// [inline replies]: WebhookHandler::inline_replies
+    pub async fn handle(&self, secret_token: Option<&[u8]>, body: &str) -> WebhookResponse {
+        use crate::update_listeners::webhooks::check_secret;
+
+        let secret_token = match secret_token.map(check_secret).transpose() {
+            Ok(secret_token) => secret_token,
+            Err(_) => return WebhookResponse::BAD_REQUEST,
+        };
+
+        // FIXME: use constant time comparison here
+        if secret_token != self.secret.as_deref().map(str::as_bytes) {
+            return WebhookResponse::UNAUTHORIZED;
+        }
+
+        let tx = match self.tx.get() {
+            None => return WebhookResponse::SERVICE_UNAVAILABLE,
+            // Do not process updates after `.stop()` is called even if the server is still
+            // running (useful for when you need to stop the bot but can't stop the server).
+            _ if self.flag.is_stopped() => {
+                self.tx.close();
+                return WebhookResponse::SERVICE_UNAVAILABLE;
+            }
+            Some(tx) => tx,
+        };
+
+        match serde_json::from_str::<Update>(body) {
+            Ok(mut update) => {
+                // See HACK comment in
+                // `teloxide_core::net::request::process_response::{closure#0}`
+                if let UpdateKind::Error(value) = &mut update.kind {
+                    *value = serde_json::from_str(body).unwrap_or_default();
+                }
+                let update_id = update.id;
+
+                let deliver = move || {
+                    tx.send(Ok(update)).expect("Cannot send an incoming update from the webhook")
+                };
+
+                if let Some((bot_id, replies)) = &self.inline_replies {
+                    let body = replies.wait(*bot_id, update_id, deliver).await;
+                    return WebhookResponse { body, ..WebhookResponse::OK };
+                }
+                deliver();
+            }
+            Err(error) => {
+                log::error!(
+                    "Cannot parse an update.\nError: {:?}\nValue: {}\n\
+                     This is a bug in teloxide-core, please open an issue here: \
+                     https://github.com/teloxide/teloxide/issues.",
+                    error,
+                    body
+                );
+            }
+        };
+
+        WebhookResponse::OK
+    }
+}
+
+/// Framework-independent webhook implementation.
+///
+/// This function does most of the work necessary for webhook to work, it:
+/// - Calls [`set_webhook`], so telegram starts sending updates our way
+/// - When the update listener is [`stop`]ped, calls [`delete_webhook`]
+///
+/// The only missing part is a server which passes requests to
+/// [`options.path`] to the returned [`WebhookHandler`].
+///
+/// [`set_webhook`]: crate::payloads::SetWebhook
+/// [`delete_webhook`]: crate::payloads::DeleteWebhook
+/// [`stop`]: crate::stop::StopToken::stop
+/// [`options.path`]: Options::path()
+///
+/// ## Returns
+///
+/// A update listener, stop-future, webhook handler triplet on success.
+///
+/// The "stop-future" is resolved after [`stop`] is called on the stop token of
+/// the returned update listener.
+///
+/// ## Fails
+///
+/// If `set_webhook()` fails.
+pub async fn handler<R>(
+    bot: R,
+    mut options: Options,
+) -> Result<
+    (impl UpdateListener<Err = Infallible>, impl Future<Output = ()> + Send, WebhookHandler),
+    R::Err,
+>
+where
+    R: Requester + Send,
+    <R as Requester>::DeleteWebhook: Send,
+{
+    use crate::{requests::Request, update_listeners::webhooks::setup_webhook};
+    use futures::FutureExt;
+
+    setup_webhook(&bot, &mut options).await?;
+
+    let (listener, stop_flag, handler) = handler_no_setup(options);
+
+    let stop_flag = stop_flag.then(move |()| async move {
+        // This assignment is needed to not require `R: Sync` since without it `&bot`
+        // temporary lives across `.await` points.
+        let req = bot.delete_webhook().send();
+        let res = req.await;
+        if let Err(err) = res {
+            log::error!("Couldn't delete webhook: {}", err);
+        }
+    });
+
+    Ok((listener, stop_flag, handler))
+}
+
+/// Framework-independent webhook implementation that doesn't perform any setup
+/// work.
+///
+/// ## Note about the stop-future
+///
+/// This function returns a future that is resolved when `.stop()` is called on
+/// a stop token of the update listener. Note that even if the future is not
+/// used, after `.stop()` is called, update listener will not produce new
+/// updates.
+///
+/// ## See also
+///
+/// [`fn@handler`] for a higher-level version of this function.
+pub fn handler_no_setup(
+    options: Options,
+) -> (impl UpdateListener<Err = Infallible>, impl Future<Output = ()>, WebhookHandler) {
+    use crate::{
+        stop::{mk_stop_token, StopToken},
+        update_listeners::{webhooks::tuple_first_mut, StatefulListener},
+    };
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    let (tx, rx): (UpdateSender, _) = mpsc::unbounded_channel();
+    let (stop_token, stop_flag) = mk_stop_token();
+
+    let handler = WebhookHandler {
+        tx: ClosableSender::new(tx),
+        flag: stop_flag.clone(),
+        secret: options.secret_token,
+        inline_replies: None,
+    };
+
+    let stream = UnboundedReceiverStream::new(rx);
+
+    // FIXME: this should support `hint_allowed_updates()`
+    let listener = StatefulListener::new(
+        (stream, stop_token),
+        tuple_first_mut,
+        |state: &mut (_, StopToken)| state.1.clone(),
+    );
+
+    (listener, stop_flag, handler)
+}
+
+type UpdateSender = mpsc::UnboundedSender<Result<Update, std::convert::Infallible>>;
+type UpdateCSender = ClosableSender<Result<Update, std::convert::Infallible>>;
+
+/// A sender which can be closed by any of its clones, so that the update
+/// listener stops even if the server keeps the handler alive.
+struct ClosableSender<T> {
+    origin: Arc<RwLock<Option<mpsc::UnboundedSender<T>>>>,
+}
+
+impl<T> Clone for ClosableSender<T> {
+    fn clone(&self) -> Self {
+        Self { origin: self.origin.clone() }
+    }
+}
+
+impl<T> ClosableSender<T> {
+    fn new(sender: mpsc::UnboundedSender<T>) -> Self {
+        Self { origin: Arc::new(RwLock::new(Some(sender))) }
+    }
+
+    fn get(&self) -> Option<mpsc::UnboundedSender<T>> {
+        self.origin.read().unwrap().clone()
+    }
+
+    fn close(&self) {
+        self.origin.write().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures::StreamExt;
+
+    use crate::update_listeners::{webhooks::Options, AsUpdateStream, UpdateListener};
+
+    use super::{handler_no_setup, WebhookResponse};
+
+    #[tokio::test]
+    async fn handle() {
+        let options = Options::new(
+            ([127, 0, 0, 1], 8443).into(),
+            "https://example.com/webhook".parse().unwrap(),
+        )
+        .secret_token("secret".to_owned());
+        let (mut listener, _stop, handler) = handler_no_setup(options);
+
+        let update = r#"{"update_id":1,"poll_answer":{"poll_id":"1","user":{"id":1,"is_bot":false,"first_name":"A"},"option_ids":[0]}}"#;
+        assert_eq!(handler.handle(None, update).await, WebhookResponse::UNAUTHORIZED);
+        assert_eq!(handler.handle(Some(b"wrong"), update).await, WebhookResponse::UNAUTHORIZED);
+        assert_eq!(handler.handle(Some(b"not valid"), update).await, WebhookResponse::BAD_REQUEST);
+        assert_eq!(handler.handle(Some(b"secret"), update).await, WebhookResponse::OK);
+
+        let received = pin!(listener.as_stream()).next().await.unwrap().unwrap();
+        assert_eq!(received.id.0, 1);
+
+        listener.stop_token().stop();
+        assert_eq!(
+            handler.handle(Some(b"secret"), update).await,
+            WebhookResponse::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::{convert::Infallible, future::Future, sync::Arc};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Body, header::CONTENT_TYPE, Method, Request, Response, StatusCode};
+
+use crate::{
+    requests::Requester,
+    update_listeners::{
+        webhooks::{self, Options, WebhookHandler, WebhookResponse, SECRET_TOKEN_HEADER},
+        UpdateListener,
+    },
+};
+
+/// Webhook implementation based on the [mod@hyper] library.
+///
+/// This function does most of the work necessary for webhook to work, it:
+/// - Calls [`set_webhook`], so telegram starts sending updates our way
+/// - When the update listener is [`stop`]ped, calls [`delete_webhook`]
+///
+/// The only missing part is serving connections with the returned
+/// [`WebhookService`], bound to [`options.address`].
+///
+/// [`set_webhook`]: crate::payloads::SetWebhook
+/// [`delete_webhook`]: crate::payloads::DeleteWebhook
+/// [`stop`]: crate::stop::StopToken::stop
+/// [`options.address`]: Options::address
+///
+/// ## Returns
+///
+/// A update listener, stop-future, hyper service triplet on success.
+///
+/// The "stop-future" is resolved after [`stop`] is called on the stop token of
+/// the returned update listener.
+///
+/// ## Fails
+///
+/// If `set_webhook()` fails.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use hyper_util::rt::TokioIo;
+/// use teloxide::{prelude::*, update_listeners::webhooks};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let bot = Bot::from_env();
+/// let address = ([0, 0, 0, 0], 8443).into();
+/// let url = "https://example.com/webhook".parse()?;
+///
+/// let (listener, _stop, service) =
+///     webhooks::hyper_to_service(bot.clone(), webhooks::Options::new(address, url)).await?;
+///
+/// let tcp_listener = tokio::net::TcpListener::bind(address).await?;
+/// tokio::spawn(async move {
+///     loop {
+///         let Ok((stream, _)) = tcp_listener.accept().await else { continue };
+///         let service = service.clone();
+///         tokio::spawn(
+///             hyper::server::conn::http1::Builder::new()
+///                 .serve_connection(TokioIo::new(stream), service),
+///         );
+///     }
+/// });
+///
+/// let handler = Update::filter_message().endpoint(|| async { Ok::<_, ()>(()) });
+/// Dispatcher::builder(bot, handler)
+///     .build()
+///     .dispatch_with_listener(listener, LoggingErrorHandler::new())
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## See also
+///
+/// [`hyper_no_setup`] for a lower-level version of this function.
+pub async fn hyper_to_service<R>(
+    bot: R,
+    options: Options,
+) -> Result<
+    (impl UpdateListener<Err = Infallible>, impl Future<Output = ()> + Send, WebhookService),
+    R::Err,
+>
+where
+    R: Requester + Send,
+    <R as Requester>::DeleteWebhook: Send,
+{
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler(bot, options).await?;
+
+    Ok((listener, stop_flag, hyper_from_handler(&path, handler)))
+}
+
+/// Webhook implementation based on the [mod@hyper] library that doesn't
+/// perform any setup work.
+///
+/// ## Note about the stop-future
+///
+/// This function returns a future that is resolved when `.stop()` is called on
+/// a stop token of the update listener. Note that even if the future is not
+/// used, after `.stop()` is called, update listener will not produce new
+/// updates.
+///
+/// ## See also
+///
+/// [`hyper_to_service`] for a higher-level version of this function.
+pub fn hyper_no_setup(
+    options: Options,
+) -> (impl UpdateListener<Err = Infallible>, impl Future<Output = ()>, WebhookService) {
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler_no_setup(options);
+
+    (listener, stop_flag, hyper_from_handler(&path, handler))
+}
+
+/// Webhook implementation based on the [mod@hyper] library that serves an
+/// existing [`WebhookHandler`].
+///
+/// Requests to `path` are passed to `handler`. This is useful to configure the
+/// handler before it's served, e.g. to enable [inline replies].
+///
+/// [inline replies]: WebhookHandler::inline_replies
+///
+/// ## See also
+///
+/// [`hyper_to_service`] for a version which also creates the handler.
+pub fn hyper_from_handler(path: &str, handler: WebhookHandler) -> WebhookService {
+    WebhookService { handler, path: path.into() }
+}
+
+/// A [`hyper::service::Service`] accepting webhook requests, returned from
+/// [`hyper_to_service`], [`hyper_no_setup`] and [`hyper_from_handler`].
+///
+/// Requests to paths other than [`Options::path`] are answered with `404 Not
+/// Found`.
+#[derive(Clone)]
+pub struct WebhookService {
+    handler: WebhookHandler,
+    path: Arc<str>,
+}
+
+impl<B> hyper::service::Service<Request<B>> for WebhookService
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let this = self.clone();
+
+        Box::pin(async move {
+            if req.uri().path() != &*this.path {
+                return Ok(empty(StatusCode::NOT_FOUND));
+            }
+            if req.method() != Method::POST {
+                return Ok(empty(StatusCode::METHOD_NOT_ALLOWED));
+            }
+
+            let secret_token =
+                req.headers().get(SECRET_TOKEN_HEADER).map(|value| value.as_bytes().to_owned());
+            let body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+            };
+            let Ok(body) = std::str::from_utf8(&body) else {
+                return Ok(empty(StatusCode::BAD_REQUEST));
+            };
+
+            let response = this.handler.handle(secret_token.as_deref(), body).await;
+            Ok(into_response(response))
+        })
+    }
+}
+
+fn into_response(response: WebhookResponse) -> Response<Full<Bytes>> {
+    let status =
+        StatusCode::from_u16(response.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let Some(body) = response.body() else { return empty(status) };
+
+    let mut response = Response::new(Full::new(Bytes::from(body.to_owned())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn empty(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::{service::Service, Method, Request, StatusCode};
+
+    use crate::update_listeners::webhooks::{Options, SECRET_TOKEN_HEADER};
+
+    use super::hyper_no_setup;
+
+    #[tokio::test]
+    async fn routing() {
+        let options = Options::new(
+            ([127, 0, 0, 1], 8443).into(),
+            "https://example.com/webhook".parse().unwrap(),
+        )
+        .secret_token("secret".to_owned());
+        let (_listener, _stop, service) = hyper_no_setup(options);
+
+        let request = |method, path| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(SECRET_TOKEN_HEADER, "secret")
+                .body(Full::new(Bytes::from_static(br#"{"update_id":1}"#)))
+                .unwrap()
+        };
+        let status = |request| async { service.call(request).await.unwrap().status() };
+
+        assert_eq!(status(request(Method::POST, "/other")).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(request(Method::GET, "/webhook")).await, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(status(request(Method::POST, "/webhook")).await, StatusCode::OK);
+    }
+}
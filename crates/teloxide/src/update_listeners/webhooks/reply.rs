@@ -0,0 +1,370 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dptree::di::{DependencyMap, DependencySupplier};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::{
+    dispatching::{Middleware, Next},
+    requests::{Output, Payload, Request},
+    types::{Me, Update, UpdateId, UserId},
+};
+
+/// Replies to webhook requests with a method call, see
+/// [`WebhookHandler::inline_replies`].
+///
+/// Telegram allows answering a webhook request with a call of one API method,
+/// which saves a round trip for e.g. replying to a message. To use it, pass
+/// the same `InlineReplies` to [`WebhookHandler::inline_replies`] and to
+/// [`DispatcherBuilder::middleware`]. The middleware injects an
+/// [`InlineReply`] into the dependencies of every update, which handlers can
+/// use instead of sending a request directly. Update IDs are unique only per
+/// bot, so the webhook of each bot is given its ID, and several bots can share
+/// one `InlineReplies`.
+///
+/// The webhook waits for the handler at most for the timeout passed to
+/// [`InlineReplies::new`], and then answers with an empty response, so that
+/// Telegram is not kept waiting for a slow handler. Telegram doesn't report
+/// the result of a method called this way, so errors of such a call are not
+/// visible to the bot. If an update is delivered again while a previous request
+/// with it still waits, e.g. because Telegram has re-sent it, the new request
+/// is answered with an empty response right away.
+///
+/// [`WebhookHandler::inline_replies`]: crate::update_listeners::webhooks::WebhookHandler::inline_replies
+/// [`DispatcherBuilder::middleware`]: crate::dispatching::DispatcherBuilder::middleware
+///
+/// ## Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use teloxide::{
+///     prelude::*,
+///     update_listeners::webhooks::{self, InlineReplies, InlineReply, Options},
+/// };
+///
+/// # async fn run() -> Result<(), teloxide::RequestError> {
+/// let bot = Bot::from_env();
+/// let address = ([0, 0, 0, 0], 8443).into();
+/// let url = "https://example.com/webhook".parse().unwrap();
+///
+/// let me = bot.get_me().await?;
+/// let replies = InlineReplies::new(Duration::from_secs(2));
+/// let options = Options::new(address, url);
+/// let path = options.path.clone();
+/// let (listener, stop_flag, handler) = webhooks::handler(bot.clone(), options).await?;
+/// let router = webhooks::axum_from_handler(&path, handler.inline_replies(me.id, replies.clone()));
+///
+/// tokio::spawn(async move {
+///     let tcp_listener = tokio::net::TcpListener::bind(address).await.unwrap();
+///     axum::serve(tcp_listener, router).with_graceful_shutdown(stop_flag).await.unwrap();
+/// });
+///
+/// let handler = Update::filter_message().endpoint(
+///     |bot: Bot, msg: Message, reply: InlineReply| async move {
+///         reply.send(bot.send_message(msg.chat.id, "pong")).await?;
+///         respond(())
+///     },
+/// );
+/// Dispatcher::builder(bot, handler)
+///     .middleware(replies)
+///     .build()
+///     .dispatch_with_listener(listener, LoggingErrorHandler::new())
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct InlineReplies {
+    pending: Arc<Mutex<HashMap<ReplyKey, oneshot::Sender<Option<String>>>>>,
+    timeout: Duration,
+}
+
+/// Identifies an update, whose IDs are unique only per bot.
+type ReplyKey = (UserId, UpdateId);
+
+/// A way to answer the webhook request that delivered an update, injected by
+/// the [`InlineReplies`] middleware.
+#[derive(Clone)]
+pub struct InlineReply {
+    replies: InlineReplies,
+    bot_id: UserId,
+    update_id: UpdateId,
+}
+
+impl InlineReplies {
+    /// Creates inline replies which wait for the handler at most `timeout`.
+    ///
+    /// Telegram doesn't send new updates to a webhook connection while it
+    /// waits for a response, so keep the timeout short.
+    #[must_use]
+    pub fn new(timeout: Duration) -> Self {
+        Self { pending: Arc::default(), timeout }
+    }
+
+    /// Waits for the reply to the update `update_id` of the bot `bot_id`
+    /// delivered by a webhook request. `deliver` passes the update to the
+    /// dispatcher.
+    ///
+    /// Returns the body of the response, if a handler replied in time.
+    pub(crate) async fn wait(
+        &self,
+        bot_id: UserId,
+        update_id: UpdateId,
+        deliver: impl FnOnce(),
+    ) -> Option<String> {
+        let key = (bot_id, update_id);
+        let (tx, mut rx) = oneshot::channel();
+        let duplicate = {
+            let mut pending = self.pending.lock().unwrap();
+            // Forget requests which were cancelled, e.g. because the connection was closed.
+            pending.retain(|_, tx| !tx.is_closed());
+            // The reply belongs to the request which is already waiting for the update.
+            let duplicate = pending.contains_key(&key);
+            if !duplicate {
+                pending.insert(key, tx);
+            }
+            duplicate
+        };
+        deliver();
+        if duplicate {
+            return None;
+        }
+
+        if let Ok(reply) = tokio::time::timeout(self.timeout, &mut rx).await {
+            return reply.ok().flatten();
+        }
+
+        // Replies are sent while the lock is held, so if the sender is gone, the reply
+        // is already in the channel.
+        match self.pending.lock().unwrap().remove(&key) {
+            Some(_) => None,
+            None => rx.try_recv().ok().flatten(),
+        }
+    }
+
+    fn reply(&self, key: ReplyKey, body: Option<String>) -> Result<(), Option<String>> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.remove(&key) {
+            Some(tx) => tx.send(body),
+            None => Err(body),
+        }
+    }
+}
+
+impl<Err> Middleware<Err> for InlineReplies
+where
+    Err: Send + Sync + 'static,
+{
+    fn handle(
+        self: Arc<Self>,
+        mut deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, Result<(), Err>> {
+        let update: Arc<Update> = deps.get();
+        let me: Arc<Me> = deps.get();
+        let (bot_id, update_id) = (me.id, update.id);
+        deps.insert(InlineReply { replies: (*self).clone(), bot_id, update_id });
+
+        Box::pin(async move {
+            let res = next.run(deps).await;
+            // Don't keep the webhook waiting if the handler didn't reply.
+            let _ = self.reply((bot_id, update_id), None);
+            res
+        })
+    }
+}
+
+impl InlineReply {
+    /// Sends `request` in the response to the webhook request that delivered
+    /// the update, returning `Ok(None)`.
+    ///
+    /// If the response is already sent, e.g. because the timeout has expired,
+    /// this method was already called or the update wasn't received via a
+    /// webhook with [inline replies], the request is sent as usual and its
+    /// output is returned. The same happens to requests uploading files, which
+    /// can't be sent in a response.
+    ///
+    /// **Note**: the request is sent as is, so adaptors which modify requests
+    /// when they are sent, such as [`DefaultParseMode`], have no effect on it.
+    ///
+    /// [inline replies]: crate::update_listeners::webhooks::WebhookHandler::inline_replies
+    /// [`DefaultParseMode`]: crate::adaptors::DefaultParseMode
+    pub async fn send<R>(&self, request: R) -> Result<Option<Output<R>>, R::Err>
+    where
+        R: Request,
+        R::Payload: Serialize,
+    {
+        if let Some(body) = method_call(request.payload_ref()) {
+            if self.replies.reply((self.bot_id, self.update_id), Some(body)).is_ok() {
+                return Ok(None);
+            }
+        }
+
+        request.send().await.map(Some)
+    }
+}
+
+/// Serializes `payload` into a method call for a webhook response.
+fn method_call<P>(payload: &P) -> Option<String>
+where
+    P: Payload + Serialize,
+{
+    let mut value = serde_json::to_value(payload).ok()?;
+    if uploads_file(&value) {
+        return None;
+    }
+
+    value.as_object_mut()?.insert("method".to_owned(), P::NAME.into());
+    serde_json::to_string(&value).ok()
+}
+
+/// Checks if a serialized payload refers to a file in a multipart request.
+fn uploads_file(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.starts_with("attach://"),
+        Value::Array(values) => values.iter().any(uploads_file),
+        Value::Object(values) => values.values().any(uploads_file),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, sync::Arc, time::Duration};
+
+    use futures::StreamExt;
+
+    use crate::{
+        dispatching::{Next, UpdateHandler},
+        payloads::{SendMessage, SendPhoto},
+        prelude::*,
+        types::{ChatId, InputFile, Me, UpdateId, User, UserId},
+        update_listeners::{webhooks, AsUpdateStream},
+        RequestError,
+    };
+
+    use super::{method_call, InlineReplies, InlineReply};
+
+    #[test]
+    fn method_calls() {
+        let call = method_call(&SendMessage::new(ChatId(1), "hi")).unwrap();
+        let call: serde_json::Value = serde_json::from_str(&call).unwrap();
+        assert_eq!(call, serde_json::json!({"method": "SendMessage", "chat_id": 1, "text": "hi"}));
+
+        let url = InputFile::url("https://example.com/cat.png".parse().unwrap());
+        assert!(method_call(&SendPhoto::new(ChatId(1), url)).is_some());
+        let file = InputFile::file("cat.png");
+        assert_eq!(method_call(&SendPhoto::new(ChatId(1), file)), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn webhook_response() {
+        let replies = InlineReplies::new(Duration::from_secs(1));
+        let options = webhooks::Options::new(
+            ([127, 0, 0, 1], 8443).into(),
+            "https://example.com/webhook".parse().unwrap(),
+        )
+        .secret_token("secret".to_owned());
+        let (mut listener, _stop, webhook) = webhooks::handler_no_setup(options);
+        let webhook = webhook.inline_replies(UserId(42), replies.clone());
+
+        let handler: UpdateHandler<RequestError> =
+            dptree::endpoint(|bot: Bot, update: Update, reply: InlineReply| async move {
+                match update.id.0 {
+                    // Replies in time.
+                    1 => assert!(reply.send(bot.send_message(ChatId(1), "hi")).await?.is_none()),
+                    // Replies too late.
+                    3 => {
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                        assert!(reply.replies.reply((reply.bot_id, update.id), None).is_err());
+                    }
+                    _ => {}
+                }
+                Ok(())
+            });
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            let mut updates = pin!(listener.as_stream());
+            while let Some(Ok(update)) = updates.next().await {
+                let next = Next::new(
+                    Arc::new([Arc::new(replies.clone())]),
+                    Arc::clone(&handler),
+                    Arc::new(|_| Box::pin(async {})),
+                );
+                tokio::spawn(next.run(dptree::deps![Bot::new("TOKEN"), me(42), update]));
+            }
+        });
+
+        let handle = |id: u32| {
+            let update = format!(
+                r#"{{"update_id":{id},"poll_answer":{{"poll_id":"1","user":{{"id":1,"is_bot":false,"first_name":"A"}},"option_ids":[0]}}}}"#
+            );
+            let webhook = webhook.clone();
+            async move {
+                let started = tokio::time::Instant::now();
+                let response = webhook.handle(Some(b"secret"), &update).await;
+                assert_eq!(response.status(), 200);
+                (response.body().map(str::to_owned), started.elapsed())
+            }
+        };
+
+        let (body, _) = handle(1).await;
+        let body: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+        assert_eq!(body["method"], "SendMessage");
+
+        // The handler didn't reply, so the response isn't delayed.
+        assert_eq!(handle(2).await, (None, Duration::ZERO));
+
+        assert_eq!(handle(3).await, (None, Duration::from_secs(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicates() {
+        let replies = InlineReplies::new(Duration::from_secs(1));
+        let wait = |bot_id, update_id| {
+            let replies = replies.clone();
+            tokio::spawn(async move {
+                let started = tokio::time::Instant::now();
+                (replies.wait(UserId(bot_id), UpdateId(update_id), || {}).await, started.elapsed())
+            })
+        };
+
+        let first = wait(1, 1);
+        let other_bot = wait(2, 1);
+        tokio::task::yield_now().await;
+
+        // A re-sent update is answered right away and doesn't take the reply.
+        assert_eq!(wait(1, 1).await.unwrap(), (None, Duration::ZERO));
+
+        replies.reply((UserId(1), UpdateId(1)), Some("first".to_owned())).unwrap();
+        replies.reply((UserId(2), UpdateId(1)), Some("other".to_owned())).unwrap();
+        assert_eq!(first.await.unwrap(), (Some("first".to_owned()), Duration::ZERO));
+        assert_eq!(other_bot.await.unwrap(), (Some("other".to_owned()), Duration::ZERO));
+    }
+
+    fn me(id: u64) -> Me {
+        Me {
+            user: User {
+                id: UserId(id),
+                is_bot: true,
+                first_name: "Bot".to_owned(),
+                last_name: None,
+                username: Some("bot".to_owned()),
+                language_code: None,
+                is_premium: false,
+                added_to_attachment_menu: false,
+            },
+            can_join_groups: true,
+            can_read_all_group_messages: false,
+            supports_inline_queries: false,
+            can_connect_to_business: false,
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use futures::future::{self, Either};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::CryptoProvider,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+};
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS settings of a webhook server, see [`axum_tls`].
+///
+/// The certificate chain and the private key are read from PEM files, e.g. the
+/// ones issued by [Let's Encrypt] or generated with `openssl` as described in
+/// Telegram's [self-signed guide]. With [`TlsConfig::reload_every`], they are
+/// re-read when the files change, so a renewed certificate is picked up
+/// without restarting the bot.
+///
+/// [`axum_tls`]: crate::update_listeners::webhooks::axum_tls
+/// [Let's Encrypt]: https://letsencrypt.org/
+/// [self-signed guide]: https://core.telegram.org/bots/self-signed
+///
+/// ## Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use teloxide::{
+///     prelude::*,
+///     update_listeners::webhooks::{self, Options, TlsConfig},
+/// };
+///
+/// # async fn run() -> Result<(), teloxide::RequestError> {
+/// let bot = Bot::from_env();
+/// let address = ([0, 0, 0, 0], 8443).into();
+/// let url = "https://example.com:8443/webhook".parse().unwrap();
+///
+/// let tls = TlsConfig::from_pem_files("cert.pem", "key.pem")
+///     .self_signed()
+///     .reload_every(Duration::from_secs(60));
+/// let listener = webhooks::axum_tls(bot.clone(), Options::new(address, url), tls).await?;
+/// # let _ = listener;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    self_signed: bool,
+    reload_interval: Option<Duration>,
+}
+
+/// An error returned when TLS cannot be set up.
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("cannot read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("no certificates found in {}", .0.display())]
+    NoCertificates(PathBuf),
+
+    #[error("no private key found in {}", .0.display())]
+    NoPrivateKey(PathBuf),
+
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TlsConfig {
+    /// Creates TLS settings with a certificate chain and a private key from
+    /// the PEM files at `cert_path` and `key_path`.
+    pub fn from_pem_files<C, K>(cert_path: C, key_path: K) -> Self
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            self_signed: false,
+            reload_interval: None,
+        }
+    }
+
+    /// Marks the certificate as self-signed, so that [`axum_tls`] uploads it
+    /// to Telegram when the webhook is set up, unless another one is set via
+    /// [`Options::certificate`].
+    ///
+    /// [`axum_tls`]: crate::update_listeners::webhooks::axum_tls
+    /// [`Options::certificate`]: crate::update_listeners::webhooks::Options::certificate
+    #[must_use]
+    pub fn self_signed(self) -> Self {
+        Self { self_signed: true, ..self }
+    }
+
+    /// Checks whether the files were modified every `interval` and reloads
+    /// them if so.
+    ///
+    /// New connections use the new certificate, while established ones are
+    /// not affected. If the files cannot be loaded, e.g. because only one of
+    /// them has been replaced so far, the error is logged and the previous
+    /// certificate is kept until the files change again.
+    ///
+    /// By default, the files are loaded only once.
+    #[must_use]
+    pub fn reload_every(self, interval: Duration) -> Self {
+        Self { reload_interval: Some(interval), ..self }
+    }
+
+    /// Accepts TLS connections from `listener`.
+    ///
+    /// The returned listener can be passed to [`axum::serve`](fn@axum::serve),
+    /// e.g. with the router returned from [`axum_to_router`]. Handshakes
+    /// are performed in the background, so a slow client doesn't delay
+    /// others.
+    ///
+    /// This must be called from within a Tokio runtime.
+    ///
+    /// [`axum_to_router`]: crate::update_listeners::webhooks::axum_to_router
+    pub fn listener(self, listener: TcpListener) -> Result<TlsListener, TlsError> {
+        Ok(self.acceptor()?.listen(listener)?)
+    }
+
+    pub(crate) fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    pub(crate) fn is_self_signed(&self) -> bool {
+        self.self_signed
+    }
+
+    /// Loads the files, so that errors are reported before the server starts.
+    pub(crate) fn acceptor(self) -> Result<Acceptor, TlsError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let modified = self.modified();
+        let resolver = Arc::new(Resolver { key: RwLock::new(Arc::new(self.load(&provider)?)) });
+
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&resolver) as _);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        if let Some(interval) = self.reload_interval {
+            tokio::spawn(reload(self, provider, Arc::downgrade(&resolver), interval, modified));
+        }
+
+        Ok(Acceptor(tokio_rustls::TlsAcceptor::from(Arc::new(config))))
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> Result<CertifiedKey, TlsError> {
+        let certs = rustls_pemfile::certs(&mut self.open(&self.cert_path)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|source| TlsError::Read { path: self.cert_path.clone(), source })?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(self.cert_path.clone()));
+        }
+
+        let key = rustls_pemfile::private_key(&mut self.open(&self.key_path)?)
+            .map_err(|source| TlsError::Read { path: self.key_path.clone(), source })?
+            .ok_or_else(|| TlsError::NoPrivateKey(self.key_path.clone()))?;
+        let key = provider.key_provider.load_private_key(key)?;
+
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    fn open(&self, path: &Path) -> Result<BufReader<File>, TlsError> {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|source| TlsError::Read { path: path.to_owned(), source })
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+}
+
+async fn reload(
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    resolver: Weak<Resolver>,
+    interval: Duration,
+    mut modified: Option<(SystemTime, SystemTime)>,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        // The server has stopped.
+        let Some(resolver) = resolver.upgrade() else { return };
+
+        let new_modified = config.modified();
+        if new_modified.is_none() || new_modified == modified {
+            continue;
+        }
+        modified = new_modified;
+
+        match config.load(&provider) {
+            Ok(key) => {
+                *resolver.key.write().unwrap() = Arc::new(key);
+                log::info!("Reloaded the TLS certificate from {}", config.cert_path.display());
+            }
+            Err(err) => log::error!("Couldn't reload the TLS certificate: {err}"),
+        }
+    }
+}
+
+/// Serves the current certificate.
+#[derive(Debug)]
+struct Resolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.key.read().unwrap()))
+    }
+}
+
+pub(crate) struct Acceptor(tokio_rustls::TlsAcceptor);
+
+impl Acceptor {
+    pub(crate) fn listen(self, listener: TcpListener) -> io::Result<TlsListener> {
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(1);
+        tokio::spawn(accept(listener, self.0, tx));
+
+        Ok(TlsListener { connections, local_addr })
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+    tx: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let (stream, addr) = match future::select(pin!(tx.closed()), pin!(listener.accept())).await
+        {
+            // The listener was dropped.
+            Either::Left(_) => return,
+            Either::Right((Ok(accepted), _)) => accepted,
+            Either::Right((Err(err), _)) => {
+                log::error!("Couldn't accept a connection: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = tx.send((stream, addr)).await;
+                }
+                Ok(Err(err)) => log::debug!("TLS handshake with {addr} failed: {err}"),
+                Err(_) => log::debug!("TLS handshake with {addr} timed out"),
+            }
+        });
+    }
+}
+
+/// A listener of TLS connections, created by [`TlsConfig::listener`].
+///
+/// It implements [`axum::serve::Listener`], so it can be passed to
+/// [`axum::serve`](fn@axum::serve).
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accepting task never stops while the listener is alive.
+            None => future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
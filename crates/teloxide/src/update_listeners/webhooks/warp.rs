@@ -0,0 +1,170 @@
+use std::{convert::Infallible, future::Future, sync::Arc};
+
+use bytes::Bytes;
+use warp::{
+    filters::{path::FullPath, BoxedFilter},
+    http::{HeaderMap, StatusCode},
+    reply::Response,
+    Filter, Reply,
+};
+
+use crate::{
+    requests::Requester,
+    update_listeners::{
+        webhooks::{self, Options, WebhookHandler, SECRET_TOKEN_HEADER},
+        UpdateListener,
+    },
+};
+
+/// Webhook implementation based on the [mod@warp] framework.
+///
+/// This function does most of the work necessary for webhook to work, it:
+/// - Calls [`set_webhook`], so telegram starts sending updates our way
+/// - When the update listener is [`stop`]ped, calls [`delete_webhook`]
+///
+/// The only missing part is serving the returned filter, possibly combined
+/// with other filters of your app, on [`options.address`].
+///
+/// [`set_webhook`]: crate::payloads::SetWebhook
+/// [`delete_webhook`]: crate::payloads::DeleteWebhook
+/// [`stop`]: crate::stop::StopToken::stop
+/// [`options.address`]: Options::address
+///
+/// ## Returns
+///
+/// A update listener, stop-future, warp filter triplet on success.
+///
+/// The "stop-future" is resolved after [`stop`] is called on the stop token of
+/// the returned update listener. It can be passed to
+/// [`warp::Server::bind_with_graceful_shutdown`].
+///
+/// ## Fails
+///
+/// If `set_webhook()` fails.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use teloxide::{prelude::*, update_listeners::webhooks};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let bot = Bot::from_env();
+/// let address = ([0, 0, 0, 0], 8443).into();
+/// let url = "https://example.com/webhook".parse()?;
+///
+/// let (listener, stop, filter) =
+///     webhooks::warp_to_filter(bot.clone(), webhooks::Options::new(address, url)).await?;
+///
+/// let (_, server) = warp::serve(filter).bind_with_graceful_shutdown(address, stop);
+/// tokio::spawn(server);
+///
+/// let handler = Update::filter_message().endpoint(|| async { Ok::<_, ()>(()) });
+/// Dispatcher::builder(bot, handler)
+///     .build()
+///     .dispatch_with_listener(listener, LoggingErrorHandler::new())
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ## See also
+///
+/// [`warp_no_setup`] for a lower-level version of this function.
+pub async fn warp_to_filter<R>(
+    bot: R,
+    options: Options,
+) -> Result<
+    (
+        impl UpdateListener<Err = Infallible>,
+        impl Future<Output = ()> + Send,
+        BoxedFilter<(Response,)>,
+    ),
+    R::Err,
+>
+where
+    R: Requester + Send,
+    <R as Requester>::DeleteWebhook: Send,
+{
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler(bot, options).await?;
+
+    Ok((listener, stop_flag, warp_from_handler(&path, handler)))
+}
+
+/// Webhook implementation based on the [mod@warp] framework that doesn't
+/// perform any setup work.
+///
+/// ## Note about the stop-future
+///
+/// This function returns a future that is resolved when `.stop()` is called on
+/// a stop token of the update listener. Note that even if the future is not
+/// used, after `.stop()` is called, update listener will not produce new
+/// updates.
+///
+/// ## See also
+///
+/// [`warp_to_filter`] for a higher-level version of this function.
+pub fn warp_no_setup(
+    options: Options,
+) -> (impl UpdateListener<Err = Infallible>, impl Future<Output = ()>, BoxedFilter<(Response,)>) {
+    let path = options.path.clone();
+    let (listener, stop_flag, handler) = webhooks::handler_no_setup(options);
+
+    (listener, stop_flag, warp_from_handler(&path, handler))
+}
+
+/// Webhook implementation based on the [mod@warp] framework that serves an
+/// existing [`WebhookHandler`].
+///
+/// Requests to `path` are passed to `handler`. This is useful to configure the
+/// handler before it's served, e.g. to enable [inline replies].
+///
+/// [inline replies]: WebhookHandler::inline_replies
+///
+/// ## See also
+///
+/// [`warp_to_filter`] for a version which also creates the handler.
+pub fn warp_from_handler(path: &str, handler: WebhookHandler) -> BoxedFilter<(Response,)> {
+    let path: Arc<str> = path.into();
+
+    warp::post()
+        .and(warp::path::full())
+        .and_then(move |full_path: FullPath| {
+            let matches = full_path.as_str() == &*path;
+            async move {
+                if matches {
+                    Ok::<_, warp::Rejection>(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .then(move |headers: HeaderMap, body: Bytes| {
+            let handler = handler.clone();
+            async move {
+                let secret_token = headers.get(SECRET_TOKEN_HEADER).map(|value| value.as_bytes());
+                let Ok(body) = std::str::from_utf8(&body) else {
+                    return StatusCode::BAD_REQUEST.into_response();
+                };
+
+                let response = handler.handle(secret_token, body).await;
+                let status = StatusCode::from_u16(response.status())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                match response.body() {
+                    Some(body) => {
+                        let reply = warp::reply::with_header(
+                            body.to_owned(),
+                            "content-type",
+                            "application/json",
+                        );
+                        warp::reply::with_status(reply, status).into_response()
+                    }
+                    None => status.into_response(),
+                }
+            }
+        })
+        .boxed()
+}
@@ -0,0 +1,305 @@
+use futures::TryStreamExt;
+use std::{
+    fmt::{Debug, Display},
+    fs,
+    sync::Arc,
+    time::Duration,
+};
+use teloxide::{
+    dispatching::{
+        data::{ChatData, UserData},
+        dedup::DedupStorage,
+        dialogue::{
+            DialogueKey, EnumerableStorage, FileStorage, FileStorageError, Serializer, Storage,
+            VersionedDialogue, VersionedStorage,
+        },
+        jobs::{Job, JobStorage},
+    },
+    types::{ChatId, MessageId, ThreadId, UpdateId, UserId},
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_file_json() {
+    fs::create_dir("./test_file_db1").unwrap();
+    let storage = FileStorage::open(
+        "./test_file_db1/storage",
+        teloxide::dispatching::dialogue::serializer::Json,
+    )
+    .await
+    .unwrap();
+    test_file(Arc::clone(&storage)).await;
+    test_data_names(storage).await;
+    fs::remove_dir_all("./test_file_db1").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_file_bincode() {
+    fs::create_dir("./test_file_db2").unwrap();
+    let storage = FileStorage::open(
+        "./test_file_db2/storage",
+        teloxide::dispatching::dialogue::serializer::Bincode,
+    )
+    .await
+    .unwrap();
+    test_file(storage).await;
+    fs::remove_dir_all("./test_file_db2").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_file_cbor() {
+    fs::create_dir("./test_file_db3").unwrap();
+    let storage = FileStorage::open(
+        "./test_file_db3/storage",
+        teloxide::dispatching::dialogue::serializer::Cbor,
+    )
+    .await
+    .unwrap();
+    test_file(storage).await;
+    fs::remove_dir_all("./test_file_db3").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(feature = "encrypted-serializer")]
+async fn test_file_encrypted() {
+    use teloxide::dispatching::dialogue::serializer::{Encrypted, EncryptionKey, Json};
+
+    fs::create_dir("./test_file_db5").unwrap();
+    let storage = FileStorage::open(
+        "./test_file_db5/storage",
+        Encrypted::new(Json, EncryptionKey::new(1, &[7; 32])),
+    )
+    .await
+    .unwrap();
+    test_file(storage).await;
+    fs::remove_dir_all("./test_file_db5").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_file_migrating() {
+    use teloxide::dispatching::dialogue::serializer::{FallbackPolicy, Json, Migrating};
+
+    fs::create_dir("./test_file_db4").unwrap();
+    let path = "./test_file_db4/storage";
+
+    // States written before the schema was versioned.
+    let storage = FileStorage::open(path, Json).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(1), "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(2), "DEF".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(3), 123).await.unwrap();
+    drop(storage);
+
+    let serializer = Migrating::new(Json, 1)
+        .migration(0, |name: String| (name, 0))
+        .fallback(FallbackPolicy::Reset);
+    let storage = FileStorage::open(path, serializer).await.unwrap();
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(),
+        Some(("ABC".to_owned(), 0))
+    );
+
+    Arc::clone(&storage).update_dialogue(ChatId(2), ("XYZ".to_owned(), 1)).await.unwrap();
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(ChatId(2)).await.unwrap(),
+        Some(("XYZ".to_owned(), 1))
+    );
+
+    // `123` cannot be migrated to `(String, u8)`, so it's reset.
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(ChatId(3)).await.unwrap(),
+        Some((String::new(), 0))
+    );
+
+    fs::remove_dir_all("./test_file_db4").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_file_dialogue_key() {
+    fs::create_dir("./test_file_db6").unwrap();
+    let storage: Arc<FileStorage<_, DialogueKey>> = FileStorage::open(
+        "./test_file_db6/storage",
+        teloxide::dispatching::dialogue::serializer::Json,
+    )
+    .await
+    .unwrap();
+
+    let chat = DialogueKey::Chat(ChatId(-100));
+    let user = DialogueKey::ChatUser(ChatId(-100), UserId(1));
+    let thread = DialogueKey::Thread(ChatId(-100), ThreadId(MessageId(7)));
+
+    Arc::clone(&storage).update_dialogue(chat.clone(), "chat".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(user.clone(), "user".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(thread.clone(), "thread".to_owned()).await.unwrap();
+
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(chat.clone()).await.unwrap(),
+        Some("chat".to_owned())
+    );
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(user.clone()).await.unwrap(),
+        Some("user".to_owned())
+    );
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(thread.clone()).await.unwrap(),
+        Some("thread".to_owned())
+    );
+
+    let mut dialogues: Vec<(DialogueKey, Dialogue)> =
+        Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+    dialogues.sort_by_key(|(_, d)| d.clone());
+    assert_eq!(
+        dialogues,
+        [(chat, "chat".to_owned()), (thread, "thread".to_owned()), (user, "user".to_owned())]
+    );
+
+    <FileStorage<_, _> as EnumerableStorage<Dialogue, _>>::clear_all(Arc::clone(&storage))
+        .await
+        .unwrap();
+    assert_eq!(
+        <FileStorage<_, _> as EnumerableStorage<Dialogue, _>>::count_dialogues(storage)
+            .await
+            .unwrap(),
+        0
+    );
+    fs::remove_dir_all("./test_file_db6").unwrap();
+}
+
+type Dialogue = String;
+
+macro_rules! test_dialogues {
+    ($storage:expr, $_0:expr, $_1:expr, $_2:expr) => {
+        assert_eq!(Arc::clone(&$storage).get_dialogue(ChatId(1)).await.unwrap(), $_0);
+        assert_eq!(Arc::clone(&$storage).get_dialogue(ChatId(11)).await.unwrap(), $_1);
+        assert_eq!(Arc::clone(&$storage).get_dialogue(ChatId(256)).await.unwrap(), $_2);
+    };
+}
+
+async fn test_file<S>(storage: Arc<FileStorage<S>>)
+where
+    S: Send + Sync + Serializer<Dialogue> + 'static,
+    <S as Serializer<Dialogue>>::Error: Debug + Display,
+{
+    test_dialogues!(storage, None, None, None);
+
+    Arc::clone(&storage).update_dialogue(ChatId(1), "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(11), "DEF".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(256), "GHI".to_owned()).await.unwrap();
+
+    test_dialogues!(
+        storage,
+        Some("ABC".to_owned()),
+        Some("DEF".to_owned()),
+        Some("GHI".to_owned())
+    );
+
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+    Arc::clone(&storage).remove_dialogue(ChatId(11)).await.unwrap();
+    Arc::clone(&storage).remove_dialogue(ChatId(256)).await.unwrap();
+
+    test_dialogues!(storage, None, None, None);
+
+    // Check that an expired dialogue is treated as absent.
+    Arc::clone(&storage)
+        .update_dialogue_with_ttl(ChatId(1), "JKL".to_owned(), Duration::from_millis(500))
+        .await
+        .unwrap();
+    test_dialogues!(storage, Some("JKL".to_owned()), None, None);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    test_dialogues!(storage, None, None, None);
+
+    // Check that concurrent modifications are detected.
+    let v1 = Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "ABC".to_owned(), None)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "DEF".to_owned(), None)
+            .await
+            .unwrap_err(),
+        FileStorageError::VersionConflict
+    ));
+    Arc::clone(&storage).update_dialogue(ChatId(1), "GHI".to_owned()).await.unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "JKL".to_owned(), Some(v1))
+            .await
+            .unwrap_err(),
+        FileStorageError::VersionConflict
+    ));
+    let VersionedDialogue { dialogue, version } =
+        Arc::clone(&storage).get_dialogue_versioned(ChatId(1)).await.unwrap().unwrap();
+    assert_eq!(dialogue, "GHI");
+    Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "MNO".to_owned(), Some(version))
+        .await
+        .unwrap();
+    test_dialogues!(storage, Some("MNO".to_owned()), None, None);
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+
+    // Check that dialogues can be enumerated.
+    for i in 0..250 {
+        Arc::clone(&storage).update_dialogue(ChatId(i), i.to_string()).await.unwrap();
+    }
+    let count =
+        <FileStorage<S> as EnumerableStorage<Dialogue>>::count_dialogues(Arc::clone(&storage))
+            .await
+            .unwrap();
+    assert_eq!(count, 250);
+    let mut dialogues: Vec<(ChatId, Dialogue)> =
+        Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+    dialogues.sort();
+    dialogues.dedup();
+    assert_eq!(dialogues, (0..250).map(|i| (ChatId(i), i.to_string())).collect::<Vec<_>>());
+    <FileStorage<S> as EnumerableStorage<Dialogue>>::clear_all(Arc::clone(&storage)).await.unwrap();
+    test_dialogues!(storage, None, None, None);
+
+    // Check that a try to remove a non-existing dialogue results in an error.
+    assert!(matches!(
+        Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap_err(),
+        FileStorageError::DialogueNotFound
+    ));
+
+    // Per-user and per-chat data is kept separately from dialogues.
+    let user = UserData::<Dialogue, _>::new(Arc::clone(&storage), "language", UserId(1));
+    let chat = ChatData::<Dialogue, _>::new(Arc::clone(&storage), "language", ChatId(1));
+    assert_eq!(user.get().await.unwrap(), None);
+    user.set("en".to_owned()).await.unwrap();
+    chat.update(|lang| lang + "uk").await.unwrap();
+    assert_eq!(user.get().await.unwrap(), Some("en".to_owned()));
+    assert_eq!(chat.get().await.unwrap(), Some("uk".to_owned()));
+    test_dialogues!(storage, None, None, None);
+    user.remove().await.unwrap();
+    chat.remove().await.unwrap();
+    assert_eq!(user.get().await.unwrap(), None);
+
+    // Check that update IDs are deduplicated, including when old ones are
+    // pruned (every 1024th update).
+    for id in [1023, 1024] {
+        assert!(Arc::clone(&storage).mark_seen(UserId(1), UpdateId(id)).await.unwrap());
+        assert!(!Arc::clone(&storage).mark_seen(UserId(1), UpdateId(id)).await.unwrap());
+    }
+
+    // Jobs are replaced by ID and decoded back.
+    let job = Job::after("reminder", Duration::from_secs(60)).with_id("job");
+    Arc::clone(&storage).save_job(job.clone()).await.unwrap();
+    let job = job.with_payload(&ChatId(1)).unwrap();
+    Arc::clone(&storage).save_job(job.clone()).await.unwrap();
+    assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap(), [job]);
+    Arc::clone(&storage).remove_job("job".to_owned()).await.unwrap();
+    assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap(), []);
+}
+
+async fn test_data_names(
+    storage: Arc<FileStorage<teloxide::dispatching::dialogue::serializer::Json>>,
+) {
+    // Data of different types of the same user doesn't collide.
+    let language = UserData::<String, _>::new(Arc::clone(&storage), "language", UserId(2));
+    let timezone = UserData::<i64, _>::new(Arc::clone(&storage), "timezone", UserId(2));
+    language.set("en".to_owned()).await.unwrap();
+    timezone.set(3).await.unwrap();
+    assert_eq!(language.get().await.unwrap(), Some("en".to_owned()));
+    assert_eq!(timezone.get().await.unwrap(), Some(3));
+    language.remove().await.unwrap();
+    assert_eq!(timezone.get().await.unwrap(), Some(3));
+    timezone.remove().await.unwrap();
+}
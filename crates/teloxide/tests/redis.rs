@@ -1,10 +1,18 @@
+use futures::TryStreamExt;
 use std::{
     fmt::{Debug, Display},
     sync::Arc,
+    time::Duration,
 };
 use teloxide::{
-    dispatching::dialogue::{RedisStorage, RedisStorageError, Serializer, Storage},
-    types::ChatId,
+    dispatching::{
+        data::{ChatData, UserData},
+        dialogue::{
+            DialogueKey, EnumerableStorage, RedisStorage, RedisStorageError, Serializer, Storage,
+            VersionedDialogue, VersionedStorage,
+        },
+    },
+    types::{ChatId, MessageId, ThreadId, UserId},
 };
 
 #[tokio::test]
@@ -16,7 +24,8 @@ async fn test_redis_json() {
     )
     .await
     .unwrap();
-    test_redis(storage).await;
+    test_redis(Arc::clone(&storage)).await;
+    test_data_names(storage).await;
 }
 
 #[tokio::test]
@@ -43,6 +52,56 @@ async fn test_redis_cbor() {
     test_redis(storage).await;
 }
 
+#[tokio::test]
+#[cfg_attr(not(CI_REDIS), ignore)]
+async fn test_redis_dialogue_key() {
+    let storage: Arc<RedisStorage<_, DialogueKey>> = RedisStorage::open(
+        "redis://127.0.0.1:7777",
+        teloxide::dispatching::dialogue::serializer::Json,
+    )
+    .await
+    .unwrap();
+
+    let chat = DialogueKey::Chat(ChatId(-100));
+    let user = DialogueKey::ChatUser(ChatId(-100), UserId(1));
+    let thread = DialogueKey::Thread(ChatId(-100), ThreadId(MessageId(7)));
+
+    Arc::clone(&storage).update_dialogue(chat.clone(), "chat".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(user.clone(), "user".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(thread.clone(), "thread".to_owned()).await.unwrap();
+
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(chat.clone()).await.unwrap(),
+        Some("chat".to_owned())
+    );
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(user.clone()).await.unwrap(),
+        Some("user".to_owned())
+    );
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(thread.clone()).await.unwrap(),
+        Some("thread".to_owned())
+    );
+
+    let mut dialogues: Vec<(DialogueKey, Dialogue)> =
+        Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+    dialogues.sort_by_key(|(_, d)| d.clone());
+    assert_eq!(
+        dialogues,
+        [(chat, "chat".to_owned()), (thread, "thread".to_owned()), (user, "user".to_owned())]
+    );
+
+    <RedisStorage<_, _> as EnumerableStorage<Dialogue, _>>::clear_all(Arc::clone(&storage))
+        .await
+        .unwrap();
+    assert_eq!(
+        <RedisStorage<_, _> as EnumerableStorage<Dialogue, _>>::count_dialogues(storage)
+            .await
+            .unwrap(),
+        0
+    );
+}
+
 type Dialogue = String;
 
 macro_rules! test_dialogues {
@@ -77,9 +136,136 @@ where
 
     test_dialogues!(storage, None, None, None);
 
+    // Check that an expired dialogue is treated as absent.
+    Arc::clone(&storage)
+        .update_dialogue_with_ttl(ChatId(1), "JKL".to_owned(), Duration::from_millis(500))
+        .await
+        .unwrap();
+    test_dialogues!(storage, Some("JKL".to_owned()), None, None);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    test_dialogues!(storage, None, None, None);
+
+    // Check that concurrent modifications are detected.
+    let v1 = Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "ABC".to_owned(), None)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "DEF".to_owned(), None)
+            .await
+            .unwrap_err(),
+        RedisStorageError::VersionConflict
+    ));
+    Arc::clone(&storage).update_dialogue(ChatId(1), "GHI".to_owned()).await.unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "JKL".to_owned(), Some(v1))
+            .await
+            .unwrap_err(),
+        RedisStorageError::VersionConflict
+    ));
+    let VersionedDialogue { dialogue, version } =
+        Arc::clone(&storage).get_dialogue_versioned(ChatId(1)).await.unwrap().unwrap();
+    assert_eq!(dialogue, "GHI");
+    Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "MNO".to_owned(), Some(version))
+        .await
+        .unwrap();
+    test_dialogues!(storage, Some("MNO".to_owned()), None, None);
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+
+    // Check that a dialogue created again after removal doesn't reuse an old
+    // version.
+    let v1 = Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "PQR".to_owned(), None)
+        .await
+        .unwrap();
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+    Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "STU".to_owned(), None)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "VWX".to_owned(), Some(v1))
+            .await
+            .unwrap_err(),
+        RedisStorageError::VersionConflict
+    ));
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+
+    // The same goes for a dialogue created again after expiration.
+    Arc::clone(&storage)
+        .update_dialogue_with_ttl(ChatId(1), "ABC".to_owned(), Duration::from_millis(500))
+        .await
+        .unwrap();
+    let VersionedDialogue { version: v1, .. } =
+        Arc::clone(&storage).get_dialogue_versioned(ChatId(1)).await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "DEF".to_owned(), None)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "GHI".to_owned(), Some(v1))
+            .await
+            .unwrap_err(),
+        RedisStorageError::VersionConflict
+    ));
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+
+    // Check that dialogues can be enumerated.
+    for i in 0..250 {
+        Arc::clone(&storage).update_dialogue(ChatId(i), i.to_string()).await.unwrap();
+    }
+    let count =
+        <RedisStorage<S> as EnumerableStorage<Dialogue>>::count_dialogues(Arc::clone(&storage))
+            .await
+            .unwrap();
+    assert_eq!(count, 250);
+    let mut dialogues: Vec<(ChatId, Dialogue)> =
+        Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+    dialogues.sort();
+    dialogues.dedup();
+    assert_eq!(dialogues, (0..250).map(|i| (ChatId(i), i.to_string())).collect::<Vec<_>>());
+    <RedisStorage<S> as EnumerableStorage<Dialogue>>::clear_all(Arc::clone(&storage))
+        .await
+        .unwrap();
+    test_dialogues!(storage, None, None, None);
+
     // Check that a try to remove a non-existing dialogue results in an error.
     assert!(matches!(
         Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap_err(),
         RedisStorageError::DialogueNotFound
     ));
+
+    // Per-user and per-chat data is kept separately from dialogues.
+    let user = UserData::<Dialogue, _>::new(Arc::clone(&storage), "language", UserId(1));
+    let chat = ChatData::<Dialogue, _>::new(Arc::clone(&storage), "language", ChatId(1));
+    assert_eq!(user.get().await.unwrap(), None);
+    user.set("en".to_owned()).await.unwrap();
+    chat.update(|lang| lang + "uk").await.unwrap();
+    assert_eq!(user.get().await.unwrap(), Some("en".to_owned()));
+    assert_eq!(chat.get().await.unwrap(), Some("uk".to_owned()));
+    test_dialogues!(storage, None, None, None);
+    user.remove().await.unwrap();
+    chat.remove().await.unwrap();
+    assert_eq!(user.get().await.unwrap(), None);
+}
+
+async fn test_data_names(
+    storage: Arc<RedisStorage<teloxide::dispatching::dialogue::serializer::Json>>,
+) {
+    // Data of different types of the same user doesn't collide.
+    let language = UserData::<String, _>::new(Arc::clone(&storage), "language", UserId(2));
+    let timezone = UserData::<i64, _>::new(Arc::clone(&storage), "timezone", UserId(2));
+    language.set("en".to_owned()).await.unwrap();
+    timezone.set(3).await.unwrap();
+    assert_eq!(language.get().await.unwrap(), Some("en".to_owned()));
+    assert_eq!(timezone.get().await.unwrap(), Some(3));
+    language.remove().await.unwrap();
+    assert_eq!(timezone.get().await.unwrap(), Some(3));
+    timezone.remove().await.unwrap();
 }
@@ -1,11 +1,21 @@
+use futures::TryStreamExt;
 use std::{
     fmt::{Debug, Display},
     fs,
     sync::Arc,
+    time::Duration,
 };
 use teloxide::{
-    dispatching::dialogue::{Serializer, SqliteStorage, SqliteStorageError, Storage},
-    types::ChatId,
+    dispatching::{
+        data::{ChatData, UserData},
+        dedup::DedupStorage,
+        dialogue::{
+            DialogueKey, EnumerableStorage, Serializer, SqliteStorage, SqliteStorageError, Storage,
+            VersionedDialogue, VersionedStorage,
+        },
+        jobs::{Job, JobStorage},
+    },
+    types::{ChatId, MessageId, ThreadId, UpdateId, UserId},
 };
 
 #[tokio::test(flavor = "multi_thread")]
@@ -17,7 +27,8 @@ async fn test_sqlite_json() {
     )
     .await
     .unwrap();
-    test_sqlite(storage).await;
+    test_sqlite(Arc::clone(&storage)).await;
+    test_data_names(storage).await;
     fs::remove_dir_all("./test_db1").unwrap();
 }
 
@@ -47,6 +58,119 @@ async fn test_sqlite_cbor() {
     fs::remove_dir_all("./test_db3").unwrap();
 }
 
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(feature = "encrypted-serializer")]
+async fn test_sqlite_encrypted() {
+    use teloxide::dispatching::dialogue::serializer::{Encrypted, EncryptionKey, Json};
+
+    fs::create_dir("./test_db5").unwrap();
+    let storage = SqliteStorage::open(
+        "./test_db5/test_db5.sqlite",
+        Encrypted::new(Json, EncryptionKey::new(1, &[7; 32])),
+    )
+    .await
+    .unwrap();
+    test_sqlite(storage).await;
+    fs::remove_dir_all("./test_db5").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_migrating() {
+    use teloxide::dispatching::dialogue::serializer::{FallbackPolicy, Json, Migrating};
+
+    fs::create_dir("./test_db4").unwrap();
+    let path = "./test_db4/test_db4.sqlite";
+
+    // States written before the schema was versioned.
+    let storage = SqliteStorage::open(path, Json).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(1), "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(2), "DEF".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(3), 123).await.unwrap();
+
+    let serializer = Migrating::new(Json, 1)
+        .migration(0, |name: String| (name, 0))
+        .fallback(FallbackPolicy::Reset);
+    let storage = SqliteStorage::open(path, serializer).await.unwrap();
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(ChatId(1)).await.unwrap(),
+        Some(("ABC".to_owned(), 0))
+    );
+
+    Arc::clone(&storage).update_dialogue(ChatId(2), ("XYZ".to_owned(), 1)).await.unwrap();
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(ChatId(2)).await.unwrap(),
+        Some(("XYZ".to_owned(), 1))
+    );
+
+    // `123` cannot be migrated to `(String, u8)`, so it's reset.
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(ChatId(3)).await.unwrap(),
+        Some((String::new(), 0))
+    );
+
+    fs::remove_dir_all("./test_db4").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_dialogue_key() {
+    use teloxide::dispatching::dialogue::serializer::Json;
+
+    fs::create_dir("./test_db6").unwrap();
+    let path = "./test_db6/test_db6.sqlite";
+
+    // Dialogues indexed by chat IDs are stored the same way as `DialogueKey::Chat`.
+    let storage: Arc<SqliteStorage<_>> = SqliteStorage::open(path, Json).await.unwrap();
+    Arc::clone(&storage).update_dialogue(ChatId(-100), "old".to_owned()).await.unwrap();
+    drop(storage);
+
+    let storage: Arc<SqliteStorage<_, DialogueKey>> =
+        SqliteStorage::open(path, Json).await.unwrap();
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(DialogueKey::Chat(ChatId(-100))).await.unwrap(),
+        Some("old".to_owned())
+    );
+
+    let chat = DialogueKey::Chat(ChatId(-100));
+    let user = DialogueKey::ChatUser(ChatId(-100), UserId(1));
+    let thread = DialogueKey::Thread(ChatId(-100), ThreadId(MessageId(7)));
+
+    Arc::clone(&storage).update_dialogue(chat.clone(), "chat".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(user.clone(), "user".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(thread.clone(), "thread".to_owned()).await.unwrap();
+
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(chat.clone()).await.unwrap(),
+        Some("chat".to_owned())
+    );
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(user.clone()).await.unwrap(),
+        Some("user".to_owned())
+    );
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue(thread.clone()).await.unwrap(),
+        Some("thread".to_owned())
+    );
+
+    let mut dialogues: Vec<(DialogueKey, Dialogue)> =
+        Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+    dialogues.sort_by_key(|(_, d)| d.clone());
+    assert_eq!(
+        dialogues,
+        [(chat, "chat".to_owned()), (thread, "thread".to_owned()), (user, "user".to_owned())]
+    );
+
+    <SqliteStorage<_, _> as EnumerableStorage<Dialogue, _>>::clear_all(Arc::clone(&storage))
+        .await
+        .unwrap();
+    assert_eq!(
+        <SqliteStorage<_, _> as EnumerableStorage<Dialogue, _>>::count_dialogues(storage)
+            .await
+            .unwrap(),
+        0
+    );
+    fs::remove_dir_all("./test_db6").unwrap();
+}
+
 type Dialogue = String;
 
 macro_rules! test_dialogues {
@@ -81,9 +205,111 @@ where
 
     test_dialogues!(storage, None, None, None);
 
+    // Check that an expired dialogue is treated as absent.
+    Arc::clone(&storage)
+        .update_dialogue_with_ttl(ChatId(1), "JKL".to_owned(), Duration::from_millis(500))
+        .await
+        .unwrap();
+    test_dialogues!(storage, Some("JKL".to_owned()), None, None);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    test_dialogues!(storage, None, None, None);
+
+    // Check that concurrent modifications are detected.
+    let v1 = Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "ABC".to_owned(), None)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "DEF".to_owned(), None)
+            .await
+            .unwrap_err(),
+        SqliteStorageError::VersionConflict
+    ));
+    Arc::clone(&storage).update_dialogue(ChatId(1), "GHI".to_owned()).await.unwrap();
+    assert!(matches!(
+        Arc::clone(&storage)
+            .update_dialogue_versioned(ChatId(1), "JKL".to_owned(), Some(v1))
+            .await
+            .unwrap_err(),
+        SqliteStorageError::VersionConflict
+    ));
+    let VersionedDialogue { dialogue, version } =
+        Arc::clone(&storage).get_dialogue_versioned(ChatId(1)).await.unwrap().unwrap();
+    assert_eq!(dialogue, "GHI");
+    Arc::clone(&storage)
+        .update_dialogue_versioned(ChatId(1), "MNO".to_owned(), Some(version))
+        .await
+        .unwrap();
+    test_dialogues!(storage, Some("MNO".to_owned()), None, None);
+    Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap();
+
+    // Check that dialogues can be enumerated.
+    for i in 0..250 {
+        Arc::clone(&storage).update_dialogue(ChatId(i), i.to_string()).await.unwrap();
+    }
+    let count =
+        <SqliteStorage<S> as EnumerableStorage<Dialogue>>::count_dialogues(Arc::clone(&storage))
+            .await
+            .unwrap();
+    assert_eq!(count, 250);
+    let mut dialogues: Vec<(ChatId, Dialogue)> =
+        Arc::clone(&storage).list_dialogues().try_collect().await.unwrap();
+    dialogues.sort();
+    dialogues.dedup();
+    assert_eq!(dialogues, (0..250).map(|i| (ChatId(i), i.to_string())).collect::<Vec<_>>());
+    <SqliteStorage<S> as EnumerableStorage<Dialogue>>::clear_all(Arc::clone(&storage))
+        .await
+        .unwrap();
+    test_dialogues!(storage, None, None, None);
+
     // Check that a try to remove a non-existing dialogue results in an error.
     assert!(matches!(
         Arc::clone(&storage).remove_dialogue(ChatId(1)).await.unwrap_err(),
         SqliteStorageError::DialogueNotFound
     ));
+
+    // Per-user and per-chat data is kept separately from dialogues.
+    let user = UserData::<Dialogue, _>::new(Arc::clone(&storage), "language", UserId(1));
+    let chat = ChatData::<Dialogue, _>::new(Arc::clone(&storage), "language", ChatId(1));
+    assert_eq!(user.get().await.unwrap(), None);
+    user.set("en".to_owned()).await.unwrap();
+    chat.update(|lang| lang + "uk").await.unwrap();
+    assert_eq!(user.get().await.unwrap(), Some("en".to_owned()));
+    assert_eq!(chat.get().await.unwrap(), Some("uk".to_owned()));
+    test_dialogues!(storage, None, None, None);
+    user.remove().await.unwrap();
+    chat.remove().await.unwrap();
+    assert_eq!(user.get().await.unwrap(), None);
+
+    // Check that update IDs are deduplicated, including when old ones are
+    // pruned (every 1024th update).
+    for id in [1023, 1024] {
+        assert!(Arc::clone(&storage).mark_seen(UserId(1), UpdateId(id)).await.unwrap());
+        assert!(!Arc::clone(&storage).mark_seen(UserId(1), UpdateId(id)).await.unwrap());
+    }
+
+    // Jobs are replaced by ID and decoded back.
+    let job = Job::after("reminder", Duration::from_secs(60)).with_id("job");
+    Arc::clone(&storage).save_job(job.clone()).await.unwrap();
+    let job = job.with_payload(&ChatId(1)).unwrap();
+    Arc::clone(&storage).save_job(job.clone()).await.unwrap();
+    assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap(), [job]);
+    Arc::clone(&storage).remove_job("job".to_owned()).await.unwrap();
+    assert_eq!(Arc::clone(&storage).load_jobs().await.unwrap(), []);
+}
+
+async fn test_data_names(
+    storage: Arc<SqliteStorage<teloxide::dispatching::dialogue::serializer::Json>>,
+) {
+    // Data of different types of the same user doesn't collide.
+    let language = UserData::<String, _>::new(Arc::clone(&storage), "language", UserId(2));
+    let timezone = UserData::<i64, _>::new(Arc::clone(&storage), "timezone", UserId(2));
+    language.set("en".to_owned()).await.unwrap();
+    timezone.set(3).await.unwrap();
+    assert_eq!(language.get().await.unwrap(), Some("en".to_owned()));
+    assert_eq!(timezone.get().await.unwrap(), Some(3));
+    language.remove().await.unwrap();
+    assert_eq!(timezone.get().await.unwrap(), Some(3));
+    timezone.remove().await.unwrap();
 }
@@ -0,0 +1,124 @@
+#![cfg(feature = "webhooks-axum-tls")]
+
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+use teloxide::update_listeners::webhooks::{TlsConfig, TlsError};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+const DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_webhook_tls() {
+    let dir = Path::new("./test_webhook_tls");
+    fs::create_dir_all(dir).unwrap();
+    copy_pair("first", dir);
+
+    let tls = TlsConfig::from_pem_files(dir.join("cert.pem"), dir.join("key.pem"))
+        .reload_every(Duration::from_millis(50));
+    let listener = tls.listener(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap();
+    let address = axum::serve::Listener::local_addr(&listener).unwrap();
+    let router = axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    assert_eq!(served_certificate(address).await, certificate("first"));
+
+    // Let the modification time change even on file systems with a coarse one.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    copy_pair("second", dir);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(served_certificate(address).await, certificate("second"));
+
+    // An invalid key is not picked up.
+    fs::write(dir.join("key.pem"), "garbage").unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(served_certificate(address).await, certificate("second"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_webhook_tls_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tls = TlsConfig::from_pem_files(format!("{DIR}/first.crt"), format!("{DIR}/missing.key"));
+    assert!(matches!(tls.listener(listener), Err(TlsError::Read { .. })));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tls = TlsConfig::from_pem_files(format!("{DIR}/first.key"), format!("{DIR}/first.key"));
+    assert!(matches!(tls.listener(listener), Err(TlsError::NoCertificates(_))));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tls = TlsConfig::from_pem_files(format!("{DIR}/first.crt"), format!("{DIR}/first.crt"));
+    assert!(matches!(tls.listener(listener), Err(TlsError::NoPrivateKey(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(feature = "testing")]
+async fn test_webhook_axum_tls() {
+    use teloxide::{
+        testing::MockBot,
+        update_listeners::{
+            webhooks::{self, Options},
+            UpdateListener,
+        },
+    };
+
+    let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let url = format!("https://localhost:{}/webhook", address.port()).parse().unwrap();
+    let tls = TlsConfig::from_pem_files(format!("{DIR}/first.crt"), format!("{DIR}/first.key"))
+        .self_signed();
+
+    let bot = MockBot::new();
+    let mut listener =
+        webhooks::axum_tls(bot.clone(), Options::new(address, url), tls).await.unwrap();
+    let stop_token = listener.stop_token();
+
+    // The self-signed certificate is uploaded along with the webhook.
+    let sent = bot.sent();
+    assert_eq!(sent[0].method, "SetWebhook");
+    assert!(!sent[0].payload["certificate"].is_null());
+
+    // The server is started in the background.
+    while TcpStream::connect(address).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(served_certificate(address).await, certificate("first"));
+
+    stop_token.stop();
+}
+
+fn copy_pair(name: &str, dir: &Path) {
+    fs::copy(format!("{DIR}/{name}.crt"), dir.join("cert.pem")).unwrap();
+    fs::copy(format!("{DIR}/{name}.key"), dir.join("key.pem")).unwrap();
+}
+
+fn certificate(name: &str) -> CertificateDer<'static> {
+    let pem = fs::read(format!("{DIR}/{name}.crt")).unwrap();
+    let certificate = rustls_pemfile::certs(&mut &pem[..]).next().unwrap().unwrap();
+    certificate
+}
+
+/// Connects to `address`, trusting both test certificates, and returns the
+/// certificate presented by the server.
+async fn served_certificate(address: std::net::SocketAddr) -> CertificateDer<'static> {
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate("first")).unwrap();
+    roots.add(certificate("second")).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    let stream = TcpStream::connect(address).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    stream.get_ref().1.peer_certificates().unwrap()[0].clone().into_owned()
+}